@@ -324,6 +324,7 @@ vim.keymap.set("n", "<Leader>ts", ":FloatermNew ssh justin@bos-webdev1<CR>", { d
 -- This application will then manage parsing & fetching when needed
 
 ---- Grep In Background Process - (Fix) The escape characters are wonky need 3 \\\ to escape \ I think it's lua string? Or maybe std_in
+local rgExe = './rust/search-history/target/debug/search-history' -- A complete rg unless started with --server
 local queryText = ""
 local results = {}
 local grepNotif = false
@@ -331,7 +332,7 @@ local g_notif_opts = { title = "Grep Proccess", timeout = 3000, render = "wrappe
 function LiveGrep(query, flag) --I'm leaving the idea of flags here because it could be handy
     results = {} -- Reset results on a new search
     queryText = query -- Save the query text as an identifier to each search
-    local job_id = vim.fn.jobstart("rg --vimgrep --glob '!*.min.{js,css,js.map,css.map}' --glob '!public/js/jquery*' --glob '!wordpress/wp-includes/*' --glob '!wordpress/wp-admin/*' --glob '!wordpress/wp-content/plugins/*' --glob '!migrations/*/seeds/*' --max-filesize 295K -o --color=never --no-heading --with-filename --line-number --column --smart-case --no-binary --no-search-zip -uu " .. query .. " ./", {
+    local job_id = vim.fn.jobstart(rgExe .. " --vimgrep --glob '!*.min.{js,css,js.map,css.map}' --glob '!public/js/jquery*' --glob '!wordpress/wp-includes/*' --glob '!wordpress/wp-admin/*' --glob '!wordpress/wp-content/plugins/*' --glob '!migrations/*/seeds/*' --max-filesize 295K -o --color=never --no-heading --with-filename --line-number --column --smart-case --no-binary --no-search-zip -uu " .. query .. " ./", {
         on_exit = function(job_id, code, event)
             g_notif_opts['timeout'] = 3000 -- Reset timeout on finish
             if grepNotif ~= false then
@@ -645,8 +646,8 @@ local debugFn = function(job_id, data, event)
     vim.pretty_print({jobId = job_id, data = data, event = event})
 end
 
-local rgExe = './rust/search-history/target/debug/search-history'
 local rgArgs = {
+    "--server", -- Without it the binary runs as a plain rg
     "--vimgrep",
     "-o",
     "-uu",
@@ -674,13 +675,294 @@ local rgArgs = {
 --    on_stderr = debugFn
 --})
 
-function rustGrep(search)
-    vim.rpcnotify(searchHistoryJobId, 'search', search)
+rustGrepOptions = nil -- e.g. { case = 'smart', hidden = true, glob = { '!*.min.js' } }, applied to every RustGrep
+function rustGrep(search, ...)
+    local paths = { ... } -- Any extra arguments are the roots to search, the server defaults to ./
+    if vim.tbl_isempty(paths) then
+        paths = nil
+    end
+    rustGrepRequest({ pattern = search, paths = paths, options = rustGrepOptions })
+end
+function rustGrepArgs(line)
+    -- The whole line as typed, e.g. -t php -w get_option wp-content, the server splits and parses it like rg
+    rustGrepRequest({ args = line, options = rustGrepOptions })
+end
+function rustGrepRequest(request)
+    local ok, result = pcall(vim.rpcrequest, searchHistoryJobId, 'search', request)
+    if not ok then
+        vim.notify(vim.inspect(result), vim.log.levels.ERROR) -- Structured { kind, message } error from the server
+        return
+    end
+    vim.notify("Grep Process Searching (" .. result.id .. ")", vim.log.levels.INFO)
+end
+function rustGrepCancel(id)
+    pcall(vim.rpcrequest, searchHistoryJobId, 'cancel', { id = id })
+end
+function rustGrepCombine(op, ...)
+    -- e.g. :RustGrepCombine difference search-1 search-2, files in search-1 that aren't in search-2
+    local ok, result = pcall(vim.rpcrequest, searchHistoryJobId, 'combine', { op = op, ids = { ... } })
+    if not ok then
+        vim.notify(vim.inspect(result), vim.log.levels.ERROR)
+        return
+    end
+    vim.notify("Grep Combined: " .. result.count .. " (" .. result.id .. ")", vim.log.levels.INFO)
+end
+function rustGrepRefine(parent, pattern, scope)
+    -- Narrows a stored search, e.g. :RustGrepRefine search-1 meta_query, scope 'files' re-reads the parent's files
+    local ok, result = pcall(vim.rpcrequest, searchHistoryJobId, 'refine', { parent = parent, pattern = pattern, scope = scope, options = rustGrepOptions })
+    if not ok then
+        vim.notify(vim.inspect(result), vim.log.levels.ERROR)
+        return
+    end
+    if result.status == 'done' then
+        vim.notify("Grep Refined: " .. result.count .. " (" .. result.id .. ")", vim.log.levels.INFO)
+    else
+        vim.notify("Grep Process Refining (" .. result.id .. ")", vim.log.levels.INFO)
+    end
+end
+-- The server fires User autocmds while a search it started in the background runs and when it's over
+local rustGrepResults = {}
+local rustGrepNotif = false
+local rg_notif_opts = { title = "Rust Grep", timeout = 3000, render = "wrapped-compact", hide_from_history = true, on_close = function() rustGrepNotif = false end }
+vim.api.nvim_create_autocmd('User', {
+    pattern = 'SearchHistoryBatch',
+    callback = function(ev)
+        if ev.data.generation ~= nil then
+            return -- Live searches fill their own picker, see rustGrepLive
+        end
+        rustGrepResults[ev.data.id] = rustGrepResults[ev.data.id] or {}
+        for _, result in ipairs(ev.data.results) do
+            table.insert(rustGrepResults[ev.data.id], result.path .. ":" .. result.line .. ":" .. result.text)
+        end
+    end,
+})
+vim.api.nvim_create_autocmd('User', {
+    pattern = 'SearchHistoryProgress',
+    callback = function(ev)
+        if ev.data.done or ev.data.generation ~= nil then
+            return
+        end
+        rg_notif_opts["replace"] = nil
+        if rustGrepNotif ~= false then
+            rg_notif_opts["replace"] = rustGrepNotif
+        end
+        local msg = ev.data.matches .. " matches in " .. ev.data.files_scanned .. " files (" .. ev.data.elapsed_ms .. "ms)"
+        rustGrepNotif = require('notify')(msg, "info", rg_notif_opts)
+    end,
+})
+vim.api.nvim_create_autocmd('User', {
+    pattern = 'SearchHistoryDone',
+    callback = function(ev)
+        if ev.data.generation ~= nil then
+            return
+        end
+        if ev.data.error ~= nil then
+            vim.notify(vim.inspect(ev.data.error), vim.log.levels.ERROR)
+        elseif ev.data.diff ~= nil then
+            local diff = ev.data.diff
+            vim.notify("Grep Refreshed: " .. ev.data.count .. ", +" .. #diff.added .. " -" .. #diff.removed .. " moved " .. #diff.moved .. " (" .. ev.data.id .. ")", vim.log.levels.INFO)
+        elseif ev.data.complete then
+            vim.notify("Grep Results Ready: " .. ev.data.count .. " (" .. ev.data.id .. ")", vim.log.levels.INFO)
+        else
+            vim.notify("Grep Cancelled: " .. ev.data.count .. " partial results (" .. ev.data.id .. ")", vim.log.levels.WARN)
+        end
+    end,
+})
+vim.api.nvim_create_autocmd('User', {
+    pattern = 'SearchHistoryChanged',
+    callback = function(ev)
+        -- Files a stored search covers changed on disk and were searched again, only with the watch feature
+        rustGrepResults[ev.data.id] = nil
+        vim.notify("Grep Updated: " .. ev.data.count .. " after " .. #ev.data.paths .. " files changed (" .. ev.data.id .. ")", vim.log.levels.INFO)
+    end,
+})
+-- Live grep through the server, every keystroke goes to one live session and only the latest pattern's results come back
+local rustGrepLiveState = { generation = 0, results = {}, picker = nil }
+local function rustGrepLiveRefresh()
+    local state = rustGrepLiveState
+    if state.picker == nil then
+        return
+    end
+    state.picker:refresh(finders.new_table({ results = state.results, entry_maker = make_entry.gen_from_vimgrep({}) }), { reset_prompt = false })
+end
+vim.api.nvim_create_autocmd('User', {
+    pattern = 'SearchHistoryBatch',
+    callback = function(ev)
+        local state = rustGrepLiveState
+        if ev.data.id ~= 'live:telescope-live' or ev.data.generation ~= state.generation then
+            return -- Anything from a pattern since typed over is dropped
+        end
+        for _, result in ipairs(ev.data.results) do
+            local col = result.submatches[1] and result.submatches[1].start.bytes + 1 or 1
+            table.insert(state.results, result.path .. ":" .. result.line .. ":" .. col .. ":" .. result.text)
+        end
+        rustGrepLiveRefresh()
+    end,
+})
+function rustGrepLive()
+    rustGrepLiveState = { generation = 0, results = {}, picker = nil }
+    local picker = pickers.new({}, {
+        prompt_title = "Rust Live Grep",
+        finder = finders.new_table({ results = {} }),
+        sorter = require("telescope.sorters").empty(),
+        previewer = conf.grep_previewer({}),
+        on_input_filter_cb = function(prompt)
+            local ok, result = pcall(vim.rpcrequest, searchHistoryJobId, 'live', { session = 'telescope-live', pattern = prompt })
+            if ok then
+                rustGrepLiveState.generation = result.generation
+                rustGrepLiveState.results = {}
+                if prompt == "" then
+                    rustGrepLiveRefresh()
+                end
+            end
+            return { prompt = prompt }
+        end,
+    })
+    rustGrepLiveState.picker = picker
+    picker:find()
+end
+function rustGrepRefresh(id)
+    -- Re-runs a stored search as it was made, unchanged files are skipped and the Done event has the diff
+    rustGrepResults[id] = nil
+    local ok, result = pcall(vim.rpcrequest, searchHistoryJobId, 'refresh', { id = id })
+    if not ok then
+        vim.notify(vim.inspect(result), vim.log.levels.ERROR)
+        return
+    end
+    vim.notify("Grep Process Refreshing (" .. result.id .. ")", vim.log.levels.INFO)
+end
+function rustGrepRanked(id, limit)
+    -- Best results first, scored against the current file, each line ends with why it scored what it did
+    local ok, result = pcall(vim.rpcrequest, searchHistoryJobId, 'query', { id = id, limit = tonumber(limit) or 20, current_file = vim.fn.expand('%:p'), explain = true })
+    if not ok then
+        vim.notify(vim.inspect(result), vim.log.levels.ERROR)
+        return
+    end
+    local lines = {}
+    for _, r in ipairs(result.results) do
+        local why = {}
+        for _, s in ipairs(r.why) do
+            table.insert(why, s.signal .. " " .. s.points)
+        end
+        table.insert(lines, r.score .. "  " .. r.path .. ":" .. r.line .. ":" .. r.text .. "  (" .. table.concat(why, ", ") .. ")")
+    end
+    vim.notify(table.concat(lines, "\n"), vim.log.levels.INFO, { title = "Grep Ranked (" .. id .. ")" })
+end
+function rustGrepQuickfix(id, glob)
+    -- The server sets the list and opens it itself, e.g. :RustGrepQf search-1 *.php
+    local ok, result = pcall(vim.rpcrequest, searchHistoryJobId, 'quickfix', { id = id, glob = glob, open = true })
+    if not ok then
+        vim.notify(vim.inspect(result), vim.log.levels.ERROR)
+    end
+end
+function rustGrepLoclist(id, glob)
+    local ok, result = pcall(vim.rpcrequest, searchHistoryJobId, 'quickfix', { id = id, glob = glob, list = 'location', window = vim.api.nvim_get_current_win(), open = true })
+    if not ok then
+        vim.notify(vim.inspect(result), vim.log.levels.ERROR)
+    end
+end
+local rustGrepJournals = {}
+function rustGrepReplace(id, replacement)
+    -- Shows every hunk first, then writes the ones picked, e.g. :RustGrepReplace search-1 ${1}Id
+    local ok, preview = pcall(vim.rpcrequest, searchHistoryJobId, 'replace', { id = id, replacement = replacement or "" })
+    if not ok then
+        vim.notify(vim.inspect(preview), vim.log.levels.ERROR)
+        return
+    end
+    if #preview.hunks == 0 then
+        vim.notify("Grep Replace: nothing to replace (" .. id .. ")", vim.log.levels.INFO)
+        return
+    end
+    local lines = {}
+    for _, h in ipairs(preview.hunks) do
+        table.insert(lines, h.hunk .. "  " .. h.path .. ":" .. h.line .. "\n  - " .. h.before .. "\n  + " .. h.after)
+    end
+    print(table.concat(lines, "\n"))
+    local picked = vim.fn.input(#preview.hunks .. " hunks in " .. preview.files .. " files, apply (all / numbers / empty to cancel): ")
+    local hunks = nil
+    if picked == "" then
+        return
+    elseif picked ~= "all" then
+        hunks = {}
+        for n in picked:gmatch("%d+") do
+            table.insert(hunks, tonumber(n))
+        end
+    end
+    local ok, result = pcall(vim.rpcrequest, searchHistoryJobId, 'replace', { id = id, replacement = replacement or "", hunks = hunks, dry_run = false })
+    if not ok then
+        vim.notify(vim.inspect(result), vim.log.levels.ERROR)
+        return
+    end
+    table.insert(rustGrepJournals, result.journal)
+    vim.cmd('checktime')
+    vim.notify("Grep Replaced: " .. result.hunks .. " hunks in " .. #result.files .. " files, undo with :RustGrepRevert (" .. result.journal .. ")", vim.log.levels.INFO)
+end
+function rustGrepRevert(journal)
+    -- Without a journal id the last replacement is put back
+    journal = journal or table.remove(rustGrepJournals)
+    if journal == nil then
+        vim.notify("Grep Revert: nothing to revert", vim.log.levels.WARN)
+        return
+    end
+    local ok, result = pcall(vim.rpcrequest, searchHistoryJobId, 'revert', { journal = journal })
+    if not ok then
+        vim.notify(vim.inspect(result), vim.log.levels.ERROR)
+        return
+    end
+    vim.cmd('checktime')
+    vim.notify("Grep Reverted: " .. #result.files .. " files (" .. result.journal .. ")", vim.log.levels.INFO)
+end
+function rustGrepStats(id)
+    -- Where a search spent its time, walk and search time point at the disk, collect time at the server
+    local ok, result = pcall(vim.rpcrequest, searchHistoryJobId, 'stats', { id = id })
+    if not ok then
+        vim.notify(vim.inspect(result), vim.log.levels.ERROR)
+        return
+    end
+    local lines = {}
+    if result.memory ~= vim.NIL then
+        table.insert(lines, "memory: " .. math.floor(result.memory.physical / 1048576) .. "MiB")
+    end
+    table.insert(lines, "searches: " .. result.searches.count .. " (" .. math.floor(result.searches.bytes / 1024) .. "KiB, " .. result.searches.running .. " running)")
+    for _, s in ipairs(result.stats) do
+        table.insert(lines, string.format("%s: %.1fms, walk %.1fms, search %.1fms, collect %.1fms, %d searched, skipped %d binary %d too big %d ignored, %d errors",
+            s.id, s.elapsed_ms, s.walk_ms, s.search_ms, s.collect_ms, s.searched, s.skipped.binary, s.skipped.max_filesize, s.skipped.ignored, s.errors))
+    end
+    vim.notify(table.concat(lines, "\n"), vim.log.levels.INFO, { title = "Grep Stats" })
+end
+function rustGrepErrors(id, cause)
+    -- The files a search failed on, e.g. unreadable directories and broken symlinks
+    local ok, result = pcall(vim.rpcrequest, searchHistoryJobId, 'query_errors', { id = id, cause = cause })
+    if not ok then
+        vim.notify(vim.inspect(result), vim.log.levels.ERROR)
+        return
+    end
+    local items = {}
+    for _, err in ipairs(result.errors) do
+        table.insert(items, { filename = err.path, text = err.cause .. ": " .. err.message })
+    end
+    vim.fn.setqflist({}, ' ', { title = "Grep Errors: " .. result.id, items = items })
+    vim.notify("Grep Errors: " .. result.total .. " of " .. result.unfiltered, vim.log.levels.INFO)
+    if #items > 0 then
+        vim.cmd('copen')
+    end
 end
 function close()
     vim.fn.jobclose(searchHistoryJobId)
 end
-vim.cmd('command! -nargs=+ RustGrep lua rustGrep(<f-args>)')
+vim.cmd('command! -nargs=+ RustGrep lua rustGrepArgs(<q-args>)')
 vim.cmd('command! -nargs=+ Close lua close(<f-args>)')
+vim.cmd('command! -nargs=1 RustGrepCancel lua rustGrepCancel(<f-args>)')
+vim.cmd('command! -nargs=+ RustGrepRefine lua rustGrepRefine(<f-args>)')
+vim.cmd('command! -nargs=+ RustGrepCombine lua rustGrepCombine(<f-args>)')
+vim.cmd('command! -nargs=1 RustGrepRefresh lua rustGrepRefresh(<f-args>)')
+vim.cmd('command! -nargs=+ RustGrepRanked lua rustGrepRanked(<f-args>)')
+vim.cmd('command! -nargs=+ RustGrepQf lua rustGrepQuickfix(<f-args>)')
+vim.cmd('command! -nargs=+ RustGrepLoc lua rustGrepLoclist(<f-args>)')
+vim.cmd('command! -nargs=+ RustGrepReplace lua rustGrepReplace(<f-args>)')
+vim.cmd('command! -nargs=? RustGrepRevert lua rustGrepRevert(<f-args>)')
+vim.cmd('command! -nargs=? RustGrepStats lua rustGrepStats(<f-args>)')
+vim.cmd('command! -nargs=+ RustGrepErrors lua rustGrepErrors(<f-args>)')
+vim.cmd('command! RustGrepLive lua rustGrepLive()')
 
 vim.keymap.set('n', '<leader>rg', ":RustGrep ", { desc = "Grep Process (Run in background)", noremap = true, silent = true })
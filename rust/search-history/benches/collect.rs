@@ -0,0 +1,238 @@
+/*!
+This module benchmarks how the parallel search collects results from its
+walker threads, see `src/collect.rs`. It compares the per-thread collectors
+against how `search_parallel` used to collect them: every walker thread took
+the lock on the shared results before each file and held it while building
+the haystack and searching it, so only one file was searched at a time.
+
+Each benchmark walks a synthetic tree with the parallel walker and searches
+every file with the server's own `SearchWorker`. The tree is written to a
+temporary directory and removed again once the benchmarks are done.
+
+Run it with `cargo bench --bench collect`. Each benchmark runs a few times,
+and the fastest and the median run are printed.
+*/
+
+use std::{
+    path::{Path, PathBuf},
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use ignore::{WalkBuilder, WalkState};
+
+// The server's own modules, as they are. Only a few of their items are used
+// here, and their lints are already checked where the server is built.
+#[allow(dead_code, unused_imports, unused_macros, clippy::all)]
+#[macro_use]
+#[path = "../src/messages.rs"]
+mod messages;
+
+#[allow(dead_code, unused_imports, clippy::all)]
+#[path = "../src/collect.rs"]
+mod collect;
+
+#[allow(dead_code, unused_imports, clippy::all)]
+#[path = "../src/haystack.rs"]
+mod haystack;
+
+#[allow(dead_code, unused_imports, clippy::all)]
+#[path = "../src/search.rs"]
+mod search;
+
+use crate::{
+    collect::{Collector, FlushPolicy},
+    haystack::HaystackBuilder,
+    search::{
+        PatternMatcher, SearchResults, SearchWorker, SearchWorkerBuilder,
+    },
+};
+
+const DIRS: usize = 40;
+const FILES_PER_DIR: usize = 100;
+const LINES_PER_FILE: usize = 60;
+/// One in this many files has matches, like most searches of a big tree.
+const MATCHING_EVERY: usize = 20;
+const MATCHES_PER_FILE: usize = 5;
+const NEEDLE: &str = "wp_query";
+/// How many times each benchmark is run, after one run to warm up.
+const RUNS: usize = 10;
+
+fn contents(file: usize) -> Vec<u8> {
+    let mut contents = vec![];
+    for line in 0..LINES_PER_FILE {
+        let matching = file.is_multiple_of(MATCHING_EVERY)
+            && line.is_multiple_of(LINES_PER_FILE / MATCHES_PER_FILE);
+        let word = if matching { NEEDLE } else { "post_meta" };
+        contents.extend_from_slice(
+            format!("    $value = get_{word}({file}, {line});\n").as_bytes(),
+        );
+    }
+    contents
+}
+
+/// The synthetic tree in a temporary directory, which is removed when this
+/// is dropped.
+struct Tree {
+    root: PathBuf,
+}
+
+impl Tree {
+    fn new() -> Tree {
+        let root = std::env::temp_dir().join(format!(
+            "search-history-bench-{}-collect",
+            std::process::id()
+        ));
+        let _ = std::fs::remove_dir_all(&root);
+        for dir in 0..DIRS {
+            let dir_path = root.join(format!("dir{dir}"));
+            std::fs::create_dir_all(&dir_path).unwrap();
+            for i in 0..FILES_PER_DIR {
+                let file = dir * FILES_PER_DIR + i;
+                let path = dir_path.join(format!("file{file}.php"));
+                std::fs::write(path, contents(file)).unwrap();
+            }
+        }
+        Tree { root }
+    }
+}
+
+impl Drop for Tree {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.root);
+    }
+}
+
+/// A search worker for `NEEDLE`, set up the way the server's is.
+fn worker() -> SearchWorker {
+    let matcher = grep::regex::RegexMatcher::new_line_matcher(NEEDLE).unwrap();
+    let searcher =
+        grep::searcher::SearcherBuilder::new().line_number(true).build();
+    SearchWorkerBuilder::new()
+        .build(PatternMatcher::RustRegex(matcher), searcher)
+}
+
+fn threads() -> usize {
+    std::thread::available_parallelism().map_or(2, |n| n.get()).min(12)
+}
+
+/// Walk `root` with the parallel walker, searching every file with a worker
+/// per thread, and call a visitor made by `visitor` for each thread with the
+/// worker and path of every file that had matches.
+fn walk<'s, V>(root: &Path, mut visitor: impl FnMut() -> V)
+where
+    V: FnMut(&mut SearchWorker, String) + Send + 's,
+{
+    let haystack_builder = HaystackBuilder::new();
+    let worker = worker();
+    WalkBuilder::new(root).threads(threads()).build_parallel().run(|| {
+        let haystack_builder = &haystack_builder;
+        let mut worker = worker.clone();
+        let mut visit = visitor();
+        Box::new(move |result| {
+            let Some(haystack) = haystack_builder.build(result.unwrap())
+            else {
+                return WalkState::Continue;
+            };
+            if worker.search(&haystack).unwrap() {
+                visit(&mut worker, haystack.path().to_string_lossy().into());
+            }
+            WalkState::Continue
+        })
+    });
+}
+
+/// The old design: the lock on the shared results is taken before every
+/// file and held while its haystack is built and searched, with every result
+/// cloned into them.
+fn walk_locked(root: &Path) -> usize {
+    let shared = Mutex::new(SearchResults::new());
+    let haystack_builder = HaystackBuilder::new();
+    let worker = worker();
+    WalkBuilder::new(root).threads(threads()).build_parallel().run(|| {
+        let (shared, haystack_builder) = (&shared, &haystack_builder);
+        let mut worker = worker.clone();
+        Box::new(move |result| {
+            let mut shared = shared.lock().unwrap();
+            let Some(haystack) = haystack_builder.build(result.unwrap())
+            else {
+                return WalkState::Continue;
+            };
+            if worker.search(&haystack).unwrap() {
+                let path = haystack.path().to_string_lossy().to_string();
+                for result in worker.get_results().iter() {
+                    let mut result = result.clone();
+                    result.set_file_name(Some(path.clone()));
+                    shared.store_result(result);
+                }
+            }
+            WalkState::Continue
+        })
+    });
+    shared.into_inner().unwrap().len()
+}
+
+/// A collector per thread, which only locks the shared results to flush,
+/// with the results moved out of the worker like `search_parallel` does.
+fn walk_collected(root: &Path, policy: FlushPolicy) -> usize {
+    let shared = Mutex::new(SearchResults::new());
+    walk(root, || {
+        let mut results = Collector::new(&shared, policy);
+        move |worker: &mut SearchWorker, path: String| {
+            let found = std::mem::take(worker.get_results().get_mut());
+            results.add(found.into_iter().map(|mut result| {
+                result.set_file_name(Some(path.clone()));
+                result
+            }));
+            results.tick();
+        }
+    });
+    shared.into_inner().unwrap().len()
+}
+
+/// Run `run` once to warm up and then `RUNS` times, checking that it found
+/// every match, and print the fastest and the median run.
+fn bench(name: &str, mut run: impl FnMut() -> usize) {
+    let expected = DIRS * FILES_PER_DIR / MATCHING_EVERY * MATCHES_PER_FILE;
+    assert_eq!(expected, run());
+    let mut times: Vec<Duration> = (0..RUNS)
+        .map(|_| {
+            let start = Instant::now();
+            assert_eq!(expected, run());
+            start.elapsed()
+        })
+        .collect();
+    times.sort();
+    println!(
+        "{name:<26} fastest {:>10.3?}   median {:>10.3?}",
+        times[0],
+        times[RUNS / 2]
+    );
+}
+
+fn main() {
+    // `cargo bench` passes `--bench`, anything else is a filter on the
+    // benchmarks' names.
+    let filter: Vec<String> = std::env::args()
+        .skip(1)
+        .filter(|arg| !arg.starts_with("--"))
+        .collect();
+    let selected = |name: &str| {
+        filter.is_empty() || filter.iter().any(|f| name.contains(f.as_str()))
+    };
+    let streaming = FlushPolicy::streaming(1000, Duration::from_millis(200));
+
+    let tree = Tree::new();
+    let root = tree.root.as_path();
+    if selected("tree_locked") {
+        bench("tree_locked", || walk_locked(root));
+    }
+    if selected("tree_collected") {
+        bench("tree_collected", || {
+            walk_collected(root, FlushPolicy::at_end())
+        });
+    }
+    if selected("tree_collected_streaming") {
+        bench("tree_collected_streaming", || walk_collected(root, streaming));
+    }
+}
@@ -49,8 +49,8 @@ See the documentation for `WalkBuilder` for many other options.
 use std::path::{Path, PathBuf};
 
 pub use crate::walk::{
-    DirEntry, ParallelVisitor, ParallelVisitorBuilder, Walk, WalkBuilder,
-    WalkParallel, WalkState,
+    DirEntry, ParallelVisitor, ParallelVisitorBuilder, SkipReason, Walk,
+    WalkBuilder, WalkParallel, WalkState,
 };
 
 mod default_types;
@@ -491,6 +491,7 @@ pub struct WalkBuilder {
     threads: usize,
     skip: Option<Arc<Handle>>,
     filter: Option<Filter>,
+    on_skip: Option<OnSkip>,
 }
 
 #[derive(Clone)]
@@ -502,6 +503,19 @@ enum Sorter {
 #[derive(Clone)]
 struct Filter(Arc<dyn Fn(&DirEntry) -> bool + Send + Sync + 'static>);
 
+#[derive(Clone)]
+struct OnSkip(Arc<dyn Fn(&DirEntry, SkipReason) + Send + Sync + 'static>);
+
+/// Why an entry was skipped, as reported to the callback given to
+/// `WalkBuilder::on_skip`.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum SkipReason {
+    /// The entry matched an ignore rule, or is hidden.
+    Ignored,
+    /// The entry is a file bigger than the max filesize.
+    MaxFilesize,
+}
+
 impl std::fmt::Debug for WalkBuilder {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         f.debug_struct("WalkBuilder")
@@ -535,6 +549,7 @@ impl WalkBuilder {
             threads: 0,
             skip: None,
             filter: None,
+            on_skip: None,
         }
     }
 
@@ -584,6 +599,7 @@ impl WalkBuilder {
             max_filesize: self.max_filesize,
             skip: self.skip.clone(),
             filter: self.filter.clone(),
+            on_skip: self.on_skip.clone(),
         }
     }
 
@@ -603,6 +619,7 @@ impl WalkBuilder {
             threads: self.threads,
             skip: self.skip.clone(),
             filter: self.filter.clone(),
+            on_skip: self.on_skip.clone(),
         }
     }
 
@@ -901,6 +918,20 @@ impl WalkBuilder {
         self.filter = Some(Filter(Arc::new(filter)));
         self
     }
+
+    /// Calls the given function with every entry that's skipped because it
+    /// is ignored or because it exceeds the max filesize, along with why.
+    ///
+    /// This is meant for collecting statistics about a walk. Entries skipped
+    /// by the predicate given to `filter_entry` aren't reported. When walking
+    /// in parallel, the function is called from every worker thread.
+    pub fn on_skip<F>(&mut self, f: F) -> &mut WalkBuilder
+    where
+        F: Fn(&DirEntry, SkipReason) + Send + Sync + 'static,
+    {
+        self.on_skip = Some(OnSkip(Arc::new(f)));
+        self
+    }
 }
 
 /// Walk is a recursive directory iterator over file paths in one or more
@@ -917,6 +948,7 @@ pub struct Walk {
     max_filesize: Option<u64>,
     skip: Option<Arc<Handle>>,
     filter: Option<Filter>,
+    on_skip: Option<OnSkip>,
 }
 
 impl Walk {
@@ -943,6 +975,7 @@ impl Walk {
         // who ensured correct file-type filters were being used could still
         // get unnecessary file access resulting in large downloads.
         if should_skip_entry(&self.ig, ent) {
+            report_skip(&self.on_skip, ent, SkipReason::Ignored);
             return Ok(true);
         }
         if let Some(ref stdout) = self.skip {
@@ -951,11 +984,15 @@ impl Walk {
             }
         }
         if self.max_filesize.is_some() && !ent.is_dir() {
-            return Ok(skip_filesize(
+            let skip = skip_filesize(
                 self.max_filesize.unwrap(),
                 ent.path(),
                 &ent.metadata().ok(),
-            ));
+            );
+            if skip {
+                report_skip(&self.on_skip, ent, SkipReason::MaxFilesize);
+            }
+            return Ok(skip);
         }
         if let Some(Filter(filter)) = &self.filter {
             if !filter(ent) {
@@ -1196,6 +1233,7 @@ pub struct WalkParallel {
     threads: usize,
     skip: Option<Arc<Handle>>,
     filter: Option<Filter>,
+    on_skip: Option<OnSkip>,
 }
 
 impl WalkParallel {
@@ -1294,6 +1332,7 @@ impl WalkParallel {
                     follow_links: self.follow_links,
                     skip: self.skip.clone(),
                     filter: self.filter.clone(),
+                    on_skip: self.on_skip.clone(),
                 })
                 .map(|worker| s.spawn(|| worker.run()))
                 .collect();
@@ -1488,6 +1527,8 @@ struct Worker<'s> {
     /// A predicate applied to dir entries. If true, the entry and all
     /// children will be skipped.
     filter: Option<Filter>,
+    /// Called with every entry skipped because it's ignored or too big.
+    on_skip: Option<OnSkip>,
 }
 
 impl<'s> Worker<'s> {
@@ -1624,6 +1665,7 @@ impl<'s> Worker<'s> {
         // N.B. See analogous call in the single-threaded implementation about
         // why it's important for this to come before the checks below.
         if should_skip_entry(ig, &dent) {
+            report_skip(&self.on_skip, &dent, SkipReason::Ignored);
             return WalkState::Continue;
         }
         if let Some(ref stdout) = self.skip {
@@ -1645,6 +1687,9 @@ impl<'s> Worker<'s> {
             } else {
                 false
             };
+        if should_skip_filesize {
+            report_skip(&self.on_skip, &dent, SkipReason::MaxFilesize);
+        }
         let should_skip_filtered =
             if let Some(Filter(predicate)) = &self.filter {
                 !predicate(&dent)
@@ -1793,6 +1838,12 @@ fn skip_filesize(
     }
 }
 
+fn report_skip(on_skip: &Option<OnSkip>, dent: &DirEntry, why: SkipReason) {
+    if let Some(OnSkip(f)) = on_skip {
+        f(dent, why);
+    }
+}
+
 fn should_skip_entry(ig: &Ignore, dent: &DirEntry) -> bool {
     let m = ig.matched_dir_entry(dent);
     if m.is_ignore() {
@@ -1909,7 +1960,7 @@ mod tests {
     use std::path::Path;
     use std::sync::{Arc, Mutex};
 
-    use super::{DirEntry, WalkBuilder, WalkState};
+    use super::{DirEntry, SkipReason, WalkBuilder, WalkState};
     use crate::tests::TempDir;
 
     fn wfile<P: AsRef<Path>>(path: P, contents: &str) {
@@ -2307,4 +2358,39 @@ mod tests {
             &["x", "x/y", "x/y/foo"],
         );
     }
+
+    #[test]
+    fn on_skip() {
+        let td = tmpdir();
+        mkdirp(td.path().join("a"));
+        wfile(td.path().join(".ignore"), "*.log\n");
+        wfile(td.path().join("a/foo.log"), "");
+        wfile(td.path().join("a/foo"), "");
+        wfile_size(td.path().join("big"), 600);
+
+        let skipped = Arc::new(Mutex::new(vec![]));
+        let mut builder = WalkBuilder::new(td.path());
+        builder.hidden(false).max_filesize(Some(500));
+        builder.on_skip({
+            let (skipped, root) = (skipped.clone(), td.path().to_path_buf());
+            move |dent, why| {
+                let path = dent.path().strip_prefix(&root).unwrap();
+                let path = normal_path(path.to_str().unwrap());
+                skipped.lock().unwrap().push((path, why));
+            }
+        });
+        let sorted = || {
+            let mut skipped = std::mem::take(&mut *skipped.lock().unwrap());
+            skipped.sort_by(|a: &(String, _), b| a.0.cmp(&b.0));
+            skipped
+        };
+        let expected = vec![
+            ("a/foo.log".to_string(), SkipReason::Ignored),
+            ("big".to_string(), SkipReason::MaxFilesize),
+        ];
+        walk_collect(td.path(), &builder);
+        assert_eq!(expected, sorted(), "single threaded");
+        walk_collect_parallel(td.path(), &builder);
+        assert_eq!(expected, sorted(), "parallel");
+    }
 }
@@ -123,6 +123,23 @@ impl InnerLiterals {
     }
 }
 
+/// Returns literals such that every match of the given HIR expression
+/// contains at least one of them.
+///
+/// Unlike `InnerLiterals`, this doesn't care whether searching for the
+/// literals would be faster than running the regex. It's meant for callers
+/// that want to rule out haystacks without reading them, e.g., with an
+/// index. `None` is returned when no such set of literals could be found,
+/// in which case every haystack may contain a match.
+pub(crate) fn required_literals(chir: &ConfiguredHIR) -> Option<Vec<Vec<u8>>> {
+    let seq = Extractor::new().extract_untagged(chir.hir());
+    let lits = seq.literals()?;
+    if lits.is_empty() {
+        return None;
+    }
+    Some(lits.iter().map(|lit| lit.as_bytes().to_vec()).collect())
+}
+
 /// An inner literal extractor.
 ///
 /// This is a somewhat stripped down version of the extractor from
@@ -693,6 +710,21 @@ mod tests {
         );
     }
 
+    #[test]
+    fn required() {
+        let chir = |pattern: &str| {
+            crate::config::Config::default().build_many(&[pattern]).unwrap()
+        };
+        assert_eq!(
+            Some(vec![b"foo".to_vec()]),
+            required_literals(&chir(r"\w+foo\w+"))
+        );
+        let mut lits = required_literals(&chir(r"foo|bar")).unwrap();
+        lits.sort();
+        assert_eq!(vec![b"bar".to_vec(), b"foo".to_vec()], lits);
+        assert_eq!(None, required_literals(&chir(r"\w+")));
+    }
+
     // These test that some of our suspicious heuristics try to "pick better
     // literals."
     #[test]
@@ -9,7 +9,11 @@ use {
     },
 };
 
-use crate::{config::Config, error::Error, literal::InnerLiterals};
+use crate::{
+    config::Config,
+    error::Error,
+    literal::{required_literals, InnerLiterals},
+};
 
 /// A builder for constructing a `Matcher` using regular expressions.
 ///
@@ -96,6 +100,26 @@ impl RegexMatcherBuilder {
         self.build_many(literals)
     }
 
+    /// Returns literals such that every match of a matcher built from the
+    /// given patterns with the current configuration contains at least one
+    /// of them.
+    ///
+    /// This is useful for ruling out haystacks without searching them, e.g.,
+    /// by consulting an index. If no such set of literals could be found,
+    /// then `None` is returned and any haystack may contain a match.
+    pub fn required_literals<P: AsRef<str>>(
+        &self,
+        patterns: &[P],
+    ) -> Result<Option<Vec<Vec<u8>>>, Error> {
+        let mut chir = self.config.build_many(patterns)?;
+        if chir.config().whole_line {
+            chir = chir.into_whole_line();
+        } else if chir.config().word {
+            chir = chir.into_word();
+        }
+        Ok(required_literals(&chir))
+    }
+
     /// Set the value for the case insensitive (`i`) flag.
     ///
     /// When enabled, letters in the pattern will match both upper case and
@@ -0,0 +1,205 @@
+/*!
+Collects what the walker threads of a parallel search produce without making
+them take turns on a lock for every file.
+
+Each walker thread adds to its own [`Collector`], which buffers items and
+moves the whole buffer into the shared collection in one go when it's
+flushed. A file without matches adds nothing, so it never touches the shared
+collection at all. By default a collector only flushes when it's dropped,
+which the walker does for each thread's visitor before `run` returns. A search
+whose results are streamed flushes earlier, see [`FlushPolicy::streaming`],
+so the streamer sees results while the search is still running.
+
+This module only depends on `std`, so `benches/collect.rs` can include it and
+compare it against taking the lock on the shared results for every file and
+holding it while searching, which is how results used to be collected.
+*/
+
+use std::{
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+/// When a [`Collector`] moves its buffer into the shared collection.
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct FlushPolicy {
+    /// Flush once this many items are buffered.
+    flush_at: usize,
+    /// Flush on `tick` once this long passed since the last flush.
+    interval: Option<Duration>,
+}
+
+impl FlushPolicy {
+    /// Flush only when the collector is dropped.
+    pub(crate) fn at_end() -> FlushPolicy {
+        FlushPolicy { flush_at: usize::MAX, interval: None }
+    }
+
+    /// Flush once `flush_at` items are buffered, or on `tick` once
+    /// `interval` passed since the last flush with anything buffered.
+    ///
+    /// A thread that's waiting for work doesn't tick, so its last few items
+    /// may wait until the walk is over.
+    pub(crate) fn streaming(
+        flush_at: usize,
+        interval: Duration,
+    ) -> FlushPolicy {
+        FlushPolicy { flush_at: flush_at.max(1), interval: Some(interval) }
+    }
+}
+
+/// Called after every flush with the number of items moved and how long it
+/// took, including waiting for the lock.
+type OnFlush<'s> = Box<dyn Fn(usize, Duration) + Send + 's>;
+
+/// Buffers the items of one thread for a shared collection.
+pub(crate) struct Collector<'s, T, C: Extend<T>> {
+    shared: &'s Mutex<C>,
+    buffer: Vec<T>,
+    policy: FlushPolicy,
+    flushed: Instant,
+    on_flush: Option<OnFlush<'s>>,
+}
+
+impl<'s, T, C: Extend<T>> Collector<'s, T, C> {
+    /// A collector for `shared` that flushes according to `policy`.
+    pub(crate) fn new(
+        shared: &'s Mutex<C>,
+        policy: FlushPolicy,
+    ) -> Collector<'s, T, C> {
+        Collector {
+            shared,
+            buffer: vec![],
+            policy,
+            flushed: Instant::now(),
+            on_flush: None,
+        }
+    }
+
+    /// Call `on_flush` after every flush that moved anything, e.g., to
+    /// count results only once they can be seen in `shared`.
+    pub(crate) fn on_flush(
+        mut self,
+        on_flush: impl Fn(usize, Duration) + Send + 's,
+    ) -> Collector<'s, T, C> {
+        self.on_flush = Some(Box::new(on_flush));
+        self
+    }
+
+    /// Add one item, flushing if the buffer is full.
+    pub(crate) fn push(&mut self, item: T) {
+        self.buffer.push(item);
+        if self.buffer.len() >= self.policy.flush_at {
+            self.flush();
+        }
+    }
+
+    /// Add several items, flushing if the buffer is full.
+    pub(crate) fn add(&mut self, items: impl IntoIterator<Item = T>) {
+        self.buffer.extend(items);
+        if self.buffer.len() >= self.policy.flush_at {
+            self.flush();
+        }
+    }
+
+    /// Flush if the policy's interval passed since the last flush and
+    /// anything is buffered. Meant to be called once per file.
+    pub(crate) fn tick(&mut self) {
+        if let Some(interval) = self.policy.interval {
+            if !self.buffer.is_empty() && self.flushed.elapsed() >= interval {
+                self.flush();
+            }
+        }
+    }
+
+    /// Move everything buffered into the shared collection.
+    pub(crate) fn flush(&mut self) {
+        self.flushed = Instant::now();
+        if self.buffer.is_empty() {
+            return;
+        }
+        let count = self.buffer.len();
+        // A poisoned lock means another thread panicked, which fails the
+        // whole search anyway.
+        if let Ok(mut shared) = self.shared.lock() {
+            shared.extend(self.buffer.drain(..));
+        }
+        if let Some(ref on_flush) = self.on_flush {
+            on_flush(count, self.flushed.elapsed());
+        }
+    }
+}
+
+impl<'s, T, C: Extend<T>> Drop for Collector<'s, T, C> {
+    fn drop(&mut self) {
+        self.flush();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use super::*;
+
+    #[test]
+    fn flushes_at_end() {
+        let shared = Mutex::new(vec![0]);
+        let mut collector = Collector::new(&shared, FlushPolicy::at_end());
+        collector.add(vec![1, 2]);
+        collector.push(3);
+        collector.tick();
+        assert_eq!(vec![0], *shared.lock().unwrap());
+        drop(collector);
+        assert_eq!(vec![0, 1, 2, 3], *shared.lock().unwrap());
+    }
+
+    #[test]
+    fn flushes_while_streaming() {
+        let shared = Mutex::new(vec![]);
+        let flushed = AtomicUsize::new(0);
+        let policy = FlushPolicy::streaming(3, Duration::from_millis(20));
+        let mut collector =
+            Collector::new(&shared, policy).on_flush(|count, _| {
+                flushed.fetch_add(count, Ordering::SeqCst);
+            });
+
+        // A full buffer is flushed right away.
+        collector.add(vec![1, 2]);
+        assert!(shared.lock().unwrap().is_empty());
+        collector.push(3);
+        assert_eq!(vec![1, 2, 3], *shared.lock().unwrap());
+
+        // Anything less waits for the interval.
+        collector.push(4);
+        collector.tick();
+        assert_eq!(3, shared.lock().unwrap().len());
+        std::thread::sleep(Duration::from_millis(25));
+        collector.tick();
+        assert_eq!(vec![1, 2, 3, 4], *shared.lock().unwrap());
+
+        // Nothing buffered, nothing to do.
+        drop(collector);
+        assert_eq!(4, flushed.load(Ordering::SeqCst));
+    }
+
+    #[test]
+    fn threads() {
+        let shared = Mutex::new(vec![]);
+        std::thread::scope(|scope| {
+            for thread in 0..4 {
+                let shared = &shared;
+                scope.spawn(move || {
+                    let mut collector =
+                        Collector::new(shared, FlushPolicy::at_end());
+                    for i in 0..100 {
+                        collector.push(thread * 100 + i);
+                    }
+                });
+            }
+        });
+        let mut got = shared.into_inner().unwrap();
+        got.sort();
+        assert_eq!((0..400).collect::<Vec<_>>(), got);
+    }
+}
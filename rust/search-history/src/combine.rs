@@ -0,0 +1,251 @@
+/*!
+Combines stored searches with set operations.
+
+Questions like "files matching A but not B" are answered by combining the
+results of stored searches instead of searching again. Searches are combined
+either by the files they matched in or by the lines they matched:
+
+* At the `files` level, a file is in a search's set if the search has at
+  least one hit in it. The combined search keeps every hit of its inputs
+  that is in a file of the resulting set.
+* At the `lines` level, a hit is identified by its file and where it
+  starts in that file. The combined search keeps the hits of the resulting
+  set.
+
+A hit found by several inputs is kept once, as found by the first of them.
+The combination is stored as a new search, which clients page through like
+any other.
+*/
+
+use std::collections::HashSet;
+
+use crate::search::{SearchResult, SearchResults};
+
+/// A set operation over the results of stored searches.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub(crate) enum SetOp {
+    /// In any of the inputs.
+    Union,
+    /// In every one of the inputs.
+    Intersection,
+    /// In the first input but in none of the others.
+    Difference,
+}
+
+impl SetOp {
+    /// Returns the name of this operation as sent to and from the client.
+    pub(crate) fn as_str(&self) -> &'static str {
+        match *self {
+            SetOp::Union => "union",
+            SetOp::Intersection => "intersection",
+            SetOp::Difference => "difference",
+        }
+    }
+
+    /// Parse the name of an operation, as returned by `as_str`.
+    pub(crate) fn from_name(name: &str) -> Option<SetOp> {
+        match name {
+            "union" => Some(SetOp::Union),
+            "intersection" => Some(SetOp::Intersection),
+            "difference" => Some(SetOp::Difference),
+            _ => None,
+        }
+    }
+
+    /// The word joining the patterns of the inputs when describing the
+    /// combined search, e.g., `wp_query and not meta_query`.
+    pub(crate) fn conjunction(&self) -> &'static str {
+        match *self {
+            SetOp::Union => "or",
+            SetOp::Intersection => "and",
+            SetOp::Difference => "and not",
+        }
+    }
+}
+
+/// What the sets being combined are made of.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub(crate) enum SetLevel {
+    /// The files each search matched in.
+    Files,
+    /// The lines each search matched.
+    Lines,
+}
+
+impl SetLevel {
+    /// Returns the name of this level as sent to and from the client.
+    pub(crate) fn as_str(&self) -> &'static str {
+        match *self {
+            SetLevel::Files => "files",
+            SetLevel::Lines => "lines",
+        }
+    }
+
+    /// Parse the name of a level, as returned by `as_str`.
+    pub(crate) fn from_name(name: &str) -> Option<SetLevel> {
+        match name {
+            "files" => Some(SetLevel::Files),
+            "lines" => Some(SetLevel::Lines),
+            _ => None,
+        }
+    }
+}
+
+/// How a stored search was combined from others.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub(crate) struct Combination {
+    /// The operation applied.
+    pub(crate) op: SetOp,
+    /// What the combined sets were made of.
+    pub(crate) level: SetLevel,
+    /// The ids of the combined searches, in the order given. For a
+    /// difference, the first is the one the others are taken from.
+    pub(crate) ids: Vec<String>,
+}
+
+/// Combine the results of several searches.
+///
+/// For a difference, `inputs[0]` is the search the others are taken away
+/// from. With no inputs, the result is empty.
+pub(crate) fn combine(
+    op: SetOp,
+    level: SetLevel,
+    inputs: &[&SearchResults],
+) -> SearchResults {
+    let key = |result: &SearchResult| -> Key {
+        match level {
+            SetLevel::Files => Key::File(file_key(result.get_file_name())),
+            SetLevel::Lines => Key::Line(
+                file_key(result.get_file_name()),
+                result.get_byte_offset(),
+            ),
+        }
+    };
+    let sets: Vec<HashSet<Key>> = inputs
+        .iter()
+        .map(|results| results.iter().map(key).collect())
+        .collect();
+    let keep = |k: &Key| -> bool {
+        match op {
+            SetOp::Union => true,
+            SetOp::Intersection => sets.iter().all(|set| set.contains(k)),
+            SetOp::Difference => {
+                sets[0].contains(k)
+                    && sets[1..].iter().all(|set| !set.contains(k))
+            }
+        }
+    };
+    // Only a union or a files level intersection can keep hits from inputs
+    // other than the first one.
+    let from = match (op, level) {
+        (SetOp::Union, _) | (SetOp::Intersection, SetLevel::Files) => {
+            inputs.len()
+        }
+        _ => inputs.len().min(1),
+    };
+
+    let mut seen = HashSet::new();
+    let mut combined = SearchResults::new();
+    for results in inputs[..from].iter() {
+        for result in results.iter() {
+            if !keep(&key(result)) {
+                continue;
+            }
+            let line = Key::Line(
+                file_key(result.get_file_name()),
+                result.get_byte_offset(),
+            );
+            if seen.insert(line) {
+                combined.store_result(result.clone());
+            }
+        }
+    }
+    combined
+}
+
+/// What a hit is compared by.
+#[derive(Clone, Debug, Eq, Hash, PartialEq)]
+enum Key {
+    File(Option<String>),
+    Line(Option<String>, u64),
+}
+
+/// The path of a hit as compared between searches. A leading `./` is
+/// dropped, since searches of `./` and of a subdirectory name the same file
+/// differently.
+fn file_key(path: Option<&str>) -> Option<String> {
+    let mut path = path?;
+    while let Some(rest) = path.strip_prefix("./") {
+        path = rest;
+    }
+    Some(path.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::search::SubMatch;
+
+    use super::*;
+
+    /// Hits given as `path:offset`.
+    fn results(hits: &[&str]) -> SearchResults {
+        let mut results = SearchResults::new();
+        for hit in hits.iter() {
+            let (path, offset) = hit.split_once(':').unwrap();
+            let bytes = hit.as_bytes().to_vec();
+            let submatches = SubMatch::from_ranges(&bytes, Some(0..1));
+            results.store_result(SearchResult::new(
+                Some(path.to_string()),
+                0,
+                offset.parse().unwrap(),
+                bytes,
+                submatches,
+            ));
+        }
+        results
+    }
+
+    fn hits(results: &SearchResults) -> Vec<String> {
+        results
+            .iter()
+            .map(|r| String::from_utf8(r.get_matched_bytes().clone()).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn lines() {
+        let a = results(&["a:0", "a:10", "b:0"]);
+        let b = results(&["./a:10", "c:5"]);
+        let run = |op| hits(&combine(op, SetLevel::Lines, &[&a, &b]));
+        assert_eq!(vec!["a:0", "a:10", "b:0", "c:5"], run(SetOp::Union));
+        assert_eq!(vec!["a:10"], run(SetOp::Intersection));
+        assert_eq!(vec!["a:0", "b:0"], run(SetOp::Difference));
+    }
+
+    #[test]
+    fn files() {
+        let a = results(&["a:0", "a:10", "b:0"]);
+        let b = results(&["./a:20", "c:5"]);
+        let c = results(&["a:30", "b:40"]);
+        let run = |op, inputs: &[&SearchResults]| {
+            hits(&combine(op, SetLevel::Files, inputs))
+        };
+        assert_eq!(
+            vec!["a:0", "a:10", "./a:20", "a:30"],
+            run(SetOp::Intersection, &[&a, &b, &c])
+        );
+        assert_eq!(vec!["b:0"], run(SetOp::Difference, &[&a, &b]));
+        assert_eq!(Vec::<String>::new(), run(SetOp::Difference, &[&a, &b, &c]));
+        assert_eq!(
+            vec!["a:0", "a:10", "b:0", "./a:20", "c:5"],
+            run(SetOp::Union, &[&a, &b])
+        );
+    }
+
+    #[test]
+    fn no_inputs() {
+        for op in [SetOp::Union, SetOp::Intersection, SetOp::Difference] {
+            assert_eq!(0, combine(op, SetLevel::Lines, &[]).len());
+        }
+    }
+}
@@ -0,0 +1,197 @@
+/*!
+Keeps the files a search failed on with the search, rather than printing them
+to stderr where nvim swallows them.
+
+Every failure is a [`FileError`] with the path of the file, as the search
+reports paths, the kind of the underlying I/O error, its message and a rough
+cause a client can act on: a permission error, a broken symlink or a file
+that couldn't be decoded. The `query_errors` RPC returns them.
+*/
+
+use std::{io, path::Path};
+
+/// A rough cause of a failure.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub(crate) enum ErrorCause {
+    /// The file or a directory above it can't be read by this user.
+    Permission,
+    /// The file is a symlink to something that doesn't exist.
+    BrokenSymlink,
+    /// The file could be read, but not decoded, e.g., it's a corrupt
+    /// archive with `--search-zip`, or it isn't valid in `--encoding`.
+    Decode,
+    /// Anything else, e.g., a symlink loop.
+    Other,
+}
+
+impl ErrorCause {
+    /// Returns the name of this cause as sent to the client.
+    pub(crate) fn as_str(&self) -> &'static str {
+        match *self {
+            ErrorCause::Permission => "permission",
+            ErrorCause::BrokenSymlink => "broken_symlink",
+            ErrorCause::Decode => "decode",
+            ErrorCause::Other => "other",
+        }
+    }
+
+    /// Parse the name of a cause, as returned by `as_str`.
+    pub(crate) fn from_name(name: &str) -> Option<ErrorCause> {
+        match name {
+            "permission" => Some(ErrorCause::Permission),
+            "broken_symlink" => Some(ErrorCause::BrokenSymlink),
+            "decode" => Some(ErrorCause::Decode),
+            "other" => Some(ErrorCause::Other),
+            _ => None,
+        }
+    }
+}
+
+/// A file, or directory, that a search failed on.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub(crate) struct FileError {
+    /// The path of the file as the search reports it, or empty when the
+    /// error doesn't say.
+    pub(crate) path: String,
+    /// The kind of the I/O error, e.g., `PermissionDenied`, or `Other` when
+    /// it wasn't an I/O error.
+    pub(crate) kind: String,
+    /// The error message, without the path.
+    pub(crate) message: String,
+    /// What probably caused it.
+    pub(crate) cause: ErrorCause,
+}
+
+impl FileError {
+    /// The error searching the file at `path`.
+    pub(crate) fn searching(path: &Path, err: &io::Error) -> FileError {
+        FileError {
+            path: path.to_string_lossy().into_owned(),
+            kind: format!("{:?}", err.kind()),
+            message: err.to_string(),
+            cause: cause(Some(path), err),
+        }
+    }
+
+    /// An error of the directory walk, e.g., a directory that couldn't be
+    /// read.
+    pub(crate) fn walking(err: &ignore::Error) -> FileError {
+        let path = walk_path(err);
+        let (kind, cause) = match err.io_error() {
+            Some(io) => (io.kind(), cause(path, io)),
+            None => (io::ErrorKind::Other, ErrorCause::Other),
+        };
+        FileError {
+            path: path
+                .map_or(String::new(), |p| p.to_string_lossy().into_owned()),
+            kind: format!("{:?}", kind),
+            message: walk_message(err),
+            cause,
+        }
+    }
+
+    /// The approximate number of bytes this error takes up in memory.
+    pub(crate) fn approx_bytes(&self) -> usize {
+        std::mem::size_of::<FileError>()
+            + self.path.len()
+            + self.kind.len()
+            + self.message.len()
+    }
+}
+
+/// Guess what caused `err` when reading `path`.
+fn cause(path: Option<&Path>, err: &io::Error) -> ErrorCause {
+    match err.kind() {
+        io::ErrorKind::PermissionDenied => ErrorCause::Permission,
+        io::ErrorKind::InvalidData => ErrorCause::Decode,
+        // The link itself is there, but what it points to isn't.
+        io::ErrorKind::NotFound
+            if path.is_some_and(|path| {
+                path.symlink_metadata().is_ok_and(|md| md.is_symlink())
+            }) =>
+        {
+            ErrorCause::BrokenSymlink
+        }
+        _ => ErrorCause::Other,
+    }
+}
+
+/// The path a walk error is about, if it says.
+fn walk_path(err: &ignore::Error) -> Option<&Path> {
+    match *err {
+        ignore::Error::WithPath { ref path, .. } => Some(path),
+        ignore::Error::WithDepth { ref err, .. }
+        | ignore::Error::WithLineNumber { ref err, .. } => walk_path(err),
+        ignore::Error::Loop { ref child, .. } => Some(child),
+        _ => None,
+    }
+}
+
+/// The message of a walk error without the path, which is kept separately.
+fn walk_message(err: &ignore::Error) -> String {
+    match *err {
+        ignore::Error::WithPath { ref err, .. }
+        | ignore::Error::WithDepth { ref err, .. } => walk_message(err),
+        ref err => err.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn causes() {
+        let err = io::Error::from(io::ErrorKind::PermissionDenied);
+        let got = FileError::searching(Path::new("a/b.txt"), &err);
+        assert_eq!("a/b.txt", got.path);
+        assert_eq!("PermissionDenied", got.kind);
+        assert_eq!(ErrorCause::Permission, got.cause);
+
+        let err = io::Error::new(io::ErrorKind::InvalidData, "bad gzip");
+        let got = FileError::searching(Path::new("a.gz"), &err);
+        assert_eq!(ErrorCause::Decode, got.cause);
+        assert_eq!("bad gzip", got.message);
+
+        let err = ignore::Error::WithDepth {
+            depth: 2,
+            err: Box::new(ignore::Error::WithPath {
+                path: "a/secret".into(),
+                err: Box::new(ignore::Error::Io(io::Error::from(
+                    io::ErrorKind::PermissionDenied,
+                ))),
+            }),
+        };
+        let got = FileError::walking(&err);
+        assert_eq!("a/secret", got.path);
+        assert_eq!(ErrorCause::Permission, got.cause);
+        assert!(!got.message.contains("a/secret"));
+
+        let loop_err = ignore::Error::Loop {
+            ancestor: "a".into(),
+            child: "a/b/link".into(),
+        };
+        let got = FileError::walking(&loop_err);
+        assert_eq!(
+            ("a/b/link", ErrorCause::Other),
+            (&got.path[..], got.cause)
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn broken_symlink() {
+        let dir = std::env::temp_dir().join(format!(
+            "search-history-test-{}-broken-symlink",
+            std::process::id()
+        ));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        let link = dir.join("link");
+        std::os::unix::fs::symlink(dir.join("gone"), &link).unwrap();
+        let err = std::fs::File::open(&link).unwrap_err();
+        let got = FileError::searching(&link, &err);
+        assert_eq!(ErrorCause::BrokenSymlink, got.cause);
+        assert_eq!("NotFound", got.kind);
+    }
+}
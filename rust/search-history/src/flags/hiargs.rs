@@ -9,7 +9,7 @@ use std::{
 
 use {
     bstr::BString,
-    grep::printer::ColorSpecs,
+    grep::printer::{ColorSpecs, SummaryKind},
 };
 
 use crate::{
@@ -20,6 +20,7 @@ use crate::{
         PatternSource, SearchMode, SortMode, SortModeKind, TypeChange,
     },
     haystack::{Haystack, HaystackBuilder},
+    rg,
     search::{PatternMatcher, SearchWorker, SearchWorkerBuilder},
 };
 
@@ -457,6 +458,42 @@ impl HiArgs {
     /// If there was a problem building the matcher (such as a regex syntax
     /// error), then an error is returned.
     fn matcher_rust(&self) -> anyhow::Result<PatternMatcher> {
+        let m = match self.rust_builder().build_many(&self.patterns.patterns) {
+            Ok(m) => m,
+            Err(err) => {
+                anyhow::bail!(suggest_text(suggest_multiline(err.to_string())))
+            }
+        };
+        Ok(PatternMatcher::RustRegex(m))
+    }
+
+    /// Returns literals such that every line the matcher reports contains at
+    /// least one of them, as raw bytes of the haystack.
+    ///
+    /// This returns `None` whenever a haystack might match without having
+    /// any of the literals in it, e.g., because matches are inverted or the
+    /// haystack is transcoded or decompressed before it's searched. It also
+    /// returns `None` for PCRE2, whose patterns aren't analyzed.
+    pub(crate) fn required_literals(&self) -> Option<Vec<Vec<u8>>> {
+        if self.engine == EngineChoice::PCRE2
+            || self.invert_match
+            || self.pre.is_some()
+            || self.search_zip
+            || !matches!(self.encoding, EncodingMode::Auto)
+        {
+            return None;
+        }
+        match self.rust_builder().required_literals(&self.patterns.patterns) {
+            Ok(lits) => lits,
+            Err(err) => {
+                log::debug!("no required literals: {err}");
+                None
+            }
+        }
+    }
+
+    /// A builder for Rust's regex engine, configured from the flags.
+    fn rust_builder(&self) -> grep::regex::RegexMatcherBuilder {
         let mut builder = grep::regex::RegexMatcherBuilder::new();
         builder
             .multi_line(true)
@@ -502,13 +539,7 @@ impl HiArgs {
         if !self.binary.is_none() {
             builder.ban_byte(Some(b'\x00'));
         }
-        let m = match builder.build_many(&self.patterns.patterns) {
-            Ok(m) => m,
-            Err(err) => {
-                anyhow::bail!(suggest_text(suggest_multiline(err.to_string())))
-            }
-        };
-        Ok(PatternMatcher::RustRegex(m))
+        builder
     }
 
     /// Returns true if some non-zero number of matches is believed to be
@@ -535,8 +566,66 @@ impl HiArgs {
         self.mode
     }
 
-    /// Builds a "standard" grep printer where matches are printed as plain
+    /// Returns a builder for constructing a "path printer."
+    ///
+    /// This is useful for the `--files` mode in ripgrep, where the printer
+    /// just needs to emit paths and not need to worry about the functionality
+    /// of searching.
+    pub(crate) fn path_printer_builder(
+        &self,
+    ) -> grep::printer::PathPrinterBuilder {
+        let mut builder = grep::printer::PathPrinterBuilder::new();
+        builder
+            .color_specs(self.colors.clone())
+            .hyperlink(self.hyperlink_config.clone())
+            .separator(self.path_separator)
+            .terminator(self.path_terminator.unwrap_or(b'\n'));
+        builder
+    }
+
+    /// Returns a printer for the given search mode.
+    ///
+    /// This chooses which printer to build (JSON, summary or standard) based
+    /// on the search mode given.
     pub(crate) fn printer<W: termcolor::WriteColor>(
+        &self,
+        search_mode: SearchMode,
+        wtr: W,
+    ) -> rg::Printer<W> {
+        let summary_kind = if self.quiet {
+            SummaryKind::Quiet
+        } else {
+            match search_mode {
+                SearchMode::FilesWithMatches => SummaryKind::PathWithMatch,
+                SearchMode::FilesWithoutMatch => SummaryKind::PathWithoutMatch,
+                SearchMode::Count => SummaryKind::Count,
+                SearchMode::CountMatches => SummaryKind::CountMatches,
+                SearchMode::JSON => {
+                    return rg::Printer::Json(self.printer_json(wtr))
+                }
+                SearchMode::Standard => {
+                    return rg::Printer::Standard(self.printer_standard(wtr))
+                }
+            }
+        };
+        rg::Printer::Summary(self.printer_summary(wtr, summary_kind))
+    }
+
+    /// Builds a JSON printer.
+    fn printer_json<W: std::io::Write>(
+        &self,
+        wtr: W,
+    ) -> grep::printer::JSON<W> {
+        grep::printer::JSONBuilder::new()
+            .pretty(false)
+            .max_matches(self.max_count)
+            .always_begin_end(false)
+            .build(wtr)
+    }
+
+    /// Builds a "standard" grep printer where matches are printed as plain
+    /// text lines.
+    fn printer_standard<W: termcolor::WriteColor>(
         &self,
         wtr: W,
     ) -> grep::printer::Standard<W> {
@@ -552,7 +641,7 @@ impl HiArgs {
             .max_matches(self.max_count)
             .only_matching(self.only_matching)
             .path(self.with_filename)
-            .path_terminator(self.path_terminator.clone())
+            .path_terminator(self.path_terminator)
             .per_match_one_line(true)
             .per_match(self.vimgrep)
             .replacement(self.replace.clone().map(|r| r.into()))
@@ -563,7 +652,7 @@ impl HiArgs {
             .separator_field_match(
                 self.field_match_separator.clone().into_bytes(),
             )
-            .separator_path(self.path_separator.clone())
+            .separator_path(self.path_separator)
             .stats(self.stats.is_some())
             .trim_ascii(self.trim);
         // When doing multi-threaded searching, the buffer writer is
@@ -577,6 +666,27 @@ impl HiArgs {
         builder.build(wtr)
     }
 
+    /// Builds a "summary" printer where search results are aggregated on a
+    /// file-by-file basis.
+    fn printer_summary<W: termcolor::WriteColor>(
+        &self,
+        wtr: W,
+        kind: SummaryKind,
+    ) -> grep::printer::Summary<W> {
+        grep::printer::SummaryBuilder::new()
+            .color_specs(self.colors.clone())
+            .exclude_zero(!self.include_zero)
+            .hyperlink(self.hyperlink_config.clone())
+            .kind(kind)
+            .max_matches(self.max_count)
+            .path(self.with_filename)
+            .path_terminator(self.path_terminator)
+            .separator_field(b":".to_vec())
+            .separator_path(self.path_separator)
+            .stats(self.stats.is_some())
+            .build(wtr)
+    }
+
     /// Returns true if ripgrep should operate in "quiet" mode.
     ///
     /// Generally speaking, quiet mode means that ripgrep should not print
@@ -601,20 +711,49 @@ impl HiArgs {
 
     /// Build a worker for executing searches.
     ///
+    /// Search results are found using the given matcher and collected by the
+    /// worker itself instead of being written to a printer.
+    ///
+    /// The worker always counts line numbers, since every stored result
+    /// needs one. Left to the CLI's defaults, they'd only be counted when
+    /// stdout is a tty, which a server usually doesn't have.
+    pub(crate) fn search_worker(
+        &self,
+        matcher: PatternMatcher,
+    ) -> anyhow::Result<SearchWorker> {
+        let searcher = self.searcher_builder().line_number(true).build();
+        let mut builder = SearchWorkerBuilder::new();
+        Ok(builder.build(matcher, searcher))
+    }
+
+    /// Build a worker for executing searches when running as a plain
+    /// ripgrep CLI.
+    ///
     /// Search results are found using the given matcher and written to the
     /// given printer.
-    pub(crate) fn search_worker<W: termcolor::WriteColor>(
+    pub(crate) fn print_worker<W: termcolor::WriteColor>(
         &self,
         matcher: PatternMatcher,
         searcher: grep::searcher::Searcher,
-        printer: grep::printer::Standard<W>,
-    ) -> anyhow::Result<SearchWorker<W>> {
-        let mut builder = SearchWorkerBuilder::new();
+        printer: rg::Printer<W>,
+    ) -> anyhow::Result<rg::SearchWorker<W>> {
+        let mut builder = rg::SearchWorkerBuilder::new();
+        builder
+            .preprocessor(self.pre.clone())?
+            .preprocessor_globs(self.pre_globs.clone())
+            .search_zip(self.search_zip)
+            .binary_detection_explicit(self.binary.explicit.clone())
+            .binary_detection_implicit(self.binary.implicit.clone());
         Ok(builder.build(matcher, searcher, printer))
     }
 
     /// Build a searcher from the command line parameters.
     pub(crate) fn searcher(&self) -> anyhow::Result<grep::searcher::Searcher> {
+        Ok(self.searcher_builder().build())
+    }
+
+    /// Configure a searcher from the command line parameters.
+    fn searcher_builder(&self) -> grep::searcher::SearcherBuilder {
         let line_term = if self.crlf {
             grep::matcher::LineTerminator::crlf()
         } else if self.null_data {
@@ -649,7 +788,7 @@ impl HiArgs {
                 builder.bom_sniffing(false);
             }
         }
-        Ok(builder.build())
+        builder
     }
 
     /// Given an iterator of haystacks, sort them if necessary.
@@ -35,8 +35,9 @@ pub(crate) use crate::flags::{
         },
     },
     hiargs::HiArgs,
-    lowargs::{LowArgs, GenerateMode, Mode, SearchMode, SpecialMode, ColorChoice},
-    parse::{parse, parse_low, ParseResult},
+    lowargs::{LowArgs, GenerateMode, Mode, PatternSource, SearchMode, SpecialMode, ColorChoice},
+    overrides::{apply_args_line, apply_options, OptionValue},
+    parse::{parse, parse_low, parse_low_args, ParseResult},
 };
 
 mod complete;
@@ -45,6 +46,7 @@ mod defs;
 mod doc;
 mod hiargs;
 mod lowargs;
+mod overrides;
 mod parse;
 
 /// A trait that encapsulates the definition of an optional flag for ripgrep.
@@ -0,0 +1,401 @@
+/*!
+Applies per-request option overrides on top of an existing set of arguments.
+
+The server parses its own flags once at startup. A client may then tweak a
+handful of them for a single search, e.g., to add a glob or to search
+case insensitively. Each override is mapped onto the flag it corresponds to
+and applied through that flag's `Flag::update`, so values are validated
+exactly as they would be on the command line.
+
+A client may also send a whole ripgrep command line as a single string, such
+as `-t php -w get_option wp-content`. That string is split the way a shell
+would split it and then run through the CLI parser itself.
+*/
+
+use std::{ffi::OsString, path::Path};
+
+use anyhow::Context;
+
+use crate::flags::{
+    lowargs::{LowArgs, Mode, PatternSource},
+    parse::{lookup, parse_low_onto},
+    FlagValue,
+};
+
+/// The value of a single option override, as decoded from the client.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub(crate) enum OptionValue {
+    /// A yes or no value.
+    Bool(bool),
+    /// A non-negative integer.
+    Int(u64),
+    /// A single string.
+    Str(String),
+    /// A list of strings.
+    List(Vec<String>),
+}
+
+impl OptionValue {
+    /// Returns the name of this value's type for use in error messages.
+    fn kind(&self) -> &'static str {
+        match *self {
+            OptionValue::Bool(_) => "a boolean",
+            OptionValue::Int(_) => "an integer",
+            OptionValue::Str(_) => "a string",
+            OptionValue::List(_) => "a list",
+        }
+    }
+}
+
+/// The names of the options a client may override.
+pub(crate) const OPTION_NAMES: &[&str] = &[
+    "case",
+    "fixed_strings",
+    "follow",
+    "glob",
+    "hidden",
+    "max_filesize",
+    "multiline",
+    "type",
+];
+
+/// Apply the given option overrides to `low`, in order.
+///
+/// An unknown option name, or a value of the wrong type or one that the
+/// corresponding flag rejects, results in an error. In that case `low` may
+/// have been partially updated and should be discarded.
+pub(crate) fn apply_options(
+    low: &mut LowArgs,
+    options: &[(String, OptionValue)],
+) -> anyhow::Result<()> {
+    for (name, value) in options.iter() {
+        apply_option(low, name, value)?;
+    }
+    Ok(())
+}
+
+/// Apply a single option override to `low`.
+fn apply_option(
+    low: &mut LowArgs,
+    name: &str,
+    value: &OptionValue,
+) -> anyhow::Result<()> {
+    let wrong_type = |expected: &str| {
+        anyhow::anyhow!(
+            "option '{name}' must be {expected}, but got {}",
+            value.kind()
+        )
+    };
+    match name {
+        "case" => {
+            let OptionValue::Str(ref case) = *value else {
+                return Err(wrong_type("a string"));
+            };
+            let flag = match case.as_str() {
+                "sensitive" => "case-sensitive",
+                "insensitive" => "ignore-case",
+                "smart" => "smart-case",
+                unk => anyhow::bail!(
+                    "option 'case' must be one of 'sensitive', \
+                     'insensitive' or 'smart', but got '{unk}'"
+                ),
+            };
+            update(low, name, flag, FlagValue::Switch(true))
+        }
+        "fixed_strings" | "follow" | "hidden" | "multiline" => {
+            let OptionValue::Bool(yes) = *value else {
+                return Err(wrong_type("a boolean"));
+            };
+            let flag = match name {
+                "fixed_strings" => "fixed-strings",
+                _ => name,
+            };
+            update(low, name, flag, FlagValue::Switch(yes))
+        }
+        "glob" | "type" => {
+            let values = match *value {
+                OptionValue::Str(ref v) => std::slice::from_ref(v),
+                OptionValue::List(ref vs) => vs.as_slice(),
+                _ => return Err(wrong_type("a string or a list of strings")),
+            };
+            for v in values.iter() {
+                let v = FlagValue::Value(OsString::from(v));
+                update(low, name, name, v)?;
+            }
+            Ok(())
+        }
+        "max_filesize" => {
+            let size = match *value {
+                OptionValue::Int(n) => n.to_string(),
+                OptionValue::Str(ref s) => s.clone(),
+                _ => return Err(wrong_type("an integer or a string")),
+            };
+            update(low, name, "max-filesize", FlagValue::Value(size.into()))
+        }
+        unk => {
+            anyhow::bail!(
+                "unknown option '{unk}' (expected one of: {})",
+                OPTION_NAMES.join(", ")
+            )
+        }
+    }
+}
+
+/// Update `low` with the flag of the given long name.
+fn update(
+    low: &mut LowArgs,
+    option: &str,
+    flag: &str,
+    value: FlagValue,
+) -> anyhow::Result<()> {
+    let Some(flag) = lookup(flag) else {
+        unreachable!("option '{option}' maps to unknown flag --{flag}")
+    };
+    flag.update(value, low)
+        .with_context(|| format!("invalid value for option '{option}'"))
+}
+
+/// Parse a ripgrep command line given as a single string on top of `low`.
+///
+/// Any patterns and paths already in `low` are replaced by the ones in
+/// `line`. As with `rg`, the first positional argument is the pattern unless
+/// `-e/--regexp` or `-f/--file` is given. Errors are the ones the CLI gives.
+///
+/// Since the server's stdin is its connection to the client, nothing may
+/// be read from it: `-` isn't accepted as a path or pattern file, and a line
+/// without any paths searches `./` instead of stdin. Flags that don't run a
+/// search, like `--files` or `--help`, are rejected too.
+pub(crate) fn apply_args_line(
+    low: &mut LowArgs,
+    line: &str,
+) -> anyhow::Result<()> {
+    let args = split_args(line)?;
+    low.patterns.clear();
+    low.positional.clear();
+    parse_low_onto(args, low)?;
+
+    if low.special.is_some() {
+        anyhow::bail!("-h/--help and -V/--version can't be used in a search");
+    }
+    if !matches!(low.mode, Mode::Search(_)) {
+        anyhow::bail!("only searches can be run, not {:?}", low.mode);
+    }
+    let stdin = Path::new("-");
+    let paths = match low.patterns.is_empty() {
+        true => low.positional.get(1..).unwrap_or(&[]),
+        false => &low.positional[..],
+    };
+    let reads_stdin = paths.iter().any(|p| Path::new(p) == stdin)
+        || low.patterns.iter().any(|source| match *source {
+            PatternSource::File(ref path) => path == stdin,
+            PatternSource::Regexp(_) => false,
+        });
+    if reads_stdin {
+        anyhow::bail!("stdin can't be searched or read patterns from");
+    }
+    // Without a pattern there is nothing to search for, and adding a path
+    // would only turn it into the pattern. Leave it to `HiArgs` to complain.
+    let no_pattern = low.patterns.is_empty() && low.positional.is_empty();
+    if paths.is_empty() && !no_pattern {
+        low.positional.push(OsString::from("./"));
+    }
+    Ok(())
+}
+
+/// Split a command line into arguments the way a POSIX shell would, minus
+/// any expansions.
+///
+/// Arguments are separated by whitespace. Single quotes keep everything up
+/// to the next single quote literally. Double quotes do the same, except
+/// that a backslash escapes a `"` or `\`. Outside of quotes, a backslash
+/// escapes any character.
+fn split_args(line: &str) -> anyhow::Result<Vec<String>> {
+    let mut args = vec![];
+    let mut arg: Option<String> = None;
+    let mut chars = line.chars();
+    while let Some(ch) = chars.next() {
+        match ch {
+            ' ' | '\t' | '\n' | '\r' => {
+                args.extend(arg.take());
+            }
+            '\'' => {
+                let arg = arg.get_or_insert_with(String::new);
+                loop {
+                    match chars.next() {
+                        Some('\'') => break,
+                        Some(ch) => arg.push(ch),
+                        None => anyhow::bail!("unterminated single quote"),
+                    }
+                }
+            }
+            '"' => {
+                let arg = arg.get_or_insert_with(String::new);
+                loop {
+                    match chars.next() {
+                        Some('"') => break,
+                        Some('\\') => match chars.next() {
+                            Some(ch @ ('"' | '\\')) => arg.push(ch),
+                            Some(ch) => {
+                                arg.push('\\');
+                                arg.push(ch);
+                            }
+                            None => anyhow::bail!("unterminated double quote"),
+                        },
+                        Some(ch) => arg.push(ch),
+                        None => anyhow::bail!("unterminated double quote"),
+                    }
+                }
+            }
+            '\\' => match chars.next() {
+                Some(ch) => arg.get_or_insert_with(String::new).push(ch),
+                None => anyhow::bail!("trailing backslash"),
+            },
+            ch => arg.get_or_insert_with(String::new).push(ch),
+        }
+    }
+    args.extend(arg);
+    Ok(args)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::flags::lowargs::{BoundaryMode, CaseMode, TypeChange};
+
+    use super::*;
+
+    fn apply(options: Vec<(&str, OptionValue)>) -> anyhow::Result<LowArgs> {
+        let options: Vec<(String, OptionValue)> = options
+            .into_iter()
+            .map(|(name, value)| (name.to_string(), value))
+            .collect();
+        let mut low = LowArgs::default();
+        apply_options(&mut low, &options)?;
+        Ok(low)
+    }
+
+    #[test]
+    fn switches() {
+        let low = apply(vec![
+            ("hidden", OptionValue::Bool(true)),
+            ("fixed_strings", OptionValue::Bool(true)),
+            ("multiline", OptionValue::Bool(true)),
+            ("follow", OptionValue::Bool(true)),
+        ])
+        .unwrap();
+        assert!(low.hidden);
+        assert!(low.fixed_strings);
+        assert!(low.multiline);
+        assert!(low.follow);
+
+        let low = apply(vec![
+            ("hidden", OptionValue::Bool(true)),
+            ("hidden", OptionValue::Bool(false)),
+        ])
+        .unwrap();
+        assert!(!low.hidden);
+    }
+
+    #[test]
+    fn case() {
+        let low = apply(vec![("case", OptionValue::Str("smart".into()))]);
+        assert_eq!(CaseMode::Smart, low.unwrap().case);
+        let low = apply(vec![("case", OptionValue::Str("insensitive".into()))]);
+        assert_eq!(CaseMode::Insensitive, low.unwrap().case);
+        assert!(apply(vec![("case", OptionValue::Str("loud".into()))]).is_err());
+        assert!(apply(vec![("case", OptionValue::Bool(true))]).is_err());
+    }
+
+    #[test]
+    fn globs_and_types() {
+        let low = apply(vec![
+            ("glob", OptionValue::Str("*.rs".into())),
+            ("glob", OptionValue::List(vec!["!a".into(), "!b".into()])),
+            ("type", OptionValue::Str("rust".into())),
+        ])
+        .unwrap();
+        assert_eq!(vec!["*.rs", "!a", "!b"], low.globs);
+        assert_eq!(
+            vec![TypeChange::Select { name: "rust".to_string() }],
+            low.type_changes
+        );
+        assert!(apply(vec![("glob", OptionValue::Int(1))]).is_err());
+    }
+
+    #[test]
+    fn max_filesize() {
+        let low = apply(vec![("max_filesize", OptionValue::Int(1024))]);
+        assert_eq!(Some(1024), low.unwrap().max_filesize);
+        let low = apply(vec![("max_filesize", OptionValue::Str("1K".into()))]);
+        assert_eq!(Some(1024), low.unwrap().max_filesize);
+        let err = apply(vec![("max_filesize", OptionValue::Str("big".into()))])
+            .unwrap_err();
+        assert!(format!("{err:#}").contains("max_filesize"));
+    }
+
+    #[test]
+    fn unknown_option() {
+        let err = apply(vec![("colour", OptionValue::Bool(true))]).unwrap_err();
+        assert!(err.to_string().contains("unknown option 'colour'"));
+    }
+
+    #[test]
+    fn split() {
+        assert_eq!(
+            vec!["-t", "php", "-w", "get_option", "wp-content"],
+            split_args("  -t php -w\tget_option  wp-content ").unwrap()
+        );
+        assert_eq!(
+            vec!["a b", "c'd", "e\"f\\g\\n", "h i", ""],
+            split_args(r#"'a b' "c'd" "e\"f\\g\n" h\ i ''"#).unwrap()
+        );
+        assert_eq!(vec!["foobar"], split_args(r#"foo"bar""#).unwrap());
+        assert!(split_args("'foo").is_err());
+        assert!(split_args("\"foo").is_err());
+        assert!(split_args("foo\\").is_err());
+    }
+
+    #[test]
+    fn args_line() {
+        let mut low = LowArgs::default();
+        low.positional.push(OsString::from("stale"));
+        apply_args_line(&mut low, "-t php -w get_option wp-content").unwrap();
+        assert_eq!(
+            vec![OsString::from("get_option"), OsString::from("wp-content")],
+            low.positional
+        );
+        assert_eq!(
+            vec![TypeChange::Select { name: "php".to_string() }],
+            low.type_changes
+        );
+        assert_eq!(Some(BoundaryMode::Word), low.boundary);
+
+        let mut low = LowArgs::default();
+        apply_args_line(&mut low, "-e foo").unwrap();
+        assert_eq!(vec![OsString::from("./")], low.positional);
+        let mut low = LowArgs::default();
+        apply_args_line(&mut low, "foo").unwrap();
+        assert_eq!(
+            vec![OsString::from("foo"), OsString::from("./")],
+            low.positional
+        );
+        let mut low = LowArgs::default();
+        apply_args_line(&mut low, "").unwrap();
+        assert!(low.positional.is_empty());
+    }
+
+    #[test]
+    fn args_line_errors() {
+        let err = |line: &str| {
+            let mut low = LowArgs::default();
+            format!("{:#}", apply_args_line(&mut low, line).unwrap_err())
+        };
+        assert!(err("--no-such-flag foo")
+            .contains("unrecognized flag --no-such-flag"));
+        assert!(err("foo -").contains("stdin"));
+        assert!(err("-e foo -").contains("stdin"));
+        assert!(err("-f - ./").contains("stdin"));
+        assert!(err("--files").contains("only searches"));
+        assert!(err("--help").contains("--help"));
+        assert!(err("'foo").contains("unterminated"));
+    }
+}
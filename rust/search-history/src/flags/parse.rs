@@ -62,6 +62,16 @@ pub(crate) fn parse() -> ParseResult<HiArgs> {
 /// This will also set one-time global state flags, such as the log level and
 /// whether messages should be printed.
 pub fn parse_low() -> ParseResult<LowArgs> {
+    parse_low_args(std::env::args_os().skip(1).collect())
+}
+
+/// Like `parse_low`, but parses the given arguments instead of the ones
+/// passed to this process.
+///
+/// This is used by the server modes, which strip their own arguments before
+/// handing the rest to ripgrep's parser. The arguments given must *not*
+/// begin with the binary name.
+pub(crate) fn parse_low_args(args: Vec<OsString>) -> ParseResult<LowArgs> {
     if let Err(err) = crate::logger::Logger::init() {
         let err = anyhow::anyhow!("failed to initialize logger: {err}");
         return ParseResult::Err(err);
@@ -69,7 +79,7 @@ pub fn parse_low() -> ParseResult<LowArgs> {
 
     let parser = Parser::new();
     let mut low = LowArgs::default();
-    if let Err(err) = parser.parse(std::env::args_os().skip(1), &mut low) {
+    if let Err(err) = parser.parse(args.iter().cloned(), &mut low) {
         return ParseResult::Err(err);
     }
     // Even though we haven't parsed the config file yet (assuming it exists),
@@ -100,7 +110,7 @@ pub fn parse_low() -> ParseResult<LowArgs> {
     // The final arguments are just the arguments from the CLI appending to
     // the end of the config arguments.
     let mut final_args = config_args;
-    final_args.extend(std::env::args_os().skip(1));
+    final_args.extend(args);
 
     // Now do the CLI parsing dance again.
     let mut low = LowArgs::default();
@@ -144,6 +154,19 @@ pub(crate) fn parse_low_raw(
     Ok(args)
 }
 
+/// Parse the sequence of CLI arguments on top of the given low level
+/// arguments.
+///
+/// Unlike `parse_low`, this doesn't read config files, setup logging or
+/// treat special modes any differently. It is used for argument strings
+/// given to the server with a single search request.
+pub(super) fn parse_low_onto(
+    rawargs: impl IntoIterator<Item = impl Into<OsString>>,
+    low: &mut LowArgs,
+) -> anyhow::Result<()> {
+    Parser::new().parse(rawargs, low)
+}
+
 /// Return the metadata for the flag of the given name.
 pub(super) fn lookup(name: &str) -> Option<&'static dyn Flag> {
     // N.B. Creating a new parser might look expensive, but it only builds
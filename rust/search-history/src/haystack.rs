@@ -46,7 +46,7 @@ impl HaystackBuilder {
     /// If a directory entry could not be created or should otherwise not be
     /// searched, then this returns `None` after emitting any relevant log
     /// messages.
-    fn build(&self, dent: ignore::DirEntry) -> Option<Haystack> {
+    pub(crate) fn build(&self, dent: ignore::DirEntry) -> Option<Haystack> {
         let hay = Haystack { dent, strip_dot_prefix: self.strip_dot_prefix };
         if let Some(err) = hay.dent.error() {
             ignore_message!("{err}");
@@ -0,0 +1,541 @@
+/*!
+An optional on-disk trigram index that rules out files which can't match.
+
+Every match of most patterns contains at least one of a handful of literals,
+see `HiArgs::required_literals`. A file can only contain a literal if it
+contains every trigram (run of three bytes) of it, so keeping the set of
+trigrams of each file lets a search skip every file that has none of the
+literals without reading it. Files the index can't rule out are searched as
+usual, so the index never changes what a search finds.
+
+The index is filled in by the searches themselves. A file that isn't in the
+index yet, or whose size or modification time changed since it was indexed,
+is read once to compute its trigrams and then searched. A file that changed
+in the last couple of seconds isn't indexed yet, since another change within
+the same tick of its modification time wouldn't change its stamp, see
+`refresh.rs`. The index therefore covers the files the searches walked, with
+the same `WalkBuilder` filters, and stays up to date without ever walking the
+project on its own.
+Patterns without usable literals, e.g. `\w+` or literals shorter than three
+bytes, search every file.
+
+The index is kept next to the history file, as `<name>.index`. It starts
+with a magic number and a format version, followed by a single msgpack value
+holding the project root and a flat `[path, size, mtime, trigrams, ...]`
+array. A file's trigrams are sorted and delta encoded as varints, and are
+nil for files that are always searched, e.g., because they're too large to
+index or transcoded before searching. An index that can't be read is
+ignored and rebuilt as files are searched.
+
+The index is only used when `SEARCH_HISTORY_INDEX` is set to `1`.
+*/
+
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Mutex, OnceLock, RwLock,
+    },
+    time::SystemTime,
+};
+
+use anyhow::Context;
+use neovim_lib::Value;
+
+use crate::{
+    persist::{self, HistoryFile},
+    refresh::FileStamp,
+};
+
+/// The bytes every index file starts with.
+const MAGIC: &[u8; 4] = b"SHIX";
+
+/// The version of the format written by this build. Files with any other
+/// version are ignored when loading.
+const FORMAT_VERSION: u32 = 1;
+
+/// Files larger than this are always searched rather than indexed, since
+/// reading them twice would cost more than the index saves.
+const MAX_INDEXED_SIZE: u64 = 16 * (1 << 20);
+
+/// What the index knows about one file.
+#[derive(Clone, Debug, Eq, PartialEq)]
+struct Entry {
+    /// The stamp of the file when its trigrams were computed.
+    stamp: FileStamp,
+    /// The encoded trigrams of the file, or `None` if it's always searched.
+    trigrams: Option<Box<[u8]>>,
+}
+
+/// The trigram index of a project, shared by every search of the server.
+#[derive(Debug)]
+pub(crate) struct TrigramIndex {
+    root: PathBuf,
+    path: PathBuf,
+    /// Keyed by the absolute path of each file. Read on first use.
+    files: OnceLock<RwLock<HashMap<PathBuf, Entry>>>,
+    /// Held while writing the index to disk.
+    saving: Mutex<()>,
+}
+
+impl TrigramIndex {
+    /// Return the index kept next to the given history file if
+    /// `SEARCH_HISTORY_INDEX` is set to `1`, and `None` otherwise.
+    ///
+    /// Without a history file there's nowhere to keep the index, so it's
+    /// disabled as well.
+    pub(crate) fn from_env(
+        history_file: Option<&HistoryFile>,
+    ) -> Option<TrigramIndex> {
+        let history_file = history_file?;
+        match std::env::var("SEARCH_HISTORY_INDEX") {
+            Ok(value) if value.trim() == "1" => {}
+            Ok(value) if value.trim() == "0" || value.is_empty() => {
+                return None;
+            }
+            Ok(value) => {
+                log::warn!("ignoring SEARCH_HISTORY_INDEX={value:?}");
+                return None;
+            }
+            Err(_) => return None,
+        }
+        Some(TrigramIndex::new(history_file.root(), history_file.index_path()))
+    }
+
+    /// Return the index of the project at `root`, kept in the file at
+    /// `path`. Nothing is read until the index is first used.
+    pub(crate) fn new(root: &Path, path: PathBuf) -> TrigramIndex {
+        TrigramIndex {
+            root: root.to_path_buf(),
+            path,
+            files: OnceLock::new(),
+            saving: Mutex::new(()),
+        }
+    }
+
+    /// Start using the index for one search, whose matches each contain one
+    /// of `literals`.
+    ///
+    /// This returns `None` if the literals can't rule out any file, in which
+    /// case every file has to be searched.
+    pub(crate) fn filter(&self, literals: &[Vec<u8>]) -> Option<Filter<'_>> {
+        Some(Filter {
+            index: self,
+            query: Query::new(literals)?,
+            updates: Mutex::new(vec![]),
+            skipped: AtomicU64::new(0),
+        })
+    }
+
+    /// The entries of the index, reading them from disk the first time.
+    fn files(&self) -> &RwLock<HashMap<PathBuf, Entry>> {
+        self.files.get_or_init(|| {
+            let files = match self.load() {
+                Ok(files) => files,
+                Err(err) => {
+                    log::warn!(
+                        "{}: ignoring trigram index: {:#}",
+                        self.path.display(),
+                        err
+                    );
+                    HashMap::new()
+                }
+            };
+            RwLock::new(files)
+        })
+    }
+
+    /// Read the index from disk. A missing index is empty.
+    fn load(&self) -> anyhow::Result<HashMap<PathBuf, Entry>> {
+        let bytes = match std::fs::read(&self.path) {
+            Ok(bytes) => bytes,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => {
+                return Ok(HashMap::new());
+            }
+            Err(err) => return Err(err.into()),
+        };
+        let files = decode(&bytes, &self.root)?;
+        log::debug!(
+            "{}: loaded the trigrams of {} files",
+            self.path.display(),
+            files.len()
+        );
+        Ok(files)
+    }
+
+    /// Write the index to disk.
+    fn save(&self) -> anyhow::Result<()> {
+        let _saving = self.saving.lock().unwrap();
+        let bytes = encode(&self.root, &self.files().read().unwrap());
+        persist::write_atomic(&self.path, &bytes)
+    }
+}
+
+/// The index as used by a single search.
+///
+/// Walker threads ask it whether each file may match. Files that had to be
+/// indexed along the way are added to the index by `finish`, so that the
+/// index is only locked for writing once per search.
+#[derive(Debug)]
+pub(crate) struct Filter<'a> {
+    index: &'a TrigramIndex,
+    query: Query,
+    updates: Mutex<Vec<(PathBuf, Entry)>>,
+    skipped: AtomicU64,
+}
+
+impl Filter<'_> {
+    /// Returns false if the file at `path`, relative to the project root,
+    /// can't contain a match and needn't be searched.
+    ///
+    /// `stamp` is the stamp of the file as it's about to be searched. Files
+    /// without one are always searched.
+    pub(crate) fn may_match(
+        &self,
+        path: &Path,
+        stamp: Option<FileStamp>,
+    ) -> bool {
+        let Some(stamp) = stamp else { return true };
+        // Joined rather than canonicalized, since `Path` already ignores the
+        // `.` in paths like `./src/main.rs` when comparing and hashing.
+        let key = self.index.root.join(path);
+        let known = self
+            .index
+            .files()
+            .read()
+            .unwrap()
+            .get(&key)
+            .filter(|entry| entry.stamp == stamp)
+            .map(|entry| self.query.matches(entry.trigrams.as_deref()));
+        let may_match = match known {
+            Some(may_match) => may_match,
+            None => {
+                let Some(trigrams) = index_file(&key, stamp) else {
+                    return true;
+                };
+                let may_match = self.query.matches(trigrams.as_deref());
+                if stamp.settled(SystemTime::now()) {
+                    let entry = Entry { stamp, trigrams };
+                    self.updates.lock().unwrap().push((key, entry));
+                }
+                may_match
+            }
+        };
+        if !may_match {
+            self.skipped.fetch_add(1, Ordering::Relaxed);
+        }
+        may_match
+    }
+
+    /// Add the files indexed during the search to the index, and write it
+    /// to disk if any were.
+    ///
+    /// Failing to write the index only means files are indexed again by a
+    /// later server, so the error is logged rather than returned.
+    pub(crate) fn finish(self) {
+        let updates = self.updates.into_inner().unwrap();
+        log::debug!(
+            "trigram index ruled out {} files, indexed {}",
+            self.skipped.load(Ordering::Relaxed),
+            updates.len()
+        );
+        if updates.is_empty() {
+            return;
+        }
+        self.index.files().write().unwrap().extend(updates);
+        if let Err(err) = self.index.save() {
+            log::warn!("failed to save trigram index: {:#}", err);
+        }
+    }
+}
+
+/// Compute the index entry of the file at `path`, which was stamped with
+/// `stamp`.
+///
+/// The outer `None` means the file couldn't be read, and the inner one that
+/// the file is always searched. A file that changes after it was stamped
+/// is indexed as it's read, and indexed again next time since its stamp
+/// won't match anymore.
+fn index_file(path: &Path, stamp: FileStamp) -> Option<Option<Box<[u8]>>> {
+    if stamp.size > MAX_INDEXED_SIZE {
+        return Some(None);
+    }
+    let bytes = std::fs::read(path).ok()?;
+    // The searcher transcodes files with a UTF-16 BOM, so their matches
+    // aren't made of the bytes in the file.
+    if bytes.starts_with(b"\xFF\xFE") || bytes.starts_with(b"\xFE\xFF") {
+        return Some(None);
+    }
+    Some(Some(encode_trigrams(&trigrams(&bytes))))
+}
+
+/// The distinct trigrams of `bytes`, sorted.
+fn trigrams(bytes: &[u8]) -> Vec<u32> {
+    let mut trigrams: Vec<u32> = bytes
+        .windows(3)
+        .map(|w| {
+            (u32::from(w[0]) << 16) | (u32::from(w[1]) << 8) | u32::from(w[2])
+        })
+        .collect();
+    trigrams.sort_unstable();
+    trigrams.dedup();
+    trigrams
+}
+
+/// Encode sorted, distinct trigrams as the varints of the differences
+/// between consecutive trigrams.
+fn encode_trigrams(trigrams: &[u32]) -> Box<[u8]> {
+    let mut bytes = Vec::with_capacity(trigrams.len() * 2);
+    let mut prev = 0;
+    for &trigram in trigrams.iter() {
+        let mut delta = trigram - prev;
+        prev = trigram;
+        while delta >= 0x80 {
+            bytes.push((delta as u8) | 0x80);
+            delta >>= 7;
+        }
+        bytes.push(delta as u8);
+    }
+    bytes.into_boxed_slice()
+}
+
+/// Decode the trigrams encoded by `encode_trigrams`, in order.
+fn decode_trigrams(bytes: &[u8]) -> impl Iterator<Item = u32> + '_ {
+    let mut bytes = bytes.iter();
+    let mut prev = 0u32;
+    std::iter::from_fn(move || {
+        let mut delta = 0u32;
+        let mut shift = 0;
+        loop {
+            let b = *bytes.next()?;
+            delta |= u32::from(b & 0x7F) << shift;
+            if b & 0x80 == 0 {
+                break;
+            }
+            shift += 7;
+        }
+        prev = prev.wrapping_add(delta);
+        Some(prev)
+    })
+}
+
+/// The trigrams a file needs for each literal a search requires.
+#[derive(Clone, Debug)]
+struct Query {
+    /// Every trigram of every literal, sorted and distinct.
+    needed: Vec<u32>,
+    /// For each literal, the indices of its trigrams in `needed`.
+    alternatives: Vec<Vec<usize>>,
+}
+
+impl Query {
+    /// Returns `None` if the literals can't rule out any file, i.e., there
+    /// are none or one of them is shorter than a trigram.
+    fn new(literals: &[Vec<u8>]) -> Option<Query> {
+        if literals.is_empty() || literals.iter().any(|lit| lit.len() < 3) {
+            return None;
+        }
+        let per_literal: Vec<Vec<u32>> =
+            literals.iter().map(|lit| trigrams(lit)).collect();
+        let mut needed: Vec<u32> =
+            per_literal.iter().flatten().copied().collect();
+        needed.sort_unstable();
+        needed.dedup();
+        let alternatives = per_literal
+            .iter()
+            .map(|lit| {
+                lit.iter()
+                    .map(|t| needed.binary_search(t).unwrap())
+                    .collect()
+            })
+            .collect();
+        Some(Query { needed, alternatives })
+    }
+
+    /// Returns true if a file with the given encoded trigrams has every
+    /// trigram of at least one literal. Files without trigrams always match.
+    fn matches(&self, trigrams: Option<&[u8]>) -> bool {
+        let Some(trigrams) = trigrams else { return true };
+        let mut present = vec![false; self.needed.len()];
+        let mut i = 0;
+        for trigram in decode_trigrams(trigrams) {
+            while i < self.needed.len() && self.needed[i] < trigram {
+                i += 1;
+            }
+            if i == self.needed.len() {
+                break;
+            }
+            if self.needed[i] == trigram {
+                present[i] = true;
+                i += 1;
+            }
+        }
+        self.alternatives.iter().any(|alt| alt.iter().all(|&i| present[i]))
+    }
+}
+
+/// Encode the entries of the index of the project at `root`.
+fn encode(root: &Path, files: &HashMap<PathBuf, Entry>) -> Vec<u8> {
+    let entries = files
+        .iter()
+        .flat_map(|(path, entry)| {
+            [
+                Value::from(persist::path_bytes(path)),
+                Value::from(entry.stamp.size),
+                Value::from(entry.stamp.mtime),
+                entry
+                    .trigrams
+                    .as_deref()
+                    .map_or(Value::Nil, |t| Value::from(t.to_vec())),
+            ]
+        })
+        .collect();
+    let value = Value::Map(vec![
+        (Value::from("root"), Value::from(persist::path_bytes(root))),
+        (Value::from("files"), Value::Array(entries)),
+    ]);
+    let mut bytes = MAGIC.to_vec();
+    bytes.extend_from_slice(&FORMAT_VERSION.to_le_bytes());
+    // Writing to a `Vec` can't fail.
+    rmpv::encode::write_value(&mut bytes, &value).unwrap();
+    bytes
+}
+
+/// Decode an index file. An index written by another version or for
+/// another root is empty.
+fn decode(bytes: &[u8], root: &Path) -> anyhow::Result<HashMap<PathBuf, Entry>> {
+    if bytes.len() < 8 || &bytes[..4] != MAGIC {
+        anyhow::bail!("missing trigram index header");
+    }
+    let version = u32::from_le_bytes(bytes[4..8].try_into().unwrap());
+    if version != FORMAT_VERSION {
+        log::info!(
+            "ignoring trigram index with format version {} (expected {})",
+            version,
+            FORMAT_VERSION
+        );
+        return Ok(HashMap::new());
+    }
+    let mut rest = &bytes[8..];
+    let value = rmpv::decode::read_value(&mut rest)
+        .map_err(|err| anyhow::anyhow!("{}", err))?;
+    let field = |key: &str| -> anyhow::Result<&Value> {
+        value
+            .as_map()
+            .context("expected a map")?
+            .iter()
+            .find(|(k, _)| k.as_str() == Some(key))
+            .map(|(_, v)| v)
+            .with_context(|| format!("missing '{}'", key))
+    };
+    let stored_root = field("root")?.as_slice().context("bad root")?;
+    if persist::bytes_path(stored_root).as_path() != root {
+        return Ok(HashMap::new());
+    }
+    let values = field("files")?.as_array().context("bad files")?;
+    let mut files = HashMap::new();
+    for chunk in values.chunks(4) {
+        let [path, size, mtime, trigrams] = chunk else {
+            anyhow::bail!("files must be [path, size, mtime, trigrams]");
+        };
+        let path = path.as_slice().context("bad path")?;
+        let stamp = FileStamp {
+            size: size.as_u64().context("bad size")?,
+            mtime: mtime.as_u64().context("bad mtime")?,
+        };
+        let trigrams = match trigrams {
+            Value::Nil => None,
+            trigrams => Some(
+                trigrams.as_slice().context("bad trigrams")?.to_vec().into(),
+            ),
+        };
+        files.insert(persist::bytes_path(path), Entry { stamp, trigrams });
+    }
+    Ok(files)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Return a fresh, empty directory for a test to write into.
+    fn tempdir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!(
+            "search-history-test-{}-{}",
+            std::process::id(),
+            name
+        ));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn lits(literals: &[&str]) -> Vec<Vec<u8>> {
+        literals.iter().map(|lit| lit.as_bytes().to_vec()).collect()
+    }
+
+    #[test]
+    fn query() {
+        let file = encode_trigrams(&trigrams(b"let foo = bar;"));
+        assert_eq!(
+            trigrams(b"let foo = bar;"),
+            decode_trigrams(&file).collect::<Vec<u32>>()
+        );
+
+        let query = |literals: &[&str]| Query::new(&lits(literals)).unwrap();
+        assert!(query(&["foo"]).matches(Some(&file)));
+        assert!(query(&["quux", "bar;"]).matches(Some(&file)));
+        assert!(!query(&["quux"]).matches(Some(&file)));
+        assert!(!query(&["fool"]).matches(Some(&file)));
+        assert!(query(&["quux"]).matches(None));
+
+        assert!(Query::new(&lits(&["foo", "ba"])).is_none());
+        assert!(Query::new(&[]).is_none());
+    }
+
+    #[test]
+    fn filter() {
+        let dir = tempdir("index");
+        let root = dir.join("root");
+        std::fs::create_dir_all(&root).unwrap();
+        std::fs::write(root.join("a.txt"), "hello world\n").unwrap();
+        std::fs::write(root.join("b.txt"), "goodbye\n").unwrap();
+        std::fs::write(root.join("c.txt"), b"\xFF\xFEh\x00i\x00").unwrap();
+        // Stamped a minute back, since files that just changed aren't
+        // indexed yet.
+        let stamp = |name: &str| {
+            let stamp = FileStamp::of(&root.join(name))?;
+            Some(FileStamp { mtime: stamp.mtime - 60_000_000_000, ..stamp })
+        };
+        let path = dir.join("root.index");
+
+        let index = TrigramIndex::new(&root, path.clone());
+        let filter = index.filter(&lits(&["world"])).unwrap();
+        assert!(filter.may_match(Path::new("a.txt"), stamp("a.txt")));
+        assert!(!filter.may_match(Path::new("./b.txt"), stamp("b.txt")));
+        assert!(filter.may_match(Path::new("c.txt"), stamp("c.txt")));
+        assert!(filter.may_match(Path::new("d.txt"), None));
+        filter.finish();
+        assert!(path.exists());
+
+        // A new server reads the index from disk and only reads the file
+        // that changed since it was indexed.
+        std::fs::write(root.join("b.txt"), "goodbye world!\n").unwrap();
+        let index = TrigramIndex::new(&root, path.clone());
+        let filter = index.filter(&lits(&["world"])).unwrap();
+        assert!(filter.may_match(Path::new("b.txt"), stamp("b.txt")));
+        assert_eq!(1, filter.updates.lock().unwrap().len());
+        assert!(filter.may_match(Path::new("a.txt"), stamp("a.txt")));
+        assert_eq!(1, filter.updates.lock().unwrap().len());
+        let now = FileStamp::of(&root.join("a.txt"));
+        assert!(filter.may_match(Path::new("a.txt"), now));
+        assert_eq!(1, filter.updates.lock().unwrap().len());
+        filter.finish();
+
+        // The index of another root is ignored.
+        let other = TrigramIndex::new(&dir, path.clone());
+        assert!(other.files().read().unwrap().is_empty());
+        assert_eq!(3, index.files().read().unwrap().len());
+    }
+}
@@ -0,0 +1,389 @@
+/*!
+Keeps the state of live searches, which search as the user types.
+
+A client, e.g., a Telescope prompt, sends the whole pattern with a `live`
+call on every keystroke, all under one session id. Each session has at most
+one search thread, which waits until no keystroke came in for the session's
+debounce interval and then searches for the latest pattern only. A keystroke
+that comes in while a search is running cancels it, and nothing more of a
+cancelled search is sent to the client. Every event of a live search has the
+`generation` of the call that started it, so a client can also drop whatever
+was already on its way.
+
+When a pattern extends the previous one, e.g., `wp_qu` after `wp_q`, every
+line it matches was matched by the previous pattern too, so only the files
+the previous search matched are searched again. That only holds when both
+are literals, matched anywhere in a line, see [`narrows`].
+
+The latest search of a session runs and is stored under the id `live:` plus
+the session's name, see [`id`], so its results can be paged with `query`
+like any other search. Other searches can't have ids starting with `live:`,
+so a session never cancels or replaces a search that isn't its own.
+*/
+
+use std::{
+    path::PathBuf,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Condvar, Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+use neovim_lib::Value;
+
+use crate::{
+    flags::{LowArgs, PatternSource},
+    notify::StreamConfig,
+};
+
+/// What the ids of live sessions start with.
+pub(crate) const ID_PREFIX: &str = "live:";
+
+/// The id the searches of the live session named `session` run and are
+/// stored under.
+pub(crate) fn id(session: &str) -> String {
+    format!("{ID_PREFIX}{session}")
+}
+
+/// A search a live session was asked for.
+#[derive(Clone, Debug)]
+pub(crate) struct LiveQuery {
+    /// Which call of the session this is, counting from 1.
+    pub(crate) generation: u64,
+    /// The pattern as the client sent it.
+    pub(crate) pattern: String,
+    /// The paths as the client sent them.
+    pub(crate) paths: Vec<PathBuf>,
+    /// The `options` map as the client sent it.
+    pub(crate) options: Value,
+    /// The flags to search with.
+    pub(crate) low_args: LowArgs,
+    /// The directory the search runs in.
+    pub(crate) root: PathBuf,
+    /// How results are streamed back.
+    pub(crate) stream: StreamConfig,
+    /// How long to wait for another keystroke before searching.
+    pub(crate) debounce: Duration,
+    /// When the call came in.
+    pub(crate) received: Instant,
+}
+
+impl LiveQuery {
+    /// Returns true if `self` searches the same files with the same flags
+    /// as `previous`, so only the pattern differs.
+    fn same_search(&self, previous: &Matched) -> bool {
+        self.root == previous.root
+            && self.paths == previous.paths
+            && self.options == previous.options
+    }
+}
+
+/// The files matched by the last search of a session that ran to the end.
+#[derive(Clone, Debug)]
+pub(crate) struct Matched {
+    pub(crate) pattern: String,
+    pub(crate) paths: Vec<PathBuf>,
+    pub(crate) options: Value,
+    pub(crate) root: PathBuf,
+    /// The files with at least one match, relative to `root` the way the
+    /// search reports them.
+    pub(crate) files: Vec<PathBuf>,
+}
+
+/// The state of one live session.
+#[derive(Debug, Default)]
+pub(crate) struct Session {
+    state: Mutex<State>,
+    wake: Condvar,
+}
+
+#[derive(Debug, Default)]
+struct State {
+    /// The number of calls so far.
+    generation: u64,
+    /// The latest query, until the search thread takes it.
+    pending: Option<LiveQuery>,
+    /// The cancel flag of the running search.
+    running: Option<Arc<AtomicBool>>,
+    /// Whether the session has a search thread.
+    worker: bool,
+    /// What the last complete search matched.
+    matched: Option<Matched>,
+}
+
+impl Session {
+    /// Queue `query` as the latest one, cancelling the running search.
+    ///
+    /// Returns the generation of the query and whether a search thread has
+    /// to be started for the session, in which case it has to call `next`
+    /// until it returns `None`.
+    pub(crate) fn submit(&self, mut query: LiveQuery) -> (u64, bool) {
+        let mut state = self.state.lock().unwrap();
+        state.generation += 1;
+        query.generation = state.generation;
+        state.pending = Some(query);
+        if let Some(ref cancel) = state.running {
+            cancel.store(true, Ordering::SeqCst);
+        }
+        let spawn = !state.worker;
+        state.worker = true;
+        self.wake.notify_all();
+        (state.generation, spawn)
+    }
+
+    /// Cancel the running search and drop the pending one, e.g., when the
+    /// prompt is cleared. Returns the generation of the call doing so.
+    pub(crate) fn clear(&self) -> u64 {
+        let mut state = self.state.lock().unwrap();
+        state.generation += 1;
+        state.pending = None;
+        state.matched = None;
+        if let Some(ref cancel) = state.running {
+            cancel.store(true, Ordering::SeqCst);
+        }
+        self.wake.notify_all();
+        state.generation
+    }
+
+    /// Wait for the next query to search for, once no newer one came in for
+    /// its debounce interval. Returns the query along with the files to
+    /// search instead of its paths, when the previous search can be
+    /// narrowed down, and the flag that cancels it.
+    ///
+    /// Returns `None` when there's nothing left to search, after which the
+    /// search thread must exit.
+    pub(crate) fn next(
+        &self,
+    ) -> Option<(LiveQuery, Option<Vec<PathBuf>>, Arc<AtomicBool>)> {
+        let mut state = self.state.lock().unwrap();
+        state.running = None;
+        loop {
+            let due = match state.pending {
+                None => {
+                    state.worker = false;
+                    return None;
+                }
+                Some(ref query) => query.received + query.debounce,
+            };
+            let now = Instant::now();
+            if now < due {
+                state = self.wake.wait_timeout(state, due - now).unwrap().0;
+                continue;
+            }
+            let query = state.pending.take().unwrap();
+            let files = state
+                .matched
+                .as_ref()
+                .filter(|matched| {
+                    query.same_search(matched)
+                        && literal_flags(&query.low_args)
+                        && narrows(
+                            &matched.pattern,
+                            &query.pattern,
+                            query.low_args.fixed_strings,
+                        )
+                })
+                .map(|matched| matched.files.clone());
+            let cancel = Arc::new(AtomicBool::new(false));
+            state.running = Some(Arc::clone(&cancel));
+            return Some((query, files, cancel));
+        }
+    }
+
+    /// Returns true if a newer call came in after the one of `generation`.
+    pub(crate) fn is_stale(&self, generation: u64) -> bool {
+        self.state.lock().unwrap().generation != generation
+    }
+
+    /// Remember what the search of `generation` matched, if it ran to the
+    /// end and no newer call came in since.
+    pub(crate) fn matched(&self, generation: u64, matched: Matched) {
+        let mut state = self.state.lock().unwrap();
+        if state.generation == generation {
+            state.matched = Some(matched);
+        }
+    }
+}
+
+/// Returns true if every line `pattern` matches is also matched by
+/// `previous`, going by the patterns alone.
+///
+/// That's the case when `pattern` starts with `previous` and both are plain
+/// literals, either because they have no regex syntax or because of `-F`.
+/// With regex syntax it isn't, e.g., `foo|bar` starts with `foo`. Case
+/// insensitivity doesn't matter, since extending a pattern can only turn
+/// smart case sensitive, which matches less.
+pub(crate) fn narrows(
+    previous: &str,
+    pattern: &str,
+    fixed_strings: bool,
+) -> bool {
+    !previous.is_empty()
+        && pattern.starts_with(previous)
+        && (fixed_strings || (is_literal(previous) && is_literal(pattern)))
+}
+
+/// Returns true if the flags in `low_args` keep a literal pattern a plain
+/// substring match, which `narrows` relies on.
+///
+/// With `-w` or `-x`, `foo` matching a line doesn't follow from `foob`
+/// matching it, and with `-v` it's the other way around. Only a single
+/// pattern is looked at, so `-e` and `-f` rule it out as well.
+pub(crate) fn literal_flags(low_args: &LowArgs) -> bool {
+    low_args.boundary.is_none()
+        && !low_args.invert_match
+        && matches!(low_args.patterns[..], [PatternSource::Regexp(_)])
+}
+
+/// Returns true if `pattern` has no regex syntax in it.
+///
+/// `-`, `&` and `~` are only special inside a class, which takes a `[`, and
+/// `#` only in verbose mode, which takes a `(?x)`, so they aren't looked at.
+fn is_literal(pattern: &str) -> bool {
+    !pattern.chars().any(|c| {
+        matches!(
+            c,
+            '\\' | '.'
+                | '+'
+                | '*'
+                | '?'
+                | '('
+                | ')'
+                | '|'
+                | '['
+                | ']'
+                | '{'
+                | '}'
+                | '^'
+                | '$'
+        )
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::flags::{apply_options, OptionValue};
+
+    use super::*;
+
+    fn query(pattern: &str, debounce: u64) -> LiveQuery {
+        let low_args = LowArgs {
+            patterns: vec![PatternSource::Regexp(pattern.to_string())],
+            ..Default::default()
+        };
+        LiveQuery {
+            generation: 0,
+            pattern: pattern.to_string(),
+            paths: vec![PathBuf::from("./")],
+            options: Value::Nil,
+            low_args,
+            root: PathBuf::from("/project"),
+            stream: StreamConfig::default(),
+            debounce: Duration::from_millis(debounce),
+            received: Instant::now(),
+        }
+    }
+
+    #[test]
+    fn narrowing() {
+        assert!(narrows("wp_q", "wp_query", false));
+        assert!(narrows("wp_q", "wp_q", false));
+        assert!(narrows("Foo", "Foo bar", false));
+        assert!(!narrows("", "foo", false));
+        assert!(!narrows("foo", "fo", false));
+        assert!(!narrows("foo", "foo|bar", false));
+        assert!(!narrows("foo", "foo?", false));
+        assert!(!narrows("a.b", "a.bc", false));
+        assert!(narrows("a.b", "a.bc", true));
+        assert!(narrows("wp-admin", "wp-admin/", false));
+        assert!(narrows("a & b", "a & b ~ #c", false));
+
+        let mut low_args = query("foo", 0).low_args;
+        assert!(literal_flags(&low_args));
+        low_args.invert_match = true;
+        assert!(!literal_flags(&low_args));
+    }
+
+    #[test]
+    fn narrowing_with_case_flags() {
+        // Going from `foo` to `fooB` turns smart case sensitive, which only
+        // matches fewer of the lines `foo` matched.
+        for case in ["smart", "insensitive"] {
+            let session = Session::default();
+            let search = |pattern: &str| {
+                let mut query = query(pattern, 0);
+                let case = OptionValue::Str(case.to_string());
+                apply_options(&mut query.low_args, &[("case".into(), case)])
+                    .unwrap();
+                let (generation, _) = session.submit(query);
+                let (query, files, _) = session.next().unwrap();
+                session.matched(
+                    generation,
+                    Matched {
+                        pattern: query.pattern,
+                        paths: query.paths,
+                        options: query.options,
+                        root: query.root,
+                        files: vec![PathBuf::from("a.rs")],
+                    },
+                );
+                files
+            };
+            assert_eq!(None, search("foo"));
+            assert_eq!(Some(vec![PathBuf::from("a.rs")]), search("fooB"));
+            assert_eq!(Some(vec![PathBuf::from("a.rs")]), search("fooBar"));
+        }
+    }
+
+    #[test]
+    fn latest_query_wins() {
+        let session = Session::default();
+        assert_eq!((1, true), session.submit(query("f", 0)));
+        let (first, files, cancel) = session.next().unwrap();
+        assert_eq!((1, None), (first.generation, files));
+
+        // A keystroke while searching cancels the search, and only the
+        // latest of several keystrokes is searched.
+        assert_eq!((2, false), session.submit(query("fo", 0)));
+        assert!(cancel.load(Ordering::SeqCst));
+        assert!(session.is_stale(1));
+        assert_eq!((3, false), session.submit(query("foo", 0)));
+        let (latest, _, _) = session.next().unwrap();
+        assert_eq!(("foo", 3), (&latest.pattern[..], latest.generation));
+        assert!(!session.is_stale(3));
+        session.matched(
+            3,
+            Matched {
+                pattern: "foo".to_string(),
+                paths: latest.paths.clone(),
+                options: Value::Nil,
+                root: latest.root.clone(),
+                files: vec![PathBuf::from("a.rs")],
+            },
+        );
+
+        // The thread exits once there's nothing left, and a new one is
+        // needed for the next keystroke, which narrows down the last one.
+        assert!(session.next().is_none());
+        assert_eq!((4, true), session.submit(query("foob", 0)));
+        let (_, files, _) = session.next().unwrap();
+        assert_eq!(Some(vec![PathBuf::from("a.rs")]), files);
+
+        session.clear();
+        assert_eq!((6, false), session.submit(query("foobar", 0)));
+        let (_, files, _) = session.next().unwrap();
+        assert_eq!(None, files);
+    }
+
+    #[test]
+    fn debounce() {
+        let session = Session::default();
+        let start = Instant::now();
+        session.submit(query("f", 30));
+        session.submit(query("fo", 30));
+        let (query, _, _) = session.next().unwrap();
+        assert_eq!("fo", query.pattern);
+        assert!(start.elapsed() >= Duration::from_millis(30));
+    }
+}
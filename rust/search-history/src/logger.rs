@@ -4,37 +4,90 @@ Defines a super simple logger that works with the `log` crate.
 We don't do anything fancy. We just need basic log levels and the ability to
 print to stderr. We therefore avoid bringing in extra dependencies just for
 this functionality.
+
+When running as a server, stderr is nvim's to show or drop, so the server
+logs to a file instead, see [`LogConfig`]. The file is rotated once it gets
+too big, and which messages make it in can be set per module:
+
+* `SEARCH_HISTORY_LOG_FILE` is the file to log to. It defaults to
+  `server.log` in the same directory as the history files. Set it to `-` to
+  keep logging to stderr.
+* `SEARCH_HISTORY_LOG` is a list of filters like
+  `warn,search_history::watch=debug,ignore=trace`. A bare level applies to
+  every module without a filter of its own. It defaults to the level set by
+  `--debug` or `--trace`, plus every RPC call at `search_history::rpc=info`.
+* `SEARCH_HISTORY_LOG_MAX_BYTES` is the size a file is rotated at, 10MiB by
+  default. The last three rotated files are kept as `server.log.1` to
+  `server.log.3`.
+
+Every line in the file starts with the time in UTC, the level, the module
+and the process id, since several servers may share one file.
 */
 
-use log::Log;
+use std::{
+    fs::File,
+    io::{self, Write},
+    path::{Path, PathBuf},
+    sync::Mutex,
+    time::{SystemTime, UNIX_EPOCH},
+};
 
-/// The simplest possible logger that logs to stderr.
+use log::{LevelFilter, Log};
+
+/// The simplest possible logger that logs to stderr, or to a file.
 ///
-/// This logger does no filtering. Instead, it relies on the `log` crates
-/// filtering via its global max_level setting.
+/// When logging to stderr, this logger does no filtering. Instead, it relies
+/// on the `log` crates filtering via its global max_level setting. When
+/// logging to a file, the file's filter is applied as well.
 #[derive(Debug)]
-pub(crate) struct Logger(());
+pub(crate) struct Logger {
+    file: Mutex<Option<FileLog>>,
+}
 
 /// A singleton used as the target for an implementation of the `Log` trait.
-const LOGGER: &'static Logger = &Logger(());
+static LOGGER: Logger = Logger { file: Mutex::new(None) };
 
 impl Logger {
     /// Create a new logger that logs to stderr and initialize it as the
     /// global logger. If there was a problem setting the logger, then an
     /// error is returned.
     pub(crate) fn init() -> Result<(), log::SetLoggerError> {
-        log::set_logger(LOGGER)
+        log::set_logger(&LOGGER)
+    }
+
+    /// Log to the file of `config` from now on, rather than to stderr.
+    ///
+    /// This also sets the global max level to the most verbose level of the
+    /// file's filter.
+    pub(crate) fn to_file(config: LogConfig) -> io::Result<()> {
+        let file = LogFile::open(config.path, config.max_bytes, config.keep)?;
+        log::set_max_level(config.filter.max_level());
+        *LOGGER.file.lock().unwrap() =
+            Some(FileLog { file, filter: config.filter });
+        Ok(())
     }
 }
 
 impl Log for Logger {
-    fn enabled(&self, _: &log::Metadata<'_>) -> bool {
-        // We set the log level via log::set_max_level, so we don't need to
-        // implement filtering here.
-        true
+    fn enabled(&self, metadata: &log::Metadata<'_>) -> bool {
+        // On stderr, we set the log level via log::set_max_level, so we
+        // don't need to implement filtering here.
+        match *self.file.lock().unwrap() {
+            None => true,
+            Some(ref log) => log.filter.enabled(metadata),
+        }
     }
 
     fn log(&self, record: &log::Record<'_>) {
+        let mut file = self.file.lock().unwrap();
+        if let Some(ref mut log) = *file {
+            if log.filter.enabled(record.metadata()) {
+                // Nowhere left to report a failure to log to.
+                let _ = log.file.write_line(&file_line(record));
+            }
+            return;
+        }
+        drop(file);
         match (record.file(), record.line()) {
             (Some(file), Some(line)) => {
                 eprintln_locked!(
@@ -67,6 +120,341 @@ impl Log for Logger {
     }
 
     fn flush(&self) {
-        // We use eprintln_locked! which is flushed on every call.
+        // We use eprintln_locked! which is flushed on every call, and every
+        // line is written to the log file as a whole.
+    }
+}
+
+/// How the server logs to a file.
+#[derive(Clone, Debug)]
+pub(crate) struct LogConfig {
+    /// The file to log to.
+    pub(crate) path: PathBuf,
+    /// Which messages to log.
+    pub(crate) filter: Filter,
+    /// The size the file is rotated at.
+    pub(crate) max_bytes: u64,
+    /// The number of rotated files to keep.
+    pub(crate) keep: usize,
+}
+
+impl LogConfig {
+    /// Read the log file configuration from the environment, see the module
+    /// docs. Returns `None` when logging should stay on stderr.
+    ///
+    /// The default filter is based on the current global max level, so this
+    /// must be called after the flags have been parsed.
+    pub(crate) fn from_env() -> Option<LogConfig> {
+        let path = match std::env::var_os("SEARCH_HISTORY_LOG_FILE") {
+            Some(path) if path == "-" => return None,
+            Some(path) if !path.is_empty() => PathBuf::from(path),
+            _ => crate::persist::state_dir()?.join("server.log"),
+        };
+        let mut filter = Filter::new(log::max_level());
+        filter.add("search_history::rpc", LevelFilter::Info);
+        if let Ok(spec) = std::env::var("SEARCH_HISTORY_LOG") {
+            match Filter::parse(&spec, log::max_level()) {
+                Ok(parsed) => filter = parsed,
+                Err(err) => log::warn!("ignoring SEARCH_HISTORY_LOG: {err}"),
+            }
+        }
+        let mut max_bytes = 10 * (1 << 20);
+        if let Ok(value) = std::env::var("SEARCH_HISTORY_LOG_MAX_BYTES") {
+            match value.trim().parse::<u64>() {
+                Ok(n) => max_bytes = n,
+                Err(err) => log::warn!(
+                    "ignoring SEARCH_HISTORY_LOG_MAX_BYTES={value:?}: {err}"
+                ),
+            }
+        }
+        Some(LogConfig { path, filter, max_bytes, keep: 3 })
+    }
+}
+
+/// The level logged at per module.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub(crate) struct Filter {
+    /// The level of modules without a level of their own.
+    default: LevelFilter,
+    /// Module paths and their levels, longest path first so that the most
+    /// specific one wins.
+    modules: Vec<(String, LevelFilter)>,
+}
+
+impl Filter {
+    /// A filter that logs every module at `default`.
+    pub(crate) fn new(default: LevelFilter) -> Filter {
+        Filter { default, modules: vec![] }
+    }
+
+    /// Parse a comma separated list of `module=level` filters and bare
+    /// levels, where a bare level replaces `default`.
+    pub(crate) fn parse(
+        spec: &str,
+        default: LevelFilter,
+    ) -> anyhow::Result<Filter> {
+        let mut filter = Filter::new(default);
+        for directive in spec.split(',').map(str::trim) {
+            if directive.is_empty() {
+                continue;
+            }
+            let level = |name: &str| {
+                name.trim().parse::<LevelFilter>().map_err(|_| {
+                    anyhow::anyhow!("unknown log level '{}'", name.trim())
+                })
+            };
+            match directive.split_once('=') {
+                None => filter.default = level(directive)?,
+                Some((module, _)) if module.trim().is_empty() => {
+                    anyhow::bail!("missing module in '{}'", directive)
+                }
+                Some((module, name)) => {
+                    filter.add(module.trim(), level(name)?);
+                }
+            }
+        }
+        Ok(filter)
+    }
+
+    /// Log `module` and the modules inside it at `level`.
+    pub(crate) fn add(&mut self, module: &str, level: LevelFilter) {
+        self.modules.retain(|(m, _)| m != module);
+        self.modules.push((module.to_string(), level));
+        self.modules.sort_by_key(|(m, _)| std::cmp::Reverse(m.len()));
+    }
+
+    /// The level messages from `target` are logged at.
+    fn level(&self, target: &str) -> LevelFilter {
+        self.modules
+            .iter()
+            .find(|(module, _)| {
+                target.strip_prefix(module.as_str()).is_some_and(|rest| {
+                    rest.is_empty() || rest.starts_with("::")
+                })
+            })
+            .map_or(self.default, |&(_, level)| level)
+    }
+
+    /// Whether a message is logged.
+    fn enabled(&self, metadata: &log::Metadata<'_>) -> bool {
+        metadata.level() <= self.level(metadata.target())
+    }
+
+    /// The most verbose level any module is logged at.
+    fn max_level(&self) -> LevelFilter {
+        self.modules
+            .iter()
+            .map(|&(_, level)| level)
+            .fold(self.default, std::cmp::max)
+    }
+}
+
+/// The log file of the server and its filter.
+#[derive(Debug)]
+struct FileLog {
+    file: LogFile,
+    filter: Filter,
+}
+
+/// A file that's appended to and rotated once it reaches `max_bytes`.
+///
+/// Rotating renames `server.log` to `server.log.1`, `server.log.1` to
+/// `server.log.2`, and so on, dropping the oldest beyond `keep`.
+#[derive(Debug)]
+struct LogFile {
+    path: PathBuf,
+    file: File,
+    len: u64,
+    max_bytes: u64,
+    keep: usize,
+}
+
+impl LogFile {
+    /// Open the file at `path` for appending, creating it and its directory
+    /// if needed.
+    fn open(
+        path: PathBuf,
+        max_bytes: u64,
+        keep: usize,
+    ) -> io::Result<LogFile> {
+        if let Some(dir) = path.parent() {
+            std::fs::create_dir_all(dir)?;
+        }
+        let file = append(&path)?;
+        let len = file.metadata()?.len();
+        Ok(LogFile { path, file, len, max_bytes, keep })
+    }
+
+    /// Append a whole line, rotating first if it would make the file too
+    /// big. A line longer than `max_bytes` still gets a file of its own.
+    fn write_line(&mut self, line: &str) -> io::Result<()> {
+        if self.len > 0 && self.len + line.len() as u64 > self.max_bytes {
+            self.rotate()?;
+        }
+        // One write per line, so lines of servers sharing the file don't
+        // interleave.
+        self.file.write_all(line.as_bytes())?;
+        self.len += line.len() as u64;
+        Ok(())
+    }
+
+    fn rotate(&mut self) -> io::Result<()> {
+        for n in (1..self.keep).rev() {
+            rename_if_exists(
+                &rotated(&self.path, n),
+                &rotated(&self.path, n + 1),
+            )?;
+        }
+        if self.keep == 0 {
+            std::fs::remove_file(&self.path)?;
+        } else {
+            rename_if_exists(&self.path, &rotated(&self.path, 1))?;
+        }
+        self.file = append(&self.path)?;
+        self.len = 0;
+        Ok(())
+    }
+}
+
+fn append(path: &Path) -> io::Result<File> {
+    File::options().create(true).append(true).open(path)
+}
+
+/// The path of the `n`th rotated file, e.g., `server.log.2`.
+fn rotated(path: &Path, n: usize) -> PathBuf {
+    let mut name = path.as_os_str().to_os_string();
+    name.push(format!(".{}", n));
+    PathBuf::from(name)
+}
+
+/// Rename `from` to `to`, which is fine to not exist when another server
+/// sharing the file rotated it first.
+fn rename_if_exists(from: &Path, to: &Path) -> io::Result<()> {
+    match std::fs::rename(from, to) {
+        Err(err) if err.kind() != io::ErrorKind::NotFound => Err(err),
+        _ => Ok(()),
+    }
+}
+
+/// Format a record as a line of the log file.
+fn file_line(record: &log::Record<'_>) -> String {
+    let location = match (record.file(), record.line()) {
+        (Some(file), Some(line)) => format!(" {}:{}", file, line),
+        (Some(file), None) => format!(" {}", file),
+        _ => String::new(),
+    };
+    format!(
+        "{} {:<5} {}[{}]{}: {}\n",
+        timestamp(SystemTime::now()),
+        record.level(),
+        record.target(),
+        std::process::id(),
+        location,
+        record.args()
+    )
+}
+
+/// Format `time` as an RFC 3339 timestamp in UTC with milliseconds.
+fn timestamp(time: SystemTime) -> String {
+    let millis = time.duration_since(UNIX_EPOCH).map_or(0, |d| d.as_millis());
+    let secs = (millis / 1000) as u64;
+    let (days, rem) = (secs / 86400, secs % 86400);
+    // Howard Hinnant's days_from_civil, in reverse.
+    let z = days + 719468;
+    let era = z / 146097;
+    let doe = z - era * 146097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + u64::from(month <= 2);
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}.{:03}Z",
+        year,
+        month,
+        day,
+        rem / 3600,
+        rem / 60 % 60,
+        rem % 60,
+        millis % 1000
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn enabled(filter: &Filter, level: log::Level, target: &str) -> bool {
+        let metadata =
+            log::Metadata::builder().level(level).target(target).build();
+        filter.enabled(&metadata)
+    }
+
+    #[test]
+    fn filter() {
+        let filter = Filter::parse(
+            "search_history::watch=debug, ignore=trace,error",
+            LevelFilter::Warn,
+        )
+        .unwrap();
+        assert_eq!(LevelFilter::Trace, filter.max_level());
+        assert!(enabled(&filter, log::Level::Debug, "search_history::watch"));
+        assert!(!enabled(&filter, log::Level::Warn, "search_history"));
+        assert!(!enabled(&filter, log::Level::Debug, "search_history::rpc"));
+        assert!(enabled(&filter, log::Level::Trace, "ignore::walk"));
+        // Only whole module names match.
+        assert!(!enabled(&filter, log::Level::Trace, "ignored"));
+
+        let mut filter = Filter::new(LevelFilter::Warn);
+        filter.add("search_history", LevelFilter::Off);
+        filter.add("search_history::rpc", LevelFilter::Info);
+        assert!(enabled(&filter, log::Level::Info, "search_history::rpc"));
+        assert!(!enabled(&filter, log::Level::Error, "search_history::store"));
+
+        assert!(Filter::parse("loud", LevelFilter::Warn).is_err());
+        assert!(Filter::parse("=info", LevelFilter::Warn).is_err());
+    }
+
+    #[test]
+    fn rotation() {
+        let dir = std::env::temp_dir().join(format!(
+            "search-history-test-{}-log-rotation",
+            std::process::id()
+        ));
+        let _ = std::fs::remove_dir_all(&dir);
+        let path = dir.join("server.log");
+        let mut file = LogFile::open(path.clone(), 10, 2).unwrap();
+        for line in ["one\n", "two\n", "three\n", "four\n", "five\n"] {
+            file.write_line(line).unwrap();
+        }
+        let read = |path: &Path| std::fs::read_to_string(path).unwrap();
+        assert_eq!("four\nfive\n", read(&path));
+        assert_eq!("three\n", read(&rotated(&path, 1)));
+        assert_eq!("one\ntwo\n", read(&rotated(&path, 2)));
+
+        // Reopening picks up the size of what's there, and the oldest file
+        // is dropped.
+        let mut file = LogFile::open(path.clone(), 10, 2).unwrap();
+        file.write_line("six\n").unwrap();
+        assert_eq!("six\n", read(&path));
+        assert_eq!("four\nfive\n", read(&rotated(&path, 1)));
+        assert_eq!("three\n", read(&rotated(&path, 2)));
+        assert!(!rotated(&path, 3).exists());
+    }
+
+    #[test]
+    fn timestamps() {
+        let at =
+            |millis| UNIX_EPOCH + std::time::Duration::from_millis(millis);
+        assert_eq!("1970-01-01T00:00:00.000Z", timestamp(at(0)));
+        assert_eq!(
+            "2023-11-14T22:13:20.123Z",
+            timestamp(at(1_700_000_000_123))
+        );
+        assert_eq!(
+            "2024-02-29T23:59:59.999Z",
+            timestamp(at(1_709_251_199_999))
+        );
     }
 }
@@ -17,117 +17,1253 @@
 //From local
 use memory_stats::memory_stats;
 
-use std::{sync::{Arc, Mutex}, collections::HashMap, io::Write, process::ExitCode};
-use crate::{search::{SearchResult, SearchResults}, flags::{HiArgs, LowArgs, SearchMode}};
+use std::{
+    collections::HashMap,
+    io::Write,
+    process::ExitCode,
+    sync::{atomic::{AtomicBool, Ordering}, Arc, Mutex, MutexGuard, Once},
+};
+use crate::{
+    collect::{Collector, FlushPolicy},
+    notify::{Event, Notifier, Progress, StreamConfig, Streamer},
+    search::{SearchResult, SearchResults, SearchWorker},
+    errors::FileError,
+    flags::{HiArgs, LowArgs, PatternSource, SearchMode},
+    index::TrigramIndex,
+    persist::HistoryFile,
+    rank::Ranker,
+    refine::RefineScope,
+    refresh::{Diff, FileStamp, FileStamps},
+    replace::Journal,
+    rpc::RpcError,
+    stats::{Phase, SearchStats, Skip},
+    store::{SearchQuery, SearchRecord, SearchStore, StoreLimits},
+    watch::Watcher,
+};
 // End conflict
 
 use ignore::WalkState;
-use neovim_lib::{Neovim, NeovimApi, Session};
+use neovim_lib::{Neovim, NeovimApi, Session, Value};
 
 #[macro_use]
 mod messages;
+mod collect;
+mod combine;
+mod errors;
 mod flags;
 mod haystack;
+mod index;
+mod live;
 mod logger;
+mod notify;
+mod persist;
+mod quickfix;
+mod rank;
+mod refine;
+mod refresh;
+mod replace;
+mod rg;
+mod rpc;
 mod search;
-
-#[derive(Debug)]
-struct SearchStore {
-    search_store: HashMap<String, SearchResults>,
-}
+mod server;
+mod stats;
+mod store;
+mod watch;
 
 struct EventHandler {
-    nvim: Neovim,
+    nvim: Arc<Mutex<Neovim>>,
 }
 enum RpcMessages {
     Search,
     Query,
+    ListHistory,
+    Cancel,
+    Refine,
+    Combine,
+    Refresh,
+    Quickfix,
+    Replace,
+    Revert,
+    Stats,
+    QueryErrors,
+    Live,
     Unknown(String),
 }
 impl From<String> for RpcMessages {
     fn from(event: String) -> Self {
         match &event[..] {
             "search" => RpcMessages::Search,
+            "query" => RpcMessages::Query,
+            "list_history" => RpcMessages::ListHistory,
+            "cancel" => RpcMessages::Cancel,
+            "refine" => RpcMessages::Refine,
+            "combine" => RpcMessages::Combine,
+            "refresh" => RpcMessages::Refresh,
+            "quickfix" => RpcMessages::Quickfix,
+            "replace" => RpcMessages::Replace,
+            "revert" => RpcMessages::Revert,
+            "stats" => RpcMessages::Stats,
+            "query_errors" => RpcMessages::QueryErrors,
+            "live" => RpcMessages::Live,
             _ => RpcMessages::Unknown(event),
         }
     }
 }
+
+/// Answers RPC calls against the shared search store.
+///
+/// Requests are handled on neovim-lib's dispatch thread, so nothing in here
+/// may call back into nvim. Notifications are routed through the same
+/// methods from `EventHandler::recv`. Searches themselves run on their own
+/// threads and report back through the notifier.
+#[derive(Clone)]
+struct SearchHandler {
+    initial_args: LowArgs,
+    search_store: Arc<Mutex<SearchStore>>,
+    history_file: Option<HistoryFile>,
+    history_loaded: Arc<Once>,
+    running: Arc<Mutex<HashMap<String, Arc<AtomicBool>>>>,
+    notifier: Notifier,
+    watcher: Option<Watcher>,
+    index: Option<Arc<TrigramIndex>>,
+    journal: Option<Journal>,
+    live: Arc<Mutex<HashMap<String, Arc<live::Session>>>>,
+}
+impl SearchHandler {
+    fn new(initial_args: LowArgs, notifier: Notifier, history_file: Option<HistoryFile>) -> SearchHandler {
+        let index = TrigramIndex::from_env(history_file.as_ref()).map(Arc::new);
+        let journal = history_file.as_ref().map(|history| Journal::new(history.journal_dir()));
+        return SearchHandler {
+            initial_args,
+            search_store: Arc::new(Mutex::new(SearchStore::new(StoreLimits::from_env()))),
+            history_file,
+            history_loaded: Arc::new(Once::new()),
+            running: Arc::new(Mutex::new(HashMap::new())),
+            notifier,
+            watcher: Watcher::new(),
+            index,
+            journal,
+            live: Arc::new(Mutex::new(HashMap::new())),
+        };
+    }
+
+    /// The same handler, sharing the same store and running searches, but
+    /// sending the events of the searches it starts somewhere else.
+    fn with_notifier(&self, notifier: Notifier) -> SearchHandler {
+        return SearchHandler { notifier, ..self.clone() };
+    }
+
+    /// Lock the search store, reading the history from disk the first time.
+    ///
+    /// Loading waits until the store is first needed so that starting the
+    /// server stays fast even with a large history.
+    fn lock_store(&self) -> MutexGuard<'_, SearchStore> {
+        let mut search_store = self.search_store.lock().unwrap();
+        self.history_loaded.call_once(|| {
+            if let Some(ref history_file) = self.history_file {
+                if let Err(err) = history_file.load_into(&mut search_store) {
+                    log::warn!("failed to load search history: {:#}", err);
+                }
+            }
+            //Changes made while the server wasn't running need a refresh, later ones are picked up
+            for record in search_store.history() {
+                self.watch_files(record.root(), record.files());
+            }
+        });
+        return search_store;
+    }
+
+    /// Write the store to disk. Failing to do so only loses history, so the
+    /// error is logged rather than returned to the client.
+    fn save_history(&self, search_store: &SearchStore) {
+        if let Some(ref history_file) = self.history_file {
+            if let Err(err) = history_file.save(search_store) {
+                log::warn!("failed to save search history: {:#}", err);
+            }
+        }
+    }
+
+    /// Start searching changed files again for the stored searches that
+    /// cover them, see `watch.rs`. Which searches changed goes to `notifier`.
+    fn start_watching(&self, notifier: Notifier) {
+        let watcher = match self.watcher {
+            Some(ref watcher) => watcher,
+            None => return,
+        };
+        let handler = self.with_notifier(notifier);
+        watcher.spawn(move |paths| handler.files_changed(paths));
+    }
+
+    /// Watch the directories of the files a search searched from `root`.
+    fn watch_files(&self, root: &std::path::Path, files: &FileStamps) {
+        if let Some(ref watcher) = self.watcher {
+            watcher.watch_dirs(watch::dirs_of(root, files));
+        }
+    }
+
+    /// Update every stored search covering the changed files at `paths`,
+    /// which are absolute, and tell the client which searches changed.
+    ///
+    /// Searches made with `refine` or `combine` aren't updated, and neither
+    /// are searches that are running or being refreshed.
+    fn files_changed(&self, paths: Vec<std::path::PathBuf>) {
+        let cwd = match std::env::current_dir() {
+            Ok(cwd) => cwd,
+            Err(err) => {
+                log::warn!("not updating searches: {}", err);
+                return;
+            }
+        };
+        //Only what's needed is copied out, the files are searched without holding the store
+        let mut affected = vec![];
+        {
+            let search_store = self.lock_store();
+            let running = self.running.lock().unwrap();
+            for record in search_store.history() {
+                let query = record.query();
+                if query.refine.is_some() || query.combine.is_some() {
+                    continue;
+                }
+                if record.root() != &cwd || running.contains_key(record.id()) {
+                    continue;
+                }
+                let roots = match query.paths.is_empty() {
+                    true => vec![std::path::PathBuf::from("./")],
+                    false => query.paths.clone(),
+                };
+                let files = watch::affected(&cwd, &roots, record.files(), &paths);
+                if !files.is_empty() {
+                    affected.push((record.id().to_string(), record.created(), query.clone(), files));
+                }
+            }
+        }
+
+        let mut changed = vec![];
+        for (id, created, query, files) in affected {
+            let (keys, searched) = match self.search_files(&cwd, &query, files) {
+                Ok(found) => found,
+                Err(err) => {
+                    log::warn!("failed to update search '{}': {:#}", id, err);
+                    continue;
+                }
+            };
+            if keys.is_empty() {
+                continue;
+            }
+            let mut search_store = self.lock_store();
+            let record = match search_store.peek(&id) {
+                //Refreshed or replaced while we searched, that's newer than what we found
+                Some(record) if record.created() == created => record,
+                _ => continue,
+            };
+            let results = watch::splice(record.results(), &keys, searched.results);
+            let mut files = record.files().clone();
+            for key in keys.iter() {
+                files.remove(key);
+            }
+            files.extend(searched.stamps);
+            //The files searched again failed or not anew
+            let mut errors: Vec<FileError> = record.errors().iter()
+                .filter(|err| !keys.contains(&err.path))
+                .cloned()
+                .collect();
+            errors.extend(searched.errors);
+            let count = results.len();
+            search_store.update_results(&id, results, files, errors);
+            changed.push((id, keys, count));
+        }
+        if changed.is_empty() {
+            return;
+        }
+        self.save_history(&self.lock_store());
+        for (id, keys, count) in changed {
+            let paths = keys.into_iter().map(Value::from).collect();
+            self.notifier.send(Event::Changed, Value::Map(vec![
+                (Value::from("id"), Value::from(id)),
+                (Value::from("paths"), Value::Array(paths)),
+                (Value::from("count"), Value::from(count as u64)),
+            ]));
+        }
+    }
+
+    /// Search the affected files of a stored search again with its flags.
+    ///
+    /// Returns the files whose results are replaced, as the search reports
+    /// them, along with what searching them found. Deleted files are
+    /// among them without any results. New files are only among them if a
+    /// walk of their directory with the search's flags finds them.
+    fn search_files(
+        &self,
+        cwd: &std::path::Path,
+        query: &SearchQuery,
+        files: watch::Affected,
+    ) -> anyhow::Result<(Vec<String>, Searched)> {
+        let options = rpc::decode_options("options", &query.options)
+            .map_err(|err| anyhow::anyhow!("{}", err))?;
+        let (low_args, _) = self.search_args(query_input(query), &options)
+            .map_err(|err| anyhow::anyhow!("{}", err))?;
+
+        let mut keys = files.changed;
+        for candidate in files.candidates {
+            let path = std::path::Path::new(&candidate);
+            //A file in the root directory is reported without a directory
+            let dir = match path.parent() {
+                Some(dir) if dir != std::path::Path::new("") => dir.as_os_str().to_os_string(),
+                _ => std::ffi::OsString::from("./"),
+            };
+            let mut walk_args = with_paths(low_args.clone(), vec![dir]);
+            walk_args.max_depth = Some(1);
+            let walk_args = HiArgs::from_low_args(walk_args)?;
+            let found = walk_args.walk_builder()?.build().filter_map(Result::ok).any(|entry| {
+                entry.file_type().is_some_and(|t| t.is_file()) && cwd.join(entry.path()) == cwd.join(path)
+            });
+            if found {
+                keys.push(candidate);
+            }
+        }
+
+        let existing: Vec<std::ffi::OsString> = keys.iter()
+            .filter(|key| std::path::Path::new(key).is_file())
+            .map(std::ffi::OsString::from)
+            .collect();
+        if existing.is_empty() {
+            return Ok((keys, Searched::empty()));
+        }
+        let args = HiArgs::from_low_args(with_paths(low_args, existing))?;
+        let searcher = args.search_worker(args.matcher()?)?;
+        let searched = search_parallel(&args, searcher, &AtomicBool::new(false), None, None, self.index.as_deref())?;
+        return Ok((keys, searched));
+    }
+
+    fn handle(&self, event: String, values: &[Value]) -> Result<Value, RpcError> {
+        //Every call is logged with how long it took, to debug the protocol from the log file
+        let start = std::time::Instant::now();
+        log::trace!(target: "search_history::rpc", "method={} args={:?}", event, values);
+        let method = event.clone();
+        let result = self.dispatch(event, values);
+        let status = match result {
+            Ok(_) => "ok",
+            Err(ref err) => err.kind().as_str(),
+        };
+        log::info!(target: "search_history::rpc", "method={} elapsed_ms={:.3} status={}", method, start.elapsed().as_secs_f64() * 1e3, status);
+        return result;
+    }
+
+    fn dispatch(&self, event: String, values: &[Value]) -> Result<Value, RpcError> {
+        match RpcMessages::from(event) {
+            RpcMessages::Search => self.search(values),
+            RpcMessages::Query => self.query(values),
+            RpcMessages::ListHistory => self.list_history(values),
+            RpcMessages::Cancel => self.cancel(values),
+            RpcMessages::Refine => self.refine(values),
+            RpcMessages::Combine => self.combine(values),
+            RpcMessages::Refresh => self.refresh(values),
+            RpcMessages::Quickfix => self.quickfix(values),
+            RpcMessages::Replace => self.replace(values),
+            RpcMessages::Revert => self.revert(values),
+            RpcMessages::Stats => self.stats(values),
+            RpcMessages::QueryErrors => self.query_errors(values),
+            RpcMessages::Live => self.live(values),
+            RpcMessages::Unknown(event) => Err(RpcError::unknown_method(&event)),
+        }
+    }
+
+    /// Start a search on its own thread and return its id right away.
+    ///
+    /// Anything wrong with the request itself, including a pattern that
+    /// doesn't compile, is returned as an error from this call. Once the
+    /// search is over its results are stored and a `Done` event is sent with
+    /// the id, the match count and whether the search ran to completion.
+    fn search(&self, values: &[Value]) -> Result<Value, RpcError> {
+        let request = rpc::SearchRequest::from_args(values)?;
+        let stream = request.stream;
+        let (cloned_args, query_args) = self.search_args(request.input, &request.options)?;
+        let (pattern, paths) = describe_search(&cloned_args);
+        let query = SearchQuery {
+            pattern,
+            paths,
+            args: query_args,
+            options: request.raw_options,
+            refine: None,
+            combine: None,
+        };
+        let args = HiArgs::from_low_args(cloned_args).map_err(RpcError::search)?;
+        let root = std::env::current_dir()
+            .map_err(|err| RpcError::search(err.into()))?;
+        return self.start_search(request.id, query, root, args, stream, None);
+    }
+
+    /// The flags for searching `input`, along with the command line to store
+    /// with the search if it was given as one. Shared by `search` and
+    /// `refresh`, so a refreshed search runs with exactly the same flags.
+    fn search_args(
+        &self,
+        input: rpc::SearchInput,
+        options: &[(String, flags::OptionValue)],
+    ) -> Result<(LowArgs, Option<String>), RpcError> {
+        //The server's own flags are the base, the request supplies pattern, roots and overrides
+        let mut cloned_args = self.initial_args.clone();
+        let query_args = match input {
+            rpc::SearchInput::Pattern { pattern, paths } => {
+                cloned_args.patterns = vec![PatternSource::Regexp(pattern)];
+                cloned_args.positional =
+                    paths.into_iter().map(|path| path.into_os_string()).collect();
+                None
+            }
+            //Same errors as the CLI gives, but they go back to the client rather than stderr
+            rpc::SearchInput::Args(line) => {
+                flags::apply_args_line(&mut cloned_args, &line)
+                    .map_err(|err| RpcError::invalid_args(format!("{:#}", err)))?;
+                Some(line)
+            }
+        };
+        flags::apply_options(&mut cloned_args, options)
+            .map_err(|err| RpcError::invalid_args(format!("{:#}", err)))?;
+        return Ok((cloned_args, query_args));
+    }
+
+    /// Start the thread for a search whose arguments are ready, returning
+    /// its id. This is shared by `search`, `refine` and `refresh`, which
+    /// passes the record being refreshed as `previous`.
+    fn start_search(
+        &self,
+        requested_id: Option<String>,
+        query: SearchQuery,
+        root: std::path::PathBuf,
+        args: HiArgs,
+        stream: StreamConfig,
+        previous: Option<SearchRecord>,
+    ) -> Result<Value, RpcError> {
+        let searcher = args.matcher()
+            .and_then(|matcher| args.search_worker(matcher))
+            .map_err(RpcError::search)?;
+
+        let id = self.lock_store().next_id(requested_id);
+        let cancel = Arc::new(AtomicBool::new(false));
+        {
+            let mut running = self.running.lock().unwrap();
+            if running.contains_key(&id) {
+                return Err(RpcError::invalid_args(format!(
+                    "a search with id '{}' is already running",
+                    id
+                )));
+            }
+            running.insert(id.clone(), Arc::clone(&cancel));
+        }
+
+        let handler = self.clone();
+        let thread_id = id.clone();
+        let spawned = std::thread::Builder::new()
+            .name(format!("search-history {}", id))
+            .spawn(move || {
+                let streamer = Streamer::new(thread_id.clone(), handler.notifier.clone(), stream);
+                handler.run_search(thread_id, query, root, args, searcher, cancel, streamer, previous)
+            });
+        if let Err(err) = spawned {
+            self.running.lock().unwrap().remove(&id);
+            return Err(RpcError::search(err.into()));
+        }
+        return Ok(Value::Map(vec![
+            (Value::from("id"), Value::from(id)),
+            (Value::from("status"), Value::from("running")),
+        ]));
+    }
+
+    /// The body of a search thread started by `start_search`.
+    ///
+    /// When refreshing, files that haven't changed since `previous` ran
+    /// aren't searched again. Their results are carried over instead, and
+    /// the `Done` event also has the `diff` against `previous`.
+    #[allow(clippy::too_many_arguments)]
+    fn run_search(
+        &self,
+        id: String,
+        query: SearchQuery,
+        root: std::path::PathBuf,
+        args: HiArgs,
+        searcher: SearchWorker,
+        cancel: Arc<AtomicBool>,
+        streamer: Streamer,
+        previous: Option<SearchRecord>,
+    ) {
+        //Stamps are only trusted if the file changed well before the search that took them started
+        let started = std::time::SystemTime::now();
+        let unchanged = previous.as_ref().map(|previous| (previous.files(), previous.created()));
+        let outcome = match args.matches_possible() {
+            true => search_parallel(&args, searcher, &cancel, Some(&streamer), unchanged, self.index.as_deref()),
+            false => Ok(Searched::empty()),
+        };
+        self.running.lock().unwrap().remove(&id);
+
+        let Searched { results: search_results, stamps: files, stats, errors } = match outcome {
+            Ok(outcome) => outcome,
+            Err(err) => {
+                let data = Value::Map(vec![
+                    (Value::from("id"), Value::from(id)),
+                    (Value::from("error"), RpcError::search(err).into_value()),
+                ]);
+                self.notifier.send(Event::Done, data);
+                return;
+            }
+        };
+        //A cancelled search keeps whatever it found so far, marked as incomplete
+        let complete = !cancel.load(Ordering::SeqCst);
+        let (search_results, diff) = match previous {
+            //A cancelled refresh would be diffed against half a walk, the stored search stays as it was
+            Some(ref previous) if !complete => {
+                self.notifier.send(Event::Done, Value::Map(vec![
+                    (Value::from("id"), Value::from(id)),
+                    (Value::from("count"), Value::from(previous.results().len() as u64)),
+                    (Value::from("complete"), Value::from(false)),
+                    (Value::from("diff"), Value::Nil),
+                ]));
+                return;
+            }
+            Some(ref previous) => {
+                let search_results = refresh::carry_over(previous.results(), previous.files(), previous.created(), &files, search_results);
+                let diff = Diff::between(previous.results(), &search_results);
+                (search_results, Some(diff))
+            }
+            None => (search_results, None),
+        };
+        let count = search_results.len();
+        let error_count = errors.len();
+        self.watch_files(&root, &files);
+        let mut record = SearchRecord::new(id.clone(), query, root, search_results).with_created(started).with_files(files).with_errors(errors).with_stats(stats);
+        record.set_complete(complete);
+        {
+            let mut search_store = self.lock_store();
+            search_store.insert(record);
+            self.save_history(&search_store);
+        }
+        let mut data = vec![
+            (Value::from("id"), Value::from(id)),
+            (Value::from("count"), Value::from(count as u64)),
+            (Value::from("complete"), Value::from(complete)),
+            (Value::from("errors"), Value::from(error_count as u64)),
+        ];
+        if let Some(ref diff) = diff {
+            data.push((Value::from("diff"), rpc::diff_to_value(diff)));
+        }
+        self.notifier.send(Event::Done, Value::Map(data));
+    }
+
+    /// Search as the user types, see `live.rs`.
+    ///
+    /// Every call returns right away with its `generation`. The search
+    /// starts once no newer call of the session came in for `debounce`, and
+    /// sends the same events as `search`, with the session's own id, see
+    /// `live::id`, and the `generation` added. Its `Done` event also has the `pattern` and
+    /// whether it was `narrowed` down to the previous search's files. An
+    /// empty pattern only cancels the session's search.
+    fn live(&self, values: &[Value]) -> Result<Value, RpcError> {
+        let request = rpc::LiveRequest::from_args(values)?;
+        let session = Arc::clone(self.live.lock().unwrap().entry(request.session.clone()).or_default());
+        if request.pattern.is_empty() {
+            let generation = session.clear();
+            return Ok(Value::Map(vec![
+                (Value::from("session"), Value::from(request.session)),
+                (Value::from("generation"), Value::from(generation)),
+                (Value::from("status"), Value::from("cleared")),
+            ]));
+        }
+        let input = rpc::SearchInput::Pattern { pattern: request.pattern.clone(), paths: request.paths.clone() };
+        let (low_args, _) = self.search_args(input, &request.options)?;
+        //A pattern that doesn't compile yet goes back to the prompt, the previous search keeps going
+        HiArgs::from_low_args(low_args.clone())
+            .and_then(|args| args.matcher())
+            .map_err(RpcError::search)?;
+        let root = std::env::current_dir()
+            .map_err(|err| RpcError::search(err.into()))?;
+        let (generation, spawn) = session.submit(live::LiveQuery {
+            generation: 0,
+            pattern: request.pattern,
+            paths: request.paths,
+            options: request.raw_options,
+            low_args,
+            root,
+            stream: request.stream,
+            debounce: request.debounce,
+            received: std::time::Instant::now(),
+        });
+        if spawn {
+            //Its own ids, so a session named like a search never cancels or replaces it
+            let (handler, name, session) = (self.clone(), live::id(&request.session), Arc::clone(&session));
+            let spawned = std::thread::Builder::new()
+                .name(format!("search-history live {}", name))
+                .spawn(move || handler.run_live(name, session));
+            if let Err(err) = spawned {
+                //The session would wait on a thread that never came, start over with a new one
+                self.live.lock().unwrap().remove(&request.session);
+                return Err(RpcError::search(err.into()));
+            }
+        }
+        return Ok(Value::Map(vec![
+            (Value::from("id"), Value::from(live::id(&request.session))),
+            (Value::from("session"), Value::from(request.session)),
+            (Value::from("generation"), Value::from(generation)),
+            (Value::from("status"), Value::from("pending")),
+        ]));
+    }
+
+    /// The body of the search thread of a live session, which searches for
+    /// the latest pattern until no newer one is left.
+    fn run_live(&self, name: String, session: Arc<live::Session>) {
+        while let Some((query, files, cancel)) = session.next() {
+            let started = std::time::SystemTime::now();
+            let generation = query.generation;
+            let narrowed = files.is_some();
+            let mut low_args = query.low_args;
+            if let Some(files) = files {
+                //Stored paths are relative to the root, which is where we are
+                low_args.positional = files.into_iter().map(|file| file.into_os_string()).collect();
+            }
+            let streamer = Streamer::new(name.clone(), self.notifier.clone(), query.stream)
+                .live(generation, Arc::clone(&cancel));
+            self.running.lock().unwrap().insert(name.clone(), Arc::clone(&cancel));
+            let outcome = match narrowed && low_args.positional.is_empty() {
+                //The previous pattern matched nothing, so neither does this one
+                true => Ok(Searched::empty()),
+                false => HiArgs::from_low_args(low_args).and_then(|args| {
+                    let searcher = args.search_worker(args.matcher()?)?;
+                    match args.matches_possible() {
+                        true => search_parallel(&args, searcher, &cancel, Some(&streamer), None, self.index.as_deref()),
+                        false => Ok(Searched::empty()),
+                    }
+                }),
+            };
+            self.running.lock().unwrap().remove(&name);
+            //Nothing of a search a newer call replaced goes back, not even that it's over
+            if session.is_stale(generation) {
+                continue;
+            }
+
+            let Searched { results, stamps, stats, errors } = match outcome {
+                Ok(searched) => searched,
+                Err(err) => {
+                    self.notifier.send(Event::Done, Value::Map(vec![
+                        (Value::from("id"), Value::from(name.as_str())),
+                        (Value::from("generation"), Value::from(generation)),
+                        (Value::from("error"), RpcError::search(err).into_value()),
+                    ]));
+                    continue;
+                }
+            };
+            let complete = !cancel.load(Ordering::SeqCst);
+            if complete {
+                let files = refine::refine_files(&results, &ignore::overrides::Override::empty());
+                session.matched(generation, live::Matched {
+                    pattern: query.pattern.clone(),
+                    paths: query.paths.clone(),
+                    options: query.options.clone(),
+                    root: query.root.clone(),
+                    files,
+                });
+            }
+            let (count, error_count) = (results.len(), errors.len());
+            let stored = SearchQuery {
+                pattern: query.pattern.clone(),
+                paths: query.paths,
+                args: None,
+                options: query.options,
+                refine: None,
+                combine: None,
+            };
+            let mut record = SearchRecord::new(name.clone(), stored, query.root, results).with_created(started).with_files(stamps).with_errors(errors).with_stats(stats);
+            record.set_complete(complete);
+            //Kept for query and the like, but only written out along with the next search rather than on every keystroke
+            self.lock_store().insert(record);
+            self.notifier.send(Event::Done, Value::Map(vec![
+                (Value::from("id"), Value::from(name.as_str())),
+                (Value::from("generation"), Value::from(generation)),
+                (Value::from("pattern"), Value::from(query.pattern)),
+                (Value::from("count"), Value::from(count as u64)),
+                (Value::from("complete"), Value::from(complete)),
+                (Value::from("errors"), Value::from(error_count as u64)),
+                (Value::from("narrowed"), Value::from(narrowed)),
+            ]));
+        }
+    }
+
+    /// Run a stored search again with the flags it was made with, see
+    /// `refresh.rs`.
+    ///
+    /// This starts a search under the same id, like `search` does, and the
+    /// search replaces the stored one when it's over. Its `Done` event has a
+    /// `diff` with the results `added`, `removed` and `moved` since the
+    /// previous run. Searches made with `refine` or `combine` can't be
+    /// refreshed, refreshing the searches they were made from works instead.
+    fn refresh(&self, values: &[Value]) -> Result<Value, RpcError> {
+        let request = rpc::RefreshRequest::from_args(values)?;
+        //Cloned so the store isn't held for the whole search, it's the baseline for the diff
+        let previous = match self.lock_store().get(&request.id) {
+            Some(previous) => previous.clone(),
+            None => return Err(RpcError::not_found(&request.id)),
+        };
+        let query = previous.query().clone();
+        if query.refine.is_some() || query.combine.is_some() {
+            return Err(RpcError::invalid_args(format!(
+                "search '{}' was made with {} and can't be refreshed",
+                request.id,
+                if query.refine.is_some() { "refine" } else { "combine" }
+            )));
+        }
+        //Stored paths are relative to where the search ran
+        let root = previous.root().clone();
+        let cwd = std::env::current_dir()
+            .map_err(|err| RpcError::search(err.into()))?;
+        if root != cwd {
+            return Err(RpcError::invalid_args(format!(
+                "search '{}' was made in {}, not in {}",
+                request.id,
+                root.display(),
+                cwd.display()
+            )));
+        }
+
+        let options = rpc::decode_options("options", &query.options)?;
+        let (low_args, _) = self.search_args(query_input(&query), &options)?;
+        let args = HiArgs::from_low_args(low_args).map_err(RpcError::search)?;
+        return self.start_search(Some(request.id), query, root, args, request.stream, Some(previous));
+    }
+
+    /// Narrow a stored search down into a new one, see `refine.rs`.
+    ///
+    /// Refining by lines only looks at the parent's results, so it's done
+    /// right away and the new search is returned as done. Refining by files
+    /// searches the parent's files again on its own thread, just like
+    /// `search`, and reports back through the same events.
+    fn refine(&self, values: &[Value]) -> Result<Value, RpcError> {
+        let request = rpc::RefineRequest::from_args(values)?;
+        let refinement = request.refine.clone();
+
+        //The new pattern gets the server's flags plus the request's overrides, same as a search
+        let mut low_args = self.initial_args.clone();
+        flags::apply_options(&mut low_args, &request.options)
+            .map_err(|err| RpcError::invalid_args(format!("{:#}", err)))?;
+        low_args.patterns = refinement.pattern.iter()
+            .map(|pattern| PatternSource::Regexp(pattern.clone()))
+            .collect();
+        low_args.positional.clear();
+        //Compiled before locking the store, the lines themselves are quick
+        let matcher = match (refinement.scope, &refinement.pattern) {
+            (RefineScope::Lines, Some(_)) => Some(
+                HiArgs::from_low_args(low_args.clone())
+                    .and_then(|args| args.matcher())
+                    .map_err(RpcError::search)?,
+            ),
+            _ => None,
+        };
+
+        let mut search_store = self.lock_store();
+        let parent = match search_store.get(&refinement.parent) {
+            Some(parent) => parent,
+            None => return Err(RpcError::not_found(&refinement.parent)),
+        };
+        let root = parent.root().clone();
+        let filter = refine::path_filter(&root, &refinement.globs)
+            .map_err(|err| RpcError::invalid_args(format!("{:#}", err)))?;
+        //Where and what the parent searched still describes the refined search
+        let mut query = parent.query().clone();
+        if let Some(ref pattern) = refinement.pattern {
+            query.pattern = pattern.clone();
+        }
+        query.args = None;
+        query.options = request.raw_options;
+        query.refine = Some(refinement.clone());
+
+        let (results, complete) = match refinement.scope {
+            RefineScope::Lines => {
+                let results = refine::refine_lines(parent.results(), matcher.as_ref(), &filter)
+                    .map_err(|err| RpcError::search(err.into()))?;
+                (results, parent.complete())
+            }
+            RefineScope::Files => {
+                let files = refine::refine_files(parent.results(), &filter);
+                drop(search_store);
+                if files.is_empty() {
+                    //Nothing left to search, which is just an empty result
+                    search_store = self.lock_store();
+                    (SearchResults::new(), true)
+                } else {
+                    //The parent's paths are relative to where it ran, which is normally where we are
+                    let cwd = std::env::current_dir()
+                        .map_err(|err| RpcError::search(err.into()))?;
+                    low_args.positional = files.into_iter()
+                        .map(|file| if root == cwd { file } else { root.join(file) })
+                        .map(|file| file.into_os_string())
+                        .collect();
+                    let args = HiArgs::from_low_args(low_args).map_err(RpcError::search)?;
+                    return self.start_search(request.id, query, root, args, request.stream, None);
+                }
+            }
+        };
+
+        return self.store_done(&mut search_store, request.id, query, root, results, complete);
+    }
+
+    /// Combine stored searches into a new one with a set operation, see
+    /// `combine.rs`.
+    ///
+    /// Only stored results are looked at, so the new search is stored and
+    /// returned as done right away.
+    fn combine(&self, values: &[Value]) -> Result<Value, RpcError> {
+        let request = rpc::CombineRequest::from_args(values)?;
+        let combination = request.combine;
+
+        let mut search_store = self.lock_store();
+        //Peeking, combining searches isn't the same as reopening them
+        let mut inputs = vec![];
+        for id in combination.ids.iter() {
+            match search_store.peek(id) {
+                Some(record) => inputs.push(record),
+                None => return Err(RpcError::not_found(id)),
+            }
+        }
+        let input_results: Vec<&SearchResults> = inputs.iter().map(|record| record.results()).collect();
+        let results = combine::combine(combination.op, combination.level, &input_results);
+        let complete = inputs.iter().all(|record| record.complete());
+        let root = inputs[0].root().clone();
+
+        //Shown in the history as e.g. "wp_query and not meta_query", over everywhere the inputs searched
+        let patterns: Vec<&str> = inputs.iter().map(|record| record.query().pattern.as_str()).collect();
+        let mut paths: Vec<std::path::PathBuf> = vec![];
+        for record in inputs.iter() {
+            for path in record.query().paths.iter() {
+                if !paths.contains(path) {
+                    paths.push(path.clone());
+                }
+            }
+        }
+        let query = SearchQuery {
+            pattern: patterns.join(&format!(" {} ", combination.op.conjunction())),
+            paths,
+            args: None,
+            options: Value::Nil,
+            refine: None,
+            combine: Some(combination),
+        };
+        return self.store_done(&mut search_store, request.id, query, root, results, complete);
+    }
+
+    /// Store a search that was made without a search thread, e.g., by
+    /// `refine` or `combine`, and return it as done.
+    fn store_done(
+        &self,
+        search_store: &mut SearchStore,
+        requested_id: Option<String>,
+        query: SearchQuery,
+        root: std::path::PathBuf,
+        results: SearchResults,
+        complete: bool,
+    ) -> Result<Value, RpcError> {
+        let id = search_store.next_id(requested_id);
+        if self.running.lock().unwrap().contains_key(&id) {
+            return Err(RpcError::invalid_args(format!(
+                "a search with id '{}' is already running",
+                id
+            )));
+        }
+        let count = results.len();
+        let mut record = SearchRecord::new(id.clone(), query, root, results);
+        record.set_complete(complete);
+        search_store.insert(record);
+        self.save_history(search_store);
+        return Ok(Value::Map(vec![
+            (Value::from("id"), Value::from(id)),
+            (Value::from("status"), Value::from("done")),
+            (Value::from("count"), Value::from(count as u64)),
+            (Value::from("complete"), Value::from(complete)),
+        ]));
+    }
+
+    /// Stop a running search. Its partial results are still stored.
+    fn cancel(&self, values: &[Value]) -> Result<Value, RpcError> {
+        let map = rpc::ArgMap::from_args("cancel", values)?;
+        let id = map.required_str("id")?;
+        match self.running.lock().unwrap().get(id) {
+            Some(cancel) => cancel.store(true, Ordering::SeqCst),
+            None => return Err(RpcError::not_found(id)),
+        }
+        return Ok(Value::Map(vec![
+            (Value::from("id"), Value::from(id)),
+            (Value::from("cancelled"), Value::from(true)),
+        ]));
+    }
+
+    /// Cancel every running search, e.g., when the client goes away.
+    fn cancel_all(&self) {
+        for cancel in self.running.lock().unwrap().values() {
+            cancel.store(true, Ordering::SeqCst);
+        }
+    }
+
+    /// Return one page of a stored search so clients never have to pull
+    /// every result in a single payload.
+    ///
+    /// With `rank`, the results are paged through best first, see
+    /// `rank.rs`. They're ranked again on every call since the current file
+    /// and the time change between calls.
+    fn query(&self, values: &[Value]) -> Result<Value, RpcError> {
+        let request = rpc::QueryRequest::from_args(values)?;
+        let mut search_store = self.lock_store();
+        let record = match search_store.get(&request.id) {
+            Some(record) => record,
+            None => return Err(RpcError::not_found(&request.id)),
+        };
+        if request.rank {
+            let ranker = Ranker::new(record.root(), request.current_file.as_deref(), std::time::SystemTime::now());
+            let ranked = ranker.rank(record.results(), record.files());
+            return Ok(rpc::ranked_page_to_value(
+                &request.id,
+                &ranked,
+                request.offset,
+                request.limit,
+                request.context,
+                request.explain,
+            ));
+        }
+        return Ok(rpc::page_to_value(
+            &request.id,
+            record.results(),
+            request.offset,
+            request.limit,
+            request.context,
+        ));
+    }
+
+    /// Put a stored search in the client's quickfix or location list, see
+    /// `quickfix.rs`.
+    ///
+    /// The list is set from its own thread, since nvim can't be called from
+    /// a request handler, so it may arrive just after this returns. Returns
+    /// the number of entries set and how many results passed the globs.
+    fn quickfix(&self, values: &[Value]) -> Result<Value, RpcError> {
+        let request = rpc::QuickfixRequest::from_args(values)?;
+        let (entries, title) = {
+            let mut search_store = self.lock_store();
+            let record = match search_store.get(&request.id) {
+                Some(record) => record,
+                None => return Err(RpcError::not_found(&request.id)),
+            };
+            let filter = refine::path_filter(record.root(), &request.globs)
+                .map_err(|err| RpcError::invalid_args(format!("{:#}", err)))?;
+            let entries = quickfix::entries(record.results(), record.root(), &filter, request.max);
+            (entries, format!("{}: {}", request.id, record.query().pattern))
+        };
+        let count = entries.items.len();
+        let args = quickfix::set_list_args(request.list, request.window, request.action, &title, entries.items);
+        let open = match (request.open, request.list) {
+            (false, _) => None,
+            (true, quickfix::ListKind::Quickfix) => Some("botright copen"),
+            (true, quickfix::ListKind::Location) => Some("lopen"),
+        };
+        let notifier = self.notifier.clone();
+        let spawned = std::thread::Builder::new()
+            .name(format!("search-history quickfix {}", request.id))
+            .spawn(move || {
+                notifier.call("nvim_call_function", args);
+                if let Some(command) = open {
+                    notifier.call("nvim_command", vec![Value::from(command)]);
+                }
+            });
+        if let Err(err) = spawned {
+            return Err(RpcError::search(err.into()));
+        }
+        return Ok(Value::Map(vec![
+            (Value::from("id"), Value::from(request.id)),
+            (Value::from("list"), Value::from(request.list.as_str())),
+            (Value::from("count"), Value::from(count as u64)),
+            (Value::from("total"), Value::from(entries.total as u64)),
+        ]));
+    }
+
+    /// Replace the hits of a stored search in their files, see `replace.rs`.
+    ///
+    /// By default this is a dry run that returns every hunk for the client
+    /// to preview. With `dry_run` off, the hunks given by their index in the
+    /// preview (or all of them) are written, and the journal id to `revert`
+    /// them with is returned. The search itself isn't changed, so its files
+    /// are stamped from before and refreshing it is needed before replacing
+    /// in them again.
+    fn replace(&self, values: &[Value]) -> Result<Value, RpcError> {
+        let request = rpc::ReplaceRequest::from_args(values)?;
+        //Cloned so the store isn't held while files are read and written
+        let record = match self.lock_store().get(&request.id) {
+            Some(record) => record.clone(),
+            None => return Err(RpcError::not_found(&request.id)),
+        };
+        let query = record.query();
+        if query.refine.is_some() || query.combine.is_some() {
+            return Err(RpcError::invalid_args(format!(
+                "search '{}' was made with {} and can't be replaced in",
+                request.id,
+                if query.refine.is_some() { "refine" } else { "combine" }
+            )));
+        }
+        //The replacement is expanded with the search's own pattern and flags, like -r would
+        let options = rpc::decode_options("options", &query.options)?;
+        let (low_args, _) = self.search_args(query_input(query), &options)?;
+        let args = HiArgs::from_low_args(low_args).map_err(RpcError::search)?;
+        let matcher = args.matcher().map_err(RpcError::search)?;
+        let filter = refine::path_filter(record.root(), &request.globs)
+            .map_err(|err| RpcError::invalid_args(format!("{:#}", err)))?;
+        let hunks = replace::plan(record.results(), &matcher, request.replacement.as_bytes(), &filter)
+            .map_err(|err| RpcError::search(err.into()))?;
+        if request.dry_run {
+            return Ok(rpc::hunks_to_value(&request.id, &hunks));
+        }
+
+        let journal = match self.journal {
+            Some(ref journal) => journal,
+            None => return Err(RpcError::search(anyhow::anyhow!("no history file to keep the undo journal next to"))),
+        };
+        let accepted: Vec<&replace::Hunk> = match request.hunks {
+            None => hunks.iter().collect(),
+            Some(ref indexes) => {
+                let mut accepted = vec![];
+                for &i in indexes.iter() {
+                    match hunks.get(i) {
+                        Some(hunk) => accepted.push(hunk),
+                        None => return Err(RpcError::invalid_args(format!("no hunk {} (there are {})", i, hunks.len()))),
+                    }
+                }
+                accepted
+            }
+        };
+        let applied = journal.apply(record.root(), &accepted, record.files()).map_err(replace_error)?;
+        let files = applied.files.into_iter().map(Value::from).collect();
+        return Ok(Value::Map(vec![
+            (Value::from("id"), Value::from(request.id)),
+            (Value::from("dry_run"), Value::from(false)),
+            (Value::from("journal"), Value::from(applied.journal)),
+            (Value::from("hunks"), Value::from(accepted.len() as u64)),
+            (Value::from("files"), Value::Array(files)),
+        ]));
+    }
+
+    /// Put back the files of a replacement from its undo journal. Returns
+    /// the files put back.
+    fn revert(&self, values: &[Value]) -> Result<Value, RpcError> {
+        let request = rpc::RevertRequest::from_args(values)?;
+        let journal = match self.journal {
+            Some(ref journal) => journal,
+            None => return Err(RpcError::search(anyhow::anyhow!("no history file, so no undo journals"))),
+        };
+        let files = journal.revert(&request.journal).map_err(replace_error)?;
+        return Ok(Value::Map(vec![
+            (Value::from("journal"), Value::from(request.journal)),
+            (Value::from("files"), Value::Array(files.into_iter().map(Value::from).collect())),
+        ]));
+    }
+
+    /// Report on the health of the server, to tell whether it or the
+    /// filesystem is slow.
+    ///
+    /// This has the process's `memory` use, the number, size and running
+    /// count of `searches`, and the `stats` of every stored search that has
+    /// them, most recently used first, see `stats.rs`. With an `id`, only
+    /// that search's stats are included.
+    fn stats(&self, values: &[Value]) -> Result<Value, RpcError> {
+        let request = rpc::StatsRequest::from_args(values)?;
+        let memory = match memory_stats() {
+            Some(usage) => Value::Map(vec![
+                (Value::from("physical"), Value::from(usage.physical_mem as u64)),
+                (Value::from("virtual"), Value::from(usage.virtual_mem as u64)),
+            ]),
+            None => Value::Nil,
+        };
+        let running = self.running.lock().unwrap().len();
+        let search_store = self.lock_store();
+        let records = match request.id {
+            Some(ref id) => match search_store.peek(id) {
+                Some(record) => vec![record],
+                None => return Err(RpcError::not_found(id)),
+            },
+            None => search_store.history(),
+        };
+        let stats = records.into_iter()
+            .filter_map(|record| record.stats().map(|stats| rpc::stats_to_value(record.id(), stats)))
+            .collect();
+        return Ok(Value::Map(vec![
+            (Value::from("memory"), memory),
+            (Value::from("searches"), Value::Map(vec![
+                (Value::from("count"), Value::from(search_store.len() as u64)),
+                (Value::from("bytes"), Value::from(search_store.bytes() as u64)),
+                (Value::from("running"), Value::from(running as u64)),
+            ])),
+            (Value::from("stats"), Value::Array(stats)),
+        ]));
+    }
+
+    /// Return the files a stored search failed on, optionally only those
+    /// with one cause, see `errors.rs`.
+    fn query_errors(&self, values: &[Value]) -> Result<Value, RpcError> {
+        let request = rpc::QueryErrorsRequest::from_args(values)?;
+        let search_store = self.lock_store();
+        let record = match search_store.peek(&request.id) {
+            Some(record) => record,
+            None => return Err(RpcError::not_found(&request.id)),
+        };
+        let errors: Vec<&FileError> = record.errors().iter()
+            .filter(|err| request.cause.is_none_or(|cause| err.cause == cause))
+            .collect();
+        return Ok(rpc::errors_to_value(&request.id, record.errors().len(), &errors));
+    }
+
+    /// List every stored search, most recently used first, so the client can
+    /// pick one to reopen with `query`.
+    fn list_history(&self, values: &[Value]) -> Result<Value, RpcError> {
+        if !values.is_empty() {
+            rpc::ArgMap::from_args("list_history", values)?;
+        }
+        let search_store = self.lock_store();
+        let history = search_store.history().into_iter().map(rpc::record_to_value);
+        return Ok(Value::Array(history.collect()));
+    }
+}
+impl neovim_lib::RequestHandler for SearchHandler {
+    fn handle_request(&mut self, name: &str, args: Vec<Value>) -> Result<Value, Value> {
+        return self.handle(name.to_string(), &args).map_err(RpcError::into_value);
+    }
+}
+
 impl EventHandler {
     fn new() -> EventHandler {
         let session = Session::new_parent().unwrap();
         let nvim = Neovim::new(session);
-        return EventHandler { nvim };
+        return EventHandler { nvim: Arc::new(Mutex::new(nvim)) };
     }
 
     //For now I'm not sure how better to handle unhappy path except return and end
-    fn recv(&mut self) -> anyhow::Result<bool> {
-        let mut search_store = SearchStore {
-            search_store: HashMap::new()
-        };
-
+    fn recv(&mut self, server_args: Vec<std::ffi::OsString>) -> anyhow::Result<bool> {
         //Get the initial low args (Inject search-positional in search call)
-        let initial_args = match flags::parse_low(){
+        let initial_args = match flags::parse_low_args(server_args){
             crate::flags::ParseResult::Ok(low) => low,
             crate::flags::ParseResult::Err(err) => return Err(err),
             _ => return Ok(false),
         };
+        log_to_file();
+        let notifier = Notifier::new(Arc::clone(&self.nvim));
+        let handler = SearchHandler::new(initial_args, notifier.clone(), default_history_file());
+        handler.start_watching(notifier);
 
-        let receiver = self.nvim.session.start_event_loop_channel();
+        //Requests (rpcrequest) are answered by the handler directly, notifications land here
+        let receiver = self.nvim
+            .lock()
+            .unwrap()
+            .session
+            .start_event_loop_channel_handler(handler.clone());
         for (event, values) in receiver {
-            match RpcMessages::from(event) {
-                RpcMessages::Search => {
-                    //let search_string: &str = values.iter().next().unwrap().as_str().unwrap();
-
-                    let mut cloned_args = initial_args.clone();
-                    cloned_args.positional.push(std::ffi::OsString::from("test")); //Term
-                    cloned_args.positional.push(std::ffi::OsString::from("./")); //Dir
-                    let hi_args_result = match crate::flags::HiArgs::from_low_args(cloned_args) {
-                        Ok(hi_args) => crate::flags::ParseResult::Ok(hi_args),
-                        Err(err) => crate::flags::ParseResult::Err(err),
-                    };
-                    let args = match hi_args_result{
-                        crate::flags::ParseResult::Ok(args) => args,
-                        crate::flags::ParseResult::Err(err) => return Err(err),
-                        _ => return Ok(false),
-                    };
-
-                    match rg_search(&args) {
-                        Ok(search_results) => {
-                            search_store.search_store.insert(String::from("Test-Search"), search_results);
-                        },
-                        Err(err) => eprintln_locked!("{:#}", err), 
-                    };
-                    //let mut file = std::fs::File::create("testargs.txt")?; //writeln!(&mut file, "{:#?}", args)?;
-                    //eprintln_locked!("{:#?}", std::env::current_dir()); //Better way of print debugging - stderr
-                }
-                RpcMessages::Query => {
-                    let search_string: u64 = values.iter().next().unwrap().as_u64().unwrap();
-                }
-                RpcMessages::Unknown(event) => {
-                    self.nvim.command("echo \"test\"").unwrap();
-                    //Unknown Event
-                }
+            //A notification has no one to return an error to, so surface it in nvim instead
+            if let Err(err) = handler.handle(event, &values) {
+                self.nvim
+                    .lock()
+                    .unwrap()
+                    .err_writeln(&format!("search-history: {}", err))
+                    .map_err(|err| anyhow::anyhow!("{}", err))?;
             }
         }
+        //The channel closes when nvim goes away, stop searching and write out whatever we have
+        handler.cancel_all();
+        handler.save_history(&handler.lock_store());
         return Ok(true);
     }
 }
 
+/// Once the flags set the log level, a server logs to a file, stderr is nvim's to show or drop.
+/// See `logger.rs` for the env vars that configure it.
+fn log_to_file() {
+    let config = match logger::LogConfig::from_env() {
+        Some(config) => config,
+        None => return,
+    };
+    let path = config.path.clone();
+    if let Err(err) = logger::Logger::to_file(config) {
+        log::warn!("{}: not logging to a file: {}", path.display(), err);
+    }
+}
+
+/// History is keyed by the directory the server was started in.
+fn default_history_file() -> Option<HistoryFile> {
+    return match std::env::current_dir() {
+        Ok(root) => HistoryFile::for_root(&root),
+        Err(err) => {
+            log::warn!("not persisting search history: {}", err);
+            None
+        }
+    };
+}
+
+/// Run `--serve --socket PATH [FLAGS...]`: share one store between every
+/// client that connects to the socket, until the process is killed.
+fn serve(args: Vec<std::ffi::OsString>) -> anyhow::Result<()> {
+    let serve_args = server::parse_serve(&args)?;
+    //Every flag other than --socket is a server flag, same as when nvim spawns us
+    let initial_args = match flags::parse_low_args(serve_args.rest) {
+        crate::flags::ParseResult::Ok(low) => low,
+        crate::flags::ParseResult::Err(err) => return Err(err),
+        _ => return Err(anyhow::anyhow!("--serve: --help and --version aren't supported")),
+    };
+    log_to_file();
+    let listener = server::bind(&serve_args.socket)?;
+    let handler = SearchHandler::new(initial_args, Notifier::none(), default_history_file());
+    log::debug!("listening on {}", serve_args.socket.display());
+    return server::serve(listener, handler);
+}
+
+/// Report an error from `--serve` or `--client`, treating a closed pipe as success.
+fn exit_code(result: anyhow::Result<bool>) -> ExitCode {
+    let err = match result {
+        Ok(true) => return ExitCode::SUCCESS,
+        Ok(false) => return ExitCode::FAILURE,
+        Err(err) => err,
+    };
+    for cause in err.chain() {
+        if let Some(ioerr) = cause.downcast_ref::<std::io::Error>() {
+            if ioerr.kind() == std::io::ErrorKind::BrokenPipe {
+                return ExitCode::SUCCESS;
+            }
+        }
+    }
+    eprintln_locked!("{:#}", err);
+    return ExitCode::FAILURE;
+}
+
 fn main() -> ExitCode {
+    //Modes are only ever the first argument, anywhere else the same word is rg's to search for
+    let mut cli_args: Vec<std::ffi::OsString> = std::env::args_os().skip(1).collect();
+    match cli_args.first().and_then(|arg| arg.to_str()) {
+        Some("--serve") => {
+            cli_args.remove(0);
+            return exit_code(serve(cli_args).map(|()| true));
+        }
+        Some("--client") => {
+            cli_args.remove(0);
+            return exit_code(server::parse_client(&cli_args).and_then(server::run_client));
+        }
+        _ => {}
+    }
+
+    //Only run as nvim's server when asked to first thing, from a terminal we're just rg
+    let server_args = match cli_args.first() {
+        Some(arg) if arg == "--server" || arg == "--embed" => {
+            cli_args.remove(0);
+            cli_args
+        }
+        _ => return rg::main(),
+    };
+
     let mut debug_mode: bool = false;
-    let args: Vec<String> = std::env::args().collect();
-    for arg in args {
-        if arg.as_str() == "debug" {
+    for arg in server_args.iter() {
+        if arg == "debug" {
             debug_mode = true;
         }
     }
     if debug_mode {
-        let mut search_store = SearchStore {
-            search_store: HashMap::new()
-        };
 
         //Get the initial low args (Inject search-positional in search call)
-        let initial_args = match flags::parse_low(){
+        let initial_args = match flags::parse_low_args(server_args){
             crate::flags::ParseResult::Ok(low) => low,
             crate::flags::ParseResult::Err(err) => return ExitCode::FAILURE,
             _ => return ExitCode::FAILURE,
@@ -160,8 +1296,6 @@ fn main() -> ExitCode {
                 return ExitCode::FAILURE;
             }, 
         };
-        //search_store.search_store.insert(String::from("Test-Search"), search_results);
-        //println!("{:#?}", search_store);
 
         if let Some(usage) = memory_stats() {
             println!("Current physical memory usage: {}", usage.physical_mem);
@@ -173,21 +1307,7 @@ fn main() -> ExitCode {
     }
 
     let mut event_handler = EventHandler::new();
-    match event_handler.recv() {
-        Ok(res) => (),
-        Err(err) => {
-            for cause in err.chain() {
-                if let Some(ioerr) = cause.downcast_ref::<std::io::Error>() {
-                    if ioerr.kind() == std::io::ErrorKind::BrokenPipe {
-                        return ExitCode::SUCCESS;
-                    }
-                }
-            }
-            eprintln_locked!("{:#}", err);
-            return ExitCode::FAILURE;
-        }
-    };
-    return ExitCode::SUCCESS;
+    return exit_code(event_handler.recv(server_args).map(|_| true));
 
     //Easier debugging for when converting to array return
     //match rg(flags::parse()) {
@@ -244,13 +1364,66 @@ fn main() -> ExitCode {
     //match run_search(flags::parse()) {
 }
 
+/// What to search for to run a stored search again.
+fn query_input(query: &SearchQuery) -> rpc::SearchInput {
+    return match query.args {
+        Some(ref line) => rpc::SearchInput::Args(line.clone()),
+        None => rpc::SearchInput::Pattern {
+            pattern: query.pattern.clone(),
+            paths: query.paths.clone(),
+        },
+    };
+}
+
+/// The RPC error of a failed replace or undo. Files that changed in the
+/// meantime are a conflict, anything else failed.
+fn replace_error(err: replace::ReplaceError) -> RpcError {
+    return match err {
+        replace::ReplaceError::Changed(_) => RpcError::conflict(err.to_string()),
+        replace::ReplaceError::Other(err) => RpcError::search(err),
+    };
+}
+
+/// The same flags, but searching `paths`. A pattern given as the first
+/// positional, rather than with `-e`, stays the pattern.
+fn with_paths(mut low: LowArgs, paths: Vec<std::ffi::OsString>) -> LowArgs {
+    if low.patterns.is_empty() && !low.positional.is_empty() {
+        let pattern = low.positional.remove(0);
+        low.patterns.push(PatternSource::Regexp(pattern.to_string_lossy().into_owned()));
+    }
+    low.positional = paths;
+    return low;
+}
+
+/// The pattern and paths of a search, for showing it in the history.
+///
+/// Patterns from `-e` are joined by newlines, like `-f` files are read, and
+/// pattern files show up as `-f path`. Otherwise the first positional is the
+/// pattern, the same way `HiArgs` picks it.
+fn describe_search(low: &LowArgs) -> (String, Vec<std::path::PathBuf>) {
+    let mut positional = low.positional.iter().map(std::path::PathBuf::from);
+    if low.patterns.is_empty() {
+        let pattern = match positional.next() {
+            Some(pattern) => pattern.to_string_lossy().into_owned(),
+            None => String::new(),
+        };
+        return (pattern, positional.collect());
+    }
+    let patterns: Vec<String> = low.patterns.iter().map(|source| match source {
+        PatternSource::Regexp(pattern) => pattern.clone(),
+        PatternSource::File(path) => format!("-f {}", path.display()),
+    }).collect();
+    return (patterns.join("\n"), positional.collect());
+}
+
 fn rg_search(args: &crate::flags::HiArgs) -> anyhow::Result<SearchResults> {
+    let searcher = args.search_worker(args.matcher()?)?;
     let search_results = match args.matches_possible() {
-        true => search_parallel(&args),
+        true => search_parallel(args, searcher, &AtomicBool::new(false), None, None, None),
         _ => return Err(anyhow::anyhow!("No results found")),
     };
     let search_results = match search_results {
-        Ok(search_results) => search_results,
+        Ok(searched) => searched.results,
         Err(err) => return Err(err),
     };
 
@@ -260,136 +1433,185 @@ fn rg_search(args: &crate::flags::HiArgs) -> anyhow::Result<SearchResults> {
     return Err(anyhow::anyhow!("No results found"));
 }
 
-fn search_parallel(args: &crate::flags::HiArgs) -> anyhow::Result<SearchResults> {
+/// Everything a search in parallel found.
+struct Searched {
+    results: SearchResults,
+    //The stamp of every file walked
+    stamps: FileStamps,
+    stats: SearchStats,
+    //The files and directories the search failed on, see errors.rs
+    errors: Vec<FileError>,
+}
+impl Searched {
+    /// What a search that didn't walk any files found.
+    fn empty() -> Searched {
+        return Searched {
+            results: SearchResults::new(),
+            stamps: FileStamps::new(),
+            stats: SearchStats::default(),
+            errors: vec![],
+        };
+    }
+}
+
+/// Search every haystack in parallel, collecting the results of all threads
+/// along with the stamp of every file walked and every file that failed.
+///
+/// Once `cancel` is set, every walker thread quits at its next file and the
+/// results found up to that point are returned. When a streamer is given,
+/// it runs on its own thread alongside the walkers, sending batches of new
+/// results and progress to the client until the walk is over. Files stamped
+/// the same as in `unchanged`, which comes with when its search started, are
+/// only stamped, not searched, and so are files the trigram `index` rules
+/// out, see `index.rs`. Where the time went and which files were skipped is
+/// returned too, see `stats.rs`. Failures are kept rather than printed, so
+/// they only go to the log.
+fn search_parallel(
+    args: &crate::flags::HiArgs,
+    searcher: SearchWorker,
+    cancel: &AtomicBool,
+    streamer: Option<&Streamer>,
+    unchanged: Option<(&FileStamps, std::time::SystemTime)>,
+    index: Option<&TrigramIndex>,
+) -> anyhow::Result<Searched> {
+    let started = std::time::Instant::now();
+    let recorder = Arc::new(stats::Recorder::default());
     let haystack_builder = args.haystack_builder();
-    let bufwtr = args.buffer_writer();
-
-    //Actually I just realized my options right now have a color writer that's not correct
-    //This however forces no color
-    let test_buffer = termcolor::Buffer::no_color(); //Termcolor buffer_writer().buffer();
-        //println!("{:#?}", bufwtr.color_choice);
-        //test_buffer.write_all("test");
-
-    //let test_vec_as_buf = vec![];
-    //let test_printer_with_vec = search::Printer::Standard(self.printer_standard(wtr));
-
-            //println!("{:#?}", "Create Search Worker");
-    //Have to rewrite this to not use a printer at all
-    //Everything depends on W being a termcolor::WriteColor generic
-
-
-    //Next step is to completely remove the printer and work with the sole buffers
-    //Search worker will manage the buffer and it will create a CustomSink
-    //SearchWorker likely need to store buffer of buffers & create  single use buffer for each thread
-    //This custom sink will implement the ability to Vec<u8> buffers.push() it's buffer of matched bytes
-    //Custom sink will also need to get that line number and store it somehow
-    // (This is likely way beyond my skillset to do efficiently I'd have to rewrite all the search algorithms)
-    //I might have to start with an Arc<Mutex<SearchResults>> and try to rewrite multi-threading to return values
-    //let search_results = Arc<Mutex<SearchResults::new()>>;
-    let mut threaded_search_results = Arc::new(Mutex::new(SearchResults::new()));
-
-    let mut searcher = args.search_worker(
-        args.matcher()?,
-        args.searcher()?,
-        args.printer(bufwtr.buffer()), //test_vec_as_buf, //args.printer(mode, test_buffer), //This is doable
-    )?;
-
-            //println!("{:#?}", "After Create Search Worker");
-=======
-
->>>>>>> Stashed changes
-    args.walk_builder()?.build_parallel().run(|| {
-        let bufwtr = &bufwtr;
-        let haystack_builder = &haystack_builder;
-
-        /*
-        * I'm working on a few things at once
-        * One Id like to consume_results to remove .clone() & return ownership then compare performance
-        * Also trying use fixed length buffer matched bytes, see if time save with paging, compare performance
-        * Lastly I'm separating thread search result structs from regular one to separate storing
-        * the &str from path using haystack.path() -> Path then Path.to_str() -> Option<&str>
-        */
-
-
-        //let mut searcher = &searcher; //Can I use a refence does this need to clone?
-        let mut searcher = searcher.clone(); //Can I use a refence does this need to clone?
-        let mut threaded_search_results = &threaded_search_results;
-
-        return Box::new(move |result| {
-            let mut threaded_search_results = threaded_search_results.lock().unwrap();
-
-            let haystack = match haystack_builder.build_from_result(result) {
-                Some(haystack) => haystack,
-                None => return WalkState::Continue,
-            };
-            searcher.printer().get_mut().clear();
-            let search_result = match searcher.search(&haystack) {
-                Ok(search_result) => search_result,
-                Err(err) => {
-                    err_message!("{}: {}", haystack.path().display(), err);
-                    return WalkState::Continue;
-                }
-            };
-            //The wtr stored in the print is some how populated with the raw output buffer
-            //This includes the desired results for linenum & preview line
-            //Unlike the state of sink in core.rs this is a joined buffer
-            //If I can somehow get the raw line by line that gets matched to sink and just store
-            //Each line in a vector that would be ideal, though I can split raw buffer just as easily
-            //I believe this will have something to do with bufwtr and the buffer()
-            //That printer is constructed with as that is the wtr it's writing to
-            //It'll be tough but I might be able to create a custom buffer that will write to a
-            //Vector index for each line
-            //println!("{:#?}", searcher.printer().get_mut()); //I believe this is the buffer
-
-            //haystack.path() - With this I can store the path
-            //
-            //sunk.line_number() //Somehow I have to get the line number out of the sunk
-            // -Look to this (standard.rs)
-            // -- Sunk is created with SinkMatch where SinkMatch.line_number()
-            // -- SinkMatch is passed in to matched as param so CustomSink should get this
-            //
-            //fn from_match(
-            //    searcher: &'a Searcher,
-            //    sink: &'a StandardSink<'_, '_, M, W>,
-            //    mat: &'a SinkMatch<'a>,
-            //) -> StandardImpl<'a, M, W> {
-            //    let sunk = Sunk::from_sink_match(
-            //        mat,
-            //        &sink.standard.matches,
-            //        sink.replacer.replacement(),
-            //    );
-            //    StandardImpl { sunk, ..StandardImpl::new(searcher, sink) }
-            //}
-            //
-            if let Err(err) = bufwtr.print(searcher.printer().get_mut()) {
-                if err.kind() == std::io::ErrorKind::BrokenPipe {
-                    return WalkState::Quit; //Broken pipe means graceful termination.
-                }
-                err_message!("{}: {}", haystack.path().display(), err);
-            //Push to outer search results vector
-            //let path = Some(haystack.path().to_string_lossy().to_string());
-            let path = haystack.path().as_os_str();
-            if searcher.search(&haystack) {
-                //for search_result in searcher.consume_searcher_results().consume_results().iter_mut() {
-                for search_result in searcher.get_results().get_mut().iter_mut() {
-                    search_result.set_file_name(Some(path.to_str().clone()));
-                    //search_result.set_file_name(Some(haystack.path().to_str()));
-                    threaded_search_results.store_result(search_result.clone());
-                }
-            }
-            //return WalkState::Quit;
-            return WalkState::Continue;
+    let walker = {
+        let recorder = Arc::clone(&recorder);
+        args.walk_builder()?.on_skip(move |_, why| recorder.skipped(why.into())).build_parallel()
+    };
+    //Patterns without literals to look up search every file, same as without an index
+    let filter = match (index, args.required_literals()) {
+        (Some(index), Some(literals)) => index.filter(&literals),
+        _ => None,
+    };
+    let progress = Progress::new(streamer.map_or(usize::MAX, |s| s.config().batch_size));
+
+    //The search worker collects matches through CustomSink rather than a printer
+    //Each thread buffers its own results, stamps and errors, so the shared ones are only locked to flush a buffer
+    let search_results = Mutex::new(SearchResults::new());
+    let stamps = Mutex::new(FileStamps::new());
+    let errors: Mutex<Vec<FileError>> = Mutex::new(vec![]);
+    //Without a streamer nobody looks at the results before the end
+    let results_policy = match streamer {
+        Some(streamer) => FlushPolicy::streaming(streamer.config().batch_size, streamer.config().interval),
+        None => FlushPolicy::at_end(),
+    };
+
+    std::thread::scope(|scope| {
+        if let Some(streamer) = streamer {
+            let (progress, search_results) = (&progress, &search_results);
+            scope.spawn(move || streamer.run(progress, search_results));
+        }
+        walker.run(|| {
+            let haystack_builder = &haystack_builder;
+            let mut searcher = searcher.clone();
+            let progress = &progress;
+            let filter = &filter;
+            let recorder = &recorder;
+            //Matches only count once the streamer can see them
+            let mut results = Collector::new(&search_results, results_policy).on_flush(move |count, took| {
+                recorder.spent(Phase::Collect, took);
+                progress.matched(count as u64);
+            });
+            let mut stamps = Collector::new(&stamps, FlushPolicy::at_end()).on_flush(move |_, took| recorder.spent(Phase::Collect, took));
+            let mut errors = Collector::new(&errors, FlushPolicy::at_end()).on_flush(move |_, took| recorder.spent(Phase::Collect, took));
+            //Everything between two files on this thread is the walker's
+            let mut walked_since = std::time::Instant::now();
+
+            return Box::new(move |result| {
+                recorder.spent(Phase::Walk, walked_since.elapsed());
+                let state = 'file: {
+                    if cancel.load(Ordering::SeqCst) {
+                        break 'file WalkState::Quit;
+                    }
+                    let dent = match result {
+                        Ok(dent) => dent,
+                        Err(err) => {
+                            log::debug!("{}", err);
+                            recorder.error();
+                            errors.push(FileError::walking(&err));
+                            break 'file WalkState::Continue;
+                        }
+                    };
+                    let haystack = match haystack_builder.build(dent) {
+                        Some(haystack) => haystack,
+                        None => break 'file WalkState::Continue,
+                    };
+                    //Stamped before searching so an edit made mid-search is picked up next time, stdin has no stamp
+                    let path = haystack.path().to_string_lossy().to_string();
+                    let stamp = match haystack.is_stdin() {
+                        true => None,
+                        false => FileStamp::of(haystack.path()),
+                    };
+                    if unchanged.is_some_and(|(unchanged, since)| refresh::is_unchanged(unchanged, since, &path, stamp)) {
+                        stamps.push((path, stamp.unwrap()));
+                        progress.file_scanned();
+                        break 'file WalkState::Continue;
+                    }
+                    //Can't match, so it's stamped as searched without results
+                    if filter.as_ref().is_some_and(|filter| !filter.may_match(haystack.path(), stamp)) {
+                        stamps.push((path, stamp.unwrap()));
+                        progress.file_scanned();
+                        break 'file WalkState::Continue;
+                    }
+                    let searched_since = std::time::Instant::now();
+                    let searched = searcher.search(&haystack);
+                    recorder.spent(Phase::Search, searched_since.elapsed());
+                    let has_match = match searched {
+                        Ok(has_match) => has_match,
+                        Err(err) => {
+                            //Left unstamped, so a refresh tries it again
+                            log::debug!("{}: {}", haystack.path().display(), err);
+                            recorder.error();
+                            errors.push(FileError::searching(haystack.path(), &err));
+                            break 'file WalkState::Continue;
+                        }
+                    };
+                    match searcher.quit_on_binary() {
+                        true => recorder.skipped(Skip::Binary),
+                        false => recorder.searched(),
+                    }
+                    progress.file_scanned();
+                    //The next search starts from an empty store anyway, so the results are moved rather than cloned
+                    if has_match {
+                        let found = std::mem::take(searcher.get_results().get_mut());
+                        results.add(found.into_iter().map(|mut search_result| {
+                            search_result.set_file_name(Some(path.clone()));
+                            search_result
+                        }));
+                    }
+                    if let Some(stamp) = stamp {
+                        stamps.push((path, stamp));
+                    }
+                    WalkState::Continue
+                };
+                results.tick();
+                walked_since = std::time::Instant::now();
+                return state;
+            });
         });
+        progress.finish();
     });
+    if let Some(filter) = filter {
+        filter.finish();
+    }
 
-    let mutex_search_results = match Arc::into_inner(threaded_search_results) {
-        Some(mutex_results) => mutex_results,
-        None => return Err(anyhow::anyhow!("Could not unwrap Mutex from Arc")),
+    let search_results = match search_results.into_inner() {
+        Ok(results) => results,
+        Err(err) => return Err(anyhow::anyhow!("{}", err)),
+    };
+    let stamps = match stamps.into_inner() {
+        Ok(stamps) => stamps,
+        Err(err) => return Err(anyhow::anyhow!("{}", err)),
     };
-    return match mutex_search_results.into_inner() {
-        Ok(search_results) => Ok(search_results),
-        Err(err) => Err(err.into()),
+    let errors = match errors.into_inner() {
+        Ok(errors) => errors,
+        Err(err) => return Err(anyhow::anyhow!("{}", err)),
     };
+    let stats = recorder.finish(started.elapsed());
+    return Ok(Searched { results: search_results, stamps, stats, errors });
 }
 
 //Might want to see this syntax later
@@ -0,0 +1,360 @@
+/*!
+Sends events about running searches back to the client.
+
+Searches run on their own threads, so their outcome can't be returned from
+the `search` call itself. Instead, the server fires a `User` autocommand in
+nvim for each event, with the event's payload in the autocommand's `data`.
+While a search runs, its results are streamed back in batches along with
+progress counters, so a picker can fill in before the search is over. A
+client subscribes to the events it cares about:
+
+```lua
+vim.api.nvim_create_autocmd('User', {
+    pattern = 'SearchHistoryDone',
+    callback = function(ev) print(ev.data.id, ev.data.count) end,
+})
+```
+*/
+
+use std::{
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc, Condvar, Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+use neovim_lib::{Neovim, Value};
+
+use crate::{
+    search::SearchResults,
+    server::{Message, Peer},
+};
+
+/// The kinds of events sent to the client.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub(crate) enum Event {
+    /// A search finished, was cancelled or failed.
+    Done,
+    /// A batch of new results from a running search.
+    Batch,
+    /// The counters of a running search.
+    Progress,
+    /// A stored search was updated because files it searched changed.
+    Changed,
+}
+
+impl Event {
+    /// The autocommand pattern the event is fired with.
+    pub(crate) fn pattern(&self) -> &'static str {
+        match *self {
+            Event::Done => "SearchHistoryDone",
+            Event::Batch => "SearchHistoryBatch",
+            Event::Progress => "SearchHistoryProgress",
+            Event::Changed => "SearchHistoryChanged",
+        }
+    }
+}
+
+/// A handle for sending events to the client.
+///
+/// This is cheap to clone and is meant to be handed to every search thread.
+/// It must never be used from neovim-lib's dispatch thread (i.e., from inside
+/// a request handler), since a call into nvim from that thread would wait
+/// forever for a response that only that thread can read.
+#[derive(Clone)]
+pub(crate) struct Notifier {
+    target: Target,
+}
+
+/// Where a notifier sends its events.
+#[derive(Clone)]
+enum Target {
+    None,
+    /// The nvim instance that spawned the server.
+    Nvim(Arc<Mutex<Neovim>>),
+    /// A client connected to the server's socket.
+    Socket(Arc<Peer>),
+    /// Every client connected to the server's socket.
+    Broadcast(Peers),
+}
+
+/// The clients connected to the server's socket.
+pub(crate) type Peers = Arc<Mutex<Vec<Arc<Peer>>>>;
+
+impl Notifier {
+    /// Create a notifier that sends events to the given nvim instance.
+    pub(crate) fn new(nvim: Arc<Mutex<Neovim>>) -> Notifier {
+        Notifier { target: Target::Nvim(nvim) }
+    }
+
+    /// Create a notifier that sends events to a client of `--serve`.
+    ///
+    /// Events are sent as `nvim_exec_autocmds` notifications with the same
+    /// arguments used for nvim, so a client that is itself an nvim instance
+    /// fires the same autocommands. Nothing waits for a response, so unlike
+    /// other notifiers this one may be used from a request handler.
+    pub(crate) fn socket(peer: Arc<Peer>) -> Notifier {
+        Notifier { target: Target::Socket(peer) }
+    }
+
+    /// Create a notifier that sends events to every client of `--serve`, for
+    /// events that aren't about a search any one client started. Clients
+    /// that went away are dropped from `peers` as events are sent.
+    pub(crate) fn broadcast(peers: Peers) -> Notifier {
+        Notifier { target: Target::Broadcast(peers) }
+    }
+
+    /// Create a notifier that drops every event. This is used when there is
+    /// no client to notify, e.g., in debug mode.
+    pub(crate) fn none() -> Notifier {
+        Notifier { target: Target::None }
+    }
+
+    /// Send an event to the client.
+    ///
+    /// Failing to deliver an event isn't fatal for the search that produced
+    /// it, so errors are only logged.
+    pub(crate) fn send(&self, event: Event, data: Value) {
+        let opts = Value::Map(vec![
+            (Value::from("pattern"), Value::from(event.pattern())),
+            (Value::from("data"), data),
+        ]);
+        self.call("nvim_exec_autocmds", vec![Value::from("User"), opts]);
+    }
+
+    /// Call an API method of the client, e.g., `nvim_call_function`, without
+    /// waiting for what it returns.
+    ///
+    /// Like events, errors are only logged. Clients of `--serve` get the call
+    /// as a notification, so they never report errors back at all.
+    pub(crate) fn call(&self, method: &str, args: Vec<Value>) {
+        if let Target::Broadcast(ref peers) = self.target {
+            let mut peers = peers.lock().unwrap();
+            peers.retain(|peer| !peer.is_closed());
+            for peer in peers.iter() {
+                Notifier::socket(Arc::clone(peer)).call(method, args.clone());
+            }
+            return;
+        }
+        let result = match self.target {
+            Target::None => return,
+            // A client that went away was already warned about once.
+            Target::Socket(ref peer) if peer.is_closed() => return,
+            Target::Nvim(ref nvim) => {
+                let mut nvim = nvim.lock().unwrap();
+                nvim.session
+                    .call(method, args)
+                    .map(|_| ())
+                    .map_err(|err| err.to_string())
+            }
+            Target::Socket(ref peer) => peer
+                .send(Message::Notification {
+                    method: method.to_string(),
+                    params: args,
+                })
+                .map_err(|err| err.to_string()),
+            Target::Broadcast(_) => unreachable!(),
+        };
+        if let Err(err) = result {
+            log::warn!("failed to call {} on client: {}", method, err);
+        }
+    }
+}
+
+/// Controls how often a running search streams results back.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub(crate) struct StreamConfig {
+    /// Send a batch as soon as this many new matches are waiting.
+    pub(crate) batch_size: usize,
+    /// Send whatever is waiting at least this often, along with progress.
+    pub(crate) interval: Duration,
+}
+
+impl Default for StreamConfig {
+    fn default() -> StreamConfig {
+        StreamConfig { batch_size: 1000, interval: Duration::from_millis(200) }
+    }
+}
+
+/// Counters shared by the walker threads of a single search.
+///
+/// Walker threads bump the counters as they go, and the streamer sleeps on
+/// them until either a full batch of matches is waiting or its interval has
+/// passed.
+#[derive(Debug)]
+pub(crate) struct Progress {
+    start: Instant,
+    batch_size: u64,
+    files_scanned: AtomicU64,
+    matches: AtomicU64,
+    sent: AtomicU64,
+    done: AtomicBool,
+    lock: Mutex<()>,
+    wake: Condvar,
+}
+
+impl Progress {
+    /// Start counting for a search that streams in batches of `batch_size`.
+    pub(crate) fn new(batch_size: usize) -> Progress {
+        Progress {
+            start: Instant::now(),
+            batch_size: batch_size.max(1) as u64,
+            files_scanned: AtomicU64::new(0),
+            matches: AtomicU64::new(0),
+            sent: AtomicU64::new(0),
+            done: AtomicBool::new(false),
+            lock: Mutex::new(()),
+            wake: Condvar::new(),
+        }
+    }
+
+    /// Record that one more file was searched.
+    pub(crate) fn file_scanned(&self) {
+        self.files_scanned.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Record that `count` more matches were stored, waking the streamer if
+    /// a full batch is now waiting.
+    pub(crate) fn matched(&self, count: u64) {
+        let matches = self.matches.fetch_add(count, Ordering::SeqCst) + count;
+        if matches - self.sent.load(Ordering::SeqCst) >= self.batch_size {
+            self.wake.notify_one();
+        }
+    }
+
+    /// Record that the search is over, waking the streamer for its final
+    /// batch.
+    pub(crate) fn finish(&self) {
+        let _guard = self.lock.lock().unwrap();
+        self.done.store(true, Ordering::SeqCst);
+        self.wake.notify_all();
+    }
+
+    /// Returns true once `finish` has been called.
+    pub(crate) fn is_done(&self) -> bool {
+        self.done.load(Ordering::SeqCst)
+    }
+
+    /// Sleep until a batch is waiting, the search is over or the timeout
+    /// passes, whichever comes first.
+    fn wait(&self, timeout: Duration) {
+        let guard = self.lock.lock().unwrap();
+        if self.is_done() {
+            return;
+        }
+        let _ = self.wake.wait_timeout(guard, timeout).unwrap();
+    }
+
+    /// Encode the counters as the payload of a `Progress` event.
+    fn to_value(&self, id: &str) -> Value {
+        Value::Map(vec![
+            (Value::from("id"), Value::from(id)),
+            (
+                Value::from("files_scanned"),
+                Value::from(self.files_scanned.load(Ordering::Relaxed)),
+            ),
+            (
+                Value::from("matches"),
+                Value::from(self.matches.load(Ordering::SeqCst)),
+            ),
+            (
+                Value::from("elapsed_ms"),
+                Value::from(self.start.elapsed().as_millis() as u64),
+            ),
+            (Value::from("done"), Value::from(self.is_done())),
+        ])
+    }
+}
+
+/// Streams the results of one running search to the client.
+#[derive(Clone)]
+pub(crate) struct Streamer {
+    id: String,
+    notifier: Notifier,
+    config: StreamConfig,
+    /// For a live search, its generation and the flag set once a newer
+    /// keystroke made it stale, see `live.rs`.
+    live: Option<(u64, Arc<AtomicBool>)>,
+}
+
+impl Streamer {
+    /// Create a streamer for the search with the given id.
+    pub(crate) fn new(
+        id: String,
+        notifier: Notifier,
+        config: StreamConfig,
+    ) -> Streamer {
+        Streamer { id, notifier, config, live: None }
+    }
+
+    /// Make this the streamer of a live search. Its events get a
+    /// `generation`, and nothing is sent anymore once `stale` is set.
+    pub(crate) fn live(
+        mut self,
+        generation: u64,
+        stale: Arc<AtomicBool>,
+    ) -> Streamer {
+        self.live = Some((generation, stale));
+        self
+    }
+
+    /// The configuration this streamer was created with.
+    pub(crate) fn config(&self) -> StreamConfig {
+        self.config
+    }
+
+    /// Send batches of new results and progress until the search is done.
+    ///
+    /// Results are only ever appended to `results`, so each batch is simply
+    /// everything past the end of the previous one. Its `offset` is where it
+    /// starts in the final, stored results. Context lines are left out to
+    /// keep batches small; clients fetch them with `query` when needed.
+    pub(crate) fn run(&self, progress: &Progress, results: &Mutex<SearchResults>) {
+        let mut sent = 0;
+        loop {
+            progress.wait(self.config.interval);
+            let done = progress.is_done();
+            if let Some((_, ref stale)) = self.live {
+                if stale.load(Ordering::SeqCst) {
+                    return;
+                }
+            }
+            let batch: Vec<Value> = {
+                let results = results.lock().unwrap();
+                results
+                    .page(sent, usize::MAX)
+                    .iter()
+                    .map(|result| crate::rpc::result_to_value(result, false))
+                    .collect()
+            };
+            if !batch.is_empty() {
+                let len = batch.len();
+                let data = Value::Map(vec![
+                    (Value::from("id"), Value::from(self.id.as_str())),
+                    (Value::from("offset"), Value::from(sent as u64)),
+                    (Value::from("results"), Value::Array(batch)),
+                ]);
+                sent += len;
+                progress.sent.store(sent as u64, Ordering::SeqCst);
+                self.notifier.send(Event::Batch, self.tag(data));
+            }
+            let data = self.tag(progress.to_value(&self.id));
+            self.notifier.send(Event::Progress, data);
+            if done {
+                return;
+            }
+        }
+    }
+
+    /// Add the generation of a live search to the payload of an event.
+    fn tag(&self, data: Value) -> Value {
+        match (self.live.as_ref(), data) {
+            (Some(&(generation, _)), Value::Map(mut map)) => {
+                map.push((Value::from("generation"), Value::from(generation)));
+                Value::Map(map)
+            }
+            (_, data) => data,
+        }
+    }
+}
@@ -0,0 +1,792 @@
+/*!
+Reads and writes the search history to disk.
+
+The history of each project is kept in its own file under the XDG state
+directory, i.e., `$XDG_STATE_HOME/search-history/` or
+`~/.local/state/search-history/` when `XDG_STATE_HOME` isn't set. The file
+name is derived from a hash of the project root, which is the directory the
+server was started in.
+
+A history file starts with a magic number and a format version, followed by
+a single msgpack value holding the project root and every stored search. A
+file with a different version is ignored rather than guessed at, and a file
+that fails to decode is moved aside to `<name>.corrupt` so that it can be
+inspected. In both cases the server starts with an empty history.
+*/
+
+use std::{
+    io::{self, Read, Write},
+    path::{Path, PathBuf},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::Context;
+use neovim_lib::Value;
+
+use crate::{
+    combine::{Combination, SetLevel, SetOp},
+    errors::{ErrorCause, FileError},
+    refine::{RefineScope, Refinement},
+    refresh::{FileStamp, FileStamps},
+    search::{ContextLine, SearchResult, SearchResults, SubMatch},
+    store::{SearchQuery, SearchRecord, SearchStore},
+};
+
+/// The bytes every history file starts with.
+const MAGIC: &[u8; 4] = b"SHST";
+
+/// The version of the format written by this build.
+///
+/// This must be bumped whenever the layout of the encoded value changes.
+/// Files with any other version are ignored when loading.
+pub(crate) const FORMAT_VERSION: u32 = 4;
+
+/// The on-disk history of a single project.
+#[derive(Clone, Debug)]
+pub(crate) struct HistoryFile {
+    root: PathBuf,
+    path: PathBuf,
+}
+
+impl HistoryFile {
+    /// Return the history file for the given project root in the default
+    /// state directory.
+    ///
+    /// If no state directory could be determined (i.e., neither
+    /// `XDG_STATE_HOME` nor `HOME` is set), then `None` is returned and
+    /// history is only kept in memory.
+    pub(crate) fn for_root(root: &Path) -> Option<HistoryFile> {
+        Some(HistoryFile::in_dir(&state_dir()?, root))
+    }
+
+    /// Return the history file for the given project root in `dir`.
+    pub(crate) fn in_dir(dir: &Path, root: &Path) -> HistoryFile {
+        let name = format!("{:016x}.msgpack", fnv1a(root.as_os_str().as_encoded_bytes()));
+        HistoryFile { root: root.to_path_buf(), path: dir.join(name) }
+    }
+
+    /// The path of the file on disk.
+    pub(crate) fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// The project root the history belongs to.
+    pub(crate) fn root(&self) -> &Path {
+        &self.root
+    }
+
+    /// The path of the project's trigram index, next to its history. See
+    /// `index.rs`.
+    pub(crate) fn index_path(&self) -> PathBuf {
+        self.path.with_extension("index")
+    }
+
+    /// The directory of the project's undo journals, next to its history.
+    /// See `replace.rs`.
+    pub(crate) fn journal_dir(&self) -> PathBuf {
+        self.path.with_extension("journal")
+    }
+
+    /// Load the stored searches into the given store.
+    ///
+    /// A missing file is not an error. A file written by a different version,
+    /// or for a different project root, is skipped with a log message. A file
+    /// that can't be decoded is moved aside and skipped. Only errors reading
+    /// an otherwise valid file are returned.
+    pub(crate) fn load_into(&self, store: &mut SearchStore) -> anyhow::Result<()> {
+        let mut bytes = vec![];
+        match std::fs::File::open(&self.path) {
+            Ok(mut file) => {
+                file.read_to_end(&mut bytes)
+                    .with_context(|| format!("{}", self.path.display()))?;
+            }
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(()),
+            Err(err) => {
+                return Err(err).with_context(|| format!("{}", self.path.display()));
+            }
+        }
+        let records = match decode(&bytes, &self.root) {
+            Ok(Some(records)) => records,
+            Ok(None) => return Ok(()),
+            Err(err) => {
+                let aside = self.path.with_extension("msgpack.corrupt");
+                log::warn!(
+                    "{}: ignoring corrupt search history ({:#}), moving it to {}",
+                    self.path.display(),
+                    err,
+                    aside.display()
+                );
+                if let Err(err) = std::fs::rename(&self.path, &aside) {
+                    log::warn!("{}: {}", aside.display(), err);
+                }
+                return Ok(());
+            }
+        };
+        log::debug!(
+            "{}: restored {} searches",
+            self.path.display(),
+            records.len()
+        );
+        // Records are stored most recently used first, so insert them in
+        // reverse to rebuild the same order.
+        for record in records.into_iter().rev() {
+            store.insert(record);
+        }
+        Ok(())
+    }
+
+    /// Write every search in the store to disk.
+    ///
+    /// The file is written to a temporary file in the same directory first
+    /// and then renamed over the old one, so a crash mid-write never leaves
+    /// a truncated history behind.
+    pub(crate) fn save(&self, store: &SearchStore) -> anyhow::Result<()> {
+        write_atomic(&self.path, &encode(&self.root, store)?)
+    }
+}
+
+/// Write `bytes` to a temporary file next to `path` and rename it over
+/// `path`, creating the directory first if needed.
+pub(crate) fn write_atomic(path: &Path, bytes: &[u8]) -> anyhow::Result<()> {
+    let dir = path.parent().unwrap_or(Path::new("."));
+    std::fs::create_dir_all(dir)
+        .with_context(|| format!("{}", dir.display()))?;
+    // Named after the whole file name, so that files which only differ in
+    // their extension never share a temporary file.
+    let mut tmp = path.as_os_str().to_os_string();
+    tmp.push(format!(".tmp.{}", std::process::id()));
+    let tmp = PathBuf::from(tmp);
+    let result = std::fs::File::create(&tmp)
+        .and_then(|mut file| {
+            file.write_all(bytes)?;
+            file.sync_all()
+        })
+        .and_then(|()| std::fs::rename(&tmp, path));
+    if let Err(err) = result {
+        let _ = std::fs::remove_file(&tmp);
+        return Err(err).with_context(|| format!("{}", path.display()));
+    }
+    Ok(())
+}
+
+/// Returns the directory history files are stored in.
+pub(crate) fn state_dir() -> Option<PathBuf> {
+    let base = match std::env::var_os("XDG_STATE_HOME") {
+        Some(dir) if Path::new(&dir).is_absolute() => PathBuf::from(dir),
+        _ => PathBuf::from(std::env::var_os("HOME")?).join(".local").join("state"),
+    };
+    Some(base.join("search-history"))
+}
+
+/// A 64-bit FNV-1a hash, used to turn a project root into a file name that
+/// stays the same across runs.
+fn fnv1a(bytes: &[u8]) -> u64 {
+    let mut hash: u64 = 0xcbf29ce484222325;
+    for &b in bytes {
+        hash ^= u64::from(b);
+        hash = hash.wrapping_mul(0x100000001b3);
+    }
+    hash
+}
+
+/// Encode the given store as the contents of a history file for `root`.
+fn encode(root: &Path, store: &SearchStore) -> anyhow::Result<Vec<u8>> {
+    let records: Vec<Value> =
+        store.history().into_iter().map(encode_record).collect();
+    let value = Value::Map(vec![
+        (Value::from("root"), Value::from(path_bytes(root))),
+        (Value::from("searches"), Value::Array(records)),
+    ]);
+    let mut bytes = MAGIC.to_vec();
+    bytes.extend_from_slice(&FORMAT_VERSION.to_le_bytes());
+    rmpv::encode::write_value(&mut bytes, &value)
+        .map_err(|err| anyhow::anyhow!("failed to encode search history: {}", err))?;
+    Ok(bytes)
+}
+
+/// Decode the contents of a history file.
+///
+/// This returns `None` when the file is well formed but shouldn't be loaded,
+/// i.e., it has a different version or belongs to a different root.
+fn decode(bytes: &[u8], root: &Path) -> anyhow::Result<Option<Vec<SearchRecord>>> {
+    if bytes.len() < 8 || &bytes[..4] != MAGIC {
+        anyhow::bail!("missing search history header");
+    }
+    let version = u32::from_le_bytes(bytes[4..8].try_into().unwrap());
+    if version != FORMAT_VERSION {
+        log::info!(
+            "ignoring search history with format version {} (expected {})",
+            version,
+            FORMAT_VERSION
+        );
+        return Ok(None);
+    }
+    let mut rest = &bytes[8..];
+    let value = rmpv::decode::read_value(&mut rest)
+        .map_err(|err| anyhow::anyhow!("{}", err))?;
+    if !rest.is_empty() {
+        anyhow::bail!("{} trailing bytes after search history", rest.len());
+    }
+    let stored_root = field(&value, "root")?.as_slice().context("'root' must be binary")?;
+    if stored_root != path_bytes(root).as_slice() {
+        log::info!("ignoring search history for a different project root");
+        return Ok(None);
+    }
+    let searches = field(&value, "searches")?
+        .as_array()
+        .context("'searches' must be an array")?;
+    let mut records = Vec::with_capacity(searches.len());
+    for search in searches.iter() {
+        records.push(decode_record(search)?);
+    }
+    Ok(Some(records))
+}
+
+fn encode_record(record: &SearchRecord) -> Value {
+    let query = record.query();
+    let paths = query.paths.iter().map(|p| Value::from(path_bytes(p))).collect();
+    let created = record
+        .created()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_millis() as u64);
+    let results = record
+        .results()
+        .iter()
+        .map(|result| {
+            Value::Array(vec![
+                match result.get_file_name() {
+                    Some(name) => Value::from(name),
+                    None => Value::Nil,
+                },
+                Value::from(result.get_line_number()),
+                Value::from(result.get_byte_offset()),
+                Value::from(result.get_matched_bytes().clone()),
+                // Only the byte spans are kept, the other columns are
+                // computed again from the matched bytes when loading.
+                Value::Array(
+                    result
+                        .get_submatches()
+                        .iter()
+                        .flat_map(|m| [m.start.bytes, m.end.bytes])
+                        .map(Value::from)
+                        .collect(),
+                ),
+                encode_context(result.get_before()),
+                encode_context(result.get_after()),
+                Value::from(result.has_context_break()),
+            ])
+        })
+        .collect();
+    Value::Map(vec![
+        (Value::from("id"), Value::from(record.id())),
+        (Value::from("pattern"), Value::from(query.pattern.as_str())),
+        (Value::from("paths"), Value::Array(paths)),
+        (
+            Value::from("args"),
+            query.args.as_deref().map_or(Value::Nil, Value::from),
+        ),
+        (Value::from("options"), query.options.clone()),
+        (
+            Value::from("refine"),
+            query.refine.as_ref().map_or(Value::Nil, encode_refinement),
+        ),
+        (
+            Value::from("combine"),
+            query.combine.as_ref().map_or(Value::Nil, encode_combination),
+        ),
+        (Value::from("root"), Value::from(path_bytes(record.root()))),
+        (Value::from("created"), Value::from(created)),
+        (Value::from("complete"), Value::from(record.complete())),
+        (Value::from("files"), encode_files(record.files())),
+        (Value::from("errors"), encode_errors(record.errors())),
+        (Value::from("results"), Value::Array(results)),
+    ])
+}
+
+fn decode_record(value: &Value) -> anyhow::Result<SearchRecord> {
+    let string = |key: &str| -> anyhow::Result<String> {
+        let s = field(value, key)?.as_str();
+        Ok(s.with_context(|| format!("'{}' must be a string", key))?.to_string())
+    };
+    let path = |v: &Value| -> anyhow::Result<PathBuf> {
+        Ok(bytes_path(v.as_slice().context("paths must be binary")?))
+    };
+
+    let id = string("id")?;
+    let query = SearchQuery {
+        pattern: string("pattern")?,
+        paths: field(value, "paths")?
+            .as_array()
+            .context("'paths' must be an array")?
+            .iter()
+            .map(path)
+            .collect::<anyhow::Result<_>>()?,
+        // Searches stored before `args` existed don't have it at all.
+        args: match field(value, "args") {
+            Err(_) | Ok(Value::Nil) => None,
+            Ok(args) => Some(args.as_str().context("'args' must be a string")?.to_string()),
+        },
+        options: field(value, "options")?.clone(),
+        // Likewise for searches stored before `refine` existed.
+        refine: match field(value, "refine") {
+            Err(_) | Ok(Value::Nil) => None,
+            Ok(refine) => Some(decode_refinement(refine).context("invalid 'refine'")?),
+        },
+        combine: match field(value, "combine") {
+            Err(_) | Ok(Value::Nil) => None,
+            Ok(combine) => Some(decode_combination(combine).context("invalid 'combine'")?),
+        },
+    };
+    let root = path(field(value, "root")?)?;
+    let created = field(value, "created")?.as_u64().context("'created' must be an integer")?;
+    let created = UNIX_EPOCH + Duration::from_millis(created);
+    let complete = field(value, "complete")?.as_bool().context("'complete' must be a boolean")?;
+    // Searches stored before `refresh` existed have no file stamps, so
+    // refreshing them searches every file again.
+    let files = match field(value, "files") {
+        Err(_) | Ok(Value::Nil) => FileStamps::new(),
+        Ok(files) => decode_files(files).context("invalid 'files'")?,
+    };
+    // Likewise, searches stored before their errors were kept have none.
+    let errors = match field(value, "errors") {
+        Err(_) | Ok(Value::Nil) => vec![],
+        Ok(errors) => decode_errors(errors).context("invalid 'errors'")?,
+    };
+
+    let mut results = SearchResults::new();
+    for result in field(value, "results")?
+        .as_array()
+        .context("'results' must be an array")?
+    {
+        let result = match result.as_array().map(|r| r.as_slice()) {
+            Some([name, line, offset, bytes, spans, before, after, context_break]) => {
+                let name = match name {
+                    Value::Nil => None,
+                    name => Some(name.as_str().context("bad result path")?.to_string()),
+                };
+                let line = line.as_u64().context("bad result line")?;
+                let offset = offset.as_u64().context("bad result offset")?;
+                let bytes = bytes.as_slice().context("bad result bytes")?;
+                let spans = spans
+                    .as_array()
+                    .context("bad result spans")?
+                    .iter()
+                    .map(|v| {
+                        let n = v.as_u64().context("bad result span")?;
+                        usize::try_from(n)
+                            .ok()
+                            .filter(|&n| n <= bytes.len())
+                            .context("result span out of bounds")
+                    })
+                    .collect::<anyhow::Result<Vec<usize>>>()?;
+                let ranges: Vec<std::ops::Range<usize>> = spans
+                    .chunks(2)
+                    .map(|span| match *span {
+                        [start, end] if start <= end => Ok(start..end),
+                        _ => Err(anyhow::anyhow!("bad result span")),
+                    })
+                    .collect::<anyhow::Result<_>>()?;
+                if ranges.windows(2).any(|w| w[0].end > w[1].start) {
+                    anyhow::bail!("result spans out of order");
+                }
+                SearchResult::new(
+                    name,
+                    line,
+                    offset,
+                    bytes.to_vec(),
+                    SubMatch::from_ranges(bytes, ranges),
+                )
+                .with_context(
+                    decode_context(before)?,
+                    decode_context(after)?,
+                    context_break.as_bool().context("bad context break")?,
+                )
+            }
+            _ => anyhow::bail!(
+                "results must be [path, line, offset, bytes, spans, before, \
+                 after, context_break]"
+            ),
+        };
+        results.store_result(result);
+    }
+    Ok(SearchRecord::restore(id, query, root, created, complete, results)
+        .with_files(files)
+        .with_errors(errors))
+}
+
+/// Context lines are stored as a flat `[line, bytes, line, bytes, ...]`
+/// array, which is far smaller than a map per line.
+fn encode_context(lines: &[ContextLine]) -> Value {
+    Value::Array(
+        lines
+            .iter()
+            .flat_map(|line| {
+                [Value::from(line.line_number), Value::from(line.bytes.clone())]
+            })
+            .collect(),
+    )
+}
+
+fn decode_context(value: &Value) -> anyhow::Result<Vec<ContextLine>> {
+    let values = value.as_array().context("bad context lines")?;
+    let mut lines = vec![];
+    for pair in values.chunks(2) {
+        let [line_number, bytes] = pair else {
+            anyhow::bail!("bad context lines");
+        };
+        lines.push(ContextLine {
+            line_number: line_number.as_u64().context("bad context line")?,
+            bytes: bytes.as_slice().context("bad context bytes")?.to_vec(),
+        });
+    }
+    Ok(lines)
+}
+
+/// File stamps are stored as a flat `[path, size, mtime, ...]` array, for
+/// the same reason as context lines.
+fn encode_files(files: &FileStamps) -> Value {
+    Value::Array(
+        files
+            .iter()
+            .flat_map(|(path, stamp)| {
+                [Value::from(path.as_str()), Value::from(stamp.size), Value::from(stamp.mtime)]
+            })
+            .collect(),
+    )
+}
+
+fn decode_files(value: &Value) -> anyhow::Result<FileStamps> {
+    let values = value.as_array().context("file stamps must be an array")?;
+    let mut files = FileStamps::new();
+    for chunk in values.chunks(3) {
+        match chunk {
+            [path, size, mtime] => {
+                let path = path.as_str().context("bad file stamp path")?;
+                let stamp = FileStamp {
+                    size: size.as_u64().context("bad file stamp size")?,
+                    mtime: mtime.as_u64().context("bad file stamp mtime")?,
+                };
+                files.insert(path.to_string(), stamp);
+            }
+            _ => anyhow::bail!("file stamps must be [path, size, mtime] triples"),
+        }
+    }
+    Ok(files)
+}
+
+/// File errors are stored as a flat `[path, kind, message, cause, ...]`
+/// array, like file stamps.
+fn encode_errors(errors: &[FileError]) -> Value {
+    Value::Array(
+        errors
+            .iter()
+            .flat_map(|err| {
+                [
+                    Value::from(err.path.as_str()),
+                    Value::from(err.kind.as_str()),
+                    Value::from(err.message.as_str()),
+                    Value::from(err.cause.as_str()),
+                ]
+            })
+            .collect(),
+    )
+}
+
+fn decode_errors(value: &Value) -> anyhow::Result<Vec<FileError>> {
+    let values = value.as_array().context("file errors must be an array")?;
+    let mut errors = vec![];
+    for chunk in values.chunks(4) {
+        let [path, kind, message, cause] = chunk else {
+            anyhow::bail!("file errors must be [path, kind, message, cause]");
+        };
+        let string = |v: &Value| -> anyhow::Result<String> {
+            Ok(v.as_str().context("bad file error")?.to_string())
+        };
+        errors.push(FileError {
+            path: string(path)?,
+            kind: string(kind)?,
+            message: string(message)?,
+            cause: ErrorCause::from_name(&string(cause)?)
+                .context("bad file error cause")?,
+        });
+    }
+    Ok(errors)
+}
+
+fn encode_refinement(refine: &Refinement) -> Value {
+    Value::Map(vec![
+        (Value::from("parent"), Value::from(refine.parent.as_str())),
+        (
+            Value::from("pattern"),
+            refine.pattern.as_deref().map_or(Value::Nil, Value::from),
+        ),
+        (
+            Value::from("globs"),
+            Value::Array(refine.globs.iter().map(|g| Value::from(g.as_str())).collect()),
+        ),
+        (Value::from("scope"), Value::from(refine.scope.as_str())),
+    ])
+}
+
+fn decode_refinement(value: &Value) -> anyhow::Result<Refinement> {
+    let string = |v: &Value| -> anyhow::Result<String> {
+        Ok(v.as_str().context("expected a string")?.to_string())
+    };
+    let pattern = match field(value, "pattern")? {
+        Value::Nil => None,
+        pattern => Some(string(pattern)?),
+    };
+    let globs = field(value, "globs")?
+        .as_array()
+        .context("'globs' must be an array")?
+        .iter()
+        .map(string)
+        .collect::<anyhow::Result<_>>()?;
+    let scope = string(field(value, "scope")?)?;
+    Ok(Refinement {
+        parent: string(field(value, "parent")?)?,
+        pattern,
+        globs,
+        scope: RefineScope::from_name(&scope)
+            .with_context(|| format!("unknown scope '{}'", scope))?,
+    })
+}
+
+fn encode_combination(combine: &Combination) -> Value {
+    Value::Map(vec![
+        (Value::from("op"), Value::from(combine.op.as_str())),
+        (Value::from("level"), Value::from(combine.level.as_str())),
+        (
+            Value::from("ids"),
+            Value::Array(combine.ids.iter().map(|id| Value::from(id.as_str())).collect()),
+        ),
+    ])
+}
+
+fn decode_combination(value: &Value) -> anyhow::Result<Combination> {
+    let string = |v: &Value| -> anyhow::Result<String> {
+        Ok(v.as_str().context("expected a string")?.to_string())
+    };
+    let op = string(field(value, "op")?)?;
+    let level = string(field(value, "level")?)?;
+    Ok(Combination {
+        op: SetOp::from_name(&op).with_context(|| format!("unknown op '{}'", op))?,
+        level: SetLevel::from_name(&level)
+            .with_context(|| format!("unknown level '{}'", level))?,
+        ids: field(value, "ids")?
+            .as_array()
+            .context("'ids' must be an array")?
+            .iter()
+            .map(string)
+            .collect::<anyhow::Result<_>>()?,
+    })
+}
+
+/// Look up a field of a msgpack map.
+fn field<'a>(value: &'a Value, key: &str) -> anyhow::Result<&'a Value> {
+    value
+        .as_map()
+        .context("expected a map")?
+        .iter()
+        .find(|(k, _)| k.as_str() == Some(key))
+        .map(|(_, v)| v)
+        .with_context(|| format!("missing '{}'", key))
+}
+
+/// Paths are stored as raw bytes since they needn't be valid UTF-8.
+pub(crate) fn path_bytes(path: &Path) -> Vec<u8> {
+    path.as_os_str().as_encoded_bytes().to_vec()
+}
+
+pub(crate) fn bytes_path(bytes: &[u8]) -> PathBuf {
+    PathBuf::from(String::from_utf8_lossy(bytes).into_owned())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::store::StoreLimits;
+
+    use super::*;
+
+    /// Return a fresh, empty directory for a test to write into.
+    fn tempdir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!(
+            "search-history-test-{}-{}",
+            std::process::id(),
+            name
+        ));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn store() -> SearchStore {
+        let mut store = SearchStore::new(StoreLimits::default());
+        for (id, pattern) in [("a", "foo"), ("b", "bar")] {
+            let mut results = SearchResults::new();
+            let line = "let föo = bar; föo\n".as_bytes();
+            results.store_result(SearchResult::new(
+                Some("src/main.rs".to_string()),
+                7,
+                120,
+                line.to_vec(),
+                SubMatch::from_ranges(line, [4..8, 16..20]),
+            )
+            .with_context(
+                vec![ContextLine { line_number: 6, bytes: b"{\n".to_vec() }],
+                vec![ContextLine { line_number: 8, bytes: b"}\n".to_vec() }],
+                true,
+            ));
+            results.store_result(SearchResult::new(
+                None,
+                1,
+                0,
+                b"\xFF\n".to_vec(),
+                vec![],
+            ));
+            let query = SearchQuery {
+                pattern: pattern.to_string(),
+                paths: vec![PathBuf::from("./"), PathBuf::from("src")],
+                args: (id == "a").then(|| format!("-i {} ./ src", pattern)),
+                options: Value::Map(vec![(
+                    Value::from("hidden"),
+                    Value::from(true),
+                )]),
+                refine: (id == "b").then(|| Refinement {
+                    parent: "a".to_string(),
+                    pattern: None,
+                    globs: vec!["*.rs".to_string(), "!src/*".to_string()],
+                    scope: RefineScope::Files,
+                }),
+                combine: (id == "a").then(|| Combination {
+                    op: SetOp::Difference,
+                    level: SetLevel::Lines,
+                    ids: vec!["x".to_string(), "y".to_string()],
+                }),
+            };
+            let files = match id {
+                "a" => FileStamps::from([(
+                    "src/main.rs".to_string(),
+                    FileStamp { size: 1234, mtime: 1_700_000_000_123_456_789 },
+                )]),
+                _ => FileStamps::new(),
+            };
+            let mut record = SearchRecord::new(
+                id.to_string(),
+                query,
+                PathBuf::from("/project"),
+                results,
+            )
+            .with_files(files);
+            if id == "b" {
+                record = record.with_errors(vec![FileError {
+                    path: "secret".to_string(),
+                    kind: "PermissionDenied".to_string(),
+                    message: "Permission denied (os error 13)".to_string(),
+                    cause: ErrorCause::Permission,
+                }]);
+            }
+            record.set_complete(id == "a");
+            store.insert(record);
+        }
+        store
+    }
+
+    fn assert_same(expected: &SearchStore, got: &SearchStore) {
+        let (expected, got) = (expected.history(), got.history());
+        assert_eq!(expected.len(), got.len());
+        for (r1, r2) in expected.iter().zip(got.iter()) {
+            assert_eq!(r1.id(), r2.id());
+            assert_eq!(r1.query(), r2.query());
+            assert_eq!(r1.root(), r2.root());
+            assert_eq!(r1.complete(), r2.complete());
+            assert_eq!(r1.files(), r2.files());
+            assert_eq!(r1.errors(), r2.errors());
+            let millis = |t: SystemTime| {
+                t.duration_since(UNIX_EPOCH).unwrap().as_millis()
+            };
+            assert_eq!(millis(r1.created()), millis(r2.created()));
+            assert_eq!(r1.results().len(), r2.results().len());
+            for (a, b) in r1.results().iter().zip(r2.results().iter()) {
+                assert_eq!(a.get_file_name(), b.get_file_name());
+                assert_eq!(a.get_line_number(), b.get_line_number());
+                assert_eq!(a.get_byte_offset(), b.get_byte_offset());
+                assert_eq!(a.get_matched_bytes(), b.get_matched_bytes());
+                assert_eq!(a.get_submatches(), b.get_submatches());
+                assert_eq!(a.get_before(), b.get_before());
+                assert_eq!(a.get_after(), b.get_after());
+                assert_eq!(a.has_context_break(), b.has_context_break());
+            }
+        }
+    }
+
+    #[test]
+    fn roundtrip_bytes() {
+        let root = Path::new("/project");
+        let store = store();
+        let bytes = encode(root, &store).unwrap();
+        let records = decode(&bytes, root).unwrap().unwrap();
+
+        let mut got = SearchStore::new(StoreLimits::default());
+        for record in records.into_iter().rev() {
+            got.insert(record);
+        }
+        assert_same(&store, &got);
+    }
+
+    #[test]
+    fn roundtrip_file() {
+        let dir = tempdir("roundtrip");
+        let file = HistoryFile::in_dir(&dir, Path::new("/project"));
+        let store = store();
+        file.save(&store).unwrap();
+
+        let mut got = SearchStore::new(StoreLimits::default());
+        file.load_into(&mut got).unwrap();
+        assert_same(&store, &got);
+        // The search loaded last is still the most recently used one.
+        assert_eq!("b", got.history()[0].id());
+    }
+
+    #[test]
+    fn missing_file() {
+        let dir = tempdir("missing");
+        let file = HistoryFile::in_dir(&dir, Path::new("/project"));
+        let mut got = SearchStore::new(StoreLimits::default());
+        file.load_into(&mut got).unwrap();
+        assert_eq!(0, got.len());
+    }
+
+    #[test]
+    fn other_version_is_ignored() {
+        let root = Path::new("/project");
+        let mut bytes = encode(root, &store()).unwrap();
+        bytes[4..8].copy_from_slice(&(FORMAT_VERSION + 1).to_le_bytes());
+        assert!(decode(&bytes, root).unwrap().is_none());
+    }
+
+    #[test]
+    fn other_root_is_ignored() {
+        let bytes = encode(Path::new("/project"), &store()).unwrap();
+        assert!(decode(&bytes, Path::new("/elsewhere")).unwrap().is_none());
+    }
+
+    #[test]
+    fn corrupt_file_is_moved_aside() {
+        let dir = tempdir("corrupt");
+        let file = HistoryFile::in_dir(&dir, Path::new("/project"));
+        let mut bytes = encode(Path::new("/project"), &store()).unwrap();
+        bytes.truncate(bytes.len() / 2);
+        std::fs::write(file.path(), &bytes).unwrap();
+
+        let mut got = SearchStore::new(StoreLimits::default());
+        file.load_into(&mut got).unwrap();
+        assert_eq!(0, got.len());
+        assert!(!file.path().exists());
+        assert!(file.path().with_extension("msgpack.corrupt").exists());
+
+        std::fs::write(file.path(), b"not a history file").unwrap();
+        file.load_into(&mut got).unwrap();
+        assert_eq!(0, got.len());
+    }
+}
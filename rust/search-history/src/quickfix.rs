@@ -0,0 +1,299 @@
+/*!
+Turns a stored search into a quickfix or location list in the client.
+
+Each result becomes one entry, shaped the way `setqflist()` takes them: the
+absolute `filename`, the 1-based `lnum` and `col` of its first match, the
+`end_lnum` and `end_col` just past that match, and the matched line as
+`text`. The server then sets the list itself with `nvim_call_function`, so
+a client needs no code of its own to turn results into entries.
+
+Results can be narrowed down with the same globs `refine` takes, and capped
+at a maximum number of entries. Results without a path (i.e., from stdin)
+have nowhere to jump to and are left out.
+*/
+
+use std::path::Path;
+
+use ignore::overrides::Override;
+use neovim_lib::Value;
+
+use crate::search::{SearchResult, SearchResults};
+
+/// Which list the entries go to.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub(crate) enum ListKind {
+    /// The global quickfix list, set with `setqflist()`.
+    Quickfix,
+    /// The location list of a window, set with `setloclist()`.
+    Location,
+}
+
+impl ListKind {
+    /// Returns the name of this list as sent by the client.
+    pub(crate) fn as_str(&self) -> &'static str {
+        match *self {
+            ListKind::Quickfix => "quickfix",
+            ListKind::Location => "location",
+        }
+    }
+
+    /// Parse the name of a list, as returned by `as_str`.
+    pub(crate) fn from_name(name: &str) -> Option<ListKind> {
+        match name {
+            "quickfix" => Some(ListKind::Quickfix),
+            "location" => Some(ListKind::Location),
+            _ => None,
+        }
+    }
+}
+
+/// What to do with the list that's there already, as `setqflist()`'s
+/// `action` argument.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub(crate) enum ListAction {
+    /// Add a new list after the current one.
+    New,
+    /// Replace the entries of the current list.
+    Replace,
+    /// Add the entries to the current list.
+    Append,
+}
+
+impl ListAction {
+    /// Returns the name of this action as sent by the client.
+    pub(crate) fn as_str(&self) -> &'static str {
+        match *self {
+            ListAction::New => "new",
+            ListAction::Replace => "replace",
+            ListAction::Append => "append",
+        }
+    }
+
+    /// Parse the name of an action, as returned by `as_str`.
+    pub(crate) fn from_name(name: &str) -> Option<ListAction> {
+        match name {
+            "new" => Some(ListAction::New),
+            "replace" => Some(ListAction::Replace),
+            "append" => Some(ListAction::Append),
+            _ => None,
+        }
+    }
+
+    /// The `action` flag `setqflist()` takes.
+    fn flag(&self) -> &'static str {
+        match *self {
+            ListAction::New => " ",
+            ListAction::Replace => "r",
+            ListAction::Append => "a",
+        }
+    }
+}
+
+/// The entries of a list, along with how many results passed the filter
+/// before `max` cut them off.
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) struct Entries {
+    pub(crate) items: Vec<Value>,
+    pub(crate) total: usize,
+}
+
+/// Build the entries for the results whose path passes `filter`, keeping at
+/// most `max` of them.
+///
+/// Paths are made absolute by joining them to `root`, the directory the
+/// search ran in, so the entries work whatever the client's directory is.
+pub(crate) fn entries(
+    results: &SearchResults,
+    root: &Path,
+    filter: &Override,
+    max: Option<usize>,
+) -> Entries {
+    let mut items = vec![];
+    let mut total = 0;
+    for result in results.iter() {
+        let Some(path) = result.get_file_name() else { continue };
+        if filter.matched(path, false).is_ignore() {
+            continue;
+        }
+        total += 1;
+        if max.is_none_or(|max| items.len() < max) {
+            items.push(entry(result, &root.join(path)));
+        }
+    }
+    Entries { items, total }
+}
+
+/// The arguments of the `nvim_call_function` call that sets the list.
+///
+/// A location list is set for `window`, a window id or `0` for the window
+/// that's current when the call arrives.
+pub(crate) fn set_list_args(
+    kind: ListKind,
+    window: u64,
+    action: ListAction,
+    title: &str,
+    items: Vec<Value>,
+) -> Vec<Value> {
+    let what = Value::Map(vec![
+        (Value::from("title"), Value::from(title)),
+        (Value::from("items"), Value::Array(items)),
+    ]);
+    let (function, mut args) = match kind {
+        ListKind::Quickfix => ("setqflist", vec![]),
+        ListKind::Location => ("setloclist", vec![Value::from(window)]),
+    };
+    args.extend([Value::Array(vec![]), Value::from(action.flag()), what]);
+    vec![Value::from(function), Value::Array(args)]
+}
+
+/// The entry of a single result in the file at `path`.
+fn entry(result: &SearchResult, path: &Path) -> Value {
+    let bytes = result.get_matched_bytes();
+    let lnum = result.get_line_number();
+    let mut entry = vec![(
+        Value::from("filename"),
+        Value::from(path.to_string_lossy().as_ref()),
+    )];
+    match result.get_submatches().first() {
+        Some(m) => {
+            // In multi line mode a match may start or end on a later line.
+            // Columns are counted from the start of the line they're on.
+            let (start_line, start_col) = position(bytes, m.start.bytes);
+            let (end_line, end_col) = position(bytes, m.end.bytes);
+            entry.extend([
+                (Value::from("lnum"), Value::from(lnum + start_line)),
+                (Value::from("col"), Value::from(start_col + 1)),
+                (Value::from("end_lnum"), Value::from(lnum + end_line)),
+                (Value::from("end_col"), Value::from(end_col + 1)),
+            ]);
+        }
+        None => entry.push((Value::from("lnum"), Value::from(lnum))),
+    }
+    let line = match bytes.iter().position(|&b| b == b'\n') {
+        Some(end) => &bytes[..end],
+        None => &bytes[..],
+    };
+    let line = line.strip_suffix(b"\r").unwrap_or(line);
+    entry.push((
+        Value::from("text"),
+        Value::from(String::from_utf8_lossy(line).as_ref()),
+    ));
+    Value::Map(entry)
+}
+
+/// The line, counted from the first line of `bytes`, and the 0-based byte
+/// column of the byte offset `at`.
+fn position(bytes: &[u8], at: u64) -> (u64, u64) {
+    let at = usize::try_from(at).unwrap_or(usize::MAX);
+    let before = &bytes[..at.min(bytes.len())];
+    let lines = before.iter().filter(|&&b| b == b'\n').count();
+    let col = match before.iter().rposition(|&b| b == b'\n') {
+        Some(newline) => before.len() - newline - 1,
+        None => before.len(),
+    };
+    (lines as u64, col as u64)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{refine::path_filter, search::SubMatch};
+
+    use super::*;
+
+    fn get<'a>(value: &'a Value, key: &str) -> Option<&'a Value> {
+        value
+            .as_map()
+            .unwrap()
+            .iter()
+            .find(|(k, _)| k.as_str() == Some(key))
+            .map(|(_, v)| v)
+    }
+
+    fn results() -> SearchResults {
+        let mut results = SearchResults::new();
+        let hits = [
+            ("src/a.rs", 3, "let foo = 1;\r\n", 4..7),
+            ("src/b.rs", 7, "x\nfoo\n", 0..4),
+            ("docs/c.md", 1, "foo\n", 0..3),
+        ];
+        for (path, line, text, range) in hits {
+            let bytes = text.as_bytes().to_vec();
+            let submatches = SubMatch::from_ranges(&bytes, [range]);
+            results.store_result(SearchResult::new(
+                Some(path.to_string()),
+                line,
+                0,
+                bytes,
+                submatches,
+            ));
+        }
+        results.store_result(SearchResult::new(
+            None,
+            1,
+            0,
+            b"foo\n".to_vec(),
+            vec![],
+        ));
+        results
+    }
+
+    #[test]
+    fn entries_of_results() {
+        let root = Path::new("/project");
+        let all = path_filter(root, &[]).unwrap();
+        let got = entries(&results(), root, &all, None);
+        assert_eq!(3, got.total);
+
+        let first = &got.items[0];
+        let field = |entry: &Value, key: &str| {
+            get(entry, key).and_then(|v| v.as_u64())
+        };
+        assert_eq!(
+            Some("/project/src/a.rs"),
+            get(first, "filename").unwrap().as_str()
+        );
+        assert_eq!(Some(3), field(first, "lnum"));
+        assert_eq!(Some(5), field(first, "col"));
+        assert_eq!(Some(3), field(first, "end_lnum"));
+        assert_eq!(Some(8), field(first, "end_col"));
+        assert_eq!(Some("let foo = 1;"), get(first, "text").unwrap().as_str());
+
+        // A match running onto the next line ends there.
+        let second = &got.items[1];
+        assert_eq!(Some(7), field(second, "lnum"));
+        assert_eq!(Some(1), field(second, "col"));
+        assert_eq!(Some(8), field(second, "end_lnum"));
+        assert_eq!(Some(3), field(second, "end_col"));
+        assert_eq!(Some("x"), get(second, "text").unwrap().as_str());
+
+        let src = path_filter(root, &["src/**".to_string()]).unwrap();
+        let got = entries(&results(), root, &src, Some(1));
+        assert_eq!(2, got.total);
+        assert_eq!(1, got.items.len());
+    }
+
+    #[test]
+    fn call_args() {
+        let args = set_list_args(
+            ListKind::Location,
+            1000,
+            ListAction::Replace,
+            "search-1: foo",
+            vec![],
+        );
+        assert_eq!(Some("setloclist"), args[0].as_str());
+        let call = args[1].as_array().unwrap();
+        assert_eq!(4, call.len());
+        assert_eq!(Some(1000), call[0].as_u64());
+        assert_eq!(Some("r"), call[2].as_str());
+        assert_eq!(
+            Some("search-1: foo"),
+            get(&call[3], "title").unwrap().as_str()
+        );
+
+        let args =
+            set_list_args(ListKind::Quickfix, 0, ListAction::New, "", vec![]);
+        assert_eq!(Some("setqflist"), args[0].as_str());
+        assert_eq!(3, args[1].as_array().unwrap().len());
+    }
+}
@@ -0,0 +1,395 @@
+/*!
+Ranks the results of a stored search by how relevant they're likely to be.
+
+Results are stored in whatever order the parallel walker found them, which
+is fine for listing every hit but not for a picker that only shows the
+first few. When a client asks `query` to rank, every result is scored and
+the results are returned best first, with ties in path and line order.
+
+A score is the sum of a few signals, each worth a fixed number of points:
+
+* a match that is a whole word rather than part of one,
+* a match that is also in the file's name,
+* how deep the file is below the project root,
+* how close the file is to the client's current file,
+* how recently the file was modified, going by the stamp taken when it was
+  searched,
+* penalties for tests, vendored code and minified files.
+
+Each score keeps the signals it was made of, so a client can show why a
+result ranked where it did.
+*/
+
+use std::{
+    path::{Component, Path, PathBuf},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use crate::{
+    refresh::{FileStamp, FileStamps},
+    search::{SearchResult, SearchResults},
+};
+
+/// The points of a match that is a whole word.
+const WORD: i64 = 20;
+/// The points of a match that is also in the file's name.
+const FILE_NAME: i64 = 25;
+/// The points lost per directory between the root and the file.
+const DEPTH: i64 = -2;
+/// The most points lost to depth.
+const MAX_DEPTH: i64 = -20;
+/// The points of a match in the current file.
+const SAME_FILE: i64 = 30;
+/// The points of a match in the current file's directory. Every directory
+/// between the two files takes `NEAR_STEP` off.
+const NEAR: i64 = 20;
+const NEAR_STEP: i64 = 4;
+/// The points of files modified within each age, the first that fits wins.
+const RECENT: [(Duration, i64); 3] = [
+    (Duration::from_secs(60 * 60), 15),
+    (Duration::from_secs(24 * 60 * 60), 10),
+    (Duration::from_secs(7 * 24 * 60 * 60), 5),
+];
+/// The points lost by tests, vendored code and minified files.
+const TEST: i64 = -15;
+const VENDOR: i64 = -30;
+const MINIFIED: i64 = -30;
+/// Lines longer than this are assumed to come from minified files.
+const MINIFIED_LINE: usize = 1000;
+
+/// Directories whose files are tests.
+const TEST_DIRS: &[&str] =
+    &["test", "tests", "spec", "specs", "__tests__", "testdata", "fixtures"];
+/// Directories whose files are vendored.
+const VENDOR_DIRS: &[&str] = &[
+    "vendor",
+    "node_modules",
+    "third_party",
+    "third-party",
+    "bower_components",
+];
+
+/// Something that adds to or takes from a result's score.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub(crate) enum Signal {
+    Word,
+    FileName,
+    Depth,
+    Proximity,
+    Recent,
+    Test,
+    Vendor,
+    Minified,
+}
+
+impl Signal {
+    /// The name a signal is sent to clients with.
+    pub(crate) fn as_str(&self) -> &'static str {
+        match *self {
+            Signal::Word => "word",
+            Signal::FileName => "file_name",
+            Signal::Depth => "depth",
+            Signal::Proximity => "proximity",
+            Signal::Recent => "recent",
+            Signal::Test => "test",
+            Signal::Vendor => "vendor",
+            Signal::Minified => "minified",
+        }
+    }
+}
+
+/// The score of a result, along with the signals it was made of. Signals
+/// worth no points are left out.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub(crate) struct Score {
+    pub(crate) total: i64,
+    pub(crate) signals: Vec<(Signal, i64)>,
+}
+
+impl Score {
+    fn add(&mut self, signal: Signal, points: i64) {
+        if points != 0 {
+            self.total += points;
+            self.signals.push((signal, points));
+        }
+    }
+}
+
+/// Scores the results of the searches made in one project root.
+#[derive(Clone, Debug)]
+pub(crate) struct Ranker {
+    root: PathBuf,
+    current_file: Option<PathBuf>,
+    now: SystemTime,
+}
+
+impl Ranker {
+    /// Create a ranker for results relative to `root`.
+    ///
+    /// `current_file` is the file open in the client, either absolute or
+    /// relative to `root`. Recency is measured from `now`.
+    pub(crate) fn new(
+        root: &Path,
+        current_file: Option<&Path>,
+        now: SystemTime,
+    ) -> Ranker {
+        Ranker {
+            root: root.to_path_buf(),
+            current_file: current_file.map(|path| root.join(path)),
+            now,
+        }
+    }
+
+    /// Score every result and sort them best first.
+    ///
+    /// `files` are the stamps taken when the results were found, which is
+    /// where recency comes from. Results of files without one aren't
+    /// counted as recent.
+    pub(crate) fn rank<'a>(
+        &self,
+        results: &'a SearchResults,
+        files: &FileStamps,
+    ) -> Vec<(&'a SearchResult, Score)> {
+        let mut ranked: Vec<(&SearchResult, Score)> = results
+            .iter()
+            .map(|result| {
+                let stamp = result.get_file_name().and_then(|p| files.get(p));
+                (result, self.score(result, stamp))
+            })
+            .collect();
+        ranked.sort_by(|(r1, s1), (r2, s2)| {
+            s2.total
+                .cmp(&s1.total)
+                .then_with(|| r1.get_file_name().cmp(&r2.get_file_name()))
+                .then_with(|| r1.get_line_number().cmp(&r2.get_line_number()))
+        });
+        ranked
+    }
+
+    /// Score a single result. `stamp` is the stamp of its file, if any.
+    pub(crate) fn score(
+        &self,
+        result: &SearchResult,
+        stamp: Option<&FileStamp>,
+    ) -> Score {
+        let mut score = Score::default();
+        let line = result.get_matched_bytes();
+        let matches: Vec<&[u8]> = result
+            .get_submatches()
+            .iter()
+            .map(|m| &line[m.start.bytes as usize..m.end.bytes as usize])
+            .collect();
+        let any_word = result.get_submatches().iter().any(|m| {
+            is_word_boundary(line, m.start.bytes as usize)
+                && is_word_boundary(line, m.end.bytes as usize)
+        });
+        if any_word {
+            score.add(Signal::Word, WORD);
+        }
+        if line.len() > MINIFIED_LINE {
+            score.add(Signal::Minified, MINIFIED);
+        }
+
+        // Without a path (i.e., stdin) there's nothing else to go on.
+        let Some(path) = result.get_file_name().map(Path::new) else {
+            return score;
+        };
+        let name = path
+            .file_name()
+            .map(|name| name.to_string_lossy().to_ascii_lowercase())
+            .unwrap_or_default();
+        let in_name = matches.iter().any(|m| {
+            let m = String::from_utf8_lossy(m).to_ascii_lowercase();
+            !m.is_empty() && name.contains(&m)
+        });
+        if in_name {
+            score.add(Signal::FileName, FILE_NAME);
+        }
+
+        let dirs = dirs(path);
+        score.add(Signal::Depth, (DEPTH * dirs.len() as i64).max(MAX_DEPTH));
+        if let Some(ref current) = self.current_file {
+            score.add(Signal::Proximity, self.proximity(path, current));
+        }
+        if let Some(stamp) = stamp {
+            score.add(Signal::Recent, self.recency(stamp));
+        }
+
+        let is_test = dirs.iter().any(|dir| TEST_DIRS.contains(&dir.as_str()))
+            || name.starts_with("test_")
+            || ["_test.", ".test.", ".spec."].iter().any(|s| name.contains(s));
+        if is_test {
+            score.add(Signal::Test, TEST);
+        }
+        if dirs.iter().any(|dir| VENDOR_DIRS.contains(&dir.as_str())) {
+            score.add(Signal::Vendor, VENDOR);
+        }
+        if name.contains(".min.") && line.len() <= MINIFIED_LINE {
+            score.add(Signal::Minified, MINIFIED);
+        }
+        score
+    }
+
+    /// The points for how close the file at `path` is to `current`, which
+    /// is already joined to the root.
+    fn proximity(&self, path: &Path, current: &Path) -> i64 {
+        let path = self.root.join(path);
+        if path == *current {
+            return SAME_FILE;
+        }
+        let (Some(dir), Some(current_dir)) = (path.parent(), current.parent())
+        else {
+            return 0;
+        };
+        let a: Vec<Component<'_>> = normal(dir);
+        let b: Vec<Component<'_>> = normal(current_dir);
+        let shared = a.iter().zip(b.iter()).take_while(|(x, y)| x == y).count();
+        let distance = (a.len() - shared) + (b.len() - shared);
+        (NEAR - NEAR_STEP * distance as i64).max(0)
+    }
+
+    /// The points for how recently a file stamped with `stamp` changed.
+    fn recency(&self, stamp: &FileStamp) -> i64 {
+        let mtime = UNIX_EPOCH + Duration::from_nanos(stamp.mtime);
+        // A file modified "in the future" is as recent as it gets.
+        let age = self.now.duration_since(mtime).unwrap_or_default();
+        RECENT
+            .iter()
+            .find(|(within, _)| age <= *within)
+            .map_or(0, |&(_, points)| points)
+    }
+}
+
+/// The lowercased names of the directories in `path`, a path relative to
+/// the root as a search reports it.
+fn dirs(path: &Path) -> Vec<String> {
+    let Some(parent) = path.parent() else { return vec![] };
+    parent
+        .components()
+        .filter_map(|c| match c {
+            Component::Normal(name) => {
+                Some(name.to_string_lossy().to_ascii_lowercase())
+            }
+            _ => None,
+        })
+        .collect()
+}
+
+/// The components of `path` without any `.`.
+fn normal(path: &Path) -> Vec<Component<'_>> {
+    path.components().filter(|c| *c != Component::CurDir).collect()
+}
+
+/// Returns true if the byte offset `at` in `line` isn't in the middle of a
+/// word, i.e., the bytes on either side of it aren't both word bytes. Any
+/// non-ASCII byte counts as a word byte.
+fn is_word_boundary(line: &[u8], at: usize) -> bool {
+    let is_word = |b: u8| b.is_ascii_alphanumeric() || b == b'_' || b >= 0x80;
+    let before = at.checked_sub(1).and_then(|i| line.get(i)).copied();
+    let after = line.get(at).copied();
+    match (before, after) {
+        (Some(b), Some(a)) => !(is_word(b) && is_word(a)),
+        _ => true,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::search::SubMatch;
+
+    use super::*;
+
+    fn result(
+        path: &str,
+        line: u64,
+        text: &str,
+        pattern: &str,
+    ) -> SearchResult {
+        let bytes = format!("{}\n", text).into_bytes();
+        let start = text.find(pattern).unwrap();
+        let submatches =
+            SubMatch::from_ranges(&bytes, Some(start..start + pattern.len()));
+        SearchResult::new(Some(path.to_string()), line, 0, bytes, submatches)
+    }
+
+    fn signals(score: &Score) -> Vec<(&'static str, i64)> {
+        score.signals.iter().map(|&(s, p)| (s.as_str(), p)).collect()
+    }
+
+    fn ranker(current: Option<&str>) -> Ranker {
+        Ranker::new(Path::new("/project"), current.map(Path::new), UNIX_EPOCH)
+    }
+
+    #[test]
+    fn signals_of_a_result() {
+        let ranker = ranker(Some("src/lib.rs"));
+        let hit = result("./src/config.rs", 1, "let config = 1;", "config");
+        let score = ranker.score(&hit, None);
+        assert_eq!(
+            vec![
+                ("word", 20),
+                ("file_name", 25),
+                ("depth", -2),
+                ("proximity", 20),
+            ],
+            signals(&score)
+        );
+        assert_eq!(63, score.total);
+
+        let path = "vendor/lib/tests/reconfigure.min.js";
+        let score =
+            ranker.score(&result(path, 1, "reconfigure()", "config"), None);
+        assert_eq!(
+            vec![
+                ("file_name", 25),
+                ("depth", -6),
+                ("proximity", 4),
+                ("test", -15),
+                ("vendor", -30),
+                ("minified", -30),
+            ],
+            signals(&score)
+        );
+
+        // Same file, and modified within the last day.
+        let stamp = FileStamp { size: 1, mtime: 0 };
+        let ranker = Ranker::new(
+            Path::new("/project"),
+            Some(Path::new("/project/main.rs")),
+            UNIX_EPOCH + Duration::from_secs(2 * 60 * 60),
+        );
+        let hit = result("main.rs", 1, "foo_bar", "bar");
+        let score = ranker.score(&hit, Some(&stamp));
+        assert_eq!(vec![("proximity", 30), ("recent", 10)], signals(&score));
+    }
+
+    #[test]
+    fn rank_order() {
+        let mut results = SearchResults::new();
+        for r in [
+            result("tests/b.rs", 1, "foo", "foo"),
+            result("src/b.rs", 9, "foobar", "foo"),
+            result("src/b.rs", 2, "foobar", "foo"),
+            result("src/a.rs", 5, "foo", "foo"),
+        ] {
+            results.store_result(r);
+        }
+        let ranked = ranker(None).rank(&results, &FileStamps::new());
+        let order: Vec<(&str, u64)> = ranked
+            .iter()
+            .map(|(r, _)| (r.get_file_name().unwrap(), r.get_line_number()))
+            .collect();
+        // A whole word outweighs being a test.
+        assert_eq!(
+            vec![
+                ("src/a.rs", 5),
+                ("tests/b.rs", 1),
+                ("src/b.rs", 2),
+                ("src/b.rs", 9),
+            ],
+            order
+        );
+        let totals: Vec<i64> = ranked.iter().map(|(_, s)| s.total).collect();
+        assert_eq!(vec![18, 3, -2, -2], totals);
+    }
+}
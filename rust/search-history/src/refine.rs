@@ -0,0 +1,232 @@
+/*!
+Narrows a stored search down without walking the tree again.
+
+A refinement takes the results of an earlier search, its parent, and keeps
+only the hits that also match a new pattern, or whose paths match a set of
+globs. It re-checks one of two scopes:
+
+* `lines` checks only the parent's matched lines. Nothing is read from
+  disk, so this takes about as long as paging through the parent once.
+* `files` searches the parent's files again with the new pattern. This
+  finds hits anywhere in those files, not just on the lines the parent
+  matched, but still never walks the tree.
+
+Either way, the output is stored as a new search whose query links back to
+its parent, so refinements can themselves be refined.
+*/
+
+use std::{
+    collections::HashSet,
+    io,
+    path::{Path, PathBuf},
+};
+
+use ignore::overrides::{Override, OverrideBuilder};
+
+use crate::search::{PatternMatcher, SearchResults};
+
+/// What part of the parent search a refinement re-checks.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub(crate) enum RefineScope {
+    /// Only the lines the parent matched.
+    Lines,
+    /// Every line of the files the parent matched in.
+    Files,
+}
+
+impl RefineScope {
+    /// Returns the name of this scope as sent to and from the client.
+    pub(crate) fn as_str(&self) -> &'static str {
+        match *self {
+            RefineScope::Lines => "lines",
+            RefineScope::Files => "files",
+        }
+    }
+
+    /// Parse the name of a scope, as returned by `as_str`.
+    pub(crate) fn from_name(name: &str) -> Option<RefineScope> {
+        match name {
+            "lines" => Some(RefineScope::Lines),
+            "files" => Some(RefineScope::Files),
+            _ => None,
+        }
+    }
+}
+
+/// How a stored search was narrowed down from its parent.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub(crate) struct Refinement {
+    /// The id of the search that was refined.
+    pub(crate) parent: String,
+    /// The pattern every kept hit also matches, if any.
+    pub(crate) pattern: Option<String>,
+    /// The globs every kept hit's path matches, with the same meaning as
+    /// ripgrep's `-g/--glob`.
+    pub(crate) globs: Vec<String>,
+    /// What part of the parent was re-checked.
+    pub(crate) scope: RefineScope,
+}
+
+/// Build the path filter for the given globs.
+///
+/// Globs are matched relative to `root`, the directory the parent search
+/// was run from, the same way `-g/--glob` is. With no globs, every path
+/// passes.
+pub(crate) fn path_filter(
+    root: &Path,
+    globs: &[String],
+) -> anyhow::Result<Override> {
+    let mut builder = OverrideBuilder::new(root);
+    for glob in globs.iter() {
+        builder.add(glob)?;
+    }
+    Ok(builder.build()?)
+}
+
+/// Keep the results of `parent` whose path passes `filter` and, if a
+/// matcher is given, whose matched bytes it matches.
+///
+/// Kept results are copied as they are. In particular, their submatches
+/// still point at what the parent's pattern matched.
+pub(crate) fn refine_lines(
+    parent: &SearchResults,
+    matcher: Option<&PatternMatcher>,
+    filter: &Override,
+) -> io::Result<SearchResults> {
+    let mut results = SearchResults::new();
+    for result in parent.iter() {
+        if !keep_path(filter, result.get_file_name()) {
+            continue;
+        }
+        if let Some(matcher) = matcher {
+            // The line terminator is dropped so that, e.g., `foo$` matches
+            // a line ending in `foo`.
+            let bytes = result.get_matched_bytes();
+            let bytes = bytes.strip_suffix(b"\n").unwrap_or(bytes);
+            let bytes = bytes.strip_suffix(b"\r").unwrap_or(bytes);
+            if !matcher.is_match(bytes)? {
+                continue;
+            }
+        }
+        results.store_result(result.clone());
+    }
+    Ok(results)
+}
+
+/// The distinct files of `parent` whose path passes `filter`, in the order
+/// they first appear in its results.
+///
+/// Results without a path, which come from searching stdin, can't be
+/// searched again and are skipped.
+pub(crate) fn refine_files(
+    parent: &SearchResults,
+    filter: &Override,
+) -> Vec<PathBuf> {
+    let mut seen = HashSet::new();
+    let mut files = vec![];
+    for result in parent.iter() {
+        let Some(path) = result.get_file_name() else { continue };
+        if keep_path(filter, Some(path)) && seen.insert(path) {
+            files.push(PathBuf::from(path));
+        }
+    }
+    files
+}
+
+/// Returns true if a result with the given path passes `filter`.
+fn keep_path(filter: &Override, path: Option<&str>) -> bool {
+    match path {
+        None => filter.is_empty(),
+        Some(path) => !filter.matched(path, false).is_ignore(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use grep::regex::RegexMatcher;
+
+    use crate::search::{SearchResult, SubMatch};
+
+    use super::*;
+
+    fn results(hits: &[(&str, &str)]) -> SearchResults {
+        let mut results = SearchResults::new();
+        for (i, &(path, line)) in hits.iter().enumerate() {
+            let bytes = format!("{}\n", line).into_bytes();
+            let submatches = SubMatch::from_ranges(&bytes, Some(0..1));
+            results.store_result(SearchResult::new(
+                Some(path.to_string()),
+                i as u64 + 1,
+                0,
+                bytes,
+                submatches,
+            ));
+        }
+        results
+    }
+
+    fn lines(results: &SearchResults) -> Vec<(&str, &[u8])> {
+        results
+            .iter()
+            .map(|r| (r.get_file_name().unwrap(), &r.get_matched_bytes()[..]))
+            .collect()
+    }
+
+    fn filter(globs: &[&str]) -> Override {
+        let globs: Vec<String> = globs.iter().map(|g| g.to_string()).collect();
+        path_filter(Path::new("/project"), &globs).unwrap()
+    }
+
+    #[test]
+    fn lines_by_pattern() {
+        let parent = results(&[
+            ("./a.php", "$q = new wp_query($args);"),
+            ("./a.php", "wp_query(['meta_query' => $meta]);"),
+            ("./b.php", "// meta_query$"),
+        ]);
+        let matcher = PatternMatcher::RustRegex(
+            RegexMatcher::new_line_matcher(r"meta_query.*\]\);$").unwrap(),
+        );
+        let got = refine_lines(&parent, Some(&matcher), &filter(&[])).unwrap();
+        assert_eq!(
+            vec![("./a.php", &b"wp_query(['meta_query' => $meta]);\n"[..])],
+            lines(&got)
+        );
+        // The submatches are the parent's.
+        assert_eq!(
+            parent.iter().nth(1).unwrap().get_submatches(),
+            got.iter().next().unwrap().get_submatches()
+        );
+    }
+
+    #[test]
+    fn lines_by_glob() {
+        let parent = results(&[
+            ("./src/a.rs", "a"),
+            ("./src/a.min.js", "b"),
+            ("/project/src/c.js", "c"),
+            ("./tests/d.rs", "d"),
+        ]);
+        let got = refine_lines(&parent, None, &filter(&["src/**"])).unwrap();
+        assert_eq!(
+            vec!["./src/a.rs", "./src/a.min.js", "/project/src/c.js"],
+            lines(&got).into_iter().map(|(p, _)| p).collect::<Vec<_>>()
+        );
+        let got = refine_lines(&parent, None, &filter(&["!*.min.js"])).unwrap();
+        assert_eq!(3, got.len());
+    }
+
+    #[test]
+    fn files() {
+        let parent = results(&[
+            ("./a.php", "1"),
+            ("./b.js", "2"),
+            ("./a.php", "3"),
+            ("./c.php", "4"),
+        ]);
+        assert_eq!(
+            vec![PathBuf::from("./a.php"), PathBuf::from("./c.php")],
+            refine_files(&parent, &filter(&["*.php"]))
+        );
+    }
+}
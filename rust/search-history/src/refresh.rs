@@ -0,0 +1,320 @@
+/*!
+Re-runs stored searches and reports what changed since they last ran.
+
+Every search records the size and modification time of each file it
+searched. When a stored search is refreshed, files whose size and
+modification time are both unchanged aren't searched again. Their hits are
+carried over from the previous run instead, so refreshing a search after
+editing a handful of files only reads those files.
+
+Modification times are only as fine as the file system keeps them, which is
+a whole second on some and two on FAT. A file that's written again within
+the same tick without changing size keeps its stamp, so a stamp is only
+trusted if the file changed well before the previous run started. Files that
+changed shortly before are always searched again.
+
+The hits of the previous and the new run are then compared. A hit is matched
+up with a hit of the other run in the same file with the same text. Hits
+that were matched up but are now on another line are reported as moved, and
+the rest as added or removed.
+*/
+
+use std::{
+    collections::HashMap,
+    path::Path,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use crate::search::{SearchResult, SearchResults};
+
+/// The size and modification time of a searched file, which together tell
+/// whether it changed since it was searched.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub(crate) struct FileStamp {
+    /// The size of the file in bytes.
+    pub(crate) size: u64,
+    /// The modification time, in nanoseconds since the Unix epoch. On Unix,
+    /// this is the status change time if that's later, which also moves
+    /// when the modification time is set back, e.g., by `touch -r`.
+    pub(crate) mtime: u64,
+}
+
+/// How long before a search started a file must have changed for its stamp
+/// to tell that change apart from the next one.
+const SETTLE: Duration = Duration::from_secs(2);
+
+impl FileStamp {
+    /// Read the stamp of the file at `path`.
+    ///
+    /// This returns `None` if the file's metadata can't be read, or the
+    /// platform doesn't report modification times. Such files are always
+    /// searched again.
+    pub(crate) fn of(path: &Path) -> Option<FileStamp> {
+        let md = path.metadata().ok()?;
+        let mtime = md.modified().ok()?.duration_since(UNIX_EPOCH).ok()?;
+        let mtime = u64::try_from(mtime.as_nanos()).ok()?;
+        #[cfg(unix)]
+        let mtime = {
+            use std::os::unix::fs::MetadataExt;
+
+            let ctime = u64::try_from(md.ctime()).ok()?;
+            let ctime_nsec = u64::try_from(md.ctime_nsec()).ok()?;
+            mtime.max(
+                ctime.checked_mul(1_000_000_000)?.checked_add(ctime_nsec)?,
+            )
+        };
+        Some(FileStamp { size: md.len(), mtime })
+    }
+
+    /// Returns true if the file changed long enough before `since` that any
+    /// later change would change its stamp.
+    pub(crate) fn settled(&self, since: SystemTime) -> bool {
+        let Ok(since) = since.duration_since(UNIX_EPOCH) else {
+            return false;
+        };
+        u128::from(self.mtime) + SETTLE.as_nanos() <= since.as_nanos()
+    }
+}
+
+/// The stamps of every file a search searched, keyed by the path its hits
+/// are reported with.
+pub(crate) type FileStamps = HashMap<String, FileStamp>;
+
+/// Returns true if the file at `path` can be skipped because it's stamped
+/// the same in `before` and `now`.
+///
+/// `since` is when the search that took the stamps in `before` started. A
+/// file that changed shortly before then is never skipped, since its stamp
+/// may not have moved for a later change.
+pub(crate) fn is_unchanged(
+    before: &FileStamps,
+    since: SystemTime,
+    path: &str,
+    now: Option<FileStamp>,
+) -> bool {
+    match (before.get(path), now) {
+        (Some(before), Some(now)) => *before == now && before.settled(since),
+        _ => false,
+    }
+}
+
+/// The hits of a refreshed search: the hits found in the files that were
+/// searched again, followed by the previous hits in the files that weren't.
+///
+/// A file was skipped if [`is_unchanged`] says so for its stamps in `before`
+/// and `after`, with `since` being when the previous run started. Previous
+/// hits in files that the new run didn't come across at all, e.g., because
+/// they were deleted, are dropped.
+pub(crate) fn carry_over(
+    previous: &SearchResults,
+    before: &FileStamps,
+    since: SystemTime,
+    after: &FileStamps,
+    found: SearchResults,
+) -> SearchResults {
+    let mut results = found;
+    for result in previous.iter() {
+        let Some(path) = result.get_file_name() else { continue };
+        if is_unchanged(before, since, path, after.get(path).copied()) {
+            results.store_result(result.clone());
+        }
+    }
+    results
+}
+
+/// What changed between two runs of the same search.
+#[derive(Clone, Debug, Default)]
+pub(crate) struct Diff {
+    /// Hits only found by the new run.
+    pub(crate) added: Vec<SearchResult>,
+    /// Hits only found by the previous run.
+    pub(crate) removed: Vec<SearchResult>,
+    /// Hits found by both runs, but on another line or at another offset.
+    /// Each is given as its previous hit and its new hit.
+    pub(crate) moved: Vec<(SearchResult, SearchResult)>,
+}
+
+impl Diff {
+    /// Compare the hits of a previous and a new run of a search.
+    ///
+    /// Within each file, hits with the same text are matched up in the order
+    /// they appear in the file. Hits that couldn't be matched up with one of
+    /// the other run are added or removed. Every list is sorted by path and
+    /// then by position.
+    pub(crate) fn between(
+        previous: &SearchResults,
+        now: &SearchResults,
+    ) -> Diff {
+        type Group<'a> =
+            HashMap<(Option<&'a str>, &'a [u8]), Vec<&'a SearchResult>>;
+
+        let mut before: Group<'_> = HashMap::new();
+        for result in previous.iter() {
+            before.entry(key(result)).or_default().push(result);
+        }
+        let mut after: Group<'_> = HashMap::new();
+        for result in now.iter() {
+            after.entry(key(result)).or_default().push(result);
+        }
+
+        let mut diff = Diff::default();
+        for (k, mut old) in before.into_iter() {
+            let mut new = after.remove(&k).unwrap_or_default();
+            old.sort_by_key(|r| r.get_byte_offset());
+            new.sort_by_key(|r| r.get_byte_offset());
+            let paired = old.len().min(new.len());
+            for (o, n) in old.iter().zip(new.iter()) {
+                if o.get_line_number() != n.get_line_number()
+                    || o.get_byte_offset() != n.get_byte_offset()
+                {
+                    diff.moved.push(((*o).clone(), (*n).clone()));
+                }
+            }
+            diff.removed.extend(old[paired..].iter().map(|r| (*r).clone()));
+            diff.added.extend(new[paired..].iter().map(|r| (*r).clone()));
+        }
+        for (_, new) in after.into_iter() {
+            diff.added.extend(new.into_iter().cloned());
+        }
+
+        let order = |r: &SearchResult| {
+            (r.get_file_name().map(String::from), r.get_byte_offset())
+        };
+        diff.added.sort_by_key(order);
+        diff.removed.sort_by_key(order);
+        diff.moved.sort_by_key(|(o, _)| order(o));
+        diff
+    }
+
+    /// Returns true if nothing changed.
+    pub(crate) fn is_empty(&self) -> bool {
+        self.added.is_empty()
+            && self.removed.is_empty()
+            && self.moved.is_empty()
+    }
+}
+
+/// What a hit is matched up by: its file and its text.
+fn key(result: &SearchResult) -> (Option<&str>, &[u8]) {
+    (result.get_file_name(), &result.get_matched_bytes()[..])
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::search::SubMatch;
+
+    use super::*;
+
+    fn results(hits: &[(&str, u64, &str)]) -> SearchResults {
+        let mut results = SearchResults::new();
+        for &(path, line, text) in hits.iter() {
+            let bytes = format!("{}\n", text).into_bytes();
+            let submatches = SubMatch::from_ranges(&bytes, Some(0..1));
+            results.store_result(SearchResult::new(
+                Some(path.to_string()),
+                line,
+                line * 100,
+                bytes,
+                submatches,
+            ));
+        }
+        results
+    }
+
+    fn lines(results: &[SearchResult]) -> Vec<(&str, u64)> {
+        results
+            .iter()
+            .map(|r| (r.get_file_name().unwrap(), r.get_line_number()))
+            .collect()
+    }
+
+    fn stamp(size: u64) -> FileStamp {
+        FileStamp { size, mtime: 1 }
+    }
+
+    #[test]
+    fn carry_over_unchanged_files() {
+        let previous =
+            results(&[("a", 1, "foo"), ("b", 2, "foo"), ("c", 3, "foo")]);
+        let before: FileStamps = [("a", 1), ("b", 2), ("c", 3)]
+            .into_iter()
+            .map(|(p, s)| (p.to_string(), stamp(s)))
+            .collect();
+        // `a` is unchanged, `b` changed and `c` is gone.
+        let after: FileStamps = [("a", 1), ("b", 5)]
+            .into_iter()
+            .map(|(p, s)| (p.to_string(), stamp(s)))
+            .collect();
+        let since = SystemTime::now();
+        assert!(is_unchanged(&before, since, "a", Some(stamp(1))));
+        assert!(!is_unchanged(&before, since, "b", Some(stamp(5))));
+        assert!(!is_unchanged(&before, since, "a", None));
+        assert!(!is_unchanged(&before, since, "d", Some(stamp(1))));
+
+        let found = results(&[("b", 4, "foo")]);
+        let got = carry_over(&previous, &before, since, &after, found);
+        let got: Vec<SearchResult> = got.iter().cloned().collect();
+        assert_eq!(vec![("b", 4), ("a", 1)], lines(&got));
+    }
+
+    #[test]
+    fn recent_changes_are_searched_again() {
+        let previous = results(&[("a", 1, "foo")]);
+        let since = UNIX_EPOCH + Duration::from_secs(100);
+        let at = |secs: f64| FileStamp {
+            size: 1,
+            mtime: Duration::from_secs_f64(secs).as_nanos() as u64,
+        };
+        for (mtime, skipped) in [(90.0, true), (98.0, true), (99.5, false)] {
+            let stamps: FileStamps =
+                [("a".to_string(), at(mtime))].into_iter().collect();
+            let now = Some(at(mtime));
+            assert_eq!(skipped, is_unchanged(&stamps, since, "a", now));
+            let got =
+                carry_over(&previous, &stamps, since, &stamps, results(&[]));
+            assert_eq!(usize::from(skipped), got.len());
+        }
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn setting_mtime_back_changes_the_stamp() {
+        let path = std::env::temp_dir()
+            .join(format!("search-history-test-{}-stamp", std::process::id()));
+        std::fs::write(&path, "foo").unwrap();
+        let file = std::fs::File::options().write(true).open(&path).unwrap();
+        let before = FileStamp::of(&path).unwrap();
+        std::thread::sleep(Duration::from_millis(20));
+        file.set_modified(UNIX_EPOCH + Duration::from_secs(1)).unwrap();
+        let after = FileStamp::of(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_ne!(before, after);
+    }
+
+    #[test]
+    fn diff() {
+        let previous = results(&[
+            ("a", 1, "foo()"),
+            ("a", 5, "foo(x)"),
+            ("a", 9, "foo()"),
+            ("b", 2, "foo()"),
+        ]);
+        let now = results(&[
+            ("a", 3, "foo()"),
+            ("a", 5, "foo(x)"),
+            ("b", 2, "foo()"),
+            ("c", 7, "foo(y)"),
+        ]);
+        let diff = Diff::between(&previous, &now);
+        assert_eq!(vec![("c", 7)], lines(&diff.added));
+        assert_eq!(vec![("a", 9)], lines(&diff.removed));
+        let moved: Vec<_> = diff
+            .moved
+            .iter()
+            .map(|(o, n)| (o.get_line_number(), n.get_line_number()))
+            .collect();
+        assert_eq!(vec![(1, 3)], moved);
+
+        assert!(Diff::between(&now, &now).is_empty());
+    }
+}
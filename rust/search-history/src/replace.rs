@@ -0,0 +1,728 @@
+/*!
+Replaces the hits of a stored search in the files they were found in.
+
+Every result of the search is a hunk: its matched line, and the same line
+with each match replaced the way `-r/--replace` would print it. A client
+first previews the hunks, then applies the ones it accepts. Nothing is ever
+written to a file that changed since the search, going by the stamps taken
+when it was searched, and the line of every hunk must still be where the
+search found it.
+
+Each file is written to a temporary file next to it and renamed over the
+original, so a file is either fully replaced or not at all. Before any file
+is written, its original contents are copied into an undo journal, which
+`revert` uses to put every file of the replacement back. If writing one of
+the files fails, the files already written are put back right away.
+
+Journals are kept in their own directories under `<name>.journal/`, next to
+the history file, each with a manifest listing the files it holds and the
+hunks written to each of them. A revert is refused for files that changed
+since they were replaced, so it never throws away later edits, and the files
+already put back get their replaced contents again if one can't be written.
+*/
+
+use std::{
+    collections::BTreeMap,
+    fmt, io,
+    path::{Path, PathBuf},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::Context;
+use ignore::overrides::Override;
+use neovim_lib::Value;
+
+use crate::{
+    persist,
+    refresh::{FileStamp, FileStamps},
+    search::{PatternMatcher, SearchResults},
+};
+
+/// The name of the manifest in every journal.
+const MANIFEST: &str = "manifest.msgpack";
+
+/// A single matched line and what it's replaced with.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub(crate) struct Hunk {
+    /// The path of the file, as the search reports it.
+    pub(crate) path: String,
+    /// The line number of the first line.
+    pub(crate) line: u64,
+    /// The byte offset of the first line in the file.
+    pub(crate) offset: u64,
+    /// The lines as the search found them.
+    pub(crate) before: Vec<u8>,
+    /// The lines with every match replaced.
+    pub(crate) after: Vec<u8>,
+}
+
+/// Why a replacement or a revert didn't go ahead.
+#[derive(Debug)]
+pub(crate) enum ReplaceError {
+    /// These files changed since they were searched or, when reverting,
+    /// since they were replaced. Nothing was written.
+    Changed(Vec<String>),
+    /// Anything else, e.g., a file couldn't be read or written.
+    Other(anyhow::Error),
+}
+
+impl fmt::Display for ReplaceError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match *self {
+            ReplaceError::Changed(ref files) => {
+                write!(
+                    f,
+                    "files changed in the meantime: {}",
+                    files.join(", ")
+                )
+            }
+            ReplaceError::Other(ref err) => write!(f, "{:#}", err),
+        }
+    }
+}
+
+impl From<anyhow::Error> for ReplaceError {
+    fn from(err: anyhow::Error) -> ReplaceError {
+        ReplaceError::Other(err)
+    }
+}
+
+impl From<io::Error> for ReplaceError {
+    fn from(err: io::Error) -> ReplaceError {
+        ReplaceError::Other(err.into())
+    }
+}
+
+/// The hunks of the results whose path passes `filter`, in the order of
+/// the results.
+///
+/// Results without a path (i.e., from stdin) and results the replacement
+/// doesn't change are left out. The same results, matcher, replacement and
+/// filter always give the same hunks, so clients can accept hunks by their
+/// index in a preview.
+pub(crate) fn plan(
+    results: &SearchResults,
+    matcher: &PatternMatcher,
+    replacement: &[u8],
+    filter: &Override,
+) -> io::Result<Vec<Hunk>> {
+    let mut hunks = vec![];
+    for result in results.iter() {
+        let Some(path) = result.get_file_name() else { continue };
+        if filter.matched(path, false).is_ignore() {
+            continue;
+        }
+        let before = result.get_matched_bytes();
+        let after = matcher.replace_all(before, replacement)?;
+        if after == *before {
+            continue;
+        }
+        hunks.push(Hunk {
+            path: path.to_string(),
+            line: result.get_line_number(),
+            offset: result.get_byte_offset(),
+            before: before.clone(),
+            after,
+        });
+    }
+    Ok(hunks)
+}
+
+/// What a replacement wrote.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub(crate) struct Applied {
+    /// The id of the journal to revert the replacement with.
+    pub(crate) journal: String,
+    /// The files written, as the search reports them.
+    pub(crate) files: Vec<String>,
+}
+
+/// The undo journals of a project.
+#[derive(Clone, Debug)]
+pub(crate) struct Journal {
+    dir: PathBuf,
+}
+
+/// A file about to be written, along with what to write and what was there.
+struct Pending {
+    path: String,
+    target: PathBuf,
+    original: Vec<u8>,
+    replaced: Vec<u8>,
+}
+
+impl Journal {
+    /// Keep journals in `dir`, which is created when first needed.
+    pub(crate) fn new(dir: PathBuf) -> Journal {
+        Journal { dir }
+    }
+
+    /// Write `hunks` to their files, whose paths are relative to `root`.
+    ///
+    /// `stamps` are the stamps the search took. Every file is checked
+    /// against its stamp and every hunk against the file before anything is
+    /// written.
+    pub(crate) fn apply(
+        &self,
+        root: &Path,
+        hunks: &[&Hunk],
+        stamps: &FileStamps,
+    ) -> Result<Applied, ReplaceError> {
+        let mut by_file: BTreeMap<&str, Vec<&Hunk>> = BTreeMap::new();
+        for hunk in hunks.iter() {
+            by_file.entry(hunk.path.as_str()).or_default().push(hunk);
+        }
+        let changed: Vec<String> = by_file
+            .keys()
+            .filter(|&&path| {
+                let now = FileStamp::of(&root.join(path));
+                now.is_none() || stamps.get(path) != now.as_ref()
+            })
+            .map(|path| path.to_string())
+            .collect();
+        if !changed.is_empty() {
+            return Err(ReplaceError::Changed(changed));
+        }
+
+        let mut pending = vec![];
+        let mut pending_hunks = vec![];
+        for (path, mut hunks) in by_file.into_iter() {
+            // Written through symlinks, rather than replacing them.
+            let target = std::fs::canonicalize(root.join(path))
+                .with_context(|| path.to_string())?;
+            let original =
+                std::fs::read(&target).with_context(|| path.to_string())?;
+            hunks.sort_by_key(|hunk| hunk.offset);
+            let replaced =
+                splice(&original, &hunks).with_context(|| path.to_string())?;
+            pending.push(Pending {
+                path: path.to_string(),
+                target,
+                original,
+                replaced,
+            });
+            pending_hunks.push(hunks);
+        }
+
+        let (id, dir) = self.create()?;
+        for (i, (file, hunks)) in
+            pending.iter().zip(&pending_hunks).enumerate()
+        {
+            std::fs::write(dir.join(i.to_string()), &file.original)
+                .and_then(|()| {
+                    std::fs::write(
+                        dir.join(format!("{}.hunks", i)),
+                        encode_hunks(hunks),
+                    )
+                })
+                .with_context(|| format!("{}", dir.display()))?;
+        }
+        // Written before any file is, without the stamps after replacing,
+        // so the originals can be found even if the server dies halfway.
+        write_manifest(&dir, &pending, &vec![None; pending.len()])?;
+        let mut after = vec![];
+        for (i, file) in pending.iter().enumerate() {
+            if let Err(err) = write_file(&file.target, &file.replaced) {
+                for written in pending[..i].iter().rev() {
+                    if let Err(err) =
+                        write_file(&written.target, &written.original)
+                    {
+                        log::warn!(
+                            "{}: failed to put back: {:#}",
+                            written.path,
+                            err
+                        );
+                    }
+                }
+                let _ = std::fs::remove_dir_all(&dir);
+                return Err(err.context(file.path.clone()).into());
+            }
+            after.push(FileStamp::of(&file.target));
+        }
+        write_manifest(&dir, &pending, &after)?;
+        Ok(Applied {
+            journal: id,
+            files: pending.into_iter().map(|file| file.path).collect(),
+        })
+    }
+
+    /// Put back every file of the replacement with the given journal id,
+    /// returning their paths, and delete the journal.
+    ///
+    /// If any file changed since it was replaced, nothing is put back. A
+    /// file without a stamp after replacing, because the replacement stopped
+    /// partway, counts as changed unless it holds either its original or its
+    /// replaced contents. If writing one of the files fails, the files
+    /// already put back get their replaced contents again.
+    pub(crate) fn revert(
+        &self,
+        id: &str,
+    ) -> Result<Vec<String>, ReplaceError> {
+        if id.is_empty() || id.starts_with('.') || id.contains(['/', '\\']) {
+            return Err(anyhow::anyhow!("bad journal id '{}'", id).into());
+        }
+        let dir = self.dir.join(id);
+        let bytes = match std::fs::read(dir.join(MANIFEST)) {
+            Ok(bytes) => bytes,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => {
+                return Err(anyhow::anyhow!("no journal '{}'", id).into());
+            }
+            Err(err) => return Err(err.into()),
+        };
+        let files = read_manifest(&bytes).with_context(|| id.to_string())?;
+        let mut pending = vec![];
+        let mut stamps = vec![];
+        let mut changed = vec![];
+        for (i, (path, target, after)) in files.into_iter().enumerate() {
+            let original = std::fs::read(dir.join(i.to_string()))
+                .with_context(|| format!("{}: original of {}", id, path))?;
+            // What's there now, to put back if reverting a later file fails.
+            let replaced = match std::fs::read(&target) {
+                Ok(replaced) => replaced,
+                Err(err) if err.kind() == io::ErrorKind::NotFound => {
+                    changed.push(path);
+                    continue;
+                }
+                Err(err) => {
+                    return Err(anyhow::Error::new(err).context(path).into());
+                }
+            };
+            let unchanged = match after {
+                Some(_) => FileStamp::of(&target) == after,
+                None => {
+                    replaced == original
+                        || replaced
+                            == replay(&dir, i, &original)
+                                .with_context(|| format!("{}: {}", id, path))?
+                }
+            };
+            if !unchanged {
+                changed.push(path);
+                continue;
+            }
+            pending.push(Pending { path, target, original, replaced });
+            stamps.push(after);
+        }
+        if !changed.is_empty() {
+            return Err(ReplaceError::Changed(changed));
+        }
+        for (i, file) in pending.iter().enumerate() {
+            if let Err(err) = write_file(&file.target, &file.original) {
+                for (j, reverted) in pending[..i].iter().enumerate().rev() {
+                    match write_file(&reverted.target, &reverted.replaced) {
+                        Ok(()) => stamps[j] = FileStamp::of(&reverted.target),
+                        Err(err) => log::warn!(
+                            "{}: failed to put back: {:#}",
+                            reverted.path,
+                            err
+                        ),
+                    }
+                }
+                // The files put back were written again, so the journal
+                // needs their new stamps to be reverted later.
+                if let Err(err) = write_manifest(&dir, &pending, &stamps) {
+                    log::warn!("{}: {:#}", id, err);
+                }
+                return Err(err.context(file.path.clone()).into());
+            }
+        }
+        std::fs::remove_dir_all(&dir)
+            .with_context(|| format!("{}", dir.display()))?;
+        Ok(pending.into_iter().map(|file| file.path).collect())
+    }
+
+    /// Create the directory of a new journal, returning its id and path.
+    fn create(&self) -> anyhow::Result<(String, PathBuf)> {
+        std::fs::create_dir_all(&self.dir)
+            .with_context(|| format!("{}", self.dir.display()))?;
+        let ms = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |d| d.as_millis());
+        // Ids sort by when they were made. Two replacements in the same
+        // millisecond get a suffix rather than sharing a journal.
+        for n in 0.. {
+            let id = match n {
+                0 => format!("replace-{}", ms),
+                n => format!("replace-{}-{}", ms, n),
+            };
+            let dir = self.dir.join(&id);
+            match std::fs::create_dir(&dir) {
+                Ok(()) => return Ok((id, dir)),
+                Err(err) if err.kind() == io::ErrorKind::AlreadyExists => {}
+                Err(err) => {
+                    return Err(err)
+                        .with_context(|| format!("{}", dir.display()));
+                }
+            }
+        }
+        unreachable!()
+    }
+}
+
+/// Replace the lines of `hunks`, sorted by offset, in `original`.
+fn splice(original: &[u8], hunks: &[&Hunk]) -> anyhow::Result<Vec<u8>> {
+    let mut replaced = Vec::with_capacity(original.len());
+    let mut cursor = 0;
+    for hunk in hunks.iter() {
+        let start = usize::try_from(hunk.offset).unwrap_or(usize::MAX);
+        let end = start.saturating_add(hunk.before.len());
+        if start < cursor || original.get(start..end) != Some(&hunk.before[..])
+        {
+            anyhow::bail!("line {} isn't what the search found", hunk.line);
+        }
+        replaced.extend_from_slice(&original[cursor..start]);
+        replaced.extend_from_slice(&hunk.after);
+        cursor = end;
+    }
+    replaced.extend_from_slice(&original[cursor..]);
+    Ok(replaced)
+}
+
+/// The contents the replacement wrote to the `i`th file of the journal in
+/// `dir`, from its original contents and the hunks kept next to them.
+fn replay(dir: &Path, i: usize, original: &[u8]) -> anyhow::Result<Vec<u8>> {
+    let bytes = std::fs::read(dir.join(format!("{}.hunks", i)))?;
+    let hunks = decode_hunks(&bytes)?;
+    splice(original, &hunks.iter().collect::<Vec<_>>())
+}
+
+/// Write `bytes` over the file at `path` through a temporary file, keeping
+/// the file's permissions.
+fn write_file(path: &Path, bytes: &[u8]) -> anyhow::Result<()> {
+    let permissions = std::fs::metadata(path)
+        .with_context(|| format!("{}", path.display()))?
+        .permissions();
+    let mut tmp = path.as_os_str().to_os_string();
+    tmp.push(format!(".replace.{}", std::process::id()));
+    let tmp = PathBuf::from(tmp);
+    let result = std::fs::write(&tmp, bytes)
+        .and_then(|()| std::fs::set_permissions(&tmp, permissions))
+        .and_then(|()| std::fs::rename(&tmp, path));
+    if let Err(err) = result {
+        let _ = std::fs::remove_file(&tmp);
+        return Err(err).with_context(|| format!("{}", path.display()));
+    }
+    Ok(())
+}
+
+/// The manifest lists, for each file by its index in the journal, its path
+/// as the search reported it, the path written to and its stamp after the
+/// replacement, as a flat `[path, target, size, mtime, ...]` array. The
+/// stamp is nil until every file was written.
+fn write_manifest(
+    dir: &Path,
+    files: &[Pending],
+    after: &[Option<FileStamp>],
+) -> anyhow::Result<()> {
+    let entries = files
+        .iter()
+        .zip(after.iter())
+        .flat_map(|(file, stamp)| {
+            [
+                Value::from(file.path.as_str()),
+                Value::from(persist::path_bytes(&file.target)),
+                stamp.map_or(Value::Nil, |s| Value::from(s.size)),
+                stamp.map_or(Value::Nil, |s| Value::from(s.mtime)),
+            ]
+        })
+        .collect();
+    let mut bytes = vec![];
+    rmpv::encode::write_value(&mut bytes, &Value::Array(entries))
+        .map_err(|err| anyhow::anyhow!("{}", err))?;
+    persist::write_atomic(&dir.join(MANIFEST), &bytes)
+}
+
+fn read_manifest(
+    mut bytes: &[u8],
+) -> anyhow::Result<Vec<(String, PathBuf, Option<FileStamp>)>> {
+    let value = rmpv::decode::read_value(&mut bytes)
+        .map_err(|err| anyhow::anyhow!("{}", err))?;
+    let values = value.as_array().context("manifest must be an array")?;
+    let mut files = vec![];
+    for chunk in values.chunks(4) {
+        let [path, target, size, mtime] = chunk else {
+            anyhow::bail!("manifest must be [path, target, size, mtime]");
+        };
+        let stamp = match (size.as_u64(), mtime.as_u64()) {
+            (Some(size), Some(mtime)) => Some(FileStamp { size, mtime }),
+            _ => None,
+        };
+        files.push((
+            path.as_str().context("bad path")?.to_string(),
+            persist::bytes_path(target.as_slice().context("bad target")?),
+            stamp,
+        ));
+    }
+    Ok(files)
+}
+
+/// The hunks of a file as a flat `[line, offset, before, after, ...]` array,
+/// in the order they're spliced in.
+fn encode_hunks(hunks: &[&Hunk]) -> Vec<u8> {
+    let entries = hunks
+        .iter()
+        .flat_map(|hunk| {
+            [
+                Value::from(hunk.line),
+                Value::from(hunk.offset),
+                Value::from(hunk.before.clone()),
+                Value::from(hunk.after.clone()),
+            ]
+        })
+        .collect();
+    let mut bytes = vec![];
+    rmpv::encode::write_value(&mut bytes, &Value::Array(entries)).unwrap();
+    bytes
+}
+
+fn decode_hunks(mut bytes: &[u8]) -> anyhow::Result<Vec<Hunk>> {
+    let value = rmpv::decode::read_value(&mut bytes)
+        .map_err(|err| anyhow::anyhow!("{}", err))?;
+    let values = value.as_array().context("hunks must be an array")?;
+    let mut hunks = vec![];
+    for chunk in values.chunks(4) {
+        let [line, offset, before, after] = chunk else {
+            anyhow::bail!("hunks must be [line, offset, before, after]");
+        };
+        hunks.push(Hunk {
+            path: String::new(),
+            line: line.as_u64().context("bad line")?,
+            offset: offset.as_u64().context("bad offset")?,
+            before: before.as_slice().context("bad before")?.to_vec(),
+            after: after.as_slice().context("bad after")?.to_vec(),
+        });
+    }
+    Ok(hunks)
+}
+
+#[cfg(test)]
+mod tests {
+    use grep::regex::RegexMatcher;
+
+    use crate::{refine::path_filter, search::SearchResult};
+
+    use super::*;
+
+    /// Return a fresh, empty directory for a test to write into.
+    fn tempdir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!(
+            "search-history-test-{}-{}",
+            std::process::id(),
+            name
+        ));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    /// Search the files in `root` for `foo_(\w+)` line by line, the way a
+    /// stored search would have.
+    fn search(root: &Path, names: &[&str]) -> (SearchResults, FileStamps) {
+        let mut results = SearchResults::new();
+        let mut stamps = FileStamps::new();
+        for name in names.iter() {
+            let path = root.join(name);
+            let text = std::fs::read(&path).unwrap();
+            let mut offset = 0;
+            for (i, line) in text.split_inclusive(|&b| b == b'\n').enumerate()
+            {
+                if line.windows(4).any(|w| w == b"foo_") {
+                    results.store_result(SearchResult::new(
+                        Some(name.to_string()),
+                        i as u64 + 1,
+                        offset,
+                        line.to_vec(),
+                        vec![],
+                    ));
+                }
+                offset += line.len() as u64;
+            }
+            stamps.insert(name.to_string(), FileStamp::of(&path).unwrap());
+        }
+        (results, stamps)
+    }
+
+    fn matcher() -> PatternMatcher {
+        PatternMatcher::RustRegex(
+            RegexMatcher::new_line_matcher(r"foo_(\w+)").unwrap(),
+        )
+    }
+
+    #[test]
+    fn preview_apply_revert() {
+        let dir = tempdir("replace");
+        let root = dir.join("root");
+        std::fs::create_dir_all(&root).unwrap();
+        std::fs::write(root.join("a.rs"), "foo_a();\nbar();\nfoo_b();\n")
+            .unwrap();
+        std::fs::write(root.join("b.rs"), "x\r\nfoo_c(foo_d);\r\n").unwrap();
+        std::fs::write(root.join("c.md"), "foo_e\n").unwrap();
+        let (results, stamps) = search(&root, &["a.rs", "b.rs", "c.md"]);
+
+        let all = path_filter(&root, &["*.rs".to_string()]).unwrap();
+        let hunks = plan(&results, &matcher(), b"bar_$1", &all).unwrap();
+        let lines: Vec<(&str, u64, &[u8])> = hunks
+            .iter()
+            .map(|h| (h.path.as_str(), h.line, &h.after[..]))
+            .collect();
+        assert_eq!(
+            vec![
+                ("a.rs", 1, &b"bar_a();\n"[..]),
+                ("a.rs", 3, &b"bar_b();\n"[..]),
+                ("b.rs", 2, &b"bar_c(bar_d);\r\n"[..]),
+            ],
+            lines
+        );
+
+        // Only the accepted hunks are written.
+        let journal = Journal::new(dir.join("journal"));
+        let applied = journal.apply(&root, &[&hunks[1], &hunks[2]], &stamps);
+        let applied = applied.unwrap();
+        assert_eq!(vec!["a.rs", "b.rs"], applied.files);
+        let read =
+            |name: &str| std::fs::read_to_string(root.join(name)).unwrap();
+        assert_eq!("foo_a();\nbar();\nbar_b();\n", read("a.rs"));
+        assert_eq!("x\r\nbar_c(bar_d);\r\n", read("b.rs"));
+
+        // The stamps are stale now, so the same hunks are refused.
+        match journal.apply(&root, &[&hunks[0]], &stamps) {
+            Err(ReplaceError::Changed(files)) => {
+                assert_eq!(vec!["a.rs"], files)
+            }
+            got => panic!("expected a conflict, got {:?}", got),
+        }
+
+        let reverted = journal.revert(&applied.journal).unwrap();
+        assert_eq!(vec!["a.rs", "b.rs"], reverted);
+        assert_eq!("foo_a();\nbar();\nfoo_b();\n", read("a.rs"));
+        assert_eq!("x\r\nfoo_c(foo_d);\r\n", read("b.rs"));
+        assert!(journal.revert(&applied.journal).is_err());
+        assert!(journal.revert("../root").is_err());
+    }
+
+    #[test]
+    fn revert_refuses_later_edits() {
+        let dir = tempdir("revert");
+        let root = dir.join("root");
+        std::fs::create_dir_all(&root).unwrap();
+        std::fs::write(root.join("a.rs"), "foo_a\n").unwrap();
+        let (results, stamps) = search(&root, &["a.rs"]);
+        let all = path_filter(&root, &[]).unwrap();
+        let hunks = plan(&results, &matcher(), b"x", &all).unwrap();
+
+        let journal = Journal::new(dir.join("journal"));
+        let applied = journal.apply(&root, &[&hunks[0]], &stamps).unwrap();
+        std::fs::write(root.join("a.rs"), "edited since\n").unwrap();
+        assert!(matches!(
+            journal.revert(&applied.journal),
+            Err(ReplaceError::Changed(_))
+        ));
+        assert_eq!(
+            "edited since\n",
+            std::fs::read_to_string(root.join("a.rs")).unwrap()
+        );
+    }
+
+    #[test]
+    fn revert_without_stamps() {
+        let dir = tempdir("revert-unstamped");
+        let root = dir.join("root");
+        std::fs::create_dir_all(&root).unwrap();
+        for name in ["a.rs", "b.rs", "c.rs"] {
+            std::fs::write(root.join(name), "foo_a\n").unwrap();
+        }
+        let (results, stamps) = search(&root, &["a.rs", "b.rs", "c.rs"]);
+        let all = path_filter(&root, &[]).unwrap();
+        let hunks = plan(&results, &matcher(), b"x", &all).unwrap();
+        let journal = Journal::new(dir.join("journal"));
+        let hunks: Vec<&Hunk> = hunks.iter().collect();
+        let applied = journal.apply(&root, &hunks, &stamps).unwrap();
+
+        // As if the server stopped after writing a.rs and c.rs, and c.rs
+        // was edited since.
+        let journal_dir = dir.join("journal").join(&applied.journal);
+        let bytes = std::fs::read(journal_dir.join(MANIFEST)).unwrap();
+        let files: Vec<Pending> = read_manifest(&bytes)
+            .unwrap()
+            .into_iter()
+            .map(|(path, target, _)| Pending {
+                path,
+                target,
+                original: vec![],
+                replaced: vec![],
+            })
+            .collect();
+        write_manifest(&journal_dir, &files, &[None, None, None]).unwrap();
+        std::fs::write(root.join("b.rs"), "foo_a\n").unwrap();
+        std::fs::write(root.join("c.rs"), "edited since\n").unwrap();
+        let read =
+            |name: &str| std::fs::read_to_string(root.join(name)).unwrap();
+        match journal.revert(&applied.journal) {
+            Err(ReplaceError::Changed(files)) => {
+                assert_eq!(vec!["c.rs"], files)
+            }
+            got => panic!("expected a conflict, got {:?}", got),
+        }
+        assert_eq!("x\n", read("a.rs"));
+
+        std::fs::write(root.join("c.rs"), "x\n").unwrap();
+        let reverted = journal.revert(&applied.journal).unwrap();
+        assert_eq!(vec!["a.rs", "b.rs", "c.rs"], reverted);
+        for name in ["a.rs", "b.rs", "c.rs"] {
+            assert_eq!("foo_a\n", read(name));
+        }
+    }
+
+    #[test]
+    fn revert_puts_back_on_failure() {
+        let dir = tempdir("revert-failure");
+        let root = dir.join("root");
+        std::fs::create_dir_all(&root).unwrap();
+        std::fs::write(root.join("a.rs"), "foo_a\n").unwrap();
+        std::fs::write(root.join("b.rs"), "foo_b\n").unwrap();
+        let (results, stamps) = search(&root, &["a.rs", "b.rs"]);
+        let all = path_filter(&root, &[]).unwrap();
+        let hunks = plan(&results, &matcher(), b"x", &all).unwrap();
+        let journal = Journal::new(dir.join("journal"));
+        let applied =
+            journal.apply(&root, &[&hunks[0], &hunks[1]], &stamps).unwrap();
+
+        // A directory where b.rs's temporary file goes makes writing it
+        // fail, even for root.
+        let mut tmp =
+            std::fs::canonicalize(root.join("b.rs")).unwrap().into_os_string();
+        tmp.push(format!(".replace.{}", std::process::id()));
+        std::fs::create_dir(&tmp).unwrap();
+        let read =
+            |name: &str| std::fs::read_to_string(root.join(name)).unwrap();
+        assert!(matches!(
+            journal.revert(&applied.journal),
+            Err(ReplaceError::Other(_))
+        ));
+        assert_eq!("x\n", read("a.rs"));
+        assert_eq!("x\n", read("b.rs"));
+
+        std::fs::remove_dir(&tmp).unwrap();
+        journal.revert(&applied.journal).unwrap();
+        assert_eq!("foo_a\n", read("a.rs"));
+        assert_eq!("foo_b\n", read("b.rs"));
+    }
+
+    #[test]
+    fn splice_checks_lines() {
+        let hunk = |offset, before: &str| Hunk {
+            path: "a".to_string(),
+            line: 1,
+            offset,
+            before: before.as_bytes().to_vec(),
+            after: b"X\n".to_vec(),
+        };
+        let (a, b) = (hunk(0, "a\n"), hunk(4, "c\n"));
+        assert_eq!(
+            b"X\nb\nX\n".to_vec(),
+            splice(b"a\nb\nc\n", &[&a, &b]).unwrap()
+        );
+        let moved = hunk(2, "c\n");
+        assert!(splice(b"a\nb\nc\n", &[&moved]).is_err());
+    }
+}
@@ -0,0 +1,1773 @@
+/*!
+Decodes the msgpack arguments of RPC calls and encodes errors sent back.
+
+Neovim delivers every call as a method name followed by an array of msgpack
+values. Every method on this server takes a single map argument, which maps
+naturally onto a Lua table on the client side:
+
+```lua
+vim.rpcrequest(job, 'search', { pattern = 'wp_query', paths = { './' } })
+```
+
+Anything that doesn't have the expected shape is reported back to the client
+as an [`RpcError`] instead of panicking the server.
+*/
+
+use std::{collections::BTreeSet, fmt, path::PathBuf, time::Duration};
+
+use neovim_lib::Value;
+
+use crate::{
+    combine::{Combination, SetLevel, SetOp},
+    errors::{ErrorCause, FileError},
+    flags::OptionValue,
+    live,
+    notify::StreamConfig,
+    quickfix::{ListAction, ListKind},
+    rank::Score,
+    refine::{RefineScope, Refinement},
+    refresh::Diff,
+    replace::Hunk,
+    search::{Column, ContextLine, SearchResult, SearchResults, SubMatch},
+    stats::SearchStats,
+    store::SearchRecord,
+};
+
+/// The kind of an error returned to an RPC client.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub(crate) enum RpcErrorKind {
+    /// The arguments given to a method were missing or had the wrong type.
+    InvalidArgs,
+    /// The arguments were well formed, but the search itself failed. For
+    /// example, the pattern has a syntax error.
+    Search,
+    /// The method name isn't one the server knows about.
+    UnknownMethod,
+    /// The search id given doesn't refer to a stored search.
+    NotFound,
+    /// Files changed since they were searched, so writing to them would
+    /// throw away edits the search never saw.
+    Conflict,
+}
+
+impl RpcErrorKind {
+    /// Returns the name of this kind as sent to the client.
+    pub(crate) fn as_str(&self) -> &'static str {
+        match *self {
+            RpcErrorKind::InvalidArgs => "invalid_args",
+            RpcErrorKind::Search => "search",
+            RpcErrorKind::UnknownMethod => "unknown_method",
+            RpcErrorKind::NotFound => "not_found",
+            RpcErrorKind::Conflict => "conflict",
+        }
+    }
+}
+
+/// An error returned to an RPC client.
+///
+/// Errors are encoded as a map with a `kind` and a `message` so that clients
+/// can branch on the kind without having to parse the message.
+#[derive(Clone, Debug)]
+pub(crate) struct RpcError {
+    kind: RpcErrorKind,
+    message: String,
+}
+
+impl RpcError {
+    /// Create an error for arguments that are missing or malformed.
+    pub(crate) fn invalid_args(message: impl Into<String>) -> RpcError {
+        RpcError { kind: RpcErrorKind::InvalidArgs, message: message.into() }
+    }
+
+    /// Create an error from a failed search.
+    ///
+    /// The full chain of causes is included in the message, in the same
+    /// format used when ripgrep prints an error to stderr.
+    pub(crate) fn search(err: anyhow::Error) -> RpcError {
+        RpcError { kind: RpcErrorKind::Search, message: format!("{:#}", err) }
+    }
+
+    /// Create an error for a method name the server doesn't recognize.
+    pub(crate) fn unknown_method(name: &str) -> RpcError {
+        RpcError {
+            kind: RpcErrorKind::UnknownMethod,
+            message: format!("unknown method '{}'", name),
+        }
+    }
+
+    /// Create an error for a search id that isn't in the store.
+    pub(crate) fn not_found(id: &str) -> RpcError {
+        RpcError {
+            kind: RpcErrorKind::NotFound,
+            message: format!("no stored search with id '{}'", id),
+        }
+    }
+
+    /// Create an error for files that changed in the meantime.
+    pub(crate) fn conflict(message: impl Into<String>) -> RpcError {
+        RpcError { kind: RpcErrorKind::Conflict, message: message.into() }
+    }
+
+    /// Returns the kind of this error.
+    pub(crate) fn kind(&self) -> RpcErrorKind {
+        self.kind
+    }
+
+    /// Encode this error as the msgpack value sent to the client.
+    pub(crate) fn into_value(self) -> Value {
+        Value::Map(vec![
+            (Value::from("kind"), Value::from(self.kind.as_str())),
+            (Value::from("message"), Value::from(self.message)),
+        ])
+    }
+}
+
+impl fmt::Display for RpcError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: {}", self.kind.as_str(), self.message)
+    }
+}
+
+/// A typed view over the single map argument of an RPC call.
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct ArgMap<'a> {
+    entries: &'a [(Value, Value)],
+}
+
+impl<'a> ArgMap<'a> {
+    /// Extract the map argument from the raw arguments of a call.
+    ///
+    /// This fails unless exactly one argument was given and it is a map.
+    pub(crate) fn from_args(
+        method: &str,
+        args: &'a [Value],
+    ) -> Result<ArgMap<'a>, RpcError> {
+        match args {
+            [Value::Map(entries)] => Ok(ArgMap { entries }),
+            [_] => Err(RpcError::invalid_args(format!(
+                "{}: expected a map argument",
+                method
+            ))),
+            _ => Err(RpcError::invalid_args(format!(
+                "{}: expected exactly 1 argument but got {}",
+                method,
+                args.len()
+            ))),
+        }
+    }
+
+    /// Return the value for the given key, if present and not nil.
+    pub(crate) fn get(&self, key: &str) -> Option<&'a Value> {
+        self.entries
+            .iter()
+            .find(|(k, _)| k.as_str() == Some(key))
+            .map(|(_, v)| v)
+            .filter(|v| !v.is_nil())
+    }
+
+    /// Return the string value for the given key, if present.
+    pub(crate) fn str(&self, key: &str) -> Result<Option<&'a str>, RpcError> {
+        match self.get(key) {
+            None => Ok(None),
+            Some(v) => match v.as_str() {
+                Some(s) => Ok(Some(s)),
+                None => Err(RpcError::invalid_args(format!(
+                    "'{}' must be a string",
+                    key
+                ))),
+            },
+        }
+    }
+
+    /// Return the string value for the given key, failing if it's absent.
+    pub(crate) fn required_str(&self, key: &str) -> Result<&'a str, RpcError> {
+        match self.str(key)? {
+            Some(s) => Ok(s),
+            None => Err(RpcError::invalid_args(format!("'{}' is required", key))),
+        }
+    }
+
+    /// Return the non-negative integer value for the given key, if present.
+    pub(crate) fn u64(&self, key: &str) -> Result<Option<u64>, RpcError> {
+        match self.get(key) {
+            None => Ok(None),
+            Some(v) => match v.as_u64() {
+                Some(n) => Ok(Some(n)),
+                None => Err(RpcError::invalid_args(format!(
+                    "'{}' must be a non-negative integer",
+                    key
+                ))),
+            },
+        }
+    }
+
+    /// Return the boolean value for the given key, if present.
+    pub(crate) fn bool(&self, key: &str) -> Result<Option<bool>, RpcError> {
+        match self.get(key) {
+            None => Ok(None),
+            Some(v) => match v.as_bool() {
+                Some(yes) => Ok(Some(yes)),
+                None => Err(RpcError::invalid_args(format!(
+                    "'{}' must be a boolean",
+                    key
+                ))),
+            },
+        }
+    }
+
+    /// Return the list of strings for the given key, if present.
+    ///
+    /// A single string is accepted as a list with one element, so that
+    /// clients can write `paths = './'` instead of `paths = { './' }`.
+    pub(crate) fn strings(
+        &self,
+        key: &str,
+    ) -> Result<Option<Vec<String>>, RpcError> {
+        let invalid = || {
+            RpcError::invalid_args(format!(
+                "'{}' must be a string or a list of strings",
+                key
+            ))
+        };
+        let value = match self.get(key) {
+            None => return Ok(None),
+            Some(value) => value,
+        };
+        if let Some(s) = value.as_str() {
+            return Ok(Some(vec![s.to_string()]));
+        }
+        let mut strings = vec![];
+        for item in value.as_array().ok_or_else(invalid)?.iter() {
+            strings.push(item.as_str().ok_or_else(invalid)?.to_string());
+        }
+        Ok(Some(strings))
+    }
+
+    /// Return the option overrides under the given key, if present.
+    ///
+    /// The value must be a map from option names to booleans, integers,
+    /// strings or lists of strings. Whether a name is known and its value
+    /// makes sense for it is checked when the options are applied.
+    pub(crate) fn options(
+        &self,
+        key: &str,
+    ) -> Result<Option<Vec<(String, OptionValue)>>, RpcError> {
+        match self.get(key) {
+            None => Ok(None),
+            Some(value) => decode_options(key, value).map(Some),
+        }
+    }
+}
+
+/// Decode a map of option overrides, as taken by [`ArgMap::options`].
+///
+/// This is also used to decode the options stored with a search again when
+/// it's refreshed. `Value::Nil`, which is what's stored when a search had no
+/// options, decodes to no overrides.
+pub(crate) fn decode_options(
+    key: &str,
+    value: &Value,
+) -> Result<Vec<(String, OptionValue)>, RpcError> {
+    let entries = match *value {
+        Value::Nil => return Ok(vec![]),
+        Value::Map(ref entries) => entries,
+        _ => {
+            return Err(RpcError::invalid_args(format!(
+                "'{}' must be a map",
+                key
+            )));
+        }
+    };
+    let mut options = vec![];
+    for (name, value) in entries.iter() {
+        let name = match name.as_str() {
+            Some(name) => name.to_string(),
+            None => {
+                return Err(RpcError::invalid_args(format!(
+                    "'{}' must only have string keys",
+                    key
+                )));
+            }
+        };
+        let invalid = || {
+            RpcError::invalid_args(format!(
+                "'{}.{}' must be a boolean, an integer, a string or a \
+                 list of strings",
+                key, name
+            ))
+        };
+        let value = match *value {
+            Value::Boolean(yes) => OptionValue::Bool(yes),
+            Value::Integer(n) => {
+                OptionValue::Int(n.as_u64().ok_or_else(invalid)?)
+            }
+            Value::String(ref s) => {
+                OptionValue::Str(s.as_str().ok_or_else(invalid)?.to_string())
+            }
+            Value::Array(ref items) => {
+                let mut strings = vec![];
+                for item in items.iter() {
+                    strings.push(item.as_str().ok_or_else(invalid)?.to_string());
+                }
+                OptionValue::List(strings)
+            }
+            _ => return Err(invalid()),
+        };
+        options.push((name, value));
+    }
+    Ok(options)
+}
+
+/// What a `search` call asks to search for.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub(crate) enum SearchInput {
+    /// A pattern and the paths to search for it in.
+    Pattern {
+        /// The pattern to search for.
+        pattern: String,
+        /// The directories or files to search. This is never empty.
+        paths: Vec<PathBuf>,
+    },
+    /// A ripgrep command line, e.g., `-t php -w get_option wp-content`, to
+    /// be parsed with [`crate::flags::apply_args_line`].
+    Args(String),
+}
+
+/// The arguments of a `search` call.
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) struct SearchRequest {
+    /// The id to store the results under. When absent, the server picks one.
+    pub(crate) id: Option<String>,
+    /// What to search for, and where.
+    pub(crate) input: SearchInput,
+    /// How results are streamed back while the search runs.
+    pub(crate) stream: StreamConfig,
+    /// The option overrides applied on top of the server's own flags.
+    pub(crate) options: Vec<(String, OptionValue)>,
+    /// The `options` map exactly as the client sent it, or `Value::Nil`.
+    /// This is what gets stored with the search.
+    pub(crate) raw_options: Value,
+}
+
+impl SearchRequest {
+    /// Decode the arguments of a `search` call.
+    ///
+    /// The map accepts either `pattern` and `paths` (a string or a list of
+    /// strings, defaulting to `./`), or `args`, a single string with the
+    /// flags, pattern and paths as they'd be given to `rg`. An `id` may be
+    /// given to store the search under. `batch_size` and
+    /// `batch_interval` (in milliseconds) control how often results are
+    /// streamed back while the search runs. `options` is a map of overrides
+    /// for the server's flags, see [`crate::flags::apply_options`].
+    pub(crate) fn from_args(args: &[Value]) -> Result<SearchRequest, RpcError> {
+        let map = ArgMap::from_args("search", args)?;
+        let id = new_id(&map)?;
+        let input = match map.str("args")? {
+            Some(line) => {
+                if map.get("pattern").is_some() || map.get("paths").is_some() {
+                    return Err(RpcError::invalid_args(
+                        "'args' can't be combined with 'pattern' or 'paths'",
+                    ));
+                }
+                SearchInput::Args(line.to_string())
+            }
+            None => SearchInput::Pattern {
+                pattern: map.required_str("pattern")?.to_string(),
+                paths: SearchRequest::paths(&map)?,
+            },
+        };
+        let stream = stream_config(&map)?;
+        let options = map.options("options")?.unwrap_or_default();
+        let raw_options = map.get("options").cloned().unwrap_or(Value::Nil);
+        Ok(SearchRequest { id, input, stream, options, raw_options })
+    }
+
+    /// Decode the `paths` of a `search` call, checking that each exists.
+    fn paths(map: &ArgMap<'_>) -> Result<Vec<PathBuf>, RpcError> {
+        let paths: Vec<PathBuf> = match map.strings("paths")? {
+            None => vec![PathBuf::from("./")],
+            Some(paths) if paths.is_empty() => {
+                return Err(RpcError::invalid_args(
+                    "'paths' must contain at least one path",
+                ));
+            }
+            Some(paths) => paths.into_iter().map(PathBuf::from).collect(),
+        };
+        for path in paths.iter() {
+            if !path.exists() {
+                return Err(RpcError::invalid_args(format!(
+                    "{}: no such file or directory",
+                    path.display()
+                )));
+            }
+        }
+        Ok(paths)
+    }
+}
+
+/// The arguments of a `live` call.
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) struct LiveRequest {
+    /// The live session the call belongs to. Its searches are stored under
+    /// `live:<session>`, see `live::id`.
+    pub(crate) session: String,
+    /// What to search for. Empty to only cancel the session's search.
+    pub(crate) pattern: String,
+    /// Where to search.
+    pub(crate) paths: Vec<PathBuf>,
+    /// How long to wait for another call before searching.
+    pub(crate) debounce: Duration,
+    /// How results are streamed back while the search runs.
+    pub(crate) stream: StreamConfig,
+    /// The option overrides applied on top of the server's own flags.
+    pub(crate) options: Vec<(String, OptionValue)>,
+    /// The `options` map exactly as the client sent it, or `Value::Nil`.
+    pub(crate) raw_options: Value,
+}
+
+impl LiveRequest {
+    /// Decode the arguments of a `live` call.
+    ///
+    /// The map accepts `session` (required), `pattern` (required, but may
+    /// be empty), `paths` and `options` as for `search`, and `debounce`,
+    /// the milliseconds to wait for another call before searching, which
+    /// defaults to 100. `batch_size` and `batch_interval` are as for
+    /// `search`.
+    pub(crate) fn from_args(args: &[Value]) -> Result<LiveRequest, RpcError> {
+        let map = ArgMap::from_args("live", args)?;
+        let session = map.required_str("session")?.to_string();
+        if session.is_empty() {
+            return Err(RpcError::invalid_args("'session' must not be empty"));
+        }
+        let pattern = map.required_str("pattern")?.to_string();
+        let paths = SearchRequest::paths(&map)?;
+        let debounce = map.u64("debounce")?.unwrap_or(100);
+        let stream = stream_config(&map)?;
+        let options = map.options("options")?.unwrap_or_default();
+        let raw_options = map.get("options").cloned().unwrap_or(Value::Nil);
+        Ok(LiveRequest {
+            session,
+            pattern,
+            paths,
+            debounce: Duration::from_millis(debounce),
+            stream,
+            options,
+            raw_options,
+        })
+    }
+}
+
+/// The arguments of a `refine` call.
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) struct RefineRequest {
+    /// The id to store the refined search under. When absent, the server
+    /// picks one.
+    pub(crate) id: Option<String>,
+    /// What to keep of the parent, and which part of it to re-check.
+    pub(crate) refine: Refinement,
+    /// How results are streamed back when the parent's files are searched
+    /// again.
+    pub(crate) stream: StreamConfig,
+    /// The option overrides applied on top of the server's own flags when
+    /// building the new pattern's matcher.
+    pub(crate) options: Vec<(String, OptionValue)>,
+    /// The `options` map exactly as the client sent it, or `Value::Nil`.
+    pub(crate) raw_options: Value,
+}
+
+impl RefineRequest {
+    /// Decode the arguments of a `refine` call.
+    ///
+    /// The map accepts `parent` (required), the id of the stored search to
+    /// narrow down, plus a `pattern` and/or a `glob` (a string or a list of
+    /// strings) that every kept hit must match. `scope` is either `lines`
+    /// (the default) to only re-check the parent's matched lines, or `files`
+    /// to search the parent's files again, which needs a `pattern`. `id`,
+    /// `options`, `batch_size` and `batch_interval` work as for `search`.
+    pub(crate) fn from_args(args: &[Value]) -> Result<RefineRequest, RpcError> {
+        let map = ArgMap::from_args("refine", args)?;
+        let id = new_id(&map)?;
+        let parent = map.required_str("parent")?.to_string();
+        let pattern = map.str("pattern")?.map(|p| p.to_string());
+        let globs = map.strings("glob")?.unwrap_or_default();
+        let scope = match map.str("scope")? {
+            None => RefineScope::Lines,
+            Some(name) => RefineScope::from_name(name).ok_or_else(|| {
+                RpcError::invalid_args(format!(
+                    "unknown scope '{}' (expected 'lines' or 'files')",
+                    name
+                ))
+            })?,
+        };
+        if pattern.is_none() && globs.is_empty() {
+            return Err(RpcError::invalid_args(
+                "refine needs a 'pattern' or a 'glob'",
+            ));
+        }
+        if scope == RefineScope::Files && pattern.is_none() {
+            return Err(RpcError::invalid_args(
+                "scope 'files' needs a 'pattern'",
+            ));
+        }
+        let stream = stream_config(&map)?;
+        let options = map.options("options")?.unwrap_or_default();
+        let raw_options = map.get("options").cloned().unwrap_or(Value::Nil);
+        Ok(RefineRequest {
+            id,
+            refine: Refinement { parent, pattern, globs, scope },
+            stream,
+            options,
+            raw_options,
+        })
+    }
+}
+
+/// The arguments of a `combine` call.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub(crate) struct CombineRequest {
+    /// The id to store the combined search under. When absent, the server
+    /// picks one.
+    pub(crate) id: Option<String>,
+    /// The operation, level and the ids of the searches to combine.
+    pub(crate) combine: Combination,
+}
+
+impl CombineRequest {
+    /// Decode the arguments of a `combine` call.
+    ///
+    /// The map accepts `op` (required), one of `union`, `intersection` or
+    /// `difference`, `ids` (required), the ids of at least two stored
+    /// searches, and `level`, either `files` (the default) or `lines`. An
+    /// `id` may be given to store the combined search under.
+    pub(crate) fn from_args(args: &[Value]) -> Result<CombineRequest, RpcError> {
+        let map = ArgMap::from_args("combine", args)?;
+        let id = new_id(&map)?;
+        let op = map.required_str("op")?;
+        let op = SetOp::from_name(op).ok_or_else(|| {
+            RpcError::invalid_args(format!(
+                "unknown op '{}' (expected 'union', 'intersection' or \
+                 'difference')",
+                op
+            ))
+        })?;
+        let level = match map.str("level")? {
+            None => SetLevel::Files,
+            Some(name) => SetLevel::from_name(name).ok_or_else(|| {
+                RpcError::invalid_args(format!(
+                    "unknown level '{}' (expected 'files' or 'lines')",
+                    name
+                ))
+            })?,
+        };
+        let ids = map.strings("ids")?.unwrap_or_default();
+        if ids.len() < 2 {
+            return Err(RpcError::invalid_args(
+                "'ids' must name at least two searches",
+            ));
+        }
+        Ok(CombineRequest { id, combine: Combination { op, level, ids } })
+    }
+}
+
+/// The arguments of a `refresh` call.
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) struct RefreshRequest {
+    /// The id of the stored search to run again.
+    pub(crate) id: String,
+    /// How results are streamed back while the search runs again.
+    pub(crate) stream: StreamConfig,
+}
+
+impl RefreshRequest {
+    /// Decode the arguments of a `refresh` call.
+    ///
+    /// The map accepts `id` (required), the id of the stored search to run
+    /// again, plus `batch_size` and `batch_interval` as for `search`.
+    pub(crate) fn from_args(args: &[Value]) -> Result<RefreshRequest, RpcError> {
+        let map = ArgMap::from_args("refresh", args)?;
+        let id = map.required_str("id")?.to_string();
+        let stream = stream_config(&map)?;
+        Ok(RefreshRequest { id, stream })
+    }
+}
+
+/// The arguments of a `quickfix` call.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub(crate) struct QuickfixRequest {
+    /// The id of the stored search to export.
+    pub(crate) id: String,
+    /// Whether to set the quickfix list or a location list.
+    pub(crate) list: ListKind,
+    /// The window whose location list is set, `0` for the current one.
+    pub(crate) window: u64,
+    /// What to do with the list that's there already.
+    pub(crate) action: ListAction,
+    /// The globs every exported result's path matches.
+    pub(crate) globs: Vec<String>,
+    /// The maximum number of entries, if any.
+    pub(crate) max: Option<usize>,
+    /// Whether to open the list's window once it's set.
+    pub(crate) open: bool,
+}
+
+impl QuickfixRequest {
+    /// Decode the arguments of a `quickfix` call.
+    ///
+    /// The map accepts `id` (required), `list` (`quickfix`, the default, or
+    /// `location`), `window` (defaults to `0`), `action` (`new`, the
+    /// default, `replace` or `append`), `glob` (a string or a list of
+    /// strings, as for `refine`), `max` and `open` (defaults to `false`).
+    pub(crate) fn from_args(args: &[Value]) -> Result<QuickfixRequest, RpcError> {
+        let map = ArgMap::from_args("quickfix", args)?;
+        let id = map.required_str("id")?.to_string();
+        let list = match map.str("list")? {
+            None => ListKind::Quickfix,
+            Some(name) => ListKind::from_name(name).ok_or_else(|| {
+                RpcError::invalid_args(format!(
+                    "unknown list '{}' (expected 'quickfix' or 'location')",
+                    name
+                ))
+            })?,
+        };
+        let action = match map.str("action")? {
+            None => ListAction::New,
+            Some(name) => ListAction::from_name(name).ok_or_else(|| {
+                RpcError::invalid_args(format!(
+                    "unknown action '{}' (expected 'new', 'replace' or 'append')",
+                    name
+                ))
+            })?,
+        };
+        let window = map.u64("window")?.unwrap_or(0);
+        if window != 0 && list == ListKind::Quickfix {
+            return Err(RpcError::invalid_args(
+                "'window' only applies to the location list",
+            ));
+        }
+        let max = map
+            .u64("max")?
+            .map(|max| usize::try_from(max).unwrap_or(usize::MAX));
+        Ok(QuickfixRequest {
+            id,
+            list,
+            window,
+            action,
+            globs: map.strings("glob")?.unwrap_or_default(),
+            max,
+            open: map.bool("open")?.unwrap_or(false),
+        })
+    }
+}
+
+/// The arguments of a `replace` call.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub(crate) struct ReplaceRequest {
+    /// The id of the stored search whose hits are replaced.
+    pub(crate) id: String,
+    /// What every match is replaced with, as for `-r/--replace`.
+    pub(crate) replacement: String,
+    /// The globs every replaced result's path matches.
+    pub(crate) globs: Vec<String>,
+    /// The indexes of the hunks to apply, from a preview. All of them when
+    /// absent.
+    pub(crate) hunks: Option<Vec<usize>>,
+    /// Whether to only preview the hunks rather than apply them.
+    pub(crate) dry_run: bool,
+}
+
+impl ReplaceRequest {
+    /// Decode the arguments of a `replace` call.
+    ///
+    /// The map accepts `id` (required), `replacement` (required, but may be
+    /// empty), `glob` (as for `refine`), `hunks`, a list of hunk indexes as
+    /// returned by a preview, and `dry_run` (defaults to `true`, so nothing
+    /// is written unless the client asks for it).
+    pub(crate) fn from_args(args: &[Value]) -> Result<ReplaceRequest, RpcError> {
+        let map = ArgMap::from_args("replace", args)?;
+        let id = map.required_str("id")?.to_string();
+        let replacement = map.required_str("replacement")?.to_string();
+        let hunks = match map.get("hunks") {
+            None => None,
+            Some(value) => {
+                let invalid = || {
+                    RpcError::invalid_args(
+                        "'hunks' must be a list of non-negative integers",
+                    )
+                };
+                let mut hunks = vec![];
+                for item in value.as_array().ok_or_else(invalid)?.iter() {
+                    let hunk = item.as_u64().ok_or_else(invalid)?;
+                    hunks.push(usize::try_from(hunk).unwrap_or(usize::MAX));
+                }
+                Some(hunks)
+            }
+        };
+        Ok(ReplaceRequest {
+            id,
+            replacement,
+            globs: map.strings("glob")?.unwrap_or_default(),
+            hunks,
+            dry_run: map.bool("dry_run")?.unwrap_or(true),
+        })
+    }
+}
+
+/// The arguments of a `revert` call.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub(crate) struct RevertRequest {
+    /// The id of the undo journal, as returned by `replace`.
+    pub(crate) journal: String,
+}
+
+impl RevertRequest {
+    /// Decode the arguments of a `revert` call.
+    ///
+    /// The map accepts `journal` (required), the journal id a `replace`
+    /// call returned.
+    pub(crate) fn from_args(args: &[Value]) -> Result<RevertRequest, RpcError> {
+        let map = ArgMap::from_args("revert", args)?;
+        let journal = map.required_str("journal")?.to_string();
+        Ok(RevertRequest { journal })
+    }
+}
+
+/// The arguments of a `stats` call.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub(crate) struct StatsRequest {
+    /// The id of the one stored search to report on, rather than all.
+    pub(crate) id: Option<String>,
+}
+
+impl StatsRequest {
+    /// Decode the arguments of a `stats` call.
+    ///
+    /// The map, which may be left out, accepts `id`, a stored search to
+    /// report on rather than all of them.
+    pub(crate) fn from_args(args: &[Value]) -> Result<StatsRequest, RpcError> {
+        if args.is_empty() {
+            return Ok(StatsRequest { id: None });
+        }
+        let map = ArgMap::from_args("stats", args)?;
+        Ok(StatsRequest { id: map.str("id")?.map(|id| id.to_string()) })
+    }
+}
+
+/// The arguments of a `query_errors` call.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub(crate) struct QueryErrorsRequest {
+    /// The id of the stored search whose errors to return.
+    pub(crate) id: String,
+    /// Only return the errors with this cause.
+    pub(crate) cause: Option<ErrorCause>,
+}
+
+impl QueryErrorsRequest {
+    /// Decode the arguments of a `query_errors` call.
+    ///
+    /// The map accepts `id` (required) and `cause`, one of `permission`,
+    /// `broken_symlink`, `decode` or `other`, to only return those errors.
+    pub(crate) fn from_args(
+        args: &[Value],
+    ) -> Result<QueryErrorsRequest, RpcError> {
+        let map = ArgMap::from_args("query_errors", args)?;
+        let id = map.required_str("id")?.to_string();
+        let cause = match map.str("cause")? {
+            None => None,
+            Some(name) => Some(ErrorCause::from_name(name).ok_or_else(|| {
+                RpcError::invalid_args(format!(
+                    "'cause' must be one of 'permission', 'broken_symlink', \
+                     'decode' or 'other', got '{}'",
+                    name
+                ))
+            })?),
+        };
+        Ok(QueryErrorsRequest { id, cause })
+    }
+}
+
+/// Decode the `id` a new search is stored under, if the client gave one.
+fn new_id(map: &ArgMap<'_>) -> Result<Option<String>, RpcError> {
+    let id = map.str("id")?.map(|id| id.to_string());
+    if id.as_deref() == Some("") {
+        return Err(RpcError::invalid_args("'id' must not be empty"));
+    }
+    if id.as_ref().is_some_and(|id| id.starts_with(live::ID_PREFIX)) {
+        return Err(RpcError::invalid_args(format!(
+            "ids starting with '{}' are kept for live sessions",
+            live::ID_PREFIX
+        )));
+    }
+    Ok(id)
+}
+
+/// Decode `batch_size` and `batch_interval` (in milliseconds), which control
+/// how often results are streamed back while a search runs.
+fn stream_config(map: &ArgMap<'_>) -> Result<StreamConfig, RpcError> {
+    let mut stream = StreamConfig::default();
+    if let Some(batch_size) = map.u64("batch_size")? {
+        if batch_size == 0 {
+            return Err(RpcError::invalid_args("'batch_size' must be at least 1"));
+        }
+        stream.batch_size = usize::try_from(batch_size).unwrap_or(usize::MAX);
+    }
+    if let Some(interval) = map.u64("batch_interval")? {
+        stream.interval = Duration::from_millis(interval);
+    }
+    Ok(stream)
+}
+
+/// The arguments of a `query` call.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub(crate) struct QueryRequest {
+    /// The id of the stored search to page through.
+    pub(crate) id: String,
+    /// The index of the first result to return.
+    pub(crate) offset: usize,
+    /// The maximum number of results to return.
+    pub(crate) limit: usize,
+    /// Whether to include the context lines around each match.
+    pub(crate) context: bool,
+    /// Whether to return the results best first rather than in the order
+    /// they were found, see `rank.rs`.
+    pub(crate) rank: bool,
+    /// The file open in the client, which ranking favors results near.
+    pub(crate) current_file: Option<PathBuf>,
+    /// Whether to include why each ranked result got its score.
+    pub(crate) explain: bool,
+}
+
+impl QueryRequest {
+    /// The number of results returned when the client doesn't give a limit.
+    pub(crate) const DEFAULT_LIMIT: usize = 100;
+
+    /// Decode the arguments of a `query` call.
+    ///
+    /// The map accepts `id` (required), `offset` (defaults to `0`), `limit`
+    /// (defaults to [`QueryRequest::DEFAULT_LIMIT`]) and `context` (defaults
+    /// to `false`). Setting `rank` (defaults to `false`) pages through the
+    /// results best first instead, favoring results near `current_file` if
+    /// given. `explain` (defaults to `false`) implies `rank`.
+    pub(crate) fn from_args(args: &[Value]) -> Result<QueryRequest, RpcError> {
+        let map = ArgMap::from_args("query", args)?;
+        let id = map.required_str("id")?.to_string();
+        let offset = map.u64("offset")?.unwrap_or(0);
+        let limit = map.u64("limit")?.unwrap_or(QueryRequest::DEFAULT_LIMIT as u64);
+        let context = map.bool("context")?.unwrap_or(false);
+        let explain = map.bool("explain")?.unwrap_or(false);
+        let rank = map.bool("rank")?.unwrap_or(false) || explain;
+        let current_file = map.str("current_file")?.map(PathBuf::from);
+        Ok(QueryRequest {
+            id,
+            offset: usize::try_from(offset).unwrap_or(usize::MAX),
+            limit: usize::try_from(limit).unwrap_or(usize::MAX),
+            context,
+            rank,
+            current_file,
+            explain,
+        })
+    }
+}
+
+/// Encode a single search result as a map with `path`, `line`, `text`,
+/// `offset` and `submatches`.
+///
+/// The matched line is sent as a string, with invalid UTF-8 replaced, since
+/// that's what the picker displays. A result without a path (which only
+/// happens when searching stdin) is sent with a nil path. `offset` is the
+/// absolute byte offset of the line in its file. Each submatch has a `start`
+/// and an `end`, each with a 0-based column in `bytes`, `chars` and `utf16`
+/// units, relative to the start of the line.
+///
+/// With `context`, the map also has `before` and `after`, the context lines
+/// around the match as `{line, text}` maps, and `context_break`, which is
+/// true when lines were skipped since the previous match's context.
+pub(crate) fn result_to_value(result: &SearchResult, context: bool) -> Value {
+    let path = match result.get_file_name() {
+        Some(path) => Value::from(path),
+        None => Value::Nil,
+    };
+    let submatches = result
+        .get_submatches()
+        .iter()
+        .map(|m| {
+            Value::Map(vec![
+                (Value::from("start"), column_to_value(&m.start)),
+                (Value::from("end"), column_to_value(&m.end)),
+            ])
+        })
+        .collect();
+    let mut entries = vec![
+        (Value::from("path"), path),
+        (Value::from("line"), Value::from(result.get_line_number())),
+        (Value::from("text"), Value::from(line_text(result.get_matched_bytes()))),
+        (Value::from("offset"), Value::from(result.get_byte_offset())),
+        (Value::from("submatches"), Value::Array(submatches)),
+    ];
+    if context {
+        let lines = |lines: &[ContextLine]| {
+            Value::Array(
+                lines
+                    .iter()
+                    .map(|line| {
+                        Value::Map(vec![
+                            (Value::from("line"), Value::from(line.line_number)),
+                            (Value::from("text"), Value::from(line_text(&line.bytes))),
+                        ])
+                    })
+                    .collect(),
+            )
+        };
+        entries.push((Value::from("before"), lines(result.get_before())));
+        entries.push((Value::from("after"), lines(result.get_after())));
+        entries.push((
+            Value::from("context_break"),
+            Value::from(result.has_context_break()),
+        ));
+    }
+    Value::Map(entries)
+}
+
+/// Decode a line for display, dropping its line terminator.
+fn line_text(bytes: &[u8]) -> String {
+    let text = String::from_utf8_lossy(bytes);
+    text.trim_end_matches(&['\r', '\n'][..]).to_string()
+}
+
+/// Encode a column within a matched line in all of its units.
+fn column_to_value(column: &Column) -> Value {
+    Value::Map(vec![
+        (Value::from("bytes"), Value::from(column.bytes)),
+        (Value::from("chars"), Value::from(column.chars)),
+        (Value::from("utf16"), Value::from(column.utf16)),
+    ])
+}
+
+/// Encode one page of a stored search, along with the total result count.
+pub(crate) fn page_to_value(
+    id: &str,
+    results: &SearchResults,
+    offset: usize,
+    limit: usize,
+    context: bool,
+) -> Value {
+    let page = results.page(offset, limit);
+    let page = page.iter().map(|r| result_to_value(r, context)).collect();
+    page_value(id, results.len(), offset, page)
+}
+
+/// Encode one page of a stored search ranked best first, like
+/// `page_to_value`.
+///
+/// Each result also has its `score`. With `explain`, it also has `why`, the
+/// signals that make up its score as `{signal, points}` maps.
+pub(crate) fn ranked_page_to_value(
+    id: &str,
+    ranked: &[(&SearchResult, Score)],
+    offset: usize,
+    limit: usize,
+    context: bool,
+    explain: bool,
+) -> Value {
+    let start = offset.min(ranked.len());
+    let end = start.saturating_add(limit).min(ranked.len());
+    let page = ranked[start..end]
+        .iter()
+        .map(|(result, score)| {
+            let Value::Map(mut entries) = result_to_value(result, context)
+            else {
+                unreachable!("results are encoded as maps")
+            };
+            entries.push((Value::from("score"), Value::from(score.total)));
+            if explain {
+                let why = score
+                    .signals
+                    .iter()
+                    .map(|(signal, points)| {
+                        Value::Map(vec![
+                            (Value::from("signal"), Value::from(signal.as_str())),
+                            (Value::from("points"), Value::from(*points)),
+                        ])
+                    })
+                    .collect();
+                entries.push((Value::from("why"), Value::Array(why)));
+            }
+            Value::Map(entries)
+        })
+        .collect();
+    page_value(id, ranked.len(), offset, page)
+}
+
+/// The map a page of results is sent as.
+fn page_value(id: &str, total: usize, offset: usize, page: Vec<Value>) -> Value {
+    Value::Map(vec![
+        (Value::from("id"), Value::from(id)),
+        (Value::from("total"), Value::from(total as u64)),
+        (Value::from("offset"), Value::from(offset as u64)),
+        (Value::from("results"), Value::Array(page)),
+    ])
+}
+
+/// Encode what changed when a search was refreshed.
+///
+/// This is a map with the `added` and `removed` results, encoded as by
+/// `query` without context, and the `moved` ones as `{from, to}` maps of
+/// where each result was before and where it is now.
+pub(crate) fn diff_to_value(diff: &Diff) -> Value {
+    let results = |results: &[SearchResult]| {
+        Value::Array(results.iter().map(|r| result_to_value(r, false)).collect())
+    };
+    let moved = diff
+        .moved
+        .iter()
+        .map(|(from, to)| {
+            Value::Map(vec![
+                (Value::from("from"), result_to_value(from, false)),
+                (Value::from("to"), result_to_value(to, false)),
+            ])
+        })
+        .collect();
+    Value::Map(vec![
+        (Value::from("added"), results(&diff.added)),
+        (Value::from("removed"), results(&diff.removed)),
+        (Value::from("moved"), Value::Array(moved)),
+    ])
+}
+
+/// Encode the preview of a replacement.
+///
+/// Each hunk is a `{hunk, path, line, before, after}` map, where `hunk` is
+/// the index to accept it by and `before` and `after` are the lines as
+/// strings, without their line terminator. `files` is the number of files
+/// the hunks are in.
+pub(crate) fn hunks_to_value(id: &str, hunks: &[Hunk]) -> Value {
+    let files: BTreeSet<&str> = hunks.iter().map(|h| h.path.as_str()).collect();
+    let hunks = hunks
+        .iter()
+        .enumerate()
+        .map(|(i, hunk)| {
+            Value::Map(vec![
+                (Value::from("hunk"), Value::from(i as u64)),
+                (Value::from("path"), Value::from(hunk.path.as_str())),
+                (Value::from("line"), Value::from(hunk.line)),
+                (Value::from("before"), Value::from(line_text(&hunk.before))),
+                (Value::from("after"), Value::from(line_text(&hunk.after))),
+            ])
+        })
+        .collect();
+    Value::Map(vec![
+        (Value::from("id"), Value::from(id)),
+        (Value::from("dry_run"), Value::from(true)),
+        (Value::from("hunks"), Value::Array(hunks)),
+        (Value::from("files"), Value::from(files.len() as u64)),
+    ])
+}
+
+/// Encode where the time of a search went and which files it skipped.
+///
+/// Times are in fractional milliseconds. `walk_ms`, `search_ms` and
+/// `collect_ms` are summed across threads, see `stats.rs`. `skipped` has the
+/// number of files skipped as `binary`, for `max_filesize` and as `ignored`.
+pub(crate) fn stats_to_value(id: &str, stats: &SearchStats) -> Value {
+    let ms = |time: Duration| Value::from(time.as_nanos() as f64 / 1e6);
+    Value::Map(vec![
+        (Value::from("id"), Value::from(id)),
+        (Value::from("elapsed_ms"), ms(stats.elapsed)),
+        (Value::from("walk_ms"), ms(stats.walk)),
+        (Value::from("search_ms"), ms(stats.search)),
+        (Value::from("collect_ms"), ms(stats.collect)),
+        (Value::from("searched"), Value::from(stats.searched)),
+        (
+            Value::from("skipped"),
+            Value::Map(vec![
+                (Value::from("binary"), Value::from(stats.binary)),
+                (Value::from("max_filesize"), Value::from(stats.max_filesize)),
+                (Value::from("ignored"), Value::from(stats.ignored)),
+            ]),
+        ),
+        (Value::from("errors"), Value::from(stats.errors)),
+    ])
+}
+
+/// Encode the files a search failed on.
+///
+/// Each error is a `{path, kind, message, cause}` map, see `errors.rs`.
+/// `total` is the number of errors returned, and `unfiltered` the number of
+/// errors the search has, which is more when they were filtered by cause.
+pub(crate) fn errors_to_value(
+    id: &str,
+    unfiltered: usize,
+    errors: &[&FileError],
+) -> Value {
+    let total = errors.len();
+    let errors = errors
+        .iter()
+        .map(|err| {
+            Value::Map(vec![
+                (Value::from("path"), Value::from(err.path.as_str())),
+                (Value::from("kind"), Value::from(err.kind.as_str())),
+                (Value::from("message"), Value::from(err.message.as_str())),
+                (Value::from("cause"), Value::from(err.cause.as_str())),
+            ])
+        })
+        .collect();
+    Value::Map(vec![
+        (Value::from("id"), Value::from(id)),
+        (Value::from("total"), Value::from(total as u64)),
+        (Value::from("unfiltered"), Value::from(unfiltered as u64)),
+        (Value::from("errors"), Value::Array(errors)),
+    ])
+}
+
+/// Encode the summary of a stored search shown in the history list.
+///
+/// This has the `id`, `pattern`, `paths`, `args`, `options`, `root`,
+/// `created` (in seconds since the Unix epoch), `count`, `complete` and
+/// `bytes` of the search, but not its results. A search made with `refine`
+/// also has the `parent` it was refined from and the `refine` map with its
+/// `pattern`, `globs` and `scope`. Both are nil for other searches.
+/// Likewise, a search made with `combine` has a `combine` map with its `op`,
+/// `level` and the `ids` it was combined from.
+pub(crate) fn record_to_value(record: &SearchRecord) -> Value {
+    let query = record.query();
+    let paths = query
+        .paths
+        .iter()
+        .map(|path| Value::from(path.to_string_lossy().into_owned()))
+        .collect();
+    let created = record
+        .created()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs());
+    Value::Map(vec![
+        (Value::from("id"), Value::from(record.id())),
+        (Value::from("pattern"), Value::from(query.pattern.as_str())),
+        (Value::from("paths"), Value::Array(paths)),
+        (
+            Value::from("args"),
+            query.args.as_deref().map_or(Value::Nil, Value::from),
+        ),
+        (Value::from("options"), query.options.clone()),
+        (
+            Value::from("parent"),
+            query.refine.as_ref().map_or(Value::Nil, |r| Value::from(r.parent.as_str())),
+        ),
+        (
+            Value::from("refine"),
+            query.refine.as_ref().map_or(Value::Nil, |r| {
+                Value::Map(vec![
+                    (
+                        Value::from("pattern"),
+                        r.pattern.as_deref().map_or(Value::Nil, Value::from),
+                    ),
+                    (
+                        Value::from("globs"),
+                        Value::Array(r.globs.iter().map(|g| Value::from(g.as_str())).collect()),
+                    ),
+                    (Value::from("scope"), Value::from(r.scope.as_str())),
+                ])
+            }),
+        ),
+        (
+            Value::from("combine"),
+            query.combine.as_ref().map_or(Value::Nil, |c| {
+                Value::Map(vec![
+                    (Value::from("op"), Value::from(c.op.as_str())),
+                    (Value::from("level"), Value::from(c.level.as_str())),
+                    (
+                        Value::from("ids"),
+                        Value::Array(c.ids.iter().map(|id| Value::from(id.as_str())).collect()),
+                    ),
+                ])
+            }),
+        ),
+        (
+            Value::from("root"),
+            Value::from(record.root().to_string_lossy().into_owned()),
+        ),
+        (Value::from("created"), Value::from(created)),
+        (Value::from("count"), Value::from(record.results().len() as u64)),
+        (Value::from("complete"), Value::from(record.complete())),
+        (Value::from("bytes"), Value::from(record.bytes() as u64)),
+    ])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn map(entries: Vec<(&str, Value)>) -> Vec<Value> {
+        let entries =
+            entries.into_iter().map(|(k, v)| (Value::from(k), v)).collect();
+        vec![Value::Map(entries)]
+    }
+
+    #[test]
+    fn search_defaults() {
+        let req =
+            SearchRequest::from_args(&map(vec![("pattern", "foo".into())]))
+                .unwrap();
+        assert_eq!(
+            SearchInput::Pattern {
+                pattern: "foo".to_string(),
+                paths: vec![PathBuf::from("./")],
+            },
+            req.input
+        );
+        assert_eq!(None, req.id);
+        assert_eq!(StreamConfig::default(), req.stream);
+        assert!(req.options.is_empty());
+        assert_eq!(Value::Nil, req.raw_options);
+    }
+
+    #[test]
+    fn search_stream_config() {
+        let args = map(vec![
+            ("pattern", "foo".into()),
+            ("batch_size", Value::from(10u64)),
+            ("batch_interval", Value::from(50u64)),
+        ]);
+        let req = SearchRequest::from_args(&args).unwrap();
+        assert_eq!(10, req.stream.batch_size);
+        assert_eq!(Duration::from_millis(50), req.stream.interval);
+
+        let args =
+            map(vec![("pattern", "foo".into()), ("batch_size", Value::from(0u64))]);
+        let err = SearchRequest::from_args(&args).unwrap_err();
+        assert_eq!(RpcErrorKind::InvalidArgs, err.kind());
+    }
+
+    #[test]
+    fn search_paths_and_id() {
+        let args = map(vec![
+            ("pattern", "foo".into()),
+            ("paths", Value::Array(vec!["src".into(), "Cargo.toml".into()])),
+            ("id", "mine".into()),
+        ]);
+        let req = SearchRequest::from_args(&args).unwrap();
+        assert_eq!(
+            SearchInput::Pattern {
+                pattern: "foo".to_string(),
+                paths: vec![PathBuf::from("src"), PathBuf::from("Cargo.toml")],
+            },
+            req.input
+        );
+        assert_eq!(Some("mine".to_string()), req.id);
+        let args =
+            map(vec![("pattern", "foo".into()), ("id", "live:x".into())]);
+        let err = SearchRequest::from_args(&args).unwrap_err();
+        assert_eq!(RpcErrorKind::InvalidArgs, err.kind());
+
+        let args =
+            map(vec![("pattern", "foo".into()), ("paths", "src".into())]);
+        let req = SearchRequest::from_args(&args).unwrap();
+        assert_eq!(
+            SearchInput::Pattern {
+                pattern: "foo".to_string(),
+                paths: vec![PathBuf::from("src")],
+            },
+            req.input
+        );
+    }
+
+    #[test]
+    fn search_args() {
+        let args = map(vec![("args", "-t php -w get_option wp-content".into())]);
+        let req = SearchRequest::from_args(&args).unwrap();
+        assert_eq!(
+            SearchInput::Args("-t php -w get_option wp-content".to_string()),
+            req.input
+        );
+
+        let args = map(vec![("args", "foo".into()), ("pattern", "foo".into())]);
+        let err = SearchRequest::from_args(&args).unwrap_err();
+        assert_eq!(RpcErrorKind::InvalidArgs, err.kind());
+    }
+
+    #[test]
+    fn search_invalid() {
+        let kind = |args: Vec<Value>| {
+            SearchRequest::from_args(&args).unwrap_err().kind()
+        };
+        assert_eq!(RpcErrorKind::InvalidArgs, kind(vec![]));
+        assert_eq!(RpcErrorKind::InvalidArgs, kind(vec!["foo".into()]));
+        assert_eq!(RpcErrorKind::InvalidArgs, kind(map(vec![])));
+        assert_eq!(
+            RpcErrorKind::InvalidArgs,
+            kind(map(vec![("pattern", Value::from(5u64))]))
+        );
+        assert_eq!(
+            RpcErrorKind::InvalidArgs,
+            kind(map(vec![
+                ("pattern", "foo".into()),
+                ("paths", Value::Array(vec![])),
+            ]))
+        );
+        assert_eq!(
+            RpcErrorKind::InvalidArgs,
+            kind(map(vec![
+                ("pattern", "foo".into()),
+                ("paths", "does/not/exist".into()),
+            ]))
+        );
+    }
+
+    #[test]
+    fn search_options() {
+        let options = Value::Map(vec![
+            ("hidden".into(), Value::from(true)),
+            ("max_filesize".into(), Value::from(1024u64)),
+            ("case".into(), "smart".into()),
+            ("glob".into(), Value::Array(vec!["*.rs".into()])),
+        ]);
+        let args =
+            map(vec![("pattern", "foo".into()), ("options", options.clone())]);
+        let req = SearchRequest::from_args(&args).unwrap();
+        assert_eq!(
+            vec![
+                ("hidden".to_string(), OptionValue::Bool(true)),
+                ("max_filesize".to_string(), OptionValue::Int(1024)),
+                ("case".to_string(), OptionValue::Str("smart".to_string())),
+                (
+                    "glob".to_string(),
+                    OptionValue::List(vec!["*.rs".to_string()])
+                ),
+            ],
+            req.options
+        );
+        assert_eq!(options, req.raw_options);
+
+        let kind = |options: Value| {
+            let args = map(vec![("pattern", "foo".into()), ("options", options)]);
+            SearchRequest::from_args(&args).unwrap_err().kind()
+        };
+        assert_eq!(RpcErrorKind::InvalidArgs, kind("hidden".into()));
+        assert_eq!(
+            RpcErrorKind::InvalidArgs,
+            kind(Value::Map(vec![("hidden".into(), Value::from(1.5))]))
+        );
+    }
+
+    #[test]
+    fn refine() {
+        let args = map(vec![
+            ("parent", "search-1".into()),
+            ("pattern", "meta_query".into()),
+        ]);
+        let req = RefineRequest::from_args(&args).unwrap();
+        assert_eq!(
+            Refinement {
+                parent: "search-1".to_string(),
+                pattern: Some("meta_query".to_string()),
+                globs: vec![],
+                scope: RefineScope::Lines,
+            },
+            req.refine
+        );
+        assert_eq!(None, req.id);
+
+        let args = map(vec![
+            ("parent", "search-1".into()),
+            ("glob", "*.php".into()),
+            ("id", "php".into()),
+        ]);
+        let req = RefineRequest::from_args(&args).unwrap();
+        assert_eq!(vec!["*.php".to_string()], req.refine.globs);
+        assert_eq!(Some("php".to_string()), req.id);
+
+        let kind = |args: Vec<(&str, Value)>| {
+            RefineRequest::from_args(&map(args)).unwrap_err().kind()
+        };
+        // Nothing to refine by.
+        assert_eq!(
+            RpcErrorKind::InvalidArgs,
+            kind(vec![("parent", "search-1".into())])
+        );
+        assert_eq!(
+            RpcErrorKind::InvalidArgs,
+            kind(vec![("pattern", "foo".into())])
+        );
+        assert_eq!(
+            RpcErrorKind::InvalidArgs,
+            kind(vec![
+                ("parent", "search-1".into()),
+                ("pattern", "foo".into()),
+                ("scope", "dirs".into()),
+            ])
+        );
+        // Searching the files again needs a pattern to search for.
+        assert_eq!(
+            RpcErrorKind::InvalidArgs,
+            kind(vec![
+                ("parent", "search-1".into()),
+                ("glob", "*.php".into()),
+                ("scope", "files".into()),
+            ])
+        );
+    }
+
+    #[test]
+    fn combine() {
+        let ids = Value::Array(vec!["a".into(), "b".into()]);
+        let args = map(vec![("op", "difference".into()), ("ids", ids.clone())]);
+        let req = CombineRequest::from_args(&args).unwrap();
+        assert_eq!(
+            Combination {
+                op: SetOp::Difference,
+                level: SetLevel::Files,
+                ids: vec!["a".to_string(), "b".to_string()],
+            },
+            req.combine
+        );
+
+        let kind = |args: Vec<(&str, Value)>| {
+            CombineRequest::from_args(&map(args)).unwrap_err().kind()
+        };
+        assert_eq!(RpcErrorKind::InvalidArgs, kind(vec![("ids", ids.clone())]));
+        assert_eq!(
+            RpcErrorKind::InvalidArgs,
+            kind(vec![("op", "xor".into()), ("ids", ids.clone())])
+        );
+        assert_eq!(
+            RpcErrorKind::InvalidArgs,
+            kind(vec![
+                ("op", "union".into()),
+                ("ids", ids.clone()),
+                ("level", "dirs".into()),
+            ])
+        );
+        assert_eq!(
+            RpcErrorKind::InvalidArgs,
+            kind(vec![("op", "union".into()), ("ids", "a".into())])
+        );
+    }
+
+    #[test]
+    fn refresh() {
+        let args = map(vec![
+            ("id", "search-1".into()),
+            ("batch_size", Value::from(5u64)),
+        ]);
+        let req = RefreshRequest::from_args(&args).unwrap();
+        assert_eq!("search-1", req.id);
+        assert_eq!(5, req.stream.batch_size);
+
+        let err = RefreshRequest::from_args(&map(vec![])).unwrap_err();
+        assert_eq!(RpcErrorKind::InvalidArgs, err.kind());
+    }
+
+    #[test]
+    fn stored_options() {
+        assert!(decode_options("options", &Value::Nil).unwrap().is_empty());
+        let stored =
+            Value::Map(vec![(Value::from("hidden"), Value::from(true))]);
+        assert_eq!(
+            vec![("hidden".to_string(), OptionValue::Bool(true))],
+            decode_options("options", &stored).unwrap()
+        );
+        let err =
+            decode_options("options", &Value::from(1u64)).unwrap_err();
+        assert_eq!(RpcErrorKind::InvalidArgs, err.kind());
+    }
+
+    #[test]
+    fn quickfix() {
+        let req =
+            QuickfixRequest::from_args(&map(vec![("id", "search-1".into())]))
+                .unwrap();
+        assert_eq!(ListKind::Quickfix, req.list);
+        assert_eq!(ListAction::New, req.action);
+        assert_eq!((0, None, false), (req.window, req.max, req.open));
+        assert!(req.globs.is_empty());
+
+        let req = QuickfixRequest::from_args(&map(vec![
+            ("id", "search-1".into()),
+            ("list", "location".into()),
+            ("window", Value::from(1000u64)),
+            ("action", "replace".into()),
+            ("glob", "*.rs".into()),
+            ("max", Value::from(50u64)),
+            ("open", true.into()),
+        ]))
+        .unwrap();
+        assert_eq!(ListKind::Location, req.list);
+        assert_eq!(ListAction::Replace, req.action);
+        assert_eq!((1000, Some(50), true), (req.window, req.max, req.open));
+        assert_eq!(vec!["*.rs".to_string()], req.globs);
+
+        let kind = |args: Vec<(&str, Value)>| {
+            QuickfixRequest::from_args(&map(args)).unwrap_err().kind()
+        };
+        assert_eq!(
+            RpcErrorKind::InvalidArgs,
+            kind(vec![("id", "search-1".into()), ("list", "loclist".into())])
+        );
+        assert_eq!(
+            RpcErrorKind::InvalidArgs,
+            kind(vec![("id", "search-1".into()), ("action", "r".into())])
+        );
+        assert_eq!(
+            RpcErrorKind::InvalidArgs,
+            kind(vec![("id", "search-1".into()), ("window", Value::from(3u64))])
+        );
+    }
+
+    #[test]
+    fn replace() {
+        let req = ReplaceRequest::from_args(&map(vec![
+            ("id", "search-1".into()),
+            ("replacement", "".into()),
+        ]))
+        .unwrap();
+        assert_eq!("", req.replacement);
+        assert_eq!((None, true), (req.hunks, req.dry_run));
+
+        let req = ReplaceRequest::from_args(&map(vec![
+            ("id", "search-1".into()),
+            ("replacement", "$1".into()),
+            ("hunks", Value::Array(vec![Value::from(0u64), Value::from(2u64)])),
+            ("dry_run", false.into()),
+        ]))
+        .unwrap();
+        assert_eq!((Some(vec![0, 2]), false), (req.hunks, req.dry_run));
+
+        let kind = |args: Vec<(&str, Value)>| {
+            ReplaceRequest::from_args(&map(args)).unwrap_err().kind()
+        };
+        assert_eq!(
+            RpcErrorKind::InvalidArgs,
+            kind(vec![("id", "search-1".into())])
+        );
+        assert_eq!(
+            RpcErrorKind::InvalidArgs,
+            kind(vec![
+                ("id", "search-1".into()),
+                ("replacement", "x".into()),
+                ("hunks", Value::Array(vec!["0".into()])),
+            ])
+        );
+
+        let err = RevertRequest::from_args(&map(vec![])).unwrap_err();
+        assert_eq!(RpcErrorKind::InvalidArgs, err.kind());
+    }
+
+    #[test]
+    fn stats() {
+        assert_eq!(None, StatsRequest::from_args(&[]).unwrap().id);
+        let req =
+            StatsRequest::from_args(&map(vec![("id", "search-1".into())]))
+                .unwrap();
+        assert_eq!(Some("search-1".to_string()), req.id);
+
+        let stats = SearchStats {
+            walk: Duration::from_micros(1500),
+            ignored: 4,
+            ..SearchStats::default()
+        };
+        let value = stats_to_value("search-1", &stats);
+        let get = |value: &Value, key: &str| {
+            value
+                .as_map()
+                .unwrap()
+                .iter()
+                .find(|(k, _)| k.as_str() == Some(key))
+                .map(|(_, v)| v.clone())
+                .unwrap()
+        };
+        assert_eq!(Some(1.5), get(&value, "walk_ms").as_f64());
+        let skipped = get(&value, "skipped");
+        assert_eq!(Some(4), get(&skipped, "ignored").as_u64());
+    }
+
+    #[test]
+    fn live() {
+        let req = LiveRequest::from_args(&map(vec![
+            ("session", "prompt".into()),
+            ("pattern", "wp_q".into()),
+            ("debounce", Value::from(30u64)),
+        ]))
+        .unwrap();
+        assert_eq!(("prompt", "wp_q"), (&req.session[..], &req.pattern[..]));
+        assert_eq!(vec![PathBuf::from("./")], req.paths);
+        assert_eq!(Duration::from_millis(30), req.debounce);
+
+        let req = LiveRequest::from_args(&map(vec![
+            ("session", "prompt".into()),
+            ("pattern", "".into()),
+        ]))
+        .unwrap();
+        assert_eq!(Duration::from_millis(100), req.debounce);
+
+        let kind = |args: Vec<(&str, Value)>| {
+            LiveRequest::from_args(&map(args)).unwrap_err().kind()
+        };
+        assert_eq!(
+            RpcErrorKind::InvalidArgs,
+            kind(vec![("pattern", "foo".into())])
+        );
+        assert_eq!(
+            RpcErrorKind::InvalidArgs,
+            kind(vec![("session", "".into()), ("pattern", "foo".into())])
+        );
+    }
+
+    #[test]
+    fn query_errors() {
+        let req = QueryErrorsRequest::from_args(&map(vec![
+            ("id", "search-1".into()),
+            ("cause", "broken_symlink".into()),
+        ]))
+        .unwrap();
+        assert_eq!(Some(ErrorCause::BrokenSymlink), req.cause);
+        let err = QueryErrorsRequest::from_args(&map(vec![
+            ("id", "search-1".into()),
+            ("cause", "missing".into()),
+        ]))
+        .unwrap_err();
+        assert_eq!(RpcErrorKind::InvalidArgs, err.kind());
+
+        let err = FileError {
+            path: "a/b".to_string(),
+            kind: "PermissionDenied".to_string(),
+            message: "Permission denied (os error 13)".to_string(),
+            cause: ErrorCause::Permission,
+        };
+        let value = errors_to_value("search-1", 3, &[&err]);
+        let map = value.as_map().unwrap();
+        assert_eq!(Some(1), map[1].1.as_u64());
+        assert_eq!(Some(3), map[2].1.as_u64());
+        let errors = map[3].1.as_array().unwrap();
+        assert_eq!(1, errors.len());
+        let err = errors[0].as_map().unwrap();
+        assert_eq!(Some("permission"), err[3].1.as_str());
+    }
+
+    #[test]
+    fn query_defaults() {
+        let req =
+            QueryRequest::from_args(&map(vec![("id", "search-1".into())]))
+                .unwrap();
+        assert_eq!("search-1", req.id);
+        assert_eq!(0, req.offset);
+        assert_eq!(QueryRequest::DEFAULT_LIMIT, req.limit);
+        assert!(!req.context);
+        assert!(!req.rank);
+        assert_eq!(None, req.current_file);
+
+        let kind = |args: Vec<Value>| {
+            QueryRequest::from_args(&args).unwrap_err().kind()
+        };
+        assert_eq!(RpcErrorKind::InvalidArgs, kind(map(vec![])));
+        assert_eq!(
+            RpcErrorKind::InvalidArgs,
+            kind(map(vec![
+                ("id", "search-1".into()),
+                ("offset", Value::from(-1i64)),
+            ]))
+        );
+    }
+
+    #[test]
+    fn query_page() {
+        let mut results = SearchResults::new();
+        let text = "fn main() { \u{1F980} main }\n".as_bytes();
+        for line in 1..=5u64 {
+            results.store_result(SearchResult::new(
+                Some("src/main.rs".to_string()),
+                line,
+                (line - 1) * text.len() as u64,
+                text.to_vec(),
+                SubMatch::from_ranges(text, [3..7, 17..21]),
+            ));
+        }
+        assert_eq!(2, results.page(3, 10).len());
+        assert_eq!(0, results.page(10, 10).len());
+
+        let value = page_to_value("search-1", &results, 1, 2, false);
+        let map = value.as_map().unwrap();
+        let get = |key: &str| {
+            map.iter().find(|(k, _)| k.as_str() == Some(key)).unwrap().1.clone()
+        };
+        assert_eq!(Some(5), get("total").as_u64());
+        let page = get("results");
+        let page = page.as_array().unwrap();
+        assert_eq!(2, page.len());
+        let first = page[0].as_map().unwrap();
+        assert_eq!(Some(2), first[1].1.as_u64());
+        assert_eq!(Some("fn main() { \u{1F980} main }"), first[2].1.as_str());
+        assert_eq!(Some(24), first[3].1.as_u64());
+
+        // The crab is 4 bytes, 1 char and 2 UTF-16 units wide.
+        let submatches = first[4].1.as_array().unwrap();
+        assert_eq!(2, submatches.len());
+        let second = submatches[1].as_map().unwrap();
+        let start = second[0].1.as_map().unwrap();
+        let columns: Vec<u64> =
+            start.iter().map(|(_, v)| v.as_u64().unwrap()).collect();
+        assert_eq!(vec![17, 14, 15], columns);
+        assert_eq!(5, first.len());
+    }
+
+    #[test]
+    fn query_context() {
+        let args = map(vec![("id", "search-1".into()), ("context", true.into())]);
+        assert!(QueryRequest::from_args(&args).unwrap().context);
+
+        let mut results = SearchResults::new();
+        let line = |line_number, text: &str| ContextLine {
+            line_number,
+            bytes: text.as_bytes().to_vec(),
+        };
+        results.store_result(
+            SearchResult::new(None, 2, 4, b"foo\n".to_vec(), vec![])
+                .with_context(vec![line(1, "a\n")], vec![line(3, "b\r\n")], true),
+        );
+        let value = result_to_value(results.page(0, 1).first().unwrap(), true);
+        let map = value.as_map().unwrap();
+        let get = |key: &str| {
+            map.iter().find(|(k, _)| k.as_str() == Some(key)).unwrap().1.clone()
+        };
+        let after = get("after");
+        let after = after.as_array().unwrap()[0].as_map().unwrap();
+        assert_eq!(Some(3), after[0].1.as_u64());
+        assert_eq!(Some("b"), after[1].1.as_str());
+        assert_eq!(1, get("before").as_array().unwrap().len());
+        assert_eq!(Some(true), get("context_break").as_bool());
+    }
+
+    #[test]
+    fn query_ranked() {
+        let args = map(vec![
+            ("id", "search-1".into()),
+            ("explain", true.into()),
+            ("current_file", "src/main.rs".into()),
+        ]);
+        let req = QueryRequest::from_args(&args).unwrap();
+        assert!(req.rank && req.explain);
+        assert_eq!(Some(PathBuf::from("src/main.rs")), req.current_file);
+
+        let result = SearchResult::new(None, 1, 0, b"foo\n".to_vec(), vec![]);
+        let score = Score {
+            total: 5,
+            signals: vec![(crate::rank::Signal::Recent, 5)],
+        };
+        let ranked = vec![(&result, score)];
+        let get = |value: &Value, key: &str| {
+            let map = value.as_map().unwrap();
+            map.iter().find(|(k, _)| k.as_str() == Some(key)).map(|e| e.1.clone())
+        };
+        let value = ranked_page_to_value("search-1", &ranked, 0, 10, false, true);
+        assert_eq!(Some(1), get(&value, "total").and_then(|v| v.as_u64()));
+        let page = get(&value, "results").unwrap();
+        let first = &page.as_array().unwrap()[0];
+        assert_eq!(Some(5), get(first, "score").and_then(|v| v.as_i64()));
+        let why = get(first, "why").unwrap();
+        let why = &why.as_array().unwrap()[0];
+        assert_eq!(Some("recent"), get(why, "signal").unwrap().as_str());
+
+        let value = ranked_page_to_value("search-1", &ranked, 0, 10, false, false);
+        let page = get(&value, "results").unwrap();
+        assert!(get(&page.as_array().unwrap()[0], "why").is_none());
+        let value = ranked_page_to_value("search-1", &ranked, 5, 10, false, false);
+        assert!(get(&value, "results").unwrap().as_array().unwrap().is_empty());
+    }
+}
@@ -9,46 +9,176 @@ search worker is where things like preprocessors or decompression happens.
 
 use std::{io, path::Path};
 
-use {bstr::ByteVec, grep::matcher::Matcher, termcolor::WriteColor};
+use {
+    bstr::ByteSlice,
+    grep::{
+        matcher::{Captures, Matcher},
+        searcher::SinkError,
+    },
+};
 
 //use arrayvec::ArrayVec;
 
+/// A position within the matched bytes of a result, counted in the units
+/// different clients want.
+///
+/// nvim's API counts columns in bytes, most pickers count characters and LSP
+/// counts UTF-16 code units. All three start at 0. Invalid UTF-8 is counted
+/// the way it would be after replacing it with U+FFFD.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub(crate) struct Column {
+    pub(crate) bytes: u64,
+    pub(crate) chars: u64,
+    pub(crate) utf16: u64,
+}
+
+impl Column {
+    /// Returns this column moved past the given bytes.
+    fn advance(self, bytes: &[u8]) -> Column {
+        let mut column = self;
+        column.bytes += bytes.len() as u64;
+        for ch in bytes.chars() {
+            column.chars += 1;
+            column.utf16 += ch.len_utf16() as u64;
+        }
+        return column;
+    }
+}
+
+/// The span of a single match of the pattern within a result.
+///
+/// A matched line may contain the pattern many times, and in multi line mode
+/// a result may span several lines. Each match gets its own span, relative
+/// to the start of the result's matched bytes.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub(crate) struct SubMatch {
+    pub(crate) start: Column,
+    pub(crate) end: Column,
+}
+
+impl SubMatch {
+    /// Compute the spans for the given byte ranges of `bytes`.
+    ///
+    /// The ranges must be in order and must not overlap, which is how a
+    /// matcher's `find_iter` reports them.
+    pub(crate) fn from_ranges(
+        bytes: &[u8],
+        ranges: impl IntoIterator<Item = std::ops::Range<usize>>,
+    ) -> Vec<SubMatch> {
+        let mut column = Column::default();
+        let mut submatches = vec![];
+        for range in ranges {
+            let start = column.advance(&bytes[column.bytes as usize..range.start]);
+            let end = start.advance(&bytes[range.clone()]);
+            submatches.push(SubMatch { start, end });
+            column = end;
+        }
+        return submatches;
+    }
+}
+
+/// A line of context shown around a match, e.g., with `-A/-B/-C`.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub(crate) struct ContextLine {
+    /// The line number, counting from 1.
+    pub(crate) line_number: u64,
+    /// The line itself, including its line terminator.
+    pub(crate) bytes: Vec<u8>,
+}
+
 /// The result of executing a search.
 #[derive(Clone, Debug)]
 pub(crate) struct SearchResult {
-    //Fuck this I have a better idea (See main.rs)
     //Likely rename this to ThreadSearchResult & maybe ThreadSearchResults
     //These fill with the small easy fill data and they supply the main.rs Results
     //This eleminates dealing with lifetimes in the thread which leaks into underlying searcher code
-    //
-    //file_name: &str,
-    //file_name: String,
     file_name: Option<String>,
-    //file_name: Option<&str>,
-    //file_name: Option<&Path>,
-    line_number: u16, //If I could get like a u24 size wise that would make way more sense, maybe 3 u8's?
+    line_number: u64,
+    //Absolute offset of the first matched byte's line in the file
+    byte_offset: u64,
     matched_bytes: Vec<u8>,
-    //This is two things I can try
-    //matched_bytes: [u8; 50],
-    //matched_bytes: ArrayVec<u8>,
+    submatches: Vec<SubMatch>,
+    //Context lines only belong to one match, lines between two close matches are the first one's after
+    before: Vec<ContextLine>,
+    after: Vec<ContextLine>,
+    //Set when lines were skipped between the previous match's context and this one's
+    context_break: bool,
 }
 impl SearchResult {
-    pub(crate) fn new(file_name: Option<String>, line_number: u16, matched_bytes: Vec<u8>) -> SearchResult {
-        return SearchResult { file_name, line_number, matched_bytes };
+    pub(crate) fn new(
+        file_name: Option<String>,
+        line_number: u64,
+        byte_offset: u64,
+        matched_bytes: Vec<u8>,
+        submatches: Vec<SubMatch>,
+    ) -> SearchResult {
+        return SearchResult {
+            file_name,
+            line_number,
+            byte_offset,
+            matched_bytes,
+            submatches,
+            before: vec![],
+            after: vec![],
+            context_break: false,
+        };
+    }
+
+    /// Attach context lines to this result, e.g., when reading it back from
+    /// disk.
+    pub(crate) fn with_context(
+        mut self,
+        before: Vec<ContextLine>,
+        after: Vec<ContextLine>,
+        context_break: bool,
+    ) -> SearchResult {
+        self.before = before;
+        self.after = after;
+        self.context_break = context_break;
+        return self;
     }
 
-    //pub(crate) fn new(line_number: u16, matched_bytes: Vec<u8>) -> SearchResult {
-    //    return SearchResult { line_number, matched_bytes };
-    //}
+    pub(crate) fn get_file_name(&self) -> Option<&str> {
+        return self.file_name.as_deref();
+    }
 
-    pub(crate) fn get_line_number(&mut self) -> u16 {
+    /// The line the match starts on, counting from 1.
+    pub(crate) fn get_line_number(&self) -> u64 {
         return self.line_number;
     }
 
-    pub(crate) fn get_matched_bytes(&mut self) -> &Vec<u8> {
+    /// The absolute byte offset in the file where the matched bytes start.
+    /// This is the start of the line, not of the first submatch.
+    pub(crate) fn get_byte_offset(&self) -> u64 {
+        return self.byte_offset;
+    }
+
+    pub(crate) fn get_matched_bytes(&self) -> &Vec<u8> {
         return &self.matched_bytes;
     }
 
+    /// Every match of the pattern within the matched bytes.
+    pub(crate) fn get_submatches(&self) -> &[SubMatch] {
+        return &self.submatches;
+    }
+
+    /// The context lines right before the match, in order.
+    pub(crate) fn get_before(&self) -> &[ContextLine] {
+        return &self.before;
+    }
+
+    /// The context lines right after the match, in order.
+    pub(crate) fn get_after(&self) -> &[ContextLine] {
+        return &self.after;
+    }
+
+    /// Whether lines were skipped between the context of the previous match
+    /// in the same file and the context of this one. This is where ripgrep
+    /// prints `--`.
+    pub(crate) fn has_context_break(&self) -> bool {
+        return self.context_break;
+    }
+
     pub(crate) fn set_file_name(&mut self, file_name: Option<String>) {
         self.file_name = file_name;
     }
@@ -72,25 +202,77 @@ impl SearchResults {
         return &mut self.results_store;
     }
 
+    pub(crate) fn iter(&self) -> std::slice::Iter<'_, SearchResult> {
+        return self.results_store.iter();
+    }
+
     pub(crate) fn has_results(&self) -> bool {
         return self.results_store.len() > 0;
     }
 
+    pub(crate) fn len(&self) -> usize {
+        return self.results_store.len();
+    }
+
+    /// Returns roughly how many bytes of memory these results take up.
+    ///
+    /// This counts the results themselves plus the file names, matched
+    /// lines, submatches and context lines they own. It's only meant for bounding the size of the history.
+    pub(crate) fn approx_bytes(&self) -> usize {
+        return self.results_store.iter().fold(0, |bytes, result| {
+            bytes
+                + std::mem::size_of::<SearchResult>()
+                + result.file_name.as_ref().map_or(0, |name| name.len())
+                + result.matched_bytes.len()
+                + result.submatches.len() * std::mem::size_of::<SubMatch>()
+                + result.before.iter().chain(result.after.iter()).fold(0, |bytes, line| {
+                    bytes + std::mem::size_of::<ContextLine>() + line.bytes.len()
+                })
+        });
+    }
+
+    /// Returns at most `limit` results starting at `offset`. An offset past
+    /// the end yields an empty page rather than an error.
+    pub(crate) fn page(&self, offset: usize, limit: usize) -> &[SearchResult] {
+        let start = offset.min(self.results_store.len());
+        let end = start.saturating_add(limit).min(self.results_store.len());
+        return &self.results_store[start..end];
+    }
+
     pub(crate) fn consume_results(self) -> Vec<SearchResult> {
         return self.results_store;
     }
 }
 
+//Lets the walker threads move a whole buffer of results in at once
+impl Extend<SearchResult> for SearchResults {
+    fn extend<I: IntoIterator<Item = SearchResult>>(&mut self, iter: I) {
+        self.results_store.extend(iter);
+    }
+}
+
 //Custom sink that doesn't use underlying printer instead keeps the vector of byte or converted string
+//The searcher writes into it through MatchSink, which also has the matcher for finding submatches
 #[derive(Clone, Debug)]
 pub struct CustomSink {
     match_count: u32,
     results_store: SearchResults,
+    //Before context arrives ahead of the match it belongs to
+    pending_before: Vec<ContextLine>,
+    pending_break: bool,
+    //Set when the searcher gave up on the file because it looked binary
+    quit_on_binary: bool,
 }
 
 impl CustomSink {
     pub(crate) fn new() -> CustomSink {
-        return CustomSink { match_count: 0, results_store: SearchResults::new() };
+        return CustomSink {
+            match_count: 0,
+            results_store: SearchResults::new(),
+            pending_before: vec![],
+            pending_break: false,
+            quit_on_binary: false,
+        };
     }
 
     pub(crate) fn get_results(&mut self) -> &mut SearchResults {
@@ -105,11 +287,86 @@ impl CustomSink {
         self.match_count
     }
 
+    /// Whether the last search stopped early because the file has binary
+    /// data, i.e., it was skipped as a binary file.
+    pub(crate) fn quit_on_binary(&self) -> bool {
+        return self.quit_on_binary;
+    }
+
     pub(crate) fn consume_sink_results(self) -> SearchResults {
         return self.results_store;
     }
 }
-impl grep::searcher::Sink for CustomSink {
+
+/// The number of bytes past a multi line match the matcher may look at
+/// when finding submatches. This is the same cap ripgrep's printer uses.
+const MAX_LOOK_AHEAD: usize = 128;
+
+/// Collects the matches of one search into a `CustomSink`, along with the
+/// submatches found by re-running the matcher over each matched line.
+struct MatchSink<'s, M> {
+    matcher: M,
+    sink: &'s mut CustomSink,
+}
+
+impl<'s, M: Matcher> MatchSink<'s, M> {
+    /// Find every match of the pattern within `range` of `buf`, returning
+    /// the ranges relative to the start of `range`.
+    ///
+    /// Like ripgrep's printer, a single line is searched without its line
+    /// terminator so look-around can't see it, while a multi line match is
+    /// searched with a bit of the buffer after it so look-ahead still works.
+    fn find_ranges(
+        &self,
+        searcher: &grep::searcher::Searcher,
+        buf: &[u8],
+        range: std::ops::Range<usize>,
+    ) -> io::Result<Vec<std::ops::Range<usize>>> {
+        let bytes = match searcher.multi_line_with_matcher(&self.matcher) {
+            true => &buf[..buf.len().min(range.end + MAX_LOOK_AHEAD)],
+            false => {
+                let lineterm = searcher.line_terminator();
+                let mut end = range.end;
+                if lineterm.is_suffix(&buf[range.clone()]) {
+                    end -= 1;
+                    if lineterm.is_crlf() && end > range.start && buf[end - 1] == b'\r' {
+                        end -= 1;
+                    }
+                }
+                &buf[..end]
+            }
+        };
+        let mut ranges = vec![];
+        self.matcher
+            .find_iter_at(bytes, range.start, |m| {
+                if m.start() >= range.end {
+                    return false;
+                }
+                ranges.push(m.start() - range.start..m.end().min(range.end) - range.start);
+                return true;
+            })
+            .map_err(io::Error::error_message)?;
+        //Don't report an empty match at the very end, the printer drops it too
+        let len = range.end - range.start;
+        if ranges.last().is_some_and(|r| r.is_empty() && r.start >= len) {
+            ranges.pop();
+        }
+        return Ok(ranges);
+    }
+}
+
+/// The line number the searcher reported for a match or context line.
+///
+/// The searcher of a search worker always counts lines, see
+/// `HiArgs::search_worker`, so a missing one is a bug. Storing it as 0 would
+/// send clients to a line that doesn't exist and make every refresh see the
+/// results as moved.
+fn line_number(line_number: Option<u64>) -> Result<u64, io::Error> {
+    return line_number
+        .ok_or_else(|| io::Error::other("the searcher isn't counting line numbers"));
+}
+
+impl<'s, M: Matcher> grep::searcher::Sink for MatchSink<'s, M> {
     type Error = io::Error;
 
     fn matched(
@@ -117,25 +374,72 @@ impl grep::searcher::Sink for CustomSink {
         searcher: &grep::searcher::Searcher,
         mat: &grep::searcher::SinkMatch<'_>,
     ) -> Result<bool, io::Error> {
-        self.match_count += 1;
-
-        let line_number = match mat.line_number() {
-            Some(line_number) => line_number,
-            None => 0, //Safe default
-        };
-
-        self.results_store.store_result(
+        let ranges = self.find_ranges(searcher, mat.buffer(), mat.bytes_range_in_buffer())?;
+        let submatches = SubMatch::from_ranges(mat.bytes(), ranges);
+        let before = std::mem::take(&mut self.sink.pending_before);
+        let context_break = std::mem::take(&mut self.sink.pending_break);
+        self.sink.match_count += 1;
+        self.sink.results_store.store_result(
             SearchResult::new(
                 None,
-                line_number.try_into().unwrap(), //Going to bank no file being this large in our codebase
-                mat.bytes().to_vec() //Maybe more efficient way to do this?
+                line_number(mat.line_number())?,
+                mat.absolute_byte_offset(),
+                mat.bytes().to_vec(),
+                submatches,
             )
+            .with_context(before, vec![], context_break)
         );
         return Ok(true);
     }
 
+    /// Keep context lines next to the match they surround.
+    ///
+    /// Before context is held until its match arrives. After context, and
+    /// the lines printed with `--passthru`, go to the most recent match. Any
+    /// passthru lines ahead of the first match are held like before context.
+    fn context(
+        &mut self,
+        _searcher: &grep::searcher::Searcher,
+        context: &grep::searcher::SinkContext<'_>,
+    ) -> Result<bool, io::Error> {
+        use grep::searcher::SinkContextKind;
+
+        let line = ContextLine {
+            line_number: line_number(context.line_number())?,
+            bytes: context.bytes().to_vec(),
+        };
+        let last = self.sink.results_store.get_mut().last_mut();
+        match (context.kind(), last) {
+            (SinkContextKind::Before, _) | (_, None) => {
+                self.sink.pending_before.push(line);
+            }
+            (SinkContextKind::After | SinkContextKind::Other, Some(last)) => {
+                last.after.push(line);
+            }
+        }
+        return Ok(true);
+    }
+
+    fn context_break(&mut self, _searcher: &grep::searcher::Searcher) -> Result<bool, io::Error> {
+        self.sink.pending_break = true;
+        return Ok(true);
+    }
+
     fn begin(&mut self, _searcher: &grep::searcher::Searcher) -> Result<bool, io::Error> {
-        self.match_count = 0;
+        //Each search starts from an empty store so results don't leak into the next file
+        self.sink.match_count = 0;
+        self.sink.results_store = SearchResults::new();
+        self.sink.pending_before.clear();
+        self.sink.pending_break = false;
+        self.sink.quit_on_binary = false;
+        return Ok(true);
+    }
+
+    fn binary_data(&mut self, searcher: &grep::searcher::Searcher, _binary_byte_offset: u64) -> Result<bool, io::Error> {
+        //Only quitting skips the file, converting it keeps searching the rest
+        if searcher.binary_detection().quit_byte().is_some() {
+            self.sink.quit_on_binary = true;
+        }
         return Ok(true);
     }
 }
@@ -195,14 +499,15 @@ impl SearchWorkerBuilder {
         }
     }
 
-    /// Create a new search worker using the given searcher, matcher and
-    /// printer.
-    pub(crate) fn build<W: WriteColor>(
+    /// Create a new search worker using the given searcher and matcher.
+    ///
+    /// Matches are collected into the worker's own `CustomSink` rather than
+    /// being written to a printer.
+    pub(crate) fn build(
         &self,
         matcher: PatternMatcher,
         searcher: grep::searcher::Searcher,
-        printer: grep::printer::Standard<W>,
-    ) -> SearchWorker<W> {
+    ) -> SearchWorker {
         let config = self.config.clone();
         let command_builder = self.command_builder.clone();
         let decomp_builder = self.decomp_builder.clone();
@@ -212,22 +517,11 @@ impl SearchWorkerBuilder {
             decomp_builder,
             matcher,
             searcher,
-            printer,
+            results_store: CustomSink::new(),
         }
     }
 }
 
-/// The result of executing a search.
-///
-/// Generally speaking, the "result" of a search is sent to a printer, which
-/// writes results to an underlying writer such as stdout or a file. However,
-/// every search also has some aggregate statistics or meta data that may be
-/// useful to higher level routines.
-#[derive(Clone, Debug, Default)]
-pub(crate) struct SearchResult {
-    has_match: bool,
-}
-
 /// The pattern matcher used by a search worker.
 #[derive(Clone, Debug)]
 pub(crate) enum PatternMatcher {
@@ -236,38 +530,92 @@ pub(crate) enum PatternMatcher {
     PCRE2(grep::pcre2::RegexMatcher),
 }
 
+impl PatternMatcher {
+    /// Returns true if the pattern matches anywhere in `bytes`.
+    pub(crate) fn is_match(&self, bytes: &[u8]) -> io::Result<bool> {
+        use self::PatternMatcher::*;
+
+        match *self {
+            RustRegex(ref m) => m.is_match(bytes).map_err(io::Error::error_message),
+            #[cfg(feature = "pcre2")]
+            PCRE2(ref m) => m.is_match(bytes).map_err(io::Error::error_message),
+        }
+    }
+
+    /// Replace every match in `line` with `replacement`, the way
+    /// `-r/--replace` does, i.e., `$1` or `$name` in the replacement expand
+    /// to capture groups.
+    ///
+    /// The line terminator is kept out of the matching, so that look-arounds
+    /// like `$` see the end of the line, and is put back afterwards.
+    pub(crate) fn replace_all(&self, line: &[u8], replacement: &[u8]) -> io::Result<Vec<u8>> {
+        use self::PatternMatcher::*;
+
+        fn replace<M: Matcher>(matcher: &M, haystack: &[u8], replacement: &[u8]) -> io::Result<Vec<u8>> {
+            let mut caps = matcher.new_captures().map_err(io::Error::error_message)?;
+            let mut dst = vec![];
+            matcher
+                .replace_with_captures(haystack, &mut caps, &mut dst, |caps, dst| {
+                    caps.interpolate(|name| matcher.capture_index(name), haystack, replacement, dst);
+                    true
+                })
+                .map_err(io::Error::error_message)?;
+            return Ok(dst);
+        }
+
+        let body = line.strip_suffix(b"\n").unwrap_or(line);
+        let body = body.strip_suffix(b"\r").unwrap_or(body);
+        let mut replaced = match *self {
+            RustRegex(ref m) => replace(m, body, replacement)?,
+            #[cfg(feature = "pcre2")]
+            PCRE2(ref m) => replace(m, body, replacement)?,
+        };
+        replaced.extend_from_slice(&line[body.len()..]);
+        return Ok(replaced);
+    }
+}
+
 /// A worker for executing searches.
 ///
 /// It is intended for a single worker to execute many searches, and is
 /// generally intended to be used from a single thread. When searching using
 /// multiple threads, it is better to create a new worker for each thread.
 #[derive(Clone, Debug)]
-pub(crate) struct SearchWorker<W> {
+pub(crate) struct SearchWorker {
     config: Config,
     command_builder: grep::cli::CommandReaderBuilder,
     decomp_builder: grep::cli::DecompressionReaderBuilder,
     matcher: PatternMatcher,
     searcher: grep::searcher::Searcher,
-    printer: grep::printer::Standard<W>,
+    results_store: CustomSink,
 }
 
-impl<W: WriteColor> SearchWorker<W> {
-    /// Execute a search over the given haystack.
-    pub(crate) fn search(
-        &mut self,
-        haystack: &crate::haystack::Haystack,
-    ) -> io::Result<SearchResult> {
 impl SearchWorker {
+    /// Return the results collected by the most recent call to `search`.
     pub(crate) fn get_results(&mut self) -> &mut SearchResults {
         return self.results_store.get_results();
     }
 
+    /// Whether the most recent call to `search` gave up on the file as
+    /// binary.
+    pub(crate) fn quit_on_binary(&self) -> bool {
+        return self.results_store.quit_on_binary();
+    }
+
     //Fuck I think this pattern would help alot too but the mut is created outside of multithreading
     pub(crate) fn consume_searcher_results(self) -> SearchResults {
         return self.results_store.consume_sink_results();
     }
 
-    pub(crate) fn search(&mut self, haystack: &crate::haystack::Haystack) -> bool {
+    /// Execute a search over the given haystack.
+    ///
+    /// Returns true if and only if the haystack contained at least one
+    /// match. The matches themselves are available via `get_results` until
+    /// the next search.
+    pub(crate) fn search(
+        &mut self,
+        haystack: &crate::haystack::Haystack,
+    ) -> io::Result<bool> {
         self.searcher.set_binary_detection(
             match haystack.is_explicit() {
                 true => self.config.binary_explicit.clone(),
@@ -277,91 +625,144 @@ impl SearchWorker {
         self.search_path(haystack.path())
     }
 
-    /// Return a mutable reference to the underlying printer.
-    pub(crate) fn printer(&mut self) -> &mut grep::printer::Standard<W> {
-        &mut self.printer
-    }
-
     /// Search the contents of the given file path.
-    fn search_path(&mut self, path: &Path) -> io::Result<SearchResult> {
+    fn search_path(&mut self, path: &Path) -> io::Result<bool> {
         use self::PatternMatcher::*;
 
-            //println!("{:#?}", 1);
-        let (searcher, printer) = (&mut self.searcher, &mut self.printer);
+        let (searcher, results_store) =
+            (&mut self.searcher, &mut self.results_store);
         match self.matcher {
-            RustRegex(ref m) => search_path(m, searcher, printer, path),
+            RustRegex(ref m) => search_path(m, searcher, results_store, path),
             #[cfg(feature = "pcre2")]
-            PCRE2(ref m) => search_path(m, searcher, printer, path),
-                //for result in self.results_store.get_results().get_mut().iter_mut() {
-                //    result.file_name = path.to_string_lossy().to_string();
-                //}
-                return self.results_store.has_match();
-            },
-            //#[cfg(feature = "pcre2")]
-            //PCRE2(ref m) => search_path(m, searcher, results_store, path),
+            PCRE2(ref m) => search_path(m, searcher, results_store, path),
         }
     }
 }
 
-//Custom sink that doesn't use underlying printer instead keeps the vector of byte or converted string
-#[derive(Debug)]
-pub struct CustomSink {
-    match_count: u64,
+/// Search the contents of the given file path using the given matcher,
+/// searcher and sink.
+fn search_path<M: Matcher>(
+    matcher: M,
+    searcher: &mut grep::searcher::Searcher,
+    results_store: &mut CustomSink,
+    path: &Path,
+) -> io::Result<bool> {
+    let sink = MatchSink { matcher: &matcher, sink: &mut *results_store };
+    searcher.search_path(&matcher, path, sink)?;
+    return Ok(results_store.has_match());
 }
 
-impl CustomSink {
-    /// Returns true if and only if this sink received a match in the previous search
-    pub fn has_match(&self) -> bool {
-        self.match_count > 0
+#[cfg(test)]
+mod tests {
+    use grep::{regex::RegexMatcher, searcher::SearcherBuilder};
+
+    use super::*;
+
+    fn search(pattern: &str, haystack: &str) -> Vec<SearchResult> {
+        search_with(SearcherBuilder::new().line_number(true), pattern, haystack)
     }
 
-    /// Return total number of stored to sink. Number of times `Sink::matched` called
-    pub fn match_count(&self) -> u64 {
-        self.match_count
+    fn search_with(
+        builder: &SearcherBuilder,
+        pattern: &str,
+        haystack: &str,
+    ) -> Vec<SearchResult> {
+        let matcher = RegexMatcher::new_line_matcher(pattern).unwrap();
+        let mut searcher = builder.build();
+        let mut results_store = CustomSink::new();
+        let sink = MatchSink { matcher: &matcher, sink: &mut results_store };
+        searcher.search_slice(&matcher, haystack.as_bytes(), sink).unwrap();
+        results_store.consume_sink_results().consume_results()
     }
-}
-impl grep::searcher::Sink for CustomSink {
-    type Error = io::Error;
 
-    fn matched(
-        &mut self,
-        searcher: &grep::searcher::Searcher,
-        mat: &grep::searcher::SinkMatch<'_>,
-    ) -> Result<bool, io::Error> {
-        //println!("{:#?}", mat.bytes()); //Here
-        //println!("{:#?}", mat.buffer()); //Raw file buf
-        //println!("{:#?}", mat.bytes_range_in_buffer());
-        self.match_count += 1;
+    #[test]
+    fn columns() {
+        let line = "añb\u{1F980}c".as_bytes();
+        let submatches = SubMatch::from_ranges(line, [1..3, 4..8, 8..9]);
+        let col = |bytes, chars, utf16| Column { bytes, chars, utf16 };
+        assert_eq!(col(1, 1, 1), submatches[0].start);
+        assert_eq!(col(3, 2, 2), submatches[0].end);
+        assert_eq!(col(4, 3, 3), submatches[1].start);
+        assert_eq!(col(8, 4, 5), submatches[1].end);
+        assert_eq!(col(9, 5, 6), submatches[2].end);
+    }
 
-        //Store match in buffer look to orginal : StandardImpl::from_match(searcher, self, mat).sink()?;
-        Ok(true)
+    #[test]
+    fn submatches() {
+        let results = search("o+", "foo\nbar\nboo zoo\r\n");
+        assert_eq!(2, results.len());
+        assert_eq!(1, results[0].get_line_number());
+        assert_eq!(0, results[0].get_byte_offset());
+        assert_eq!(3, results[1].get_line_number());
+        assert_eq!(8, results[1].get_byte_offset());
+        let spans: Vec<(u64, u64)> = results[1]
+            .get_submatches()
+            .iter()
+            .map(|m| (m.start.bytes, m.end.bytes))
+            .collect();
+        assert_eq!(vec![(1, 3), (5, 7)], spans);
     }
 
-    fn begin(&mut self, _searcher: &grep::searcher::Searcher) -> Result<bool, io::Error> {
-        self.match_count = 0;
-        return Ok(true);
+    #[test]
+    fn line_numbers_past_u16() {
+        let haystack = "x\n".repeat(70_000) + "needle\n";
+        let results = search("needle", &haystack);
+        assert_eq!(70_001, results[0].get_line_number());
+        assert_eq!(140_000, results[0].get_byte_offset());
     }
-}
 
-/// Search the contents of the given file path using the given matcher,
-fn search_path<M: Matcher, W: WriteColor>(
-    matcher: M,
-    searcher: &mut grep::searcher::Searcher,
-    printer: &mut grep::printer::Standard<W>,
-    path: &Path,
-) -> io::Result<SearchResult> {
-    //println!("{:#?}", "StartSolutionHere");
-    //I think the solution is here
-    //I think the solution is here
-    //I think the solution is here
-    //
-    //This is divergence of sink being whats written into to the sink being a child of printer
-    //Returns mut sink but sink might be owned by printer and/or sink.matched() writes into printer
-    let mut sink = printer.sink_with_path(&matcher, path);
-
-    //println!("{:#?}", "BeforeSearchPath");
-    searcher.search_path(&matcher, path, &mut sink)?;
-    //println!("{:#?}", "AfterSearchPath");
-    //println!("{:#?}", sink.bytes()); //So difficult consider it impossible
-    return Ok(SearchResult { has_match: sink.has_match() })
+    #[test]
+    fn line_numbers_are_required() {
+        let matcher = RegexMatcher::new_line_matcher("a").unwrap();
+        let mut searcher = SearcherBuilder::new().line_number(false).build();
+        let mut results_store = CustomSink::new();
+        let sink = MatchSink { matcher: &matcher, sink: &mut results_store };
+        assert!(searcher.search_slice(&matcher, b"a\n", sink).is_err());
+    }
+
+    #[test]
+    fn empty_match_at_end_is_dropped() {
+        let results = search("a*", "ba\n");
+        let spans: Vec<(u64, u64)> = results[0]
+            .get_submatches()
+            .iter()
+            .map(|m| (m.start.bytes, m.end.bytes))
+            .collect();
+        assert_eq!(vec![(0, 0), (1, 2)], spans);
+    }
+
+    #[test]
+    fn replace_all() {
+        let matcher = RegexMatcher::new_line_matcher(r"(\w+)_id$").unwrap();
+        let matcher = PatternMatcher::RustRegex(matcher);
+        let got = matcher.replace_all(b"let user_id\r\n", b"${1}Id").unwrap();
+        assert_eq!("let userId\r\n", String::from_utf8(got).unwrap());
+        let got = matcher.replace_all(b"user_id = 1\n", b"x").unwrap();
+        assert_eq!("user_id = 1\n", String::from_utf8(got).unwrap());
+    }
+
+    #[test]
+    fn context_lines() {
+        let mut builder = SearcherBuilder::new();
+        builder.line_number(true).before_context(1).after_context(1);
+        let haystack = "a\nfoo\nb\nc\nd\nfoo\nfoo\ne\n";
+        let results = search_with(&builder, "foo", haystack);
+        let lines = |lines: &[ContextLine]| -> Vec<u64> {
+            lines.iter().map(|line| line.line_number).collect()
+        };
+        assert_eq!(3, results.len());
+
+        assert_eq!(vec![1], lines(results[0].get_before()));
+        assert_eq!(vec![3], lines(results[0].get_after()));
+        assert!(!results[0].has_context_break());
+
+        // Line 4 is skipped, so there's a break before line 5.
+        assert_eq!(vec![5], lines(results[1].get_before()));
+        assert!(lines(results[1].get_after()).is_empty());
+        assert!(results[1].has_context_break());
+
+        assert!(lines(results[2].get_before()).is_empty());
+        assert_eq!(vec![8], lines(results[2].get_after()));
+        assert_eq!(b"e\n", &results[2].get_after()[0].bytes[..]);
+    }
 }
@@ -0,0 +1,947 @@
+/*!
+Serves one search store to any number of clients over a Unix domain socket.
+
+When nvim spawns the server, the two talk msgpack-rpc over the server's stdin
+and stdout, so every nvim instance gets its own server and its own history.
+`search-history --serve --socket PATH` instead listens on a Unix domain socket,
+so that several nvim instances, and scripts, can share one server:
+
+```lua
+local chan = vim.fn.sockconnect('pipe', '/tmp/search-history.sock', { rpc = true })
+vim.rpcrequest(chan, 'search', { pattern = 'wp_query' })
+```
+
+Every connection is served on its own thread. Responses go back on the
+connection the request came in on, and the events of a search (see
+[`crate::notify`]) go to the client that started it. Events are sent as
+`nvim_exec_autocmds` notifications, which nvim runs as is, so an nvim client
+gets the same `User` autocommands it gets when it spawned the server itself.
+
+`search-history --client` is a small client for scripts and tests. It sends one
+request, with its argument given as JSON, and prints the response as JSON.
+*/
+
+use std::{
+    collections::VecDeque,
+    io::{self, BufReader, Read, Write},
+    os::unix::{
+        fs::PermissionsExt,
+        net::{UnixListener, UnixStream},
+    },
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+};
+
+use anyhow::Context;
+use neovim_lib::Value;
+
+use crate::{
+    notify::{Event, Notifier, Peers},
+    SearchHandler,
+};
+
+/// A single msgpack-rpc message.
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) enum Message {
+    /// A call that expects a response with the same id.
+    Request { id: u64, method: String, params: Vec<Value> },
+    /// The answer to the request with the same id. Exactly one of `error`
+    /// and `result` is not nil.
+    Response { id: u64, error: Value, result: Value },
+    /// A call that expects no response.
+    Notification { method: String, params: Vec<Value> },
+}
+
+impl Message {
+    /// Decode a message from its msgpack representation, i.e., an array
+    /// that starts with the message type.
+    pub(crate) fn from_value(value: Value) -> anyhow::Result<Message> {
+        let Value::Array(items) = value else {
+            anyhow::bail!("message must be an array");
+        };
+        let len = items.len();
+        let method = |v: Value| -> anyhow::Result<String> {
+            Ok(v.as_str().context("method must be a string")?.to_string())
+        };
+        let params = |v: Value| -> anyhow::Result<Vec<Value>> {
+            match v {
+                Value::Array(params) => Ok(params),
+                _ => anyhow::bail!("params must be an array"),
+            }
+        };
+        let id = |v: Value| v.as_u64().context("message id must be an integer");
+        let mut items = items.into_iter();
+        let mut next = || items.next().unwrap_or(Value::Nil);
+        match (next().as_u64(), len) {
+            (Some(0), 4) => Ok(Message::Request {
+                id: id(next())?,
+                method: method(next())?,
+                params: params(next())?,
+            }),
+            (Some(1), 4) => Ok(Message::Response {
+                id: id(next())?,
+                error: next(),
+                result: next(),
+            }),
+            (Some(2), 3) => Ok(Message::Notification {
+                method: method(next())?,
+                params: params(next())?,
+            }),
+            _ => anyhow::bail!("not a msgpack-rpc message"),
+        }
+    }
+
+    /// Encode this message as its msgpack representation.
+    pub(crate) fn into_value(self) -> Value {
+        match self {
+            Message::Request { id, method, params } => Value::Array(vec![
+                Value::from(0),
+                Value::from(id),
+                Value::from(method),
+                Value::Array(params),
+            ]),
+            Message::Response { id, error, result } => Value::Array(vec![
+                Value::from(1),
+                Value::from(id),
+                error,
+                result,
+            ]),
+            Message::Notification { method, params } => Value::Array(vec![
+                Value::from(2),
+                Value::from(method),
+                Value::Array(params),
+            ]),
+        }
+    }
+}
+
+/// Read the next message, returning `None` once the other side has closed
+/// the connection.
+pub(crate) fn read_message(
+    rdr: &mut impl Read,
+) -> anyhow::Result<Option<Message>> {
+    use rmpv::decode::Error;
+
+    let value = match rmpv::decode::read_value(rdr) {
+        Ok(value) => value,
+        Err(Error::InvalidMarkerRead(err))
+            if err.kind() == io::ErrorKind::UnexpectedEof =>
+        {
+            return Ok(None);
+        }
+        Err(err) => return Err(err.into()),
+    };
+    Message::from_value(value).map(Some)
+}
+
+/// Write a message in one go, so that messages written from several threads
+/// never interleave.
+pub(crate) fn write_message(
+    wtr: &mut impl Write,
+    msg: Message,
+) -> io::Result<()> {
+    let mut buf = vec![];
+    rmpv::encode::write_value(&mut buf, &msg.into_value())?;
+    wtr.write_all(&buf)?;
+    wtr.flush()
+}
+
+/// The sending half of a client connection, shared by the thread serving
+/// the connection and by the searches the client started.
+#[derive(Debug)]
+pub(crate) struct Peer {
+    stream: Mutex<UnixStream>,
+    closed: AtomicBool,
+}
+
+impl Peer {
+    fn new(stream: UnixStream) -> Peer {
+        Peer { stream: Mutex::new(stream), closed: AtomicBool::new(false) }
+    }
+
+    /// Returns true once sending to the client has failed.
+    pub(crate) fn is_closed(&self) -> bool {
+        self.closed.load(Ordering::SeqCst)
+    }
+
+    /// Send a message to the client.
+    ///
+    /// Once sending fails, the client is assumed gone and every later
+    /// message is dropped without trying.
+    pub(crate) fn send(&self, msg: Message) -> io::Result<()> {
+        if self.is_closed() {
+            return Err(io::Error::from(io::ErrorKind::BrokenPipe));
+        }
+        let mut stream = self.stream.lock().unwrap();
+        let result = write_message(&mut *stream, msg);
+        if result.is_err() {
+            self.closed.store(true, Ordering::SeqCst);
+        }
+        result
+    }
+}
+
+/// Bind the socket at `path`.
+///
+/// A socket file left behind by a server that is no longer running is
+/// replaced, but one that still accepts connections is an error. The socket
+/// is only accessible by the current user, since the history it serves can
+/// contain anything found in the searched files.
+pub(crate) fn bind(path: &Path) -> anyhow::Result<UnixListener> {
+    if path.exists() {
+        if UnixStream::connect(path).is_ok() {
+            anyhow::bail!("{}: a server is already listening", path.display());
+        }
+        std::fs::remove_file(path).with_context(|| {
+            format!("{}: failed to remove stale socket", path.display())
+        })?;
+    }
+    let listener = UnixListener::bind(path)
+        .with_context(|| format!("{}: failed to bind socket", path.display()))?;
+    std::fs::set_permissions(path, std::fs::Permissions::from_mode(0o600))
+        .with_context(|| format!("{}: failed to set permissions", path.display()))?;
+    Ok(listener)
+}
+
+/// Accept clients on `listener` forever, serving each on its own thread.
+///
+/// Every client shares the store of `handler`. Only the notifier differs, so
+/// that events reach the client that started the search. Events about stored
+/// searches changing on their own, see `watch.rs`, go to every client.
+pub(crate) fn serve(
+    listener: UnixListener,
+    handler: SearchHandler,
+) -> anyhow::Result<()> {
+    let peers: Peers = Arc::default();
+    handler.start_watching(Notifier::broadcast(Arc::clone(&peers)));
+    for (n, stream) in listener.incoming().enumerate() {
+        let stream = match stream {
+            Ok(stream) => stream,
+            Err(err) => {
+                log::warn!("failed to accept client: {}", err);
+                continue;
+            }
+        };
+        let (handler, peers) = (handler.clone(), Arc::clone(&peers));
+        std::thread::Builder::new()
+            .name(format!("search-history client {}", n))
+            .spawn(move || {
+                if let Err(err) = serve_client(stream, handler, &peers) {
+                    log::warn!("client {} failed: {:#}", n, err);
+                }
+            })
+            .context("failed to spawn client thread")?;
+    }
+    Ok(())
+}
+
+/// Answer the requests of a single client until it disconnects.
+fn serve_client(
+    stream: UnixStream,
+    handler: SearchHandler,
+    peers: &Peers,
+) -> anyhow::Result<()> {
+    let peer = Arc::new(Peer::new(stream.try_clone()?));
+    let handler = handler.with_notifier(Notifier::socket(Arc::clone(&peer)));
+    peers.lock().unwrap().push(Arc::clone(&peer));
+    let result = serve_requests(stream, &handler, &peer);
+    peers.lock().unwrap().retain(|p| !Arc::ptr_eq(p, &peer));
+    result
+}
+
+/// Answer requests read from `stream`, replying to `peer`.
+fn serve_requests(
+    stream: UnixStream,
+    handler: &SearchHandler,
+    peer: &Peer,
+) -> anyhow::Result<()> {
+    let mut rdr = BufReader::new(stream);
+    while let Some(msg) = read_message(&mut rdr)? {
+        match msg {
+            Message::Request { id, method, params } => {
+                let (error, result) = match handler.handle(method, &params) {
+                    Ok(result) => (Value::Nil, result),
+                    Err(err) => (err.into_value(), Value::Nil),
+                };
+                peer.send(Message::Response { id, error, result })?;
+            }
+            // Nobody to tell about a failed notification, so it's only logged
+            Message::Notification { method, params } => {
+                if let Err(err) = handler.handle(method, &params) {
+                    log::warn!("{}", err);
+                }
+            }
+            Message::Response { id, .. } => {
+                log::debug!("ignoring response {} from client", id);
+            }
+        }
+    }
+    Ok(())
+}
+
+/// A connection to a server, used by `--client`.
+pub(crate) struct Client {
+    wtr: UnixStream,
+    rdr: BufReader<UnixStream>,
+    next_id: u64,
+    events: VecDeque<Vec<Value>>,
+}
+
+impl Client {
+    /// Connect to the server listening on `socket`.
+    pub(crate) fn connect(socket: &Path) -> anyhow::Result<Client> {
+        let wtr = UnixStream::connect(socket)
+            .with_context(|| format!("{}: failed to connect", socket.display()))?;
+        let rdr = BufReader::new(wtr.try_clone()?);
+        Ok(Client { wtr, rdr, next_id: 0, events: VecDeque::new() })
+    }
+
+    /// Call `method` and wait for its response, which is either the result
+    /// or the error returned by the server.
+    ///
+    /// Events can arrive before the response, e.g., when a search is so
+    /// quick that it's over before its id is returned. They're kept for
+    /// `next_event`.
+    pub(crate) fn call(
+        &mut self,
+        method: &str,
+        params: Vec<Value>,
+    ) -> anyhow::Result<Result<Value, Value>> {
+        self.next_id += 1;
+        let id = self.next_id;
+        let method = method.to_string();
+        write_message(&mut self.wtr, Message::Request { id, method, params })?;
+        loop {
+            match self.read()? {
+                Message::Response { id: got, error, result } if got == id => {
+                    return Ok(match error {
+                        Value::Nil => Ok(result),
+                        error => Err(error),
+                    });
+                }
+                Message::Notification { params, .. } => {
+                    self.events.push_back(params)
+                }
+                msg => log::debug!("ignoring unexpected message {:?}", msg),
+            }
+        }
+    }
+
+    /// Wait for the next event, returning its pattern and its data.
+    pub(crate) fn next_event(&mut self) -> anyhow::Result<(String, Value)> {
+        loop {
+            let params = match self.events.pop_front() {
+                Some(params) => params,
+                None => match self.read()? {
+                    Message::Notification { params, .. } => params,
+                    msg => {
+                        log::debug!("ignoring unexpected message {:?}", msg);
+                        continue;
+                    }
+                },
+            };
+            // Events are sent as ["User", {pattern, data}], see notify.rs
+            let Some(Value::Map(opts)) = params.into_iter().nth(1) else {
+                continue;
+            };
+            let (mut pattern, mut data) = (None, Value::Nil);
+            for (key, value) in opts {
+                match key.as_str() {
+                    Some("pattern") => pattern = value.as_str().map(String::from),
+                    Some("data") => data = value,
+                    _ => {}
+                }
+            }
+            if let Some(pattern) = pattern {
+                return Ok((pattern, data));
+            }
+        }
+    }
+
+    fn read(&mut self) -> anyhow::Result<Message> {
+        read_message(&mut self.rdr)?.context("server closed the connection")
+    }
+}
+
+/// Run `--client`: make one call and print its response as
+/// JSON. Returns false if the server returned an error.
+///
+/// With `--wait` after a search, every event of that search is printed as a
+/// line of JSON, `{"event": ..., "data": ...}`, until it's done.
+pub(crate) fn run_client(args: ClientArgs) -> anyhow::Result<bool> {
+    let params = match args.args {
+        None => Value::Map(vec![]),
+        Some(ref json) => json_to_value(
+            serde_json::from_str(json).context("--client: invalid JSON argument")?,
+        ),
+    };
+    let mut client = Client::connect(&args.socket)?;
+    let response = client.call(&args.method, vec![params])?;
+    let mut stdout = io::stdout().lock();
+    let result = match response {
+        Ok(result) => result,
+        Err(error) => {
+            writeln!(stdout, "{}", value_to_json(&error))?;
+            return Ok(false);
+        }
+    };
+    writeln!(stdout, "{}", value_to_json(&result))?;
+    if !args.wait || args.method != "search" {
+        return Ok(true);
+    }
+    let id = map_get(&result, "id").context("search returned no id")?.clone();
+    loop {
+        let (pattern, data) = client.next_event()?;
+        if map_get(&data, "id") != Some(&id) {
+            continue;
+        }
+        let line = serde_json::json!({
+            "event": pattern,
+            "data": value_to_json(&data),
+        });
+        writeln!(stdout, "{}", line)?;
+        if pattern == Event::Done.pattern() {
+            return Ok(map_get(&data, "error").is_none());
+        }
+    }
+}
+
+/// Look up a string key in a msgpack map.
+fn map_get<'a>(value: &'a Value, key: &str) -> Option<&'a Value> {
+    let Value::Map(ref entries) = *value else { return None };
+    entries.iter().find(|(k, _)| k.as_str() == Some(key)).map(|(_, v)| v)
+}
+
+/// Convert JSON given on the command line to msgpack.
+pub(crate) fn json_to_value(json: serde_json::Value) -> Value {
+    use serde_json::Value as Json;
+
+    match json {
+        Json::Null => Value::Nil,
+        Json::Bool(yes) => Value::from(yes),
+        Json::Number(n) => match (n.as_u64(), n.as_i64(), n.as_f64()) {
+            (Some(n), _, _) => Value::from(n),
+            (None, Some(n), _) => Value::from(n),
+            (None, None, Some(n)) => Value::from(n),
+            (None, None, None) => Value::Nil,
+        },
+        Json::String(s) => Value::from(s),
+        Json::Array(items) => {
+            Value::Array(items.into_iter().map(json_to_value).collect())
+        }
+        Json::Object(entries) => Value::Map(
+            entries
+                .into_iter()
+                .map(|(k, v)| (Value::from(k), json_to_value(v)))
+                .collect(),
+        ),
+    }
+}
+
+/// Convert msgpack from the server to JSON for printing. Binary data and
+/// invalid UTF-8 are converted lossily, and map keys that aren't strings
+/// are printed as msgpack would show them.
+pub(crate) fn value_to_json(value: &Value) -> serde_json::Value {
+    use serde_json::Value as Json;
+
+    match *value {
+        Value::Nil | Value::Ext(..) => Json::Null,
+        Value::Boolean(yes) => Json::Bool(yes),
+        Value::Integer(n) => match (n.as_u64(), n.as_i64()) {
+            (Some(n), _) => Json::from(n),
+            (None, Some(n)) => Json::from(n),
+            (None, None) => Json::Null,
+        },
+        Value::F32(n) => Json::from(f64::from(n)),
+        Value::F64(n) => Json::from(n),
+        Value::String(ref s) => {
+            Json::String(String::from_utf8_lossy(s.as_bytes()).into_owned())
+        }
+        Value::Binary(ref bytes) => {
+            Json::String(String::from_utf8_lossy(bytes).into_owned())
+        }
+        Value::Array(ref items) => {
+            Json::Array(items.iter().map(value_to_json).collect())
+        }
+        Value::Map(ref entries) => Json::Object(
+            entries
+                .iter()
+                .map(|(k, v)| {
+                    let key = match k.as_str() {
+                        Some(k) => k.to_string(),
+                        None => k.to_string(),
+                    };
+                    (key, value_to_json(v))
+                })
+                .collect(),
+        ),
+    }
+}
+
+/// The arguments of `--serve`.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub(crate) struct ServeArgs {
+    /// The socket to listen on.
+    pub(crate) socket: PathBuf,
+    /// The remaining arguments, which are ripgrep flags for every search.
+    pub(crate) rest: Vec<std::ffi::OsString>,
+}
+
+/// The arguments of `--client`.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub(crate) struct ClientArgs {
+    /// The socket of the server to connect to.
+    pub(crate) socket: PathBuf,
+    /// Whether to wait for the search started by the request to finish.
+    pub(crate) wait: bool,
+    /// The method to call.
+    pub(crate) method: String,
+    /// The argument of the call, as JSON. Defaults to an empty map.
+    pub(crate) args: Option<String>,
+}
+
+/// Parse `--serve --socket PATH [FLAGS...]`, without `--serve` itself.
+pub(crate) fn parse_serve(
+    args: &[std::ffi::OsString],
+) -> anyhow::Result<ServeArgs> {
+    let mut serve = ServeArgs::default();
+    let mut socket = None;
+    let mut it = args.iter();
+    while let Some(arg) = it.next() {
+        if let Some(path) = socket_flag(arg, &mut it)? {
+            socket = Some(path);
+        } else {
+            serve.rest.push(arg.clone());
+        }
+    }
+    serve.socket = socket.context("--serve: --socket PATH is required")?;
+    Ok(serve)
+}
+
+/// Parse `--client --socket PATH [--wait] METHOD [JSON]`, without
+/// `--client` itself.
+pub(crate) fn parse_client(
+    args: &[std::ffi::OsString],
+) -> anyhow::Result<ClientArgs> {
+    let mut client = ClientArgs::default();
+    let mut socket = None;
+    let mut positional = vec![];
+    let mut it = args.iter();
+    while let Some(arg) = it.next() {
+        if let Some(path) = socket_flag(arg, &mut it)? {
+            socket = Some(path);
+        } else if arg == "--wait" {
+            client.wait = true;
+        } else {
+            let arg = arg
+                .to_str()
+                .with_context(|| format!("--client: {:?} is not valid UTF-8", arg))?;
+            positional.push(arg.to_string());
+        }
+    }
+    client.socket = socket.context("--client: --socket PATH is required")?;
+    let mut positional = positional.into_iter();
+    client.method = positional.next().context("--client: METHOD is required")?;
+    client.args = positional.next();
+    if let Some(extra) = positional.next() {
+        anyhow::bail!("--client: unexpected argument '{}'", extra);
+    }
+    Ok(client)
+}
+
+/// If `arg` is `--socket PATH` or `--socket=PATH`, return the path.
+fn socket_flag<'a>(
+    arg: &std::ffi::OsString,
+    rest: &mut impl Iterator<Item = &'a std::ffi::OsString>,
+) -> anyhow::Result<Option<PathBuf>> {
+    if arg == "--socket" {
+        let path = rest.next().context("--socket requires a path")?;
+        return Ok(Some(PathBuf::from(path)));
+    }
+    if let Some(path) = arg.to_str().and_then(|a| a.strip_prefix("--socket=")) {
+        return Ok(Some(PathBuf::from(path)));
+    }
+    Ok(None)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn message_roundtrip() {
+        let msgs = [
+            Message::Request {
+                id: 7,
+                method: "search".to_string(),
+                params: vec![Value::from("x")],
+            },
+            Message::Response { id: 7, error: Value::Nil, result: Value::from(1) },
+            Message::Notification { method: "n".to_string(), params: vec![] },
+        ];
+        let mut buf = vec![];
+        for msg in msgs.iter() {
+            write_message(&mut buf, msg.clone()).unwrap();
+        }
+        let mut rdr = &buf[..];
+        for msg in msgs.iter() {
+            assert_eq!(Some(msg), read_message(&mut rdr).unwrap().as_ref());
+        }
+        assert_eq!(None, read_message(&mut rdr).unwrap());
+        assert!(Message::from_value(Value::from(1)).is_err());
+        assert!(Message::from_value(Value::Array(vec![Value::from(3)])).is_err());
+    }
+
+    /// Start a server on a socket in a fresh directory, returning both.
+    fn start(name: &str) -> (PathBuf, PathBuf) {
+        let dir = std::env::temp_dir().join(format!(
+            "search-history-test-{}-{}",
+            std::process::id(),
+            name
+        ));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        let socket = dir.join("server.sock");
+        let listener = bind(&socket).unwrap();
+        // Explicit, so nothing depends on whether the tests run on a tty.
+        let low_args = crate::flags::LowArgs {
+            line_number: Some(true),
+            ..Default::default()
+        };
+        let handler = SearchHandler::new(low_args, Notifier::none(), None);
+        std::thread::spawn(move || serve(listener, handler));
+        (dir, socket)
+    }
+
+    fn string<'a>(value: &'a Value, key: &str) -> &'a str {
+        map_get(value, key).and_then(|v| v.as_str()).unwrap()
+    }
+
+    #[test]
+    fn responses_go_to_their_client() {
+        let (_, socket) = start("routing");
+        assert!(bind(&socket).is_err());
+
+        let clients: Vec<_> = ["a", "b", "c"]
+            .into_iter()
+            .map(|name| {
+                let mut client = Client::connect(&socket).unwrap();
+                std::thread::spawn(move || {
+                    for i in 0..50 {
+                        let id = format!("{name}-{i}");
+                        let args = Value::Map(vec![(
+                            Value::from("id"),
+                            Value::from(id.as_str()),
+                        )]);
+                        let err =
+                            client.call("query", vec![args]).unwrap().unwrap_err();
+                        assert_eq!("not_found", string(&err, "kind"));
+                        assert!(string(&err, "message").contains(&id));
+                    }
+                })
+            })
+            .collect();
+        for client in clients {
+            client.join().unwrap();
+        }
+    }
+
+    #[test]
+    fn clients_share_the_store() {
+        let (dir, socket) = start("shared");
+        std::fs::write(dir.join("a.txt"), "foo\nbar\nfoo\n").unwrap();
+
+        let mut a = Client::connect(&socket).unwrap();
+        let args = json_to_value(serde_json::json!({
+            "id": "shared",
+            "pattern": "foo",
+            "paths": [dir.join("a.txt").to_str().unwrap()],
+        }));
+        let result = a.call("search", vec![args]).unwrap().unwrap();
+        assert_eq!("shared", string(&result, "id"));
+        // The events of a search go to the client that started it.
+        loop {
+            let (pattern, data) = a.next_event().unwrap();
+            if pattern == Event::Done.pattern() {
+                assert_eq!(Some(&Value::from(2)), map_get(&data, "count"));
+                break;
+            }
+        }
+
+        let mut b = Client::connect(&socket).unwrap();
+        let args = json_to_value(serde_json::json!({"id": "shared"}));
+        let page = b.call("query", vec![args]).unwrap().unwrap();
+        assert_eq!(Some(&Value::from(2)), map_get(&page, "total"));
+        let history = b.call("list_history", vec![]).unwrap().unwrap();
+        assert_eq!(1, history.as_array().unwrap().len());
+    }
+
+    #[test]
+    fn refresh_reports_changes() {
+        let (dir, socket) = start("refresh");
+        std::fs::write(dir.join("a.txt"), "foo\nbar\nfoo\n").unwrap();
+        std::fs::write(dir.join("b.txt"), "foo\n").unwrap();
+
+        let mut client = Client::connect(&socket).unwrap();
+        let done = |client: &mut Client| loop {
+            let (pattern, data) = client.next_event().unwrap();
+            if pattern == Event::Done.pattern() {
+                return data;
+            }
+        };
+        let args = json_to_value(serde_json::json!({
+            "id": "calls",
+            "pattern": "foo",
+            "paths": [dir.to_str().unwrap()],
+        }));
+        client.call("search", vec![args]).unwrap().unwrap();
+        assert_eq!(Some(&Value::from(3)), map_get(&done(&mut client), "count"));
+
+        // One call site is gone and the other moved up a line.
+        std::fs::write(dir.join("a.txt"), "bar\nfoo\n").unwrap();
+        let args = json_to_value(serde_json::json!({"id": "calls"}));
+        let result = client.call("refresh", vec![args]).unwrap().unwrap();
+        assert_eq!("calls", string(&result, "id"));
+        let data = done(&mut client);
+        assert_eq!(Some(&Value::from(2)), map_get(&data, "count"));
+        let diff = map_get(&data, "diff").unwrap();
+        let len = |key| map_get(diff, key).unwrap().as_array().unwrap().len();
+        assert_eq!((0, 1, 1), (len("added"), len("removed"), len("moved")));
+        let moved = &map_get(diff, "moved").unwrap().as_array().unwrap()[0];
+        let line = |key| map_get(map_get(moved, key).unwrap(), "line").cloned();
+        assert_eq!(Some(Value::from(1)), line("from"));
+        assert_eq!(Some(Value::from(2)), line("to"));
+
+        let missing = json_to_value(serde_json::json!({"id": "nope"}));
+        assert!(client.call("refresh", vec![missing]).unwrap().is_err());
+    }
+
+    #[test]
+    fn quickfix_sets_the_list() {
+        let (dir, socket) = start("quickfix");
+        std::fs::write(dir.join("a.txt"), "foo\nbar\nfoo\n").unwrap();
+
+        let mut client = Client::connect(&socket).unwrap();
+        let args = json_to_value(serde_json::json!({
+            "id": "qf",
+            "pattern": "foo",
+            "paths": [dir.join("a.txt").to_str().unwrap()],
+        }));
+        client.call("search", vec![args]).unwrap().unwrap();
+        while client.next_event().unwrap().0 != Event::Done.pattern() {}
+
+        let args = json_to_value(serde_json::json!({"id": "qf", "max": 1}));
+        let result = client.call("quickfix", vec![args]).unwrap().unwrap();
+        assert_eq!(Some(&Value::from(1)), map_get(&result, "count"));
+        assert_eq!(Some(&Value::from(2)), map_get(&result, "total"));
+        // The list is set with a call the client makes no reply to.
+        let params = loop {
+            let params = match client.events.pop_front() {
+                Some(params) => params,
+                None => match client.read().unwrap() {
+                    Message::Notification { method, params }
+                        if method == "nvim_call_function" =>
+                    {
+                        params
+                    }
+                    _ => continue,
+                },
+            };
+            if params[0].as_str() == Some("setqflist") {
+                break params;
+            }
+        };
+        let call = params[1].as_array().unwrap();
+        let items = map_get(&call[2], "items").unwrap().as_array().unwrap();
+        assert_eq!(1, items.len());
+        assert_eq!(Some(&Value::from(1)), map_get(&items[0], "lnum"));
+        assert_eq!("qf: foo", string(&call[2], "title"));
+    }
+
+    #[test]
+    fn stats_of_a_search() {
+        let (dir, socket) = start("stats");
+        let tree = dir.join("tree");
+        std::fs::create_dir_all(&tree).unwrap();
+        std::fs::write(tree.join("a.txt"), "foo\n").unwrap();
+        std::fs::write(tree.join("b.bin"), "\0foo\n").unwrap();
+        std::fs::write(tree.join("c.log"), "foo\n").unwrap();
+        std::fs::write(tree.join(".ignore"), "*.log\n").unwrap();
+
+        let mut client = Client::connect(&socket).unwrap();
+        let args = json_to_value(serde_json::json!({
+            "id": "s",
+            "pattern": "foo",
+            "paths": [tree.to_str().unwrap()],
+        }));
+        client.call("search", vec![args]).unwrap().unwrap();
+        while client.next_event().unwrap().0 != Event::Done.pattern() {}
+
+        let args = json_to_value(serde_json::json!({"id": "s"}));
+        let result = client.call("stats", vec![args]).unwrap().unwrap();
+        let searches = map_get(&result, "searches").unwrap();
+        assert_eq!(Some(&Value::from(1)), map_get(searches, "count"));
+        let stats = map_get(&result, "stats").unwrap().as_array().unwrap();
+        assert_eq!(1, stats.len());
+        assert_eq!(Some(&Value::from(1)), map_get(&stats[0], "searched"));
+        let skipped = map_get(&stats[0], "skipped").unwrap();
+        assert_eq!(Some(&Value::from(1)), map_get(skipped, "binary"));
+        // The .ignore file itself is hidden.
+        assert_eq!(Some(&Value::from(2)), map_get(skipped, "ignored"));
+        assert!(map_get(&stats[0], "elapsed_ms").unwrap().as_f64().is_some());
+
+        let args = json_to_value(serde_json::json!({"id": "nope"}));
+        let err = client.call("stats", vec![args]).unwrap().unwrap_err();
+        assert_eq!("not_found", string(&err, "kind"));
+    }
+
+    #[test]
+    fn live_session() {
+        let (dir, socket) = start("live");
+        let tree = dir.join("tree");
+        std::fs::create_dir_all(&tree).unwrap();
+        std::fs::write(tree.join("a.txt"), "wp_query\n").unwrap();
+        std::fs::write(tree.join("b.txt"), "wp_q\n").unwrap();
+
+        let mut client = Client::connect(&socket).unwrap();
+        // A search named like the session, which the session leaves alone.
+        let args = json_to_value(serde_json::json!({
+            "id": "prompt",
+            "pattern": "wp_query",
+            "paths": [tree.to_str().unwrap()],
+        }));
+        client.call("search", vec![args]).unwrap().unwrap();
+        while client.next_event().unwrap().0 != Event::Done.pattern() {}
+
+        let mut live = |pattern: &str, debounce: u64| {
+            let args = json_to_value(serde_json::json!({
+                "session": "prompt",
+                "pattern": pattern,
+                "paths": [tree.to_str().unwrap()],
+                "debounce": debounce,
+            }));
+            let result = client.call("live", vec![args]).unwrap().unwrap();
+            map_get(&result, "generation").and_then(|g| g.as_u64()).unwrap()
+        };
+        // Only the latest of several quick calls is searched.
+        assert_eq!(1, live("wp_q", 200));
+        assert_eq!(2, live("wp_qu", 200));
+        assert_eq!(3, live("wp_quer", 200));
+        let done = loop {
+            let (pattern, data) = client.next_event().unwrap();
+            if pattern == Event::Done.pattern() {
+                break data;
+            }
+        };
+        assert_eq!(Some(&Value::from(3)), map_get(&done, "generation"));
+        assert_eq!("wp_quer", string(&done, "pattern"));
+        assert_eq!(Some(&Value::from(1)), map_get(&done, "count"));
+
+        // Narrowed down to the files "wp_quer" matched.
+        let mut live = |pattern: &str| {
+            let args = json_to_value(serde_json::json!({
+                "session": "prompt",
+                "pattern": pattern,
+                "paths": [tree.to_str().unwrap()],
+                "debounce": 0,
+            }));
+            client.call("live", vec![args]).unwrap().unwrap();
+            loop {
+                let (event, data) = client.next_event().unwrap();
+                if event == Event::Done.pattern() {
+                    return data;
+                }
+            }
+        };
+        let done = live("wp_query");
+        assert_eq!(Some(&Value::from(true)), map_get(&done, "narrowed"));
+        assert_eq!(Some(&Value::from(1)), map_get(&done, "count"));
+        let done = live("wp_.");
+        assert_eq!(Some(&Value::from(false)), map_get(&done, "narrowed"));
+        assert_eq!(Some(&Value::from(2)), map_get(&done, "count"));
+
+        // The latest search is stored under the session's own id.
+        let args = json_to_value(serde_json::json!({"id": "live:prompt"}));
+        let page = client.call("query", vec![args]).unwrap().unwrap();
+        assert_eq!(Some(&Value::from(2)), map_get(&page, "total"));
+        let args = json_to_value(serde_json::json!({"id": "prompt"}));
+        let page = client.call("query", vec![args]).unwrap().unwrap();
+        assert_eq!(Some(&Value::from(1)), map_get(&page, "total"));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn errors_of_a_search() {
+        let (dir, socket) = start("errors");
+        let tree = dir.join("tree");
+        std::fs::create_dir_all(&tree).unwrap();
+        std::fs::write(tree.join("a.txt"), "foo\n").unwrap();
+        std::os::unix::fs::symlink(tree.join("gone"), tree.join("link"))
+            .unwrap();
+
+        let mut client = Client::connect(&socket).unwrap();
+        let args = json_to_value(serde_json::json!({
+            "id": "s",
+            "pattern": "foo",
+            "paths": [tree.to_str().unwrap()],
+            "options": {"follow": true},
+        }));
+        client.call("search", vec![args]).unwrap().unwrap();
+        while client.next_event().unwrap().0 != Event::Done.pattern() {}
+
+        let args = json_to_value(serde_json::json!({"id": "s"}));
+        let result = client.call("query_errors", vec![args]).unwrap().unwrap();
+        assert_eq!(Some(&Value::from(1)), map_get(&result, "total"));
+        let errors = map_get(&result, "errors").unwrap().as_array().unwrap();
+        assert_eq!("broken_symlink", string(&errors[0], "cause"));
+        assert!(string(&errors[0], "path").ends_with("link"));
+
+        let args =
+            json_to_value(serde_json::json!({"id": "s", "cause": "decode"}));
+        let result = client.call("query_errors", vec![args]).unwrap().unwrap();
+        assert_eq!(Some(&Value::from(0)), map_get(&result, "total"));
+        assert_eq!(Some(&Value::from(1)), map_get(&result, "unfiltered"));
+        let errors = map_get(&result, "errors").unwrap().as_array().unwrap();
+        assert!(errors.is_empty());
+
+        let args = json_to_value(serde_json::json!({"id": "nope"}));
+        let err = client.call("query_errors", vec![args]).unwrap().unwrap_err();
+        assert_eq!("not_found", string(&err, "kind"));
+    }
+
+    #[test]
+    fn json() {
+        let json: serde_json::Value = serde_json::from_str(
+            r#"{"pattern": "foo", "paths": ["a", "b"], "limit": 5, "x": null}"#,
+        )
+        .unwrap();
+        assert_eq!(json, value_to_json(&json_to_value(json.clone())));
+    }
+
+    #[test]
+    fn mode_args() {
+        let args = |args: &[&str]| -> Vec<std::ffi::OsString> {
+            args.iter().map(|a| a.into()).collect()
+        };
+        let serve = parse_serve(&args(&["--socket", "/tmp/s", "-i"])).unwrap();
+        assert_eq!(PathBuf::from("/tmp/s"), serve.socket);
+        assert_eq!(args(&["-i"]), serve.rest);
+        assert!(parse_serve(&args(&["-i"])).is_err());
+
+        let client = parse_client(&args(&[
+            "--socket=/tmp/s",
+            "--wait",
+            "search",
+            r#"{"pattern":"x"}"#,
+        ]))
+        .unwrap();
+        assert_eq!(PathBuf::from("/tmp/s"), client.socket);
+        assert!(client.wait);
+        assert_eq!("search", client.method);
+        assert_eq!(Some(r#"{"pattern":"x"}"#.to_string()), client.args);
+        assert!(parse_client(&args(&["--socket", "/tmp/s"])).is_err());
+    }
+}
@@ -0,0 +1,171 @@
+/*!
+Records where the time of a search went and which files it skipped, for the
+`stats` RPC.
+
+A search runs on every walker thread at once, so its time is split up per
+thread and summed across threads:
+
+* `walk` is the time a thread spent outside of handling a file, i.e.,
+  reading directories, matching ignore rules and waiting for work.
+* `search` is the time spent searching files.
+* `collect` is the time spent moving a thread's buffered results, stamps and
+  errors into the shared ones, including waiting for the lock on them.
+
+Together they come to about `elapsed` times the number of threads. A search
+that's slow because of the disk spends its time walking and searching, while
+one held up by the server spends it collecting.
+*/
+
+use std::{
+    sync::atomic::{AtomicU64, Ordering},
+    time::Duration,
+};
+
+/// A part of a search that time is spent in.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub(crate) enum Phase {
+    Walk,
+    Search,
+    Collect,
+}
+
+/// Why a file wasn't searched.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub(crate) enum Skip {
+    /// The file has binary data, so the search gave up on it.
+    Binary,
+    /// The file is bigger than `--max-filesize`.
+    MaxFilesize,
+    /// The file matched an ignore rule or is hidden.
+    Ignored,
+}
+
+impl From<ignore::SkipReason> for Skip {
+    fn from(why: ignore::SkipReason) -> Skip {
+        match why {
+            ignore::SkipReason::Ignored => Skip::Ignored,
+            ignore::SkipReason::MaxFilesize => Skip::MaxFilesize,
+        }
+    }
+}
+
+/// The counters of a search while it runs, shared by its walker threads.
+#[derive(Debug, Default)]
+pub(crate) struct Recorder {
+    walk: AtomicU64,
+    search: AtomicU64,
+    collect: AtomicU64,
+    searched: AtomicU64,
+    binary: AtomicU64,
+    max_filesize: AtomicU64,
+    ignored: AtomicU64,
+    errors: AtomicU64,
+}
+
+impl Recorder {
+    /// Add time spent in `phase` by one thread.
+    pub(crate) fn spent(&self, phase: Phase, time: Duration) {
+        let nanos = u64::try_from(time.as_nanos()).unwrap_or(u64::MAX);
+        let counter = match phase {
+            Phase::Walk => &self.walk,
+            Phase::Search => &self.search,
+            Phase::Collect => &self.collect,
+        };
+        counter.fetch_add(nanos, Ordering::Relaxed);
+    }
+
+    /// Count a file that was searched.
+    pub(crate) fn searched(&self) {
+        self.searched.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Count a file that wasn't searched.
+    pub(crate) fn skipped(&self, why: Skip) {
+        let counter = match why {
+            Skip::Binary => &self.binary,
+            Skip::MaxFilesize => &self.max_filesize,
+            Skip::Ignored => &self.ignored,
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Count a file, or directory, that couldn't be read.
+    pub(crate) fn error(&self) {
+        self.errors.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// The stats of the search, which took `elapsed` from start to end.
+    pub(crate) fn finish(&self, elapsed: Duration) -> SearchStats {
+        let time = |counter: &AtomicU64| {
+            Duration::from_nanos(counter.load(Ordering::Relaxed))
+        };
+        let count = |counter: &AtomicU64| counter.load(Ordering::Relaxed);
+        SearchStats {
+            elapsed,
+            walk: time(&self.walk),
+            search: time(&self.search),
+            collect: time(&self.collect),
+            searched: count(&self.searched),
+            binary: count(&self.binary),
+            max_filesize: count(&self.max_filesize),
+            ignored: count(&self.ignored),
+            errors: count(&self.errors),
+        }
+    }
+}
+
+/// What a search spent its time on and which files it skipped.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub(crate) struct SearchStats {
+    /// The wall clock time from the start of the search to its end.
+    pub(crate) elapsed: Duration,
+    /// The time spent walking, summed across threads.
+    pub(crate) walk: Duration,
+    /// The time spent searching files, summed across threads.
+    pub(crate) search: Duration,
+    /// The time spent collecting results, summed across threads.
+    pub(crate) collect: Duration,
+    /// The number of files searched, not counting binary ones.
+    pub(crate) searched: u64,
+    /// The number of files given up on as binary.
+    pub(crate) binary: u64,
+    /// The number of files bigger than `--max-filesize`.
+    pub(crate) max_filesize: u64,
+    /// The number of files and directories ignored.
+    pub(crate) ignored: u64,
+    /// The number of files and directories that couldn't be read.
+    pub(crate) errors: u64,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn recorder() {
+        let recorder = Recorder::default();
+        recorder.spent(Phase::Walk, Duration::from_millis(3));
+        recorder.spent(Phase::Walk, Duration::from_millis(4));
+        recorder.spent(Phase::Collect, Duration::from_micros(5));
+        recorder.searched();
+        recorder.searched();
+        recorder.skipped(Skip::Binary);
+        recorder.skipped(ignore::SkipReason::Ignored.into());
+        recorder.error();
+
+        let stats = recorder.finish(Duration::from_millis(10));
+        assert_eq!(Duration::from_millis(7), stats.walk);
+        assert_eq!(Duration::ZERO, stats.search);
+        assert_eq!(Duration::from_micros(5), stats.collect);
+        assert_eq!(
+            (2, 1, 0, 1, 1),
+            (
+                stats.searched,
+                stats.binary,
+                stats.max_filesize,
+                stats.ignored,
+                stats.errors,
+            )
+        );
+    }
+}
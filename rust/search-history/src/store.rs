@@ -0,0 +1,512 @@
+/*!
+Defines the search store, the server's history of past searches.
+
+Every search is stored under its own id together with what was searched for
+and where, so that clients can list earlier searches and page through their
+results again. The store is bounded both by the number of searches and by the
+approximate number of bytes their results take up. When either bound is
+exceeded, the searches that were least recently queried are evicted first.
+*/
+
+use std::{
+    collections::HashMap,
+    path::PathBuf,
+    time::SystemTime,
+};
+
+use neovim_lib::Value;
+
+use crate::{
+    combine::Combination, errors::FileError, refine::Refinement,
+    refresh::FileStamps, search::SearchResults, stats::SearchStats,
+};
+
+/// What a stored search was asked to do.
+///
+/// This is kept separately from the results so that a search can be shown
+/// in the history, and re-run, without looking at its results.
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) struct SearchQuery {
+    /// The pattern that was searched for.
+    pub(crate) pattern: String,
+    /// The paths that were searched, as given by the client.
+    pub(crate) paths: Vec<PathBuf>,
+    /// The ripgrep command line the search was given as, if any. When this
+    /// is set, `pattern` and `paths` were taken from it.
+    pub(crate) args: Option<String>,
+    /// The per-request options given by the client, as they were sent. This
+    /// is `Value::Nil` when the search used only the server's own flags.
+    pub(crate) options: Value,
+    /// How this search was narrowed down from an earlier one, if it was made
+    /// with `refine` rather than `search`.
+    pub(crate) refine: Option<Refinement>,
+    /// Which searches this search was combined from, if it was made with
+    /// `combine`.
+    pub(crate) combine: Option<Combination>,
+}
+
+/// A single search in the history.
+#[derive(Clone, Debug)]
+pub(crate) struct SearchRecord {
+    id: String,
+    query: SearchQuery,
+    root: PathBuf,
+    created: SystemTime,
+    results: SearchResults,
+    files: FileStamps,
+    errors: Vec<FileError>,
+    stats: Option<SearchStats>,
+    complete: bool,
+    bytes: usize,
+    last_used: u64,
+}
+
+impl SearchRecord {
+    /// Create a new record for a search that was run from `root`.
+    pub(crate) fn new(
+        id: String,
+        query: SearchQuery,
+        root: PathBuf,
+        results: SearchResults,
+    ) -> SearchRecord {
+        let bytes = results.approx_bytes();
+        SearchRecord {
+            id,
+            query,
+            root,
+            created: SystemTime::now(),
+            results,
+            files: FileStamps::new(),
+            errors: vec![],
+            stats: None,
+            complete: true,
+            bytes,
+            last_used: 0,
+        }
+    }
+
+    /// Rebuild a record that was previously stored, e.g., read back from disk.
+    pub(crate) fn restore(
+        id: String,
+        query: SearchQuery,
+        root: PathBuf,
+        created: SystemTime,
+        complete: bool,
+        results: SearchResults,
+    ) -> SearchRecord {
+        SearchRecord {
+            created,
+            complete,
+            ..SearchRecord::new(id, query, root, results)
+        }
+    }
+
+    /// Set the stamps of the files the search searched, which `refresh`
+    /// uses to skip the files that haven't changed since.
+    pub(crate) fn with_files(mut self, files: FileStamps) -> SearchRecord {
+        self.files = files;
+        self.count_bytes();
+        self
+    }
+
+    /// Set when the search was made. A search that was run is stamped with
+    /// when it started, since `refresh` only trusts file stamps from before
+    /// then.
+    pub(crate) fn with_created(mut self, created: SystemTime) -> SearchRecord {
+        self.created = created;
+        self
+    }
+
+    /// Set the files the search failed on.
+    pub(crate) fn with_errors(mut self, errors: Vec<FileError>) -> SearchRecord {
+        self.errors = errors;
+        self.count_bytes();
+        self
+    }
+
+    /// Set where the time of the search went and which files it skipped.
+    pub(crate) fn with_stats(mut self, stats: SearchStats) -> SearchRecord {
+        self.stats = Some(stats);
+        self
+    }
+
+    /// The id this search is stored under.
+    pub(crate) fn id(&self) -> &str {
+        &self.id
+    }
+
+    /// What this search was asked to do.
+    pub(crate) fn query(&self) -> &SearchQuery {
+        &self.query
+    }
+
+    /// The directory the server was running in when the search was made.
+    /// Relative search paths are relative to this directory.
+    pub(crate) fn root(&self) -> &PathBuf {
+        &self.root
+    }
+
+    /// When the search was made.
+    pub(crate) fn created(&self) -> SystemTime {
+        self.created
+    }
+
+    /// The results of the search.
+    pub(crate) fn results(&self) -> &SearchResults {
+        &self.results
+    }
+
+    /// The stamps of the files the search searched, keyed by path. This is
+    /// empty for searches that didn't walk any files themselves, like
+    /// combined searches.
+    pub(crate) fn files(&self) -> &FileStamps {
+        &self.files
+    }
+
+    /// The files, and directories, the search failed on.
+    pub(crate) fn errors(&self) -> &[FileError] {
+        &self.errors
+    }
+
+    /// Where the time of the search went and which files it skipped. Only
+    /// searches that walked files in this process have stats, so searches
+    /// read back from the history file don't.
+    pub(crate) fn stats(&self) -> Option<&SearchStats> {
+        self.stats.as_ref()
+    }
+
+    /// Whether the search ran to completion. This is false when the search
+    /// was cancelled, in which case its results are only partial.
+    pub(crate) fn complete(&self) -> bool {
+        self.complete
+    }
+
+    /// Mark whether the search ran to completion.
+    pub(crate) fn set_complete(&mut self, yes: bool) {
+        self.complete = yes;
+    }
+
+    /// The approximate number of bytes used by the results of this search.
+    pub(crate) fn bytes(&self) -> usize {
+        self.bytes
+    }
+
+    /// Count the bytes used by the results, file stamps and errors again.
+    fn count_bytes(&mut self) {
+        self.bytes = self.results.approx_bytes()
+            + files_bytes(&self.files)
+            + self.errors.iter().map(FileError::approx_bytes).sum::<usize>();
+    }
+}
+
+/// The approximate number of bytes used by a set of file stamps.
+fn files_bytes(files: &FileStamps) -> usize {
+    files
+        .keys()
+        .map(|path| path.len() + std::mem::size_of::<(String, u64, u64)>())
+        .sum()
+}
+
+/// The bounds placed on a search store.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub(crate) struct StoreLimits {
+    /// The maximum number of searches kept.
+    pub(crate) max_entries: usize,
+    /// The maximum approximate number of bytes of results kept.
+    pub(crate) max_bytes: usize,
+}
+
+impl Default for StoreLimits {
+    fn default() -> StoreLimits {
+        StoreLimits { max_entries: 50, max_bytes: 256 * (1 << 20) }
+    }
+}
+
+impl StoreLimits {
+    /// Read the limits from the environment, falling back to the defaults.
+    ///
+    /// `SEARCH_HISTORY_MAX_ENTRIES` sets the number of searches and
+    /// `SEARCH_HISTORY_MAX_BYTES` sets the total size of their results.
+    /// Values that don't parse are logged and ignored.
+    pub(crate) fn from_env() -> StoreLimits {
+        fn read(name: &str) -> Option<usize> {
+            let value = std::env::var(name).ok()?;
+            match value.trim().parse::<usize>() {
+                Ok(n) => Some(n),
+                Err(err) => {
+                    log::warn!("ignoring {name}={value:?}: {err}");
+                    None
+                }
+            }
+        }
+        let mut limits = StoreLimits::default();
+        if let Some(n) = read("SEARCH_HISTORY_MAX_ENTRIES") {
+            limits.max_entries = n;
+        }
+        if let Some(n) = read("SEARCH_HISTORY_MAX_BYTES") {
+            limits.max_bytes = n;
+        }
+        limits
+    }
+}
+
+/// A bounded history of searches, keyed by search id.
+#[derive(Debug)]
+pub(crate) struct SearchStore {
+    search_store: HashMap<String, SearchRecord>,
+    limits: StoreLimits,
+    bytes: usize,
+    next_id: u64,
+    clock: u64,
+}
+
+impl SearchStore {
+    /// Create an empty store with the given limits.
+    pub(crate) fn new(limits: StoreLimits) -> SearchStore {
+        SearchStore {
+            search_store: HashMap::new(),
+            limits,
+            bytes: 0,
+            next_id: 0,
+            clock: 0,
+        }
+    }
+
+    /// Returns the id a new search is stored under. The id chosen by the
+    /// client wins, otherwise the next unused `search-N` id is handed out.
+    pub(crate) fn next_id(&mut self, requested: Option<String>) -> String {
+        if let Some(id) = requested {
+            return id;
+        }
+        loop {
+            self.next_id += 1;
+            let id = format!("search-{}", self.next_id);
+            if !self.search_store.contains_key(&id) {
+                return id;
+            }
+        }
+    }
+
+    /// Store a search, replacing any search with the same id.
+    ///
+    /// The new search counts as the most recently used one. If storing it
+    /// exceeds the store's limits, then the least recently queried searches
+    /// are evicted until the limits hold again. The new search itself is
+    /// never evicted, even if it alone exceeds the byte limit. The ids of the
+    /// evicted searches are returned.
+    pub(crate) fn insert(&mut self, mut record: SearchRecord) -> Vec<String> {
+        self.remove(&record.id);
+        record.last_used = self.tick();
+        self.bytes += record.bytes;
+        let id = record.id.clone();
+        self.search_store.insert(id.clone(), record);
+        self.evict(&id)
+    }
+
+    /// Replace the results of a stored search in place, e.g., after some of
+    /// its files changed on disk. Unlike `insert`, this doesn't count as
+    /// using the search. Returns false if there is no search with that id.
+    ///
+    /// If the new results exceed the store's limits, then other searches are
+    /// evicted the same way `insert` evicts them, but never this one.
+    pub(crate) fn update_results(
+        &mut self,
+        id: &str,
+        results: SearchResults,
+        files: FileStamps,
+        errors: Vec<FileError>,
+    ) -> bool {
+        let Some(record) = self.search_store.get_mut(id) else { return false };
+        self.bytes -= record.bytes;
+        record.results = results;
+        record.files = files;
+        record.errors = errors;
+        record.count_bytes();
+        self.bytes += record.bytes;
+        self.evict(id);
+        true
+    }
+
+    /// Look up a search and mark it as the most recently used one.
+    pub(crate) fn get(&mut self, id: &str) -> Option<&SearchRecord> {
+        let tick = self.tick();
+        let record = self.search_store.get_mut(id)?;
+        record.last_used = tick;
+        Some(record)
+    }
+
+    /// Look up a search without changing its position in the history.
+    pub(crate) fn peek(&self, id: &str) -> Option<&SearchRecord> {
+        self.search_store.get(id)
+    }
+
+    /// Remove a search, returning it if it was present.
+    pub(crate) fn remove(&mut self, id: &str) -> Option<SearchRecord> {
+        let record = self.search_store.remove(id)?;
+        self.bytes -= record.bytes;
+        Some(record)
+    }
+
+    /// Returns all stored searches, the most recently used first.
+    pub(crate) fn history(&self) -> Vec<&SearchRecord> {
+        let mut records: Vec<&SearchRecord> =
+            self.search_store.values().collect();
+        records.sort_by_key(|r| std::cmp::Reverse(r.last_used));
+        records
+    }
+
+    /// The number of stored searches.
+    pub(crate) fn len(&self) -> usize {
+        self.search_store.len()
+    }
+
+    /// The approximate number of bytes used by all stored results.
+    pub(crate) fn bytes(&self) -> usize {
+        self.bytes
+    }
+
+    /// Evict the least recently queried searches other than `keep` until
+    /// the store's limits hold again, returning their ids.
+    fn evict(&mut self, keep: &str) -> Vec<String> {
+        let mut evicted = vec![];
+        while self.search_store.len() > 1
+            && (self.search_store.len() > self.limits.max_entries
+                || self.bytes > self.limits.max_bytes)
+        {
+            let oldest = self
+                .search_store
+                .values()
+                .filter(|record| record.id != keep)
+                .min_by_key(|record| record.last_used)
+                .map(|record| record.id.clone());
+            match oldest {
+                Some(oldest) => {
+                    log::debug!("evicting search '{oldest}' from history");
+                    self.remove(&oldest);
+                    evicted.push(oldest);
+                }
+                None => break,
+            }
+        }
+        evicted
+    }
+
+    /// Advance the store's logical clock used to order searches by use.
+    fn tick(&mut self) -> u64 {
+        self.clock += 1;
+        self.clock
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::search::{SearchResult, SubMatch};
+
+    use super::*;
+
+    fn record(id: &str, lines: u64) -> SearchRecord {
+        let mut results = SearchResults::new();
+        for line in 1..=lines {
+            results.store_result(SearchResult::new(
+                Some("a.rs".to_string()),
+                line,
+                (line - 1) * 4,
+                b"foo\n".to_vec(),
+                SubMatch::from_ranges(b"foo\n", Some(0..3)),
+            ));
+        }
+        let query = SearchQuery {
+            pattern: "foo".to_string(),
+            paths: vec![PathBuf::from("./")],
+            args: None,
+            options: Value::Nil,
+            refine: None,
+            combine: None,
+        };
+        SearchRecord::new(id.to_string(), query, PathBuf::from("/"), results)
+    }
+
+    fn ids(store: &SearchStore) -> Vec<&str> {
+        store.history().into_iter().map(|r| r.id()).collect()
+    }
+
+    #[test]
+    fn next_id_skips_taken() {
+        let mut store = SearchStore::new(StoreLimits::default());
+        store.insert(record("search-1", 1));
+        assert_eq!("search-2", store.next_id(None));
+        assert_eq!("mine", store.next_id(Some("mine".to_string())));
+    }
+
+    #[test]
+    fn evict_by_entries() {
+        let limits = StoreLimits { max_entries: 2, max_bytes: usize::MAX };
+        let mut store = SearchStore::new(limits);
+        store.insert(record("a", 1));
+        store.insert(record("b", 1));
+        // Querying "a" makes "b" the least recently used search.
+        assert!(store.get("a").is_some());
+        assert_eq!(vec!["b".to_string()], store.insert(record("c", 1)));
+        assert_eq!(vec!["c", "a"], ids(&store));
+    }
+
+    #[test]
+    fn evict_by_bytes() {
+        let one = record("x", 10).bytes();
+        let limits = StoreLimits { max_entries: 100, max_bytes: one * 2 };
+        let mut store = SearchStore::new(limits);
+        store.insert(record("a", 10));
+        store.insert(record("b", 10));
+        assert_eq!(one * 2, store.bytes());
+        assert_eq!(vec!["a".to_string()], store.insert(record("c", 10)));
+        assert_eq!(one * 2, store.bytes());
+
+        // A single search bigger than the limit is still kept.
+        store.insert(record("big", 100));
+        assert_eq!(vec!["big"], ids(&store));
+    }
+
+    #[test]
+    fn replace_same_id() {
+        let mut store = SearchStore::new(StoreLimits::default());
+        store.insert(record("a", 10));
+        store.insert(record("a", 1));
+        assert_eq!(1, store.len());
+        assert_eq!(record("x", 1).bytes(), store.bytes());
+        assert_eq!(1, store.peek("a").unwrap().results().len());
+    }
+
+    #[test]
+    fn update_in_place() {
+        let mut store = SearchStore::new(StoreLimits::default());
+        store.insert(record("a", 1));
+        store.insert(record("b", 1));
+        let results = record("x", 10).results().clone();
+        assert!(store.update_results("a", results, FileStamps::new(), vec![]));
+        assert!(!store.update_results(
+            "c",
+            SearchResults::new(),
+            FileStamps::new(),
+            vec![]
+        ));
+        assert_eq!(10, store.peek("a").unwrap().results().len());
+        assert_eq!(record("x", 10).bytes() + record("x", 1).bytes(), store.bytes());
+        // Updating isn't using, "b" is still the most recently used search.
+        assert_eq!(vec!["b", "a"], ids(&store));
+    }
+
+    #[test]
+    fn update_evicts_others() {
+        let one = record("x", 10).bytes();
+        let limits = StoreLimits { max_entries: 100, max_bytes: one * 3 };
+        let mut store = SearchStore::new(limits);
+        store.insert(record("a", 10));
+        store.insert(record("b", 10));
+        store.insert(record("c", 10));
+        // "a" is the least recently used search, but it's the one updated.
+        let results = record("x", 20).results().clone();
+        assert!(store.update_results("a", results, FileStamps::new(), vec![]));
+        assert_eq!(vec!["c", "a"], ids(&store));
+        assert_eq!(record("x", 20).bytes() + one, store.bytes());
+    }
+}
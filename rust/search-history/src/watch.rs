@@ -0,0 +1,482 @@
+/*!
+Keeps stored searches up to date as the files they searched change.
+
+Stored results are a snapshot of the files at the time they were searched,
+so after an edit their line numbers no longer line up. When the server is
+built with the `watch` feature on Linux, it watches every directory a stored
+search found files to search in with inotify. Those directories came out of
+the search's own walk, so the ignore rules that decided what was searched
+also decide what is watched, and ignored directories like `target` or
+`node_modules` don't cost a single watch.
+
+When files in a watched directory change, the server searches just those
+files again for every stored search that covers them, and tells clients
+which searches changed. A new file is covered if a walk of its directory
+with the search's flags would find it. Without the feature, or on other
+platforms, nothing is watched and stored searches only change when they're
+refreshed.
+*/
+
+use std::{
+    collections::{HashMap, HashSet},
+    path::{Path, PathBuf},
+};
+
+use crate::{
+    refresh::FileStamps,
+    search::{SearchResult, SearchResults},
+};
+
+/// A handle to the file watcher, shared by every clone of the search
+/// handler.
+#[derive(Clone)]
+pub(crate) struct Watcher {
+    imp: std::sync::Arc<imp::Watcher>,
+}
+
+impl Watcher {
+    /// Set up a watcher.
+    ///
+    /// This returns `None` when watching isn't supported, i.e., without the
+    /// `watch` feature or on a platform other than Linux, or when it fails to
+    /// set up. Failures are logged.
+    pub(crate) fn new() -> Option<Watcher> {
+        let imp = imp::Watcher::new()?;
+        Some(Watcher { imp: std::sync::Arc::new(imp) })
+    }
+
+    /// Watch the given directories, which must be absolute. Directories that
+    /// are already watched are skipped.
+    pub(crate) fn watch_dirs(&self, dirs: impl IntoIterator<Item = PathBuf>) {
+        self.imp.watch_dirs(&mut dirs.into_iter());
+    }
+
+    /// Start reporting changed files on a thread of its own.
+    ///
+    /// `on_change` is called with the absolute paths of files that were
+    /// written, created, moved or deleted in a watched directory. Events that
+    /// arrive close together are reported together, so an editor's save is
+    /// reported once. Only the first call starts a thread.
+    pub(crate) fn spawn(
+        &self,
+        on_change: impl FnMut(Vec<PathBuf>) + Send + 'static,
+    ) {
+        self.imp.spawn(Box::new(on_change));
+    }
+}
+
+/// The directories to watch for the files a search searched, made absolute
+/// against `root`, the directory the search ran in.
+pub(crate) fn dirs_of(root: &Path, files: &FileStamps) -> Vec<PathBuf> {
+    let mut seen = HashSet::new();
+    let mut dirs = vec![];
+    for path in files.keys() {
+        if let Some(dir) = root.join(path).parent() {
+            if seen.insert(dir.to_path_buf()) {
+                dirs.push(dir.to_path_buf());
+            }
+        }
+    }
+    dirs
+}
+
+/// Which files of a stored search are affected by a set of changes.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub(crate) struct Affected {
+    /// Files the search searched that changed, as the search reports them.
+    pub(crate) changed: Vec<String>,
+    /// Files the search didn't search that appeared in a directory it did
+    /// search in, as the search would report them. Whether the search covers
+    /// them depends on its flags, which is for the caller to check.
+    pub(crate) candidates: Vec<String>,
+}
+
+impl Affected {
+    /// Returns true if the search isn't affected at all.
+    pub(crate) fn is_empty(&self) -> bool {
+        self.changed.is_empty() && self.candidates.is_empty()
+    }
+}
+
+/// Work out how the changed files at the absolute `paths` affect a stored
+/// search.
+///
+/// `root` is the directory the search ran in, `roots` are the paths it
+/// searched, relative to `root`, and `files` are the stamps of the files it
+/// searched. New files only count as candidates when they're inside one of
+/// the searched directories. Files given explicitly as roots never have new
+/// neighbours.
+pub(crate) fn affected(
+    root: &Path,
+    roots: &[PathBuf],
+    files: &FileStamps,
+    paths: &[PathBuf],
+) -> Affected {
+    // Paths are compared made absolute against `root`, so `./src/a.rs` and
+    // `src/a.rs` are the same file.
+    let by_path: HashMap<PathBuf, &str> =
+        files.keys().map(|key| (root.join(key), key.as_str())).collect();
+    let mut by_dir: HashMap<PathBuf, &Path> = HashMap::new();
+    for key in files.keys() {
+        if let (Some(dir), Some(reported)) =
+            (root.join(key).parent(), Path::new(key).parent())
+        {
+            by_dir.entry(dir.to_path_buf()).or_insert(reported);
+        }
+    }
+    let dirs: Vec<PathBuf> = roots
+        .iter()
+        .map(|path| root.join(path))
+        .filter(|path| path.is_dir())
+        .collect();
+
+    let mut affected = Affected::default();
+    for path in paths.iter() {
+        if let Some(key) = by_path.get(path) {
+            affected.changed.push(key.to_string());
+            continue;
+        }
+        let (Some(dir), Some(name)) = (path.parent(), path.file_name()) else {
+            continue;
+        };
+        let Some(reported) = by_dir.get(dir) else { continue };
+        if dirs.iter().any(|d| path.starts_with(d) && path != d) {
+            let key = reported.join(name).to_string_lossy().into_owned();
+            affected.candidates.push(key);
+        }
+    }
+    affected
+}
+
+/// The results of a search after the files named by `keys` were searched
+/// again, finding `found`.
+///
+/// The new results of each file take the place of its previous ones, so
+/// paging through the search still shows each file's results together.
+/// Results in files that had none before come last.
+pub(crate) fn splice(
+    previous: &SearchResults,
+    keys: &[String],
+    found: SearchResults,
+) -> SearchResults {
+    let keys: HashSet<&str> = keys.iter().map(|key| key.as_str()).collect();
+    let mut by_file: HashMap<String, Vec<SearchResult>> = HashMap::new();
+    let mut order = vec![];
+    for result in found.iter() {
+        let name = result.get_file_name().unwrap_or_default().to_string();
+        if !by_file.contains_key(&name) {
+            order.push(name.clone());
+        }
+        by_file.entry(name).or_default().push(result.clone());
+    }
+
+    let mut results = SearchResults::new();
+    for result in previous.iter() {
+        let Some(name) = result.get_file_name() else {
+            results.store_result(result.clone());
+            continue;
+        };
+        if !keys.contains(name) {
+            results.store_result(result.clone());
+            continue;
+        }
+        for result in by_file.remove(name).unwrap_or_default() {
+            results.store_result(result);
+        }
+    }
+    for name in order.iter() {
+        for result in by_file.remove(name).unwrap_or_default() {
+            results.store_result(result);
+        }
+    }
+    results
+}
+
+#[cfg(all(feature = "watch", target_os = "linux"))]
+mod imp {
+    use std::{
+        collections::{HashMap, HashSet},
+        ffi::OsStr,
+        io,
+        path::PathBuf,
+        sync::{Arc, Mutex},
+        time::Duration,
+    };
+
+    use inotify::{EventMask, Inotify, WatchDescriptor, WatchMask, Watches};
+
+    /// How long to wait for more events after the first one. Editors often
+    /// save by writing a temporary file and renaming it over the original,
+    /// which shouldn't search the file twice.
+    const SETTLE: Duration = Duration::from_millis(50);
+
+    pub(super) struct Watcher {
+        /// Taken by the thread reading events once it's started.
+        inotify: Mutex<Option<Inotify>>,
+        watches: Mutex<Watches>,
+        dirs: Arc<Mutex<Dirs>>,
+    }
+
+    /// The watched directories.
+    #[derive(Default)]
+    struct Dirs {
+        by_wd: HashMap<WatchDescriptor, PathBuf>,
+        watched: HashSet<PathBuf>,
+        /// Set once a watch failed to be added, so that running out of
+        /// watches is only warned about once.
+        warned: bool,
+    }
+
+    impl Watcher {
+        pub(super) fn new() -> Option<Watcher> {
+            let inotify = match Inotify::init() {
+                Ok(inotify) => inotify,
+                Err(err) => {
+                    log::warn!("not watching files: {}", err);
+                    return None;
+                }
+            };
+            let watches = inotify.watches();
+            Some(Watcher {
+                inotify: Mutex::new(Some(inotify)),
+                watches: Mutex::new(watches),
+                dirs: Arc::default(),
+            })
+        }
+
+        pub(super) fn watch_dirs(
+            &self,
+            dirs: &mut dyn Iterator<Item = PathBuf>,
+        ) {
+            let mask = WatchMask::CLOSE_WRITE
+                | WatchMask::MOVED_TO
+                | WatchMask::MOVED_FROM
+                | WatchMask::DELETE;
+            let mut state = self.dirs.lock().unwrap();
+            let mut watches = self.watches.lock().unwrap();
+            for dir in dirs {
+                if state.watched.contains(&dir) {
+                    continue;
+                }
+                match watches.add(&dir, mask) {
+                    Ok(wd) => {
+                        state.by_wd.insert(wd, dir.clone());
+                        state.watched.insert(dir);
+                    }
+                    // Most likely fs.inotify.max_user_watches was reached.
+                    Err(err) if !state.warned => {
+                        let dir = dir.display();
+                        log::warn!("failed to watch {}: {}", dir, err);
+                        state.warned = true;
+                    }
+                    Err(err) => {
+                        log::debug!(
+                            "failed to watch {}: {}",
+                            dir.display(),
+                            err
+                        );
+                    }
+                }
+            }
+        }
+
+        pub(super) fn spawn(
+            &self,
+            on_change: Box<dyn FnMut(Vec<PathBuf>) + Send>,
+        ) {
+            let Some(inotify) = self.inotify.lock().unwrap().take() else {
+                return;
+            };
+            let dirs = Arc::clone(&self.dirs);
+            let spawned = std::thread::Builder::new()
+                .name("search-history watcher".to_string())
+                .spawn(move || {
+                    if let Err(err) = run(inotify, &dirs, on_change) {
+                        log::warn!("stopped watching files: {}", err);
+                    }
+                });
+            if let Err(err) = spawned {
+                log::warn!("not watching files: {}", err);
+            }
+        }
+    }
+
+    /// Read events until reading fails, reporting the changed files.
+    fn run(
+        mut inotify: Inotify,
+        dirs: &Mutex<Dirs>,
+        mut on_change: Box<dyn FnMut(Vec<PathBuf>) + Send>,
+    ) -> io::Result<()> {
+        let mut buffer = [0; 4096];
+        loop {
+            let mut changed = vec![];
+            let events = inotify.read_events_blocking(&mut buffer)?;
+            collect(dirs, events, &mut changed);
+            std::thread::sleep(SETTLE);
+            loop {
+                match inotify.read_events(&mut buffer) {
+                    Ok(events) => {
+                        if collect(dirs, events, &mut changed) == 0 {
+                            break;
+                        }
+                    }
+                    Err(err) if err.kind() == io::ErrorKind::WouldBlock => {
+                        break;
+                    }
+                    Err(err) => return Err(err),
+                }
+            }
+            if !changed.is_empty() {
+                on_change(changed);
+            }
+        }
+    }
+
+    /// Add the files named by `events` to `changed`, returning the number
+    /// of events read.
+    fn collect<'a>(
+        dirs: &Mutex<Dirs>,
+        events: impl Iterator<Item = inotify::Event<&'a OsStr>>,
+        changed: &mut Vec<PathBuf>,
+    ) -> usize {
+        let mut dirs = dirs.lock().unwrap();
+        let mut count = 0;
+        for event in events {
+            count += 1;
+            // The directory itself went away, its watch is gone with it.
+            if event.mask.contains(EventMask::IGNORED) {
+                if let Some(dir) = dirs.by_wd.remove(&event.wd) {
+                    dirs.watched.remove(&dir);
+                }
+                continue;
+            }
+            if event.mask.contains(EventMask::ISDIR) {
+                continue;
+            }
+            let Some(dir) = dirs.by_wd.get(&event.wd) else { continue };
+            let Some(name) = event.name else { continue };
+            let path = dir.join(name);
+            if !changed.contains(&path) {
+                changed.push(path);
+            }
+        }
+        count
+    }
+}
+
+#[cfg(not(all(feature = "watch", target_os = "linux")))]
+mod imp {
+    use std::path::PathBuf;
+
+    pub(super) struct Watcher(());
+
+    impl Watcher {
+        pub(super) fn new() -> Option<Watcher> {
+            None
+        }
+
+        pub(super) fn watch_dirs(&self, _: &mut dyn Iterator<Item = PathBuf>) {
+        }
+
+        pub(super) fn spawn(&self, _: Box<dyn FnMut(Vec<PathBuf>) + Send>) {}
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{refresh::FileStamp, search::SubMatch};
+
+    use super::*;
+
+    fn tempdir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!(
+            "search-history-test-{}-{}",
+            std::process::id(),
+            name
+        ));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(dir.join("src")).unwrap();
+        dir
+    }
+
+    fn stamps(keys: &[&str]) -> FileStamps {
+        keys.iter()
+            .map(|key| (key.to_string(), FileStamp { size: 1, mtime: 1 }))
+            .collect()
+    }
+
+    fn results(hits: &[(&str, u64)]) -> SearchResults {
+        let mut results = SearchResults::new();
+        for &(path, line) in hits.iter() {
+            results.store_result(SearchResult::new(
+                Some(path.to_string()),
+                line,
+                0,
+                b"foo\n".to_vec(),
+                SubMatch::from_ranges(b"foo\n", Some(0..3)),
+            ));
+        }
+        results
+    }
+
+    #[test]
+    fn affected_files() {
+        let root = tempdir("affected");
+        let files = stamps(&["./src/a.rs", "./b.rs"]);
+        let roots = [PathBuf::from("./")];
+        let paths = [
+            root.join("src/a.rs"),
+            root.join("src/new.rs"),
+            root.join("elsewhere/c.rs"),
+        ];
+        let got = affected(&root, &roots, &files, &paths);
+        assert_eq!(vec!["./src/a.rs".to_string()], got.changed);
+        assert_eq!(vec!["./src/new.rs".to_string()], got.candidates);
+        assert_eq!(
+            vec![root.join("src")],
+            dirs_of(&root, &stamps(&["./src/a.rs", "src/b.rs"]))
+        );
+
+        // A search of single files doesn't pick up their new neighbours.
+        let roots = [PathBuf::from("src/a.rs")];
+        let files = stamps(&["src/a.rs"]);
+        let got = affected(&root, &roots, &files, &paths);
+        assert_eq!(vec!["src/a.rs".to_string()], got.changed);
+        assert!(got.candidates.is_empty());
+    }
+
+    #[cfg(all(feature = "watch", target_os = "linux"))]
+    #[test]
+    fn reports_changes() {
+        let root = tempdir("watch");
+        std::fs::write(root.join("src/a.rs"), "foo\n").unwrap();
+        let watcher = Watcher::new().unwrap();
+        watcher.watch_dirs(dirs_of(&root, &stamps(&["src/a.rs"])));
+        let (send, recv) = std::sync::mpsc::channel();
+        watcher.spawn(move |paths| send.send(paths).unwrap());
+
+        std::fs::write(root.join("src/a.rs"), "bar\n").unwrap();
+        std::fs::write(root.join("src/b.rs"), "foo\n").unwrap();
+        std::fs::write(root.join("c.rs"), "foo\n").unwrap();
+        let timeout = std::time::Duration::from_secs(5);
+        let mut got = vec![];
+        while got.len() < 2 {
+            got.extend(recv.recv_timeout(timeout).unwrap());
+        }
+        got.sort();
+        assert_eq!(vec![root.join("src/a.rs"), root.join("src/b.rs")], got);
+    }
+
+    #[test]
+    fn splice_in_place() {
+        let previous = results(&[("a", 1), ("a", 2), ("b", 1), ("c", 1)]);
+        let found = results(&[("d", 4), ("a", 3)]);
+        let keys = ["a".to_string(), "c".to_string(), "d".to_string()];
+        let spliced = splice(&previous, &keys, found);
+        let got: Vec<(&str, u64)> = spliced
+            .iter()
+            .map(|r| (r.get_file_name().unwrap(), r.get_line_number()))
+            .collect();
+        assert_eq!(vec![("a", 3), ("b", 1), ("d", 4)], got);
+    }
+}